@@ -0,0 +1,79 @@
+//! Headless Bevy app that prints the server list as it changes
+//!
+//! A game would draw [`ServerBrowser`] in its UI instead, reacting to the same [`ServerEvent`]s.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use bevy_app::{App, ScheduleRunnerPlugin, Update};
+use bevy_ecs::prelude::*;
+use clap::Parser;
+use metaserve_bevy::{ConnectionStatus, MetaservePlugin, ServerBrowser, ServerEvent};
+use metaserve_client as client;
+
+#[derive(Parser, Debug)]
+#[clap(name = "browser")]
+struct Opt {
+    /// Meta server to connect to
+    #[clap(default_value = "localhost:4433")]
+    meta: String,
+    /// Additional certificate authority to trust, in DER format
+    #[clap(parse(from_os_str), long = "ca")]
+    ca: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let ca = opt
+        .ca
+        .map(|path| fs::read(path).expect("failed to read CA"));
+    let plugin = MetaservePlugin::new(opt.meta).builder(move || {
+        let builder = client::Client::builder();
+        match ca {
+            Some(ref ca) => builder.ca(ca.clone()),
+            None => builder,
+        }
+    });
+    App::new()
+        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_millis(100)))
+        .add_plugins(plugin)
+        .add_systems(Update, (print_status, print_events).chain())
+        .run();
+}
+
+fn print_status(status: Res<ConnectionStatus>) {
+    if !status.is_changed() {
+        return;
+    }
+    match *status {
+        ConnectionStatus::Connecting => println!("connecting..."),
+        ConnectionStatus::Connected => println!("connected"),
+        ConnectionStatus::Backoff { ref error, delay } => {
+            println!("{}; retrying in {:?}", error, delay)
+        }
+    }
+}
+
+fn print_events(mut events: MessageReader<ServerEvent>, browser: Res<ServerBrowser>) {
+    for event in events.read() {
+        match event {
+            ServerEvent::Added(id, x) | ServerEvent::Updated(id, x) => println!(
+                "{}: {} {}",
+                id,
+                x.address,
+                String::from_utf8_lossy(&x.state)
+            ),
+            ServerEvent::Removed(id, _) => println!("{}: shut down", id),
+            ServerEvent::Renumbered(old, new) => println!("{}: now {}", old, new),
+            ServerEvent::Resynchronized => {
+                for (id, x) in browser.iter() {
+                    println!(
+                        "{}: {} {}",
+                        id,
+                        x.address,
+                        String::from_utf8_lossy(&x.state)
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Bevy plugin that keeps a server browser up to date from a meta server
+//!
+//! [`MetaservePlugin`] owns a [`Client`](metaserve_client::Client) on a background thread,
+//! reconnecting with exponential backoff whenever the connection is lost. Each frame, before
+//! `Update`, messages received since the last frame are applied to the [`ServerBrowser`] resource,
+//! each resulting change is written as a [`ServerEvent`] message, and [`ConnectionStatus`] is
+//! brought up to date.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use bevy_app::{App, Plugin, PreUpdate};
+use bevy_ecs::prelude::*;
+use metaserve_client::{self as client, proto::ServerId, Builder, Cursor, ServerEntry, ServerList};
+use tracing::warn;
+
+/// Changes kept between messages, beyond which a single message is reported as
+/// [`ServerEvent::Resynchronized`]
+const CHANGE_LOG_CAPACITY: usize = 4096;
+
+/// Delay before the first reconnection attempt, doubled after each failure
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connects to a meta server and keeps [`ServerBrowser`], [`ConnectionStatus`], and
+/// [`ServerEvent`]s up to date
+pub struct MetaservePlugin {
+    server: String,
+    builder: Arc<dyn Fn() -> Builder + Send + Sync>,
+}
+
+impl MetaservePlugin {
+    /// Connect to `server`, e.g. `"meta.example.com:4433"`, with [`Client::builder`]'s defaults
+    ///
+    /// [`Client::builder`]: metaserve_client::Client::builder
+    pub fn new(server: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+            builder: Arc::new(client::Client::builder),
+        }
+    }
+
+    /// Configure each connection attempt with the [`Builder`] returned by `f`, e.g. to trust an
+    /// additional certificate authority or set a filter
+    pub fn builder(mut self, f: impl Fn() -> Builder + Send + Sync + 'static) -> Self {
+        self.builder = Arc::new(f);
+        self
+    }
+}
+
+impl Plugin for MetaservePlugin {
+    fn build(&self, app: &mut App) {
+        let (send, recv) = mpsc::channel();
+        let server = self.server.clone();
+        let builder = self.builder.clone();
+        thread::Builder::new()
+            .name("metaserve".into())
+            .spawn(move || run(&server, &*builder, send))
+            .expect("failed to spawn metaserve thread");
+        let mut list = ServerList::new();
+        list.set_change_log_capacity(CHANGE_LOG_CAPACITY);
+        app.insert_resource(ServerBrowser {
+            cursor: list.cursor(),
+            list,
+        })
+        .insert_resource(ConnectionStatus::Connecting)
+        .insert_resource(Inbox(Mutex::new(recv)))
+        .add_message::<ServerEvent>()
+        .add_systems(PreUpdate, receive);
+    }
+}
+
+/// The game servers known from the meta server, as a [`ServerList`]
+///
+/// Mutable access allows e.g. [marking](ServerList::set_mark) servers as favorites.
+#[derive(Resource)]
+pub struct ServerBrowser {
+    list: ServerList,
+    /// Position in `list`'s change log up to which [`ServerEvent`]s have been written
+    cursor: Cursor,
+}
+
+impl Deref for ServerBrowser {
+    type Target = ServerList;
+    fn deref(&self) -> &ServerList {
+        &self.list
+    }
+}
+
+impl DerefMut for ServerBrowser {
+    fn deref_mut(&mut self) -> &mut ServerList {
+        &mut self.list
+    }
+}
+
+/// State of the connection to the meta server
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Attempting to connect
+    Connecting,
+    /// Connected, and receiving updates
+    Connected,
+    /// Waiting `delay` to reconnect after failing to connect, or losing the connection, because
+    /// of `error`
+    Backoff { error: String, delay: Duration },
+}
+
+/// A change to [`ServerBrowser`], written as a Bevy message each frame it occurs
+#[derive(Message, Debug, Clone)]
+pub enum ServerEvent {
+    Added(ServerId, ServerEntry),
+    Updated(ServerId, ServerEntry),
+    /// The server shut down, with its last known information
+    Removed(ServerId, ServerEntry),
+    /// The server with the first ID now has the second, after reconnecting
+    Renumbered(ServerId, ServerId),
+    /// Too many changes arrived at once to report individually, so the whole [`ServerBrowser`]
+    /// should be re-read
+    Resynchronized,
+}
+
+/// What the background thread tells the app
+enum Notice {
+    Status(ConnectionStatus),
+    /// A new connection, with its [`Client::instance`] and [`Client::table_version`]
+    ///
+    /// [`Client::instance`]: metaserve_client::Client::instance
+    /// [`Client::table_version`]: metaserve_client::Client::table_version
+    Connected(Option<u64>, Option<u64>),
+    Message(client::OwnedMessage),
+}
+
+/// Receives [`Notice`]s from the background thread
+#[derive(Resource)]
+struct Inbox(Mutex<mpsc::Receiver<Notice>>);
+
+/// Apply everything received since the last frame
+fn receive(
+    inbox: Res<Inbox>,
+    mut browser: ResMut<ServerBrowser>,
+    mut status: ResMut<ConnectionStatus>,
+    mut events: MessageWriter<ServerEvent>,
+) {
+    let inbox = inbox.0.lock().unwrap_or_else(PoisonError::into_inner);
+    for notice in inbox.try_iter() {
+        match notice {
+            Notice::Status(x) => *status = x,
+            Notice::Connected(instance, table_version) => {
+                browser.list.connected(instance, table_version);
+                *status = ConnectionStatus::Connected;
+            }
+            Notice::Message(msg) => {
+                browser.list.apply_owned(&msg);
+                let ServerBrowser { list, cursor } = &mut *browser;
+                // Drained after every message, so only a single huge one can overflow the log
+                let Ok((changes, next)) = list.changes_since(*cursor) else {
+                    *cursor = list.cursor();
+                    events.write(ServerEvent::Resynchronized);
+                    continue;
+                };
+                *cursor = next;
+                for change in changes {
+                    let event = match change {
+                        client::Change::Added(id, x) => ServerEvent::Added(id, x),
+                        client::Change::Updated(id, x) => ServerEvent::Updated(id, x),
+                        client::Change::Removed(id, x) => ServerEvent::Removed(id, x),
+                        client::Change::Renumbered(old, new) => ServerEvent::Renumbered(old, new),
+                        _ => continue,
+                    };
+                    events.write(event);
+                }
+            }
+        }
+    }
+}
+
+/// Stay connected to `server`, passing everything received to `send` until the app is dropped
+fn run(server: &str, builder: &(dyn Fn() -> Builder + Send + Sync), send: mpsc::Sender<Notice>) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build tokio runtime");
+    runtime.block_on(async {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            if send
+                .send(Notice::Status(ConnectionStatus::Connecting))
+                .is_err()
+            {
+                return;
+            }
+            let error = match builder().connect(server).await {
+                Ok(mut client) => {
+                    backoff = MIN_BACKOFF;
+                    let connected = Notice::Connected(client.instance(), client.table_version());
+                    if send.send(connected).is_err() {
+                        return;
+                    }
+                    loop {
+                        match client.recv_owned().await {
+                            Ok(msg) => {
+                                if send.send(Notice::Message(msg)).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => break e.to_string(),
+                        }
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+            warn!(%error, delay = ?backoff, "meta server connection failed; retrying");
+            let status = ConnectionStatus::Backoff {
+                error,
+                delay: backoff,
+            };
+            if send.send(Notice::Status(status)).is_err() {
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
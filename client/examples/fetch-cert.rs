@@ -0,0 +1,82 @@
+//! Fetch a meta server's certificate without trusting it, and print its fingerprint
+//!
+//! Bootstraps trust in a self-signed meta server: compare the printed fingerprint with the one
+//! the daemon's `--selftest` prints on the meta server's own host, then pass the saved certificate
+//! to `--ca`, or the fingerprint to the `proxy` example's `--pin`.
+
+use std::{fs, path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use metaserve_client as client;
+
+use client::rustls;
+
+#[derive(Parser, Debug)]
+#[clap(name = "fetch-cert")]
+struct Opt {
+    /// Meta server to fetch the certificate of
+    #[clap(default_value = "localhost:4433")]
+    meta: String,
+    /// Save the certificate to this file, in DER format
+    #[clap(parse(from_os_str), long = "out")]
+    out: Option<PathBuf>,
+    /// Fail unless the certificate's public key has this SHA-256 SPKI fingerprint, in hex
+    #[clap(long = "pin")]
+    pin: Option<String>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let code = {
+        if let Err(e) = run(opt) {
+            eprintln!("ERROR: {:#}", e);
+            1
+        } else {
+            0
+        }
+    };
+    ::std::process::exit(code);
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(options: Opt) -> Result<()> {
+    let verifier = Arc::new(match options.pin {
+        Some(ref pin) => client::PinnedVerifier::new(parse_fingerprint(pin)?),
+        None => client::PinnedVerifier::trust_any(),
+    });
+    let config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    let client = client::Client::builder()
+        .tls_config(config)
+        .connect(&options.meta)
+        .await?;
+    client.close().await;
+    let der = verifier
+        .presented()
+        .ok_or_else(|| anyhow!("no certificate presented"))?;
+    let fingerprint =
+        client::spki_fingerprint(&der).ok_or_else(|| anyhow!("certificate could not be parsed"))?;
+    println!("SPKI SHA-256: {}", hex(&fingerprint));
+    if let Some(ref path) = options.out {
+        fs::write(path, &der).context("saving certificate")?;
+        println!("saved to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Decode a fingerprint given as 64 hexadecimal digits
+fn parse_fingerprint(hex: &str) -> Result<[u8; 32]> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| anyhow!("fingerprint must be 64 hexadecimal digits"))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
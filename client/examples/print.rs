@@ -1,12 +1,6 @@
-use std::{
-    fs,
-    io::{self, Write},
-    net::ToSocketAddrs,
-    path::PathBuf,
-    sync::Arc,
-};
+use std::{fs, path::PathBuf, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use clap::Parser;
 use metaserve_client as client;
 
@@ -19,6 +13,51 @@ struct Opt {
     /// Additional certificate authority to trust, in DER format
     #[clap(parse(from_os_str), long = "ca")]
     ca: Option<PathBuf>,
+    /// Print connection statistics after every update
+    #[clap(short = 'v', long = "verbose")]
+    verbose: bool,
+    /// Record received messages to a file
+    #[clap(parse(from_os_str), long = "record")]
+    record: Option<PathBuf>,
+    /// Play back a recording instead of connecting to a meta server
+    #[clap(parse(from_os_str), long = "replay", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+    /// Playback speed multiplier for --replay
+    #[clap(long = "replay-speed", default_value = "1")]
+    replay_speed: f64,
+    /// Ask the meta server to wait at least this many seconds between updates
+    #[clap(long = "update-interval")]
+    update_interval: Option<f64>,
+    /// Ask the meta server to spread the initial snapshot over this many seconds, least loaded
+    /// game servers first
+    #[clap(long = "paced-snapshot")]
+    paced_snapshot: Option<f64>,
+    /// Ask the meta server to send only this many bytes of each game server's state
+    #[clap(long = "truncate-states")]
+    truncate_states: Option<u32>,
+    /// Give up if the meta server is silent for this many seconds
+    #[clap(long = "max-silence")]
+    max_silence: Option<f64>,
+    /// Only show game servers with this tag; may be repeated
+    #[clap(long = "require-tag")]
+    require_tags: Vec<String>,
+    /// Hide game servers with this tag; may be repeated
+    #[clap(long = "exclude-tag")]
+    exclude_tags: Vec<String>,
+    /// Only show game servers in this country, as an ISO 3166-1 alpha-2 code like `DE`
+    #[clap(long = "region")]
+    region: Option<String>,
+    /// Ask the meta server to pick a single matching game server, print it, and exit
+    #[clap(long = "find-one", possible_values = &["random", "least-loaded"])]
+    find_one: Option<String>,
+    /// Ask the meta server to introduce us to the game server with this ID, print the
+    /// introduction, and exit
+    #[clap(long = "introduce", conflicts_with = "find-one")]
+    introduce: Option<u64>,
+    /// Ask the meta server for everything it knows about the game server with this ID, print it,
+    /// and exit
+    #[clap(long = "get-server", conflicts_with_all = &["find-one", "introduce"])]
+    get_server: Option<u64>,
 }
 
 fn main() {
@@ -36,47 +75,79 @@ fn main() {
 
 #[tokio::main(flavor = "current_thread")]
 async fn run(options: Opt) -> Result<()> {
-    let mut roots = rustls::RootCertStore::empty();
-    if let Some(ca_path) = options.ca {
-        roots.add(&rustls::Certificate(
-            fs::read(&ca_path).context("reading CA")?,
-        ))?;
-    }
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-
-    client_crypto.alpn_protocols = vec![client::proto::PROTOCOL.into()];
-    let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
-    Arc::get_mut(&mut client_config.transport)
-        .unwrap()
-        .keep_alive_interval(Some(std::time::Duration::from_secs(5)))
-        .max_concurrent_bidi_streams(0u32.into())
-        .max_concurrent_uni_streams(1u32.into());
-
-    let endpoint = quinn::Endpoint::client("[::]:0".parse().unwrap())?;
-
-    let hostname = options.meta.split(':').next().unwrap();
-
-    let addr = options
-        .meta
-        .to_socket_addrs()
-        .map_err(|_| anyhow!("invalid meta server address -- did you forget a port number?"))?
-        .next()
-        .map_or_else(|| Err(anyhow!("no such hostname")), Ok)?;
-
-    print!("connecting to {}...", addr);
-    io::stdout().flush()?;
+    let mut source = match options.replay {
+        Some(ref path) => Source::Replay(
+            client::ReplayClient::open(path)
+                .context("opening recording")?
+                .speed(options.replay_speed),
+        ),
+        None => Source::Live(Box::new(connect(&options).await?)),
+    };
+    if let (Some(strategy), Source::Live(client)) = (&options.find_one, &source) {
+        let strategy = match &strategy[..] {
+            "random" => client::proto::Strategy::Random,
+            _ => client::proto::Strategy::LeastLoaded,
+        };
+        match client.find_one(filter(&options), strategy).await? {
+            Some((id, server)) => println!(
+                "{}: {} {}",
+                id,
+                server.address,
+                String::from_utf8_lossy(&server.state)
+            ),
+            None => println!("no matching server"),
+        }
+        if let Source::Live(client) = source {
+            client.close().await;
+        }
+        return Ok(());
+    }
+    if let (Some(id), Source::Live(client)) = (options.introduce, &source) {
+        match client
+            .request_introduction(client::proto::ServerId(id))
+            .await?
+        {
+            Some(x) => println!("introduced to {} with token {:016x}", x.address, x.token),
+            None => println!("introduction refused"),
+        }
+        if let Source::Live(client) = source {
+            client.close().await;
+        }
+        return Ok(());
+    }
+    if let (Some(id), Source::Live(client)) = (options.get_server, &source) {
+        match client
+            .get_server(client::proto::ServerId(id), Duration::from_secs(10))
+            .await?
+        {
+            Some(server) => println!(
+                "{}: {} {}",
+                id,
+                server.address,
+                String::from_utf8_lossy(&server.state)
+            ),
+            None => println!("no such server"),
+        }
+        if let Source::Live(client) = source {
+            client.close().await;
+        }
+        return Ok(());
+    }
+    tokio::select! {
+        result = print(&mut source, &options) => result?,
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    if let Source::Live(client) = source {
+        client.close().await;
+    }
+    Ok(())
+}
 
-    let conn = endpoint
-        .connect_with(client_config, addr, hostname)?
-        .await?;
-    println!(" connected");
-    let mut client = client::Client::new(conn);
+async fn print(source: &mut Source, options: &Opt) -> Result<()> {
     loop {
-        let msg = match client.recv().await {
+        let msg = match source.recv().await {
             Ok(x) => x,
+            Err(client::Error::EndOfRecording) => return Ok(()),
             Err(e) => return Err(e.into()),
         };
         println!("servers:");
@@ -84,12 +155,144 @@ async fn run(options: Opt) -> Result<()> {
             print!("\t{}: ", server.id);
             match server.event {
                 client::proto::Event::Update(addr, state) => {
-                    println!("{} {}", addr, String::from_utf8_lossy(&state));
+                    println!("{} {}", addr, String::from_utf8_lossy(state));
                 }
                 client::proto::Event::Shutdown => {
                     println!("shutdown");
                 }
+                client::proto::Event::Region(Some(region)) => {
+                    println!("in {}", region);
+                }
+                client::proto::Event::Region(None) => {
+                    println!("in unknown region");
+                }
+                client::proto::Event::Tags(tags) => {
+                    println!("tagged {:?}", tags);
+                }
+                client::proto::Event::LanAddresses(addresses) => {
+                    println!("on LAN at {:?}", addresses);
+                }
+                client::proto::Event::Subscribed(generation) => {
+                    println!("filter {} applied", generation);
+                }
+                client::proto::Event::Synchronized => {
+                    println!("end of snapshot");
+                }
+                client::proto::Event::SnapshotProgress { delivered, total } => {
+                    println!("snapshot {}/{}", delivered, total);
+                }
+                client::proto::Event::Limits(limits) => {
+                    println!("limits changed to {:?}", limits);
+                }
+                client::proto::Event::Reset => {
+                    println!("reset");
+                }
+                client::proto::Event::Truncated(size) => {
+                    println!("state truncated from {}B", size);
+                }
+                _ => {
+                    println!("unknown event");
+                }
+            }
+        }
+        for entry in msg.undecodable {
+            match entry.id {
+                Some(id) => println!("\t{}: undecodable", id),
+                None => println!("\t?: undecodable"),
             }
         }
+        if let (true, Source::Live(ref client)) = (options.verbose, &*source) {
+            print_stats(&client.stats());
+        }
+    }
+}
+
+async fn connect(options: &Opt) -> Result<client::Client> {
+    let mut builder = client::Client::builder();
+    if let Some(ref ca_path) = options.ca {
+        builder = builder.ca(fs::read(ca_path).context("reading CA")?);
+    }
+    if let Some(secs) = options.update_interval {
+        builder = builder.update_interval(Duration::from_secs_f64(secs));
+    }
+    if let Some(secs) = options.max_silence {
+        builder = builder.max_silence(Duration::from_secs_f64(secs));
+    }
+    if let Some(secs) = options.paced_snapshot {
+        builder = builder.paced_snapshot(
+            Duration::from_secs_f64(secs),
+            client::proto::Strategy::LeastLoaded,
+        );
+    }
+    if let Some(max) = options.truncate_states {
+        builder = builder.truncate_states(max);
+    }
+    let filter = filter(options);
+    if filter != client::proto::Filter::default() && options.find_one.is_none() {
+        builder = builder.filter(filter);
+    }
+
+    println!("connecting to {}...", options.meta);
+    let mut client = builder.connect(&options.meta).await?;
+    println!("connected to {}", client.remote_address());
+    if options.verbose {
+        println!("local address {:?}", client.local_ip());
+        println!(
+            "protocol v{} ({}), certificate {}",
+            client.protocol_version(),
+            hex(client.negotiated_alpn()),
+            client
+                .peer_certificate_fingerprint()
+                .map_or_else(|| "unknown".into(), |x| hex(&x))
+        );
+    }
+    if let Some(ref path) = options.record {
+        client.record_to(path).context("creating recording")?;
     }
+    Ok(client)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn filter(options: &Opt) -> client::proto::Filter {
+    let mut required = options.require_tags.clone();
+    if let Some(ref region) = options.region {
+        required.push(format!("{}{}", client::proto::REGION_PREFIX, region));
+    }
+    client::proto::Filter {
+        required,
+        excluded: options.exclude_tags.clone(),
+    }
+}
+
+enum Source {
+    Live(Box<client::Client>),
+    Replay(client::ReplayClient),
+}
+
+impl Source {
+    async fn recv(&mut self) -> Result<client::proto::Message<'_>, client::Error> {
+        match self {
+            Source::Live(x) => x.recv().await,
+            Source::Replay(x) => x.recv().await,
+        }
+    }
+}
+
+fn print_stats(stats: &client::Stats) {
+    println!(
+        "rtt {:?}, cwnd {}, congestion events {}, lost packets {}, sent {}B, received {}B",
+        stats.rtt,
+        stats.cwnd,
+        stats.congestion_events,
+        stats.lost_packets,
+        stats.bytes_sent,
+        stats.bytes_received
+    );
+    println!(
+        "{} messages, {}B, {:.0}B/s",
+        stats.messages_received, stats.message_bytes_received, stats.message_rate
+    );
 }
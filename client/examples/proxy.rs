@@ -0,0 +1,319 @@
+//! Connect to a meta server through a SOCKS5 proxy's UDP relay, optionally pinning its certificate
+//!
+//! Demonstrates [`Builder::endpoint`](client::Builder::endpoint),
+//! [`Builder::address`](client::Builder::address), and
+//! [`Builder::tls_config`](client::Builder::tls_config). The SOCKS5 UDP ASSOCIATE exchange (RFC
+//! 1928) is simple enough to implement here directly.
+
+use std::{
+    fmt, fs,
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::Parser;
+use metaserve_client as client;
+use quinn::{
+    udp::{RecvMeta, Transmit},
+    AsyncUdpSocket, Runtime, UdpPoller,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use client::rustls;
+
+#[derive(Parser, Debug)]
+#[clap(name = "proxy")]
+struct Opt {
+    /// Meta server to connect to
+    #[clap(default_value = "localhost:4433")]
+    meta: String,
+    /// SOCKS5 proxy to relay traffic through
+    #[clap(long = "proxy")]
+    proxy: String,
+    /// Additional certificate authority to trust, in DER format
+    #[clap(parse(from_os_str), long = "ca", conflicts_with = "pin")]
+    ca: Option<PathBuf>,
+    /// Trust only a certificate whose public key has this SHA-256 SPKI fingerprint, in hex, as
+    /// printed by the `fetch-cert` example
+    #[clap(long = "pin")]
+    pin: Option<String>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let code = {
+        if let Err(e) = run(opt) {
+            eprintln!("ERROR: {:#}", e);
+            1
+        } else {
+            0
+        }
+    };
+    ::std::process::exit(code);
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn run(options: Opt) -> Result<()> {
+    // Resolved locally, since the relay is told where to send each datagram by address
+    let meta = options
+        .meta
+        .to_socket_addrs()
+        .context("resolving meta server")?
+        .next()
+        .ok_or_else(|| anyhow!("meta server hostname did not resolve to any addresses"))?;
+    let proxy = options
+        .proxy
+        .to_socket_addrs()
+        .context("resolving proxy")?
+        .next()
+        .ok_or_else(|| anyhow!("proxy hostname did not resolve to any addresses"))?;
+    let (control, socket) = associate(proxy).await.context("setting up UDP relay")?;
+    println!("relaying through {}", socket.relay);
+    let runtime = Arc::new(quinn::TokioRuntime);
+    let endpoint = quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        None,
+        Arc::new(socket),
+        runtime,
+    )?;
+
+    let mut builder = client::Client::builder().endpoint(endpoint).address(meta);
+    if let Some(ref path) = options.ca {
+        builder = builder.ca(fs::read(path).context("reading CA")?);
+    }
+    if let Some(ref pin) = options.pin {
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(client::PinnedVerifier::new(
+                parse_fingerprint(pin)?,
+            )))
+            .with_no_client_auth();
+        builder = builder.tls_config(config);
+    }
+    println!("connecting to {} ({})...", options.meta, meta);
+    let mut client = builder.connect(&options.meta).await?;
+    println!("connected");
+    tokio::select! {
+        result = print(&mut client) => result?,
+        result = tokio::signal::ctrl_c() => result?,
+        // The relay lasts only as long as this connection
+        _ = wait_closed(control) => bail!("proxy closed the UDP association"),
+    }
+    client.close().await;
+    Ok(())
+}
+
+async fn print(client: &mut client::Client) -> Result<()> {
+    loop {
+        let msg = client.recv().await?;
+        for server in msg.servers {
+            match server.event {
+                client::proto::Event::Update(addr, state) => {
+                    println!("{}: {} {}", server.id, addr, String::from_utf8_lossy(state));
+                }
+                client::proto::Event::Shutdown => println!("{}: shutdown", server.id),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Ask the SOCKS5 proxy at `proxy` to relay UDP datagrams, returning the control connection that
+/// keeps the association alive and a socket that sends through the relay
+async fn associate(proxy: SocketAddr) -> Result<(TcpStream, SocksSocket)> {
+    let mut control = TcpStream::connect(proxy).await?;
+    // Version 5, offering only "no authentication required"
+    control.write_all(&[5, 1, 0]).await?;
+    let mut reply = [0; 2];
+    control.read_exact(&mut reply).await?;
+    if reply != [5, 0] {
+        bail!("proxy requires authentication");
+    }
+    // UDP ASSOCIATE, leaving the proxy to accept datagrams from whichever port we use
+    control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    let mut reply = [0; 3];
+    control.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        bail!("proxy refused UDP ASSOCIATE with code {}", reply[1]);
+    }
+    let mut relay = read_address(&mut control).await?;
+    if relay.ip().is_unspecified() {
+        // Relayed on the same host as the proxy
+        relay.set_ip(proxy.ip());
+    }
+
+    let bind = match relay {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let inner = quinn::TokioRuntime.wrap_udp_socket(std::net::UdpSocket::bind(bind)?)?;
+    Ok((control, SocksSocket { inner, relay }))
+}
+
+/// Read a SOCKS5 address, as in a reply
+async fn read_address(stream: &mut TcpStream) -> Result<SocketAddr> {
+    let ip = match stream.read_u8().await? {
+        1 => {
+            let mut x = [0; 4];
+            stream.read_exact(&mut x).await?;
+            IpAddr::from(x)
+        }
+        4 => {
+            let mut x = [0; 16];
+            stream.read_exact(&mut x).await?;
+            IpAddr::from(x)
+        }
+        x => bail!("unsupported address type {}", x),
+    };
+    Ok(SocketAddr::new(ip, stream.read_u16().await?))
+}
+
+async fn wait_closed(mut control: TcpStream) {
+    let mut buf = [0; 64];
+    while let Ok(1..) = control.read(&mut buf).await {}
+}
+
+/// Sends and receives datagrams through a SOCKS5 UDP relay, so that quinn sees the meta server's
+/// own address
+struct SocksSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    /// Where the proxy relays datagrams from
+    relay: SocketAddr,
+}
+
+impl AsyncUdpSocket for SocksSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        // Reserved, fragment number, and the destination address
+        let mut data = vec![0, 0, 0];
+        match transmit.destination.ip() {
+            IpAddr::V4(x) => {
+                data.push(1);
+                data.extend_from_slice(&x.octets());
+            }
+            IpAddr::V6(x) => {
+                data.push(4);
+                data.extend_from_slice(&x.octets());
+            }
+        }
+        data.extend_from_slice(&transmit.destination.port().to_be_bytes());
+        data.extend_from_slice(transmit.contents);
+        self.inner.try_send(&Transmit {
+            destination: self.relay,
+            ecn: transmit.ecn,
+            contents: &data,
+            segment_size: None,
+            src_ip: None,
+        })
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let n = match self.inner.poll_recv(cx, bufs, meta) {
+                Poll::Ready(Ok(n)) => n,
+                x => return x,
+            };
+            let mut kept = 0;
+            for i in 0..n {
+                if meta[i].addr != self.relay {
+                    continue;
+                }
+                let Some((source, payload)) = unwrap(&mut bufs[i], meta[i]) else {
+                    continue;
+                };
+                if i != kept {
+                    let (head, tail) = bufs.split_at_mut(i);
+                    head[kept][..payload.len].copy_from_slice(&tail[0][..payload.len]);
+                }
+                meta[kept] = RecvMeta {
+                    addr: source,
+                    ..payload
+                };
+                kept += 1;
+            }
+            if kept > 0 {
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+}
+
+/// Strip the relay's headers from the datagrams in `buf`, moving their payloads to its front
+///
+/// Returns where they came from and the metadata of what remains. Datagrams the relay coalesced
+/// all came from the same source, so carry headers of the same size.
+fn unwrap(buf: &mut [u8], meta: RecvMeta) -> Option<(SocketAddr, RecvMeta)> {
+    let (header, source) = match *buf.get(3)? {
+        1 => {
+            let ip = <[u8; 4]>::try_from(buf.get(4..8)?).unwrap();
+            let port = u16::from_be_bytes(buf.get(8..10)?.try_into().unwrap());
+            (10, SocketAddr::new(ip.into(), port))
+        }
+        4 => {
+            let ip = <[u8; 16]>::try_from(buf.get(4..20)?).unwrap();
+            let port = u16::from_be_bytes(buf.get(20..22)?.try_into().unwrap());
+            (22, SocketAddr::new(ip.into(), port))
+        }
+        _ => return None,
+    };
+    // Fragmented datagrams aren't supported, and QUIC never needs them
+    if buf[2] != 0 || meta.stride <= header {
+        return None;
+    }
+    let mut len = 0;
+    for start in (0..meta.len).step_by(meta.stride) {
+        let end = (start + meta.stride).min(meta.len);
+        buf.copy_within(start + header..end, len);
+        len += end - start - header;
+    }
+    Some((
+        source,
+        RecvMeta {
+            len,
+            stride: meta.stride - header,
+            ..meta
+        },
+    ))
+}
+
+impl fmt::Debug for SocksSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocksSocket")
+            .field("relay", &self.relay)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decode a fingerprint given as 64 hexadecimal digits
+fn parse_fingerprint(hex: &str) -> Result<[u8; 32]> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| anyhow!("fingerprint must be 64 hexadecimal digits"))
+}
@@ -0,0 +1,309 @@
+//! File format of [`ServerList::save`](crate::ServerList::save)
+//!
+//! A header of [`MAGIC`], the format's [`VERSION`], and a checksum of the rest, followed by a
+//! [`Contents`] encoded with `bincode`.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    proto::{Region, ServerId},
+    Mark, ServerEntry,
+};
+
+/// Identifies a server list cache
+const MAGIC: [u8; 8] = *b"msvcache";
+/// Incremented whenever the format changes incompatibly
+const VERSION: u32 = 3;
+/// Size of the header preceding [`Contents`]
+const HEADER_SIZE: usize = MAGIC.len() + 4 + 8;
+
+/// `instance` and `table_version` from the [`Welcome`](crate::proto::Welcome) of the
+/// connection a list was built from
+pub(crate) type Origin = (u64, u64);
+
+/// A cached game server, and when the meta server last confirmed it
+pub(crate) type Cached = (ServerId, ServerEntry, SystemTime);
+
+/// Marks set with [`ServerList::set_mark`](crate::ServerList::set_mark), by address
+pub(crate) type Marks = HashMap<SocketAddr, Mark>;
+
+#[derive(Serialize, Deserialize)]
+struct Contents<'a> {
+    origin: Option<Origin>,
+    #[serde(borrow)]
+    servers: Vec<Server<'a>>,
+    /// Addresses marked [`Mark::Pinned`]
+    pinned: Vec<SocketAddr>,
+    /// Addresses marked [`Mark::Hidden`]
+    hidden: Vec<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Server<'a> {
+    id: ServerId,
+    address: SocketAddr,
+    state: &'a [u8],
+    region: Option<Region>,
+    #[serde(borrow)]
+    tags: Vec<&'a str>,
+    lan_addresses: Vec<SocketAddr>,
+    truncated: Option<u32>,
+    /// Seconds since the Unix epoch
+    last_seen: u64,
+}
+
+/// Replace the file at `path` with one describing `servers` and `marks`
+pub(crate) fn write<'a>(
+    path: &Path,
+    origin: Option<Origin>,
+    servers: impl Iterator<Item = (ServerId, &'a ServerEntry, SystemTime)>,
+    marks: &Marks,
+) -> io::Result<()> {
+    let marked = |mark| {
+        let marked = marks.iter().filter(|&(_, &x)| x == mark);
+        marked.map(|(&address, _)| address).collect()
+    };
+    let contents = Contents {
+        pinned: marked(Mark::Pinned),
+        hidden: marked(Mark::Hidden),
+        origin,
+        servers: servers
+            .map(|(id, entry, last_seen)| Server {
+                id,
+                address: entry.address,
+                state: &entry.state,
+                region: entry.region,
+                tags: entry.tags.iter().map(|x| &x[..]).collect(),
+                lan_addresses: entry.lan_addresses.clone(),
+                truncated: entry.truncated,
+                last_seen: last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |x| x.as_secs()),
+            })
+            .collect(),
+    };
+    let body = bincode::serialize(&contents).unwrap();
+    let mut data = Vec::with_capacity(HEADER_SIZE + body.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&checksum(&body).to_le_bytes());
+    data.extend_from_slice(&body);
+    // Write elsewhere first, so that a crash can't leave a partial file behind
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, data)?;
+    fs::rename(&temporary, path)
+}
+
+/// Read a file written by [`write`]
+pub(crate) fn read(path: &Path) -> io::Result<(Option<Origin>, Vec<Cached>, Marks)> {
+    let data = Bytes::from(fs::read(path)?);
+    if data.len() < HEADER_SIZE || data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a server list cache"));
+    }
+    let version = u32::from_le_bytes(data[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(format!("unsupported cache version {}", version)));
+    }
+    let expected = u64::from_le_bytes(data[MAGIC.len() + 4..HEADER_SIZE].try_into().unwrap());
+    let body = &data[HEADER_SIZE..];
+    if checksum(body) != expected {
+        return Err(invalid("cache is corrupt"));
+    }
+    let contents = metaserve_proto::decode::<Contents<'_>>(body).map_err(invalid)?;
+    let servers = contents
+        .servers
+        .into_iter()
+        .map(|x| {
+            let entry = ServerEntry {
+                address: x.address,
+                state: data.slice_ref(x.state),
+                region: x.region,
+                tags: x.tags.into_iter().map(String::from).collect(),
+                lan_addresses: x.lan_addresses,
+                truncated: x.truncated,
+                user_data: None,
+            };
+            let last_seen = UNIX_EPOCH + Duration::from_secs(x.last_seen);
+            (x.id, entry, last_seen)
+        })
+        .collect();
+    let pinned = contents.pinned.into_iter().map(|x| (x, Mark::Pinned));
+    let hidden = contents.hidden.into_iter().map(|x| (x, Mark::Hidden));
+    Ok((contents.origin, servers, pinned.chain(hidden).collect()))
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// 64-bit FNV-1a, to detect corruption
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        proto::{Event, Message, Server},
+        ServerList,
+    };
+
+    /// A path unique to `name` and this process, removed when dropped
+    pub(crate) struct TempPath(pub(crate) PathBuf);
+
+    impl TempPath {
+        pub(crate) fn new(name: &str) -> Self {
+            let file = format!("metaserve-cache-{}-{}", name, std::process::id());
+            Self(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    /// A list from instance 1 of servers 0 and 1 at ports 1000 and 1001, with the first pinned
+    fn populated() -> ServerList {
+        let mut list = ServerList::new();
+        list.connected(Some(1), Some(5));
+        list.apply(&Message {
+            servers: vec![
+                Server {
+                    id: ServerId(0),
+                    event: Event::Update(addr(1000), b"a"),
+                },
+                Server {
+                    id: ServerId(0),
+                    event: Event::Region(Some(Region(*b"DE"))),
+                },
+                Server {
+                    id: ServerId(0),
+                    event: Event::Tags(vec!["eu", "ranked"]),
+                },
+                Server {
+                    id: ServerId(1),
+                    event: Event::Update(addr(1001), b"b"),
+                },
+                Server {
+                    id: ServerId(1),
+                    event: Event::Truncated(100),
+                },
+            ],
+            undecodable: Vec::new(),
+        });
+        list.set_mark(addr(1000), Some(Mark::Pinned));
+        list
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TempPath::new("round-trip");
+        let list = populated();
+        list.save(&path.0).unwrap();
+        let loaded = ServerList::load(&path.0).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            list.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(loaded.mark(addr(1000)), Some(Mark::Pinned));
+        assert_eq!(loaded.mark(addr(1001)), None);
+        for (id, _) in loaded.iter() {
+            assert!(loaded.last_seen(id).is_some());
+        }
+    }
+
+    #[test]
+    fn corruption_is_rejected() {
+        let path = TempPath::new("corruption");
+        populated().save(&path.0).unwrap();
+        let good = fs::read(&path.0).unwrap();
+        let mut flipped = good.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let mut version = good.clone();
+        version[MAGIC.len()] ^= 1;
+        let mut magic = good.clone();
+        magic[0] ^= 1;
+        for bad in [
+            flipped,
+            version,
+            magic,
+            good[..good.len() - 1].to_vec(),
+            good[..HEADER_SIZE - 1].to_vec(),
+            Vec::new(),
+        ] {
+            fs::write(&path.0, bad).unwrap();
+            let e = ServerList::load(&path.0).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+        let missing = TempPath::new("missing");
+        let e = ServerList::load(&missing.0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// After loading, a different meta server instance's snapshot confirms cached entries by
+    /// address and removes the rest
+    #[test]
+    fn stale_entries_reconcile() {
+        let path = TempPath::new("reconcile");
+        populated().save(&path.0).unwrap();
+
+        let mut list = ServerList::load(&path.0).unwrap();
+        list.set_change_log_capacity(100);
+        let cursor = list.cursor();
+        list.connected(Some(2), Some(1));
+        list.apply(&Message {
+            servers: vec![
+                Server {
+                    id: ServerId(7),
+                    event: Event::Update(addr(1000), b"a2"),
+                },
+                Server {
+                    id: ServerId::NONE,
+                    event: Event::Synchronized,
+                },
+            ],
+            undecodable: Vec::new(),
+        });
+        assert_eq!(list.len(), 1);
+        let entry = list.get(ServerId(7)).unwrap();
+        assert_eq!(&entry.state[..], b"a2");
+        assert_eq!(entry.tags, ["eu", "ranked"]);
+        assert_eq!(list.last_seen(ServerId(7)), None);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert!(changes.contains(&crate::Change::Renumbered(ServerId(0), ServerId(7))));
+        assert!(changes
+            .iter()
+            .any(|x| matches!(*x, crate::Change::Removed(ServerId(1), _))));
+
+        // The same instance keeps IDs, and an empty snapshot confirms nothing
+        let mut list = ServerList::load(&path.0).unwrap();
+        list.connected(Some(1), Some(6));
+        assert!(list.get(ServerId(1)).is_some());
+        list.apply(&Message {
+            servers: Vec::new(),
+            undecodable: Vec::new(),
+        });
+        assert!(list.is_empty());
+    }
+}
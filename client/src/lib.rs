@@ -1,42 +1,52 @@
-use futures_util::StreamExt;
-use thiserror::Error;
+//! Game client library for receiving server lists from a meta server
+//!
+//! The [`ServerList`] reconciliation logic and the [`proto`] message types have no networking
+//! dependencies and build on any target, including `wasm32-unknown-unknown`, with default features
+//! disabled. Enabling a runtime feature (`tokio`, the default, `smol`, or `async-std`) additionally
+//! provides [`Client`]. In a browser, the `web` feature provides `WebTransportClient` instead, for
+//! connecting through a WebTransport gateway.
 
+mod cache;
+mod list;
+mod message;
+#[cfg(feature = "net")]
+mod net;
+#[cfg(feature = "net")]
+mod record;
+#[cfg(feature = "web")]
+mod web;
+
+pub use list::{Change, Cursor, Mark, ServerEntry, ServerList, Stale, UserData};
+pub use message::OwnedMessage;
 pub use metaserve_proto::client as proto;
+#[cfg(feature = "net")]
+pub use net::{spki_fingerprint, Client, ClientStream, Connection, Error, Stats};
+#[cfg(feature = "helpers")]
+pub use net::{Builder, ConnectError, PinnedVerifier};
+/// The TLS library used by [`Builder::tls_config`]
+#[cfg(feature = "helpers")]
+pub use quinn::rustls;
+#[cfg(feature = "net")]
+pub use record::ReplayClient;
+#[cfg(feature = "web")]
+pub use web::{WebError, WebTransportClient};
 
-#[derive(Debug, Error)]
-pub enum Error {
-    #[error(transparent)]
-    Connection(#[from] quinn::ConnectionError),
-    #[error(transparent)]
-    Read(#[from] quinn::ReadError),
-    #[error("server sent malformed data: {0}")]
-    Parse(#[from] bincode::Error),
-}
+/// Encoding of a message with no servers, which meta servers send as a keep-alive
+#[cfg(any(feature = "net", feature = "web"))]
+const KEEPALIVE: &[u8] = &[0; 8];
 
-pub struct Client {
-    inner: quinn::IncomingUniStreams,
-    buffer: Vec<u8>,
-}
+/// Future that completes when a runtime-provided timer expires
+#[cfg(feature = "net")]
+struct Sleep(std::pin::Pin<Box<dyn quinn::AsyncTimer>>);
 
-impl Client {
-    pub fn new(connection: quinn::NewConnection) -> Self {
-        Self {
-            inner: connection.uni_streams,
-            buffer: Vec::new(),
-        }
-    }
+#[cfg(feature = "net")]
+impl std::future::Future for Sleep {
+    type Output = ();
 
-    pub async fn recv(&mut self) -> Result<proto::Message<'_>, Error> {
-        let stream = self
-            .inner
-            .next()
-            .await
-            .expect("connection locally closed unexpectedly")?;
-        self.buffer = match stream.read_to_end(usize::MAX).await {
-            Ok(x) => x,
-            Err(quinn::ReadToEndError::TooLong) => unreachable!(),
-            Err(quinn::ReadToEndError::Read(x)) => return Err(x.into()),
-        };
-        Ok(bincode::deserialize(&self.buffer)?)
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        self.0.as_mut().poll(cx)
     }
 }
@@ -0,0 +1,1058 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt, io, mem,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    cache,
+    proto::{self, ServerId},
+    OwnedMessage,
+};
+
+/// The set of game servers known to a meta server, reconstructed from a sequence of messages
+///
+/// Feed every message received from a meta server to [`apply`](Self::apply), in order.
+#[derive(Default, Clone)]
+pub struct ServerList {
+    servers: BTreeMap<ServerId, ServerEntry>,
+    /// Most recent changes, oldest first
+    changes: VecDeque<Change>,
+    /// Position of the first element of `changes` in the sequence of all changes
+    changes_start: u64,
+    change_log_capacity: usize,
+    /// From the latest [`proto::Event::Subscribed`]
+    filter_generation: u64,
+    /// Whether a [`proto::Event::Synchronized`] has been seen
+    synchronized: bool,
+    /// From the latest [`proto::Event::SnapshotProgress`]
+    snapshot_progress: Option<(u64, u64)>,
+    reconcile_by_address: bool,
+    report_unchanged: bool,
+    /// Number of updates that changed nothing
+    unchanged: u64,
+    /// Servers set aside by [`clear`](Self::clear) to be matched by address, with their old IDs
+    stale: HashMap<SocketAddr, (ServerId, ServerEntry)>,
+    /// From the connection the list was built from, for [`save`](Self::save)
+    origin: Option<cache::Origin>,
+    /// Servers [loaded](Self::load) that the meta server has yet to confirm, with when it last did
+    cached: HashMap<ServerId, SystemTime>,
+    /// Whether servers were loaded and [`connected`](Self::connected) hasn't been called since
+    loaded: bool,
+    /// From [`set_mark`](Self::set_mark)
+    marks: cache::Marks,
+    include_hidden: bool,
+    on_added: Vec<Callback>,
+    on_removed: Vec<Callback>,
+}
+
+type Callback = Arc<dyn Fn(ServerId, &ServerEntry) + Send + Sync>;
+
+impl ServerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a list written by [`save`](Self::save), e.g. to show something before the network is
+    /// up
+    ///
+    /// Every entry is [cached](Self::last_seen) until the meta server confirms it. Those that a
+    /// snapshot from the next connection doesn't confirm are then removed. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the file is corrupt, or was written by an incompatible
+    /// version of this crate.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (origin, servers, marks) = cache::read(path.as_ref())?;
+        let mut list = Self {
+            origin,
+            loaded: true,
+            marks,
+            ..Self::default()
+        };
+        for (id, entry, last_seen) in servers {
+            list.cached.insert(id, last_seen);
+            list.servers.insert(id, entry);
+        }
+        Ok(list)
+    }
+
+    /// Write the list to `path`, replacing any existing file, for [`load`](Self::load) to read
+    /// later
+    ///
+    /// [Marks](Self::set_mark) are saved too, but user data, the change log, and settings aren't.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let now = SystemTime::now();
+        cache::write(
+            path.as_ref(),
+            self.origin,
+            self.servers.iter().map(|(&id, entry)| {
+                let last_seen = self.cached.get(&id).copied().unwrap_or(now);
+                (id, entry, last_seen)
+            }),
+            &self.marks,
+        )
+    }
+
+    /// Prepare to apply messages from a new connection, whose meta server reported `instance` and
+    /// `table_version`
+    ///
+    /// [Loaded](Self::load) entries keep their IDs if `instance` is the one the list was saved
+    /// from, and are otherwise matched to new IDs by address, as when [reconciling by
+    /// address](Self::set_reconcile_by_address). Call before applying the connection's first
+    /// message, with `Client::instance` and `Client::table_version`. `Client::synchronized` calls
+    /// this itself. Loaded entries are matched by address if the first message is applied without
+    /// calling this.
+    pub fn connected(&mut self, instance: Option<u64>, table_version: Option<u64>) {
+        let same = instance.is_some() && self.origin.map(|(x, _)| x) == instance;
+        self.origin = instance.zip(table_version);
+        self.loaded = false;
+        if same {
+            return;
+        }
+        let ids = self.cached.drain().map(|(id, _)| id).collect::<Vec<_>>();
+        for id in ids {
+            let entry = self.servers.remove(&id).unwrap();
+            if let Some((id, entry)) = self.stale.insert(entry.address, (id, entry)) {
+                self.removed(id, entry);
+            }
+        }
+    }
+
+    /// When the meta server last confirmed server `id`, if its entry was [loaded](Self::load) and
+    /// hasn't been confirmed since
+    pub fn last_seen(&self, id: ServerId) -> Option<SystemTime> {
+        self.cached.get(&id).copied()
+    }
+
+    /// Update the list to reflect the changes described by `msg`
+    ///
+    /// Game server states are copied. See [`apply_owned`](Self::apply_owned) to avoid that.
+    pub fn apply(&mut self, msg: &proto::Message<'_>) {
+        self.apply_with(msg, Bytes::copy_from_slice);
+    }
+
+    /// Like [`apply`](Self::apply), but store game server states without copying them
+    ///
+    /// Entries' states refer to `msg`'s buffer, which is kept alive until every entry taken from
+    /// it has been updated or removed. Useful for large lists, e.g. to avoid holding two copies
+    /// of every state in the initial snapshot.
+    pub fn apply_owned(&mut self, msg: &OwnedMessage) {
+        let data = msg.as_bytes();
+        self.apply_with(&msg.get(), |x| data.slice_ref(x));
+    }
+
+    /// Apply `msg`, converting each game server state with `state`
+    fn apply_with(&mut self, msg: &proto::Message<'_>, state: impl Fn(&[u8]) -> Bytes) {
+        if self.loaded {
+            self.connected(None, None);
+        }
+        if msg
+            .servers
+            .iter()
+            .any(|x| matches!(x.event, proto::Event::Reset))
+        {
+            // The message is a fresh snapshot, but the subscription is unaffected
+            let generation = self.filter_generation;
+            self.clear();
+            self.filter_generation = generation;
+        }
+        let mut servers = msg.servers.iter().peekable();
+        while let Some(server) = servers.next() {
+            match server.event {
+                proto::Event::Shutdown => self.remove(server.id),
+                proto::Event::Update(address, data) => {
+                    // Fold in supplementary events, so they're reported as part of the update
+                    let mut region = None;
+                    let mut tags = None;
+                    // Only sent when applicable, so absence means there are none
+                    let mut lan_addresses = Vec::new();
+                    let mut truncated = None;
+                    while let Some(next) = servers.next_if(|x| {
+                        x.id == server.id
+                            && matches!(
+                                x.event,
+                                proto::Event::Region(_)
+                                    | proto::Event::Tags(_)
+                                    | proto::Event::LanAddresses(_)
+                                    | proto::Event::Truncated(_)
+                            )
+                    }) {
+                        match next.event {
+                            proto::Event::Region(x) => region = Some(x),
+                            proto::Event::Tags(ref x) => tags = Some(owned_tags(x)),
+                            proto::Event::LanAddresses(ref x) => lan_addresses.clone_from(x),
+                            proto::Event::Truncated(x) => truncated = Some(x),
+                            _ => unreachable!(),
+                        }
+                    }
+                    let logging = self.change_log_capacity > 0;
+                    self.cached.remove(&server.id);
+                    let change = match self.servers.get_mut(&server.id) {
+                        Some(entry)
+                            if entry.address == address
+                                && entry.state == data
+                                && region.is_none_or(|x| x == entry.region)
+                                && tags.as_ref().is_none_or(|x| *x == entry.tags)
+                                && entry.lan_addresses == lan_addresses
+                                && entry.truncated == truncated =>
+                        {
+                            // e.g. a duplicate from a mirror merging several upstreams
+                            self.unchanged += 1;
+                            if !self.report_unchanged {
+                                continue;
+                            }
+                            logging.then_some(Change::Unchanged(server.id))
+                        }
+                        Some(entry) => {
+                            entry.address = address;
+                            entry.state = state(data);
+                            if let Some(region) = region {
+                                entry.region = region;
+                            }
+                            if let Some(tags) = tags {
+                                entry.tags = tags;
+                            }
+                            entry.lan_addresses = lan_addresses;
+                            entry.truncated = truncated;
+                            logging.then(|| Change::Updated(server.id, entry.clone()))
+                        }
+                        None if self.stale.contains_key(&address) => {
+                            let (old, mut entry) = self.stale.remove(&address).unwrap();
+                            entry.state = state(data);
+                            if let Some(region) = region {
+                                entry.region = region;
+                            }
+                            if let Some(tags) = tags {
+                                entry.tags = tags;
+                            }
+                            entry.lan_addresses = lan_addresses;
+                            entry.truncated = truncated;
+                            if old != server.id {
+                                self.log(logging.then_some(Change::Renumbered(old, server.id)));
+                            }
+                            let change = logging.then(|| Change::Updated(server.id, entry.clone()));
+                            self.servers.insert(server.id, entry);
+                            change
+                        }
+                        None => {
+                            let entry = ServerEntry {
+                                address,
+                                state: state(data),
+                                region: region.flatten(),
+                                tags: tags.unwrap_or_default(),
+                                lan_addresses,
+                                truncated,
+                                user_data: None,
+                            };
+                            for f in &self.on_added {
+                                f(server.id, &entry);
+                            }
+                            let change = logging.then(|| Change::Added(server.id, entry.clone()));
+                            self.servers.insert(server.id, entry);
+                            change
+                        }
+                    };
+                    self.log(change);
+                }
+                proto::Event::Region(region) => {
+                    if let Some(entry) = self.servers.get_mut(&server.id) {
+                        let changed = entry.region != region;
+                        entry.region = region;
+                        self.updated(server.id, changed);
+                    }
+                }
+                proto::Event::Tags(ref tags) => {
+                    if let Some(entry) = self.servers.get_mut(&server.id) {
+                        let changed = entry.tags != *tags;
+                        if changed {
+                            entry.tags = owned_tags(tags);
+                        }
+                        self.updated(server.id, changed);
+                    }
+                }
+                proto::Event::LanAddresses(ref addresses) => {
+                    if let Some(entry) = self.servers.get_mut(&server.id) {
+                        let changed = entry.lan_addresses != *addresses;
+                        entry.lan_addresses.clone_from(addresses);
+                        self.updated(server.id, changed);
+                    }
+                }
+                proto::Event::Subscribed(generation) => self.filter_generation = generation,
+                proto::Event::Synchronized => self.synchronized = true,
+                proto::Event::SnapshotProgress { delivered, total } => {
+                    self.snapshot_progress = Some((delivered, total));
+                }
+                _ => {}
+            }
+        }
+        for x in &msg.undecodable {
+            let change = Change::Undecodable {
+                id: x.id,
+                bytes: state(x.bytes),
+            };
+            self.log(Some(change));
+        }
+        // A paced snapshot spans several messages
+        if self.snapshot_progress.is_some() && !self.synchronized {
+            return;
+        }
+        // The first message after reconnecting is a snapshot, so anything unmatched is gone
+        for (_, (id, entry)) in mem::take(&mut self.stale) {
+            self.removed(id, entry);
+        }
+        let unconfirmed = self.cached.keys().copied().collect::<Vec<_>>();
+        for id in unconfirmed {
+            self.remove(id);
+        }
+    }
+
+    /// Record that known server `id` was updated by an event on its own, which may have `changed`
+    /// nothing, as for an update
+    fn updated(&mut self, id: ServerId, changed: bool) {
+        if !changed {
+            self.unchanged += 1;
+            if self.report_unchanged {
+                self.log(Some(Change::Unchanged(id)));
+            }
+            return;
+        }
+        let logging = self.change_log_capacity > 0;
+        let change = logging.then(|| Change::Updated(id, self.servers[&id].clone()));
+        self.log(change);
+    }
+
+    fn remove(&mut self, id: ServerId) {
+        self.cached.remove(&id);
+        let Some(entry) = self.servers.remove(&id) else {
+            return;
+        };
+        self.removed(id, entry);
+    }
+
+    fn removed(&mut self, id: ServerId, entry: ServerEntry) {
+        for f in &self.on_removed {
+            f(id, &entry);
+        }
+        self.log(Some(Change::Removed(id, entry)));
+    }
+
+    /// Record a change, which may be `None` if the change log is disabled
+    fn log(&mut self, change: Option<Change>) {
+        let Some(change) = change.filter(|_| self.change_log_capacity > 0) else {
+            self.changes_start += 1;
+            return;
+        };
+        if self.changes.len() == self.change_log_capacity {
+            self.changes.pop_front();
+            self.changes_start += 1;
+        }
+        self.changes.push_back(change);
+    }
+
+    pub fn get(&self, id: ServerId) -> Option<&ServerEntry> {
+        self.servers.get(&id)
+    }
+
+    /// Iterate over known servers in ascending order of ID
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (ServerId, &ServerEntry)> + '_ {
+        self.servers.iter().map(|(&id, entry)| (id, entry))
+    }
+
+    /// Iterate over known servers that aren't [hidden](Mark::Hidden), pinned servers first, each
+    /// group in ascending order of ID
+    pub fn iter_visible(&self) -> impl Iterator<Item = (ServerId, &ServerEntry)> + '_ {
+        let with = move |mark| {
+            self.iter()
+                .filter(move |(_, x)| self.marks.get(&x.address).copied() == mark)
+        };
+        with(Some(Mark::Pinned)).chain(with(None))
+    }
+
+    /// Mark game servers advertising `address`, or unmark them if `mark` is `None`, returning
+    /// their previous mark
+    ///
+    /// Marks are kept by address, so they apply to servers that aren't known yet, and survive
+    /// servers being renumbered, the list being cleared, and being [saved](Self::save) and
+    /// [loaded](Self::load). Marked servers are still kept up to date. Changing a known server's
+    /// mark is recorded as [`Change::Marked`].
+    pub fn set_mark(&mut self, address: SocketAddr, mark: Option<Mark>) -> Option<Mark> {
+        let old = match mark {
+            Some(x) => self.marks.insert(address, x),
+            None => self.marks.remove(&address),
+        };
+        if old != mark {
+            let ids = self.iter().filter(|(_, x)| x.address == address);
+            let ids = ids.map(|(id, _)| id).collect::<Vec<_>>();
+            for id in ids {
+                self.log(Some(Change::Marked(id, mark)));
+            }
+        }
+        old
+    }
+
+    /// How game servers advertising `address` are marked, if at all
+    pub fn mark(&self, address: SocketAddr) -> Option<Mark> {
+        self.marks.get(&address).copied()
+    }
+
+    /// Every address that's marked, in no particular order
+    pub fn marks(&self) -> impl ExactSizeIterator<Item = (SocketAddr, Mark)> + '_ {
+        self.marks.iter().map(|(&address, &mark)| (address, mark))
+    }
+
+    /// Whether [`changes_since`](Self::changes_since) reports changes to [hidden](Mark::Hidden)
+    /// servers
+    ///
+    /// Disabled by default.
+    pub fn set_include_hidden(&mut self, enabled: bool) {
+        self.include_hidden = enabled;
+    }
+
+    /// Whether `change` is about a server that's currently hidden
+    fn hides(&self, change: &Change) -> bool {
+        let address = match *change {
+            Change::Added(_, ref x) | Change::Updated(_, ref x) | Change::Removed(_, ref x) => {
+                x.address
+            }
+            Change::Renumbered(_, id) | Change::Unchanged(id) => match self.servers.get(&id) {
+                Some(x) => x.address,
+                None => return false,
+            },
+            Change::Marked(..) | Change::Undecodable { .. } => return false,
+        };
+        self.mark(address) == Some(Mark::Hidden)
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    /// Forget all servers, e.g. after reconnecting
+    ///
+    /// Each server is reported as removed, unless [reconciling by
+    /// address](Self::set_reconcile_by_address).
+    pub fn clear(&mut self) {
+        if self.reconcile_by_address {
+            for (id, entry) in mem::take(&mut self.servers) {
+                // Should a previous clear not have been followed by a message, those servers go
+                if let Some((id, entry)) = self.stale.insert(entry.address, (id, entry)) {
+                    self.removed(id, entry);
+                }
+            }
+        }
+        while let Some((&id, _)) = self.servers.first_key_value() {
+            self.remove(id);
+        }
+        self.filter_generation = 0;
+        self.synchronized = false;
+        self.snapshot_progress = None;
+        self.cached.clear();
+        self.loaded = false;
+    }
+
+    /// Whether the meta server's initial snapshot has been fully applied
+    ///
+    /// Always false for meta servers predating [`proto::PROTOCOL_V3`], whose first message is
+    /// the snapshot. See also `Client::synchronized`.
+    pub fn is_synchronized(&self) -> bool {
+        self.synchronized
+    }
+
+    /// Number of game servers received so far out of the total in a snapshot paced by
+    /// `Builder::paced_snapshot`, or `None` if the snapshot isn't paced
+    pub fn snapshot_progress(&self) -> Option<(u64, u64)> {
+        self.snapshot_progress
+    }
+
+    /// Generation of the latest filter the meta server has applied to the list
+    ///
+    /// The list reflects the filter most recently passed to `Client::set_filter` once this
+    /// equals `Client::filter_generation`. 0 if no filter has been applied.
+    pub fn filter_generation(&self) -> u64 {
+        self.filter_generation
+    }
+
+    /// Whether [`clear`](Self::clear) should set servers aside, so that those still at the same
+    /// address in the next message keep their entries, e.g. across a meta server restart
+    ///
+    /// IDs generally change when reconnecting, so matched servers are reported as
+    /// [`Change::Renumbered`] and then updated, and keep their
+    /// [`user_data`](ServerEntry::user_data). The rest are removed once the next message has been
+    /// applied. Disabled by default.
+    pub fn set_reconcile_by_address(&mut self, enabled: bool) {
+        self.reconcile_by_address = enabled;
+    }
+
+    /// Whether to record updates that change nothing as [`Change::Unchanged`]
+    ///
+    /// Such updates are otherwise only counted by [`unchanged_updates`](Self::unchanged_updates).
+    /// Disabled by default.
+    pub fn set_report_unchanged(&mut self, enabled: bool) {
+        self.report_unchanged = enabled;
+    }
+
+    /// Number of updates applied that were identical to what was already known
+    pub fn unchanged_updates(&self) -> u64 {
+        self.unchanged
+    }
+
+    /// Attach `data` to server `id`'s entry, returning whether the server is known
+    pub fn set_user_data(&mut self, id: ServerId, data: Option<UserData>) -> bool {
+        let Some(entry) = self.servers.get_mut(&id) else {
+            return false;
+        };
+        entry.user_data = data;
+        true
+    }
+
+    /// Keep up to `capacity` of the most recent changes for [`changes_since`](Self::changes_since)
+    ///
+    /// Disabled (0) by default.
+    pub fn set_change_log_capacity(&mut self, capacity: usize) {
+        self.change_log_capacity = capacity;
+        while self.changes.len() > capacity {
+            self.changes.pop_front();
+            self.changes_start += 1;
+        }
+    }
+
+    /// Position after the most recent change, for a later call to
+    /// [`changes_since`](Self::changes_since)
+    pub fn cursor(&self) -> Cursor {
+        Cursor(self.changes_start + self.changes.len() as u64)
+    }
+
+    /// Changes made since `cursor` was obtained, oldest first, and a cursor for the next call
+    ///
+    /// Fails if changes since `cursor` have been dropped to stay within the
+    /// [change log capacity](Self::set_change_log_capacity), in which case the caller should
+    /// re-read the full list with [`iter`](Self::iter) and continue from [`cursor`](Self::cursor).
+    /// Changes to servers that are currently [hidden](Mark::Hidden) are left out, unless
+    /// [enabled](Self::set_include_hidden).
+    pub fn changes_since(&self, cursor: Cursor) -> Result<(Vec<Change>, Cursor), Stale> {
+        let skip = cursor.0.checked_sub(self.changes_start).ok_or(Stale)?;
+        let changes = self.changes.iter().skip(skip as usize);
+        let changes = changes.filter(|x| self.include_hidden || !self.hides(x));
+        Ok((changes.cloned().collect(), self.cursor()))
+    }
+
+    /// Call `f` from [`apply`](Self::apply) whenever a server is added
+    pub fn on_added(&mut self, f: impl Fn(ServerId, &ServerEntry) + Send + Sync + 'static) {
+        self.on_added.push(Arc::new(f));
+    }
+
+    /// Call `f` from [`apply`](Self::apply) or [`clear`](Self::clear) whenever a server is
+    /// removed, with its last known information
+    pub fn on_removed(&mut self, f: impl Fn(ServerId, &ServerEntry) + Send + Sync + 'static) {
+        self.on_removed.push(Arc::new(f));
+    }
+}
+
+impl fmt::Debug for ServerList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerList")
+            .field("servers", &self.servers)
+            .field("changes", &self.changes)
+            .field("changes_start", &self.changes_start)
+            .field("change_log_capacity", &self.change_log_capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Latest known information about a game server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEntry {
+    /// Address game clients should connect to
+    pub address: SocketAddr,
+    /// The game server's most recent heartbeat
+    pub state: Bytes,
+    /// Where the game server is, if the meta server knows
+    pub region: Option<proto::Region>,
+    /// Labels the game server declared, if the meta server supports them
+    pub tags: Vec<String>,
+    /// Addresses on our local network the game server may be reached at, which should be tried
+    /// alongside `address`
+    ///
+    /// Only known if the meta server thinks we share the game server's network.
+    pub lan_addresses: Vec<SocketAddr>,
+    /// Full length of the game server's state if `state` is only its start, as requested with
+    /// [`Client::truncate_states`](crate::Client::truncate_states)
+    ///
+    /// Fetch the rest on demand with [`Client::get_server`](crate::Client::get_server).
+    pub truncated: Option<u32>,
+    /// Set by the application with [`ServerList::set_user_data`]
+    pub user_data: Option<UserData>,
+}
+
+/// Application data attached to a [`ServerEntry`], e.g. whether it's selected in a UI
+///
+/// Compares equal only to clones of itself.
+#[derive(Clone)]
+pub struct UserData(pub Arc<dyn Any + Send + Sync>);
+
+impl UserData {
+    pub fn new(data: impl Any + Send + Sync) -> Self {
+        Self(Arc::new(data))
+    }
+}
+
+impl PartialEq for UserData {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for UserData {}
+
+impl fmt::Debug for UserData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UserData(..)")
+    }
+}
+
+/// How the user wants a game server treated, as set by [`ServerList::set_mark`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Mark {
+    /// Listed first by [`ServerList::iter_visible`], e.g. as a favorite
+    Pinned,
+    /// Left out of [`ServerList::iter_visible`] and [`ServerList::changes_since`]
+    Hidden,
+}
+
+fn owned_tags(tags: &[&str]) -> Vec<String> {
+    tags.iter().map(|&x| x.into()).collect()
+}
+
+/// A change to a [`ServerList`], with the affected server's ID and information
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(ServerId, ServerEntry),
+    Updated(ServerId, ServerEntry),
+    /// The server shut down, with its last known information
+    Removed(ServerId, ServerEntry),
+    /// The server with the first ID now has the second, after reconnecting
+    ///
+    /// See [`ServerList::set_reconcile_by_address`].
+    Renumbered(ServerId, ServerId),
+    /// The server was updated without any change
+    ///
+    /// Only recorded if enabled with [`ServerList::set_report_unchanged`].
+    Unchanged(ServerId),
+    /// The server's [`Mark`] changed, e.g. so it should now be shown or hidden
+    Marked(ServerId, Option<Mark>),
+    /// An entry of a message couldn't be decoded, so was skipped
+    ///
+    /// The server's entry, if any, is left as it was. See [`proto::Undecodable`].
+    Undecodable {
+        id: Option<ServerId>,
+        bytes: Bytes,
+    },
+}
+
+/// Position in a [`ServerList`]'s sequence of changes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor(u64);
+
+/// Error returned by [`ServerList::changes_since`] when the requested changes are no longer known
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Stale;
+
+impl fmt::Display for Stale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("changes are no longer in the change log")
+    }
+}
+
+impl std::error::Error for Stale {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{Event, Message, Server};
+
+    fn message(servers: Vec<Server<'_>>) -> Message<'_> {
+        Message {
+            servers,
+            undecodable: Vec::new(),
+        }
+    }
+
+    fn update(id: u64, port: u16, state: &[u8]) -> Server<'_> {
+        Server {
+            id: ServerId(id),
+            event: Event::Update(addr(port), state),
+        }
+    }
+
+    fn synchronized() -> Server<'static> {
+        Server {
+            id: ServerId::NONE,
+            event: Event::Synchronized,
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    /// A list of three servers at ports 1000, 1001, and 1002, with data attached to the second
+    fn populated(reconcile_by_address: bool) -> ServerList {
+        let mut list = ServerList::new();
+        list.set_reconcile_by_address(reconcile_by_address);
+        list.set_change_log_capacity(100);
+        list.apply(&message(vec![
+            update(0, 1000, b"a"),
+            update(1, 1001, b"b"),
+            update(2, 1002, b"c"),
+            synchronized(),
+        ]));
+        assert!(list.set_user_data(ServerId(1), Some(UserData::new("selected"))));
+        list
+    }
+
+    /// A restarted meta server's snapshot, in which every ID changed, the server at port 1002 is
+    /// gone, and one at port 1003 is new
+    fn remapped() -> Message<'static> {
+        message(vec![
+            update(10, 1001, b"b2"),
+            update(11, 1000, b"a"),
+            update(12, 1003, b"d"),
+            synchronized(),
+        ])
+    }
+
+    #[test]
+    fn reconcile_remapped_ids() {
+        let mut list = populated(true);
+        let cursor = list.cursor();
+        list.clear();
+        list.apply(&remapped());
+
+        assert_eq!(list.len(), 3);
+        let entry = list.get(ServerId(10)).unwrap();
+        assert_eq!(entry.address, addr(1001));
+        assert_eq!(&entry.state[..], b"b2");
+        let data = entry.user_data.as_ref().unwrap();
+        assert_eq!(data.0.downcast_ref::<&str>(), Some(&"selected"));
+        assert_eq!(list.get(ServerId(11)).unwrap().address, addr(1000));
+        assert!(list.get(ServerId(12)).unwrap().user_data.is_none());
+
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        let renumbered = changes
+            .iter()
+            .filter_map(|x| match *x {
+                Change::Renumbered(old, new) => Some((old, new)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            renumbered,
+            [(ServerId(1), ServerId(10)), (ServerId(0), ServerId(11))]
+        );
+        let removed = changes
+            .iter()
+            .filter_map(|x| match *x {
+                Change::Removed(id, ref entry) => Some((id, entry.address)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(removed, [(ServerId(2), addr(1002))]);
+        assert!(changes
+            .iter()
+            .any(|x| matches!(*x, Change::Added(ServerId(12), _))));
+    }
+
+    #[test]
+    fn remapped_ids_without_reconciling() {
+        let mut list = populated(false);
+        let cursor = list.cursor();
+        list.clear();
+        list.apply(&remapped());
+
+        assert_eq!(list.len(), 3);
+        assert!(list.iter().all(|(_, x)| x.user_data.is_none()));
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        let removed = changes
+            .iter()
+            .filter(|x| matches!(x, Change::Removed(..)))
+            .count();
+        let added = changes
+            .iter()
+            .filter(|x| matches!(x, Change::Added(..)))
+            .count();
+        assert_eq!((removed, added), (3, 3));
+        assert!(!changes.iter().any(|x| matches!(x, Change::Renumbered(..))));
+    }
+
+    /// A server set aside by one reconnect that doesn't reappear before the next is reported as
+    /// removed, rather than matched or leaked
+    #[test]
+    fn reconcile_across_repeated_clears() {
+        let mut list = populated(true);
+        let removed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        list.on_removed({
+            let removed = removed.clone();
+            move |id, _| removed.lock().unwrap().push(id)
+        });
+        list.clear();
+        list.clear();
+        assert!(removed.lock().unwrap().is_empty());
+        list.apply(&message(vec![update(5, 1000, b"a"), synchronized()]));
+        assert_eq!(list.len(), 1);
+        let mut removed = removed.lock().unwrap().clone();
+        removed.sort();
+        assert_eq!(removed, [ServerId(1), ServerId(2)]);
+    }
+
+    /// Marks are kept through saving and loading, and apply once the servers are confirmed
+    #[test]
+    fn marks_survive_save_and_load() {
+        let path = crate::cache::tests::TempPath::new("list-marks");
+        let mut list = populated(true);
+        list.set_mark(addr(1001), Some(Mark::Pinned));
+        list.set_mark(addr(1002), Some(Mark::Hidden));
+        list.save(&path.0).unwrap();
+
+        let mut list = ServerList::load(&path.0).unwrap();
+        let mut marks = list.marks().collect::<Vec<_>>();
+        marks.sort_by_key(|&(address, _)| address);
+        assert_eq!(
+            marks,
+            [(addr(1001), Mark::Pinned), (addr(1002), Mark::Hidden)]
+        );
+        list.connected(None, None);
+        list.apply(&remapped());
+        assert_eq!(list.mark(addr(1001)), Some(Mark::Pinned));
+        let visible = list.iter_visible().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(visible, [ServerId(10), ServerId(11), ServerId(12)]);
+    }
+
+    /// Servers keep their marks when renumbered by a reconnect, since marks follow addresses
+    #[test]
+    fn marks_survive_remapping() {
+        let mut list = populated(true);
+        list.set_mark(addr(1001), Some(Mark::Pinned));
+        list.set_mark(addr(1000), Some(Mark::Hidden));
+        let visible = list.iter_visible().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(visible, [ServerId(1), ServerId(2)]);
+
+        let cursor = list.cursor();
+        list.clear();
+        list.apply(&remapped());
+        assert_eq!(list.mark(addr(1001)), Some(Mark::Pinned));
+        assert_eq!(list.mark(addr(1000)), Some(Mark::Hidden));
+        let visible = list.iter_visible().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(visible, [ServerId(10), ServerId(12)]);
+
+        // The hidden server's renumbering and update are left out, as it's still hidden
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert!(changes.contains(&Change::Renumbered(ServerId(1), ServerId(10))));
+        assert!(!changes.iter().any(|x| matches!(
+            *x,
+            Change::Renumbered(_, ServerId(11)) | Change::Updated(ServerId(11), _)
+        )));
+        list.set_include_hidden(true);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert!(changes.contains(&Change::Renumbered(ServerId(0), ServerId(11))));
+    }
+
+    /// Changes to hidden servers are left out of the change log's output unless asked for, but
+    /// changes of mark never are
+    #[test]
+    fn changes_since_leaves_out_hidden() {
+        let mut list = populated(false);
+        let cursor = list.cursor();
+        list.set_mark(addr(1002), Some(Mark::Hidden));
+        list.apply(&message(vec![
+            update(2, 1002, b"c2"),
+            update(0, 1000, b"a2"),
+        ]));
+
+        let (changes, next) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0], Change::Marked(ServerId(2), Some(Mark::Hidden)));
+        assert!(matches!(changes[1], Change::Updated(ServerId(0), _)));
+        assert_eq!(next, list.cursor());
+
+        list.set_include_hidden(true);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(changes[1], Change::Updated(ServerId(2), _)));
+
+        // Unhiding reports the server's changes again
+        list.set_include_hidden(false);
+        list.set_mark(addr(1002), None);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes.len(), 4);
+    }
+
+    /// A cursor taken between applies sees exactly the changes made after it
+    #[test]
+    fn changes_since_interleaved() {
+        let mut list = ServerList::new();
+        list.set_change_log_capacity(100);
+        let start = list.cursor();
+        list.apply(&message(vec![update(0, 1000, b"a"), update(1, 1001, b"b")]));
+        let middle = list.cursor();
+        list.apply(&message(vec![
+            update(1, 1001, b"b2"),
+            Server {
+                id: ServerId(0),
+                event: Event::Shutdown,
+            },
+        ]));
+        let end = list.cursor();
+
+        let (changes, next) = list.changes_since(middle).unwrap();
+        assert_eq!(next, end);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(changes[0], Change::Updated(ServerId(1), ref x) if &x.state[..] == b"b2"));
+        assert!(matches!(changes[1], Change::Removed(ServerId(0), _)));
+        let (changes, _) = list.changes_since(start).unwrap();
+        assert_eq!(changes.len(), 4);
+        assert!(matches!(changes[0], Change::Added(ServerId(0), _)));
+        assert!(matches!(changes[1], Change::Added(ServerId(1), _)));
+        assert_eq!(list.changes_since(end).unwrap().0, []);
+
+        // Nothing is reported twice when following the returned cursor
+        list.apply(&message(vec![update(2, 1002, b"c")]));
+        let (changes, _) = list.changes_since(next).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0], Change::Added(ServerId(2), _)));
+    }
+
+    /// Changes that overflow the log make older cursors stale, but not newer ones
+    #[test]
+    fn changes_since_overflow() {
+        let mut list = ServerList::new();
+        list.set_change_log_capacity(2);
+        let start = list.cursor();
+        list.apply(&message(vec![update(0, 1000, b"a")]));
+        let middle = list.cursor();
+        list.apply(&message(vec![update(1, 1001, b"b"), update(2, 1002, b"c")]));
+
+        assert_eq!(list.changes_since(start), Err(Stale));
+        let (changes, _) = list.changes_since(middle).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(list.changes_since(list.cursor()).unwrap().0, []);
+
+        // Shrinking the log drops the oldest changes too
+        list.set_change_log_capacity(1);
+        assert_eq!(list.changes_since(middle), Err(Stale));
+    }
+
+    /// With the change log disabled, cursors still advance with each change, so that one taken
+    /// before a change is stale once the log is enabled
+    #[test]
+    fn cursor_advances_without_log() {
+        let mut list = ServerList::new();
+        let start = list.cursor();
+        list.apply(&message(vec![update(0, 1000, b"a"), update(1, 1001, b"b")]));
+        let after = list.cursor();
+        assert!(after > start);
+        assert_eq!(list.changes_since(start), Err(Stale));
+        assert_eq!(list.changes_since(after).unwrap(), (Vec::new(), after));
+
+        list.set_change_log_capacity(10);
+        list.apply(&message(vec![update(0, 1000, b"a2")]));
+        assert_eq!(list.changes_since(after).unwrap().0.len(), 1);
+    }
+
+    fn event(id: u64, event: Event<'static>) -> Server<'static> {
+        Server {
+            id: ServerId(id),
+            event,
+        }
+    }
+
+    /// Only updates that change something are reported as such, whatever they change
+    #[test]
+    fn unchanged_updates() {
+        let mut list = populated(false);
+        list.apply(&message(vec![event(0, Event::Tags(vec!["eu"]))]));
+        let cursor = list.cursor();
+
+        // Identical, including the tags that follow
+        list.apply(&message(vec![
+            update(0, 1000, b"a"),
+            event(0, Event::Tags(vec!["eu"])),
+        ]));
+        assert_eq!(list.unchanged_updates(), 1);
+        assert_eq!(list.cursor(), cursor);
+
+        // Only the address changed
+        list.apply(&message(vec![update(1, 2001, b"b")]));
+        // Only the information accompanying the state changed
+        list.apply(&message(vec![
+            update(2, 1002, b"c"),
+            event(2, Event::Region(Some(proto::Region(*b"DE")))),
+        ]));
+        assert_eq!(list.unchanged_updates(), 1);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(
+            matches!(changes[0], Change::Updated(ServerId(1), ref x) if x.address == addr(2001))
+        );
+        assert!(matches!(
+            changes[1],
+            Change::Updated(ServerId(2), ref x) if x.region == Some(proto::Region(*b"DE"))
+        ));
+
+        list.set_report_unchanged(true);
+        let cursor = list.cursor();
+        list.apply(&message(vec![update(1, 2001, b"b")]));
+        assert_eq!(list.unchanged_updates(), 2);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes, [Change::Unchanged(ServerId(1))]);
+    }
+
+    /// Region, tags, and LAN addresses sent on their own count as unchanged if they are
+    #[test]
+    fn unchanged_standalone_events() {
+        let lan = || vec![SocketAddr::from(([10, 0, 0, 2], 1000))];
+        let region = Some(proto::Region(*b"DE"));
+        let mut list = populated(false);
+        let cursor = list.cursor();
+        list.apply(&message(vec![
+            event(0, Event::Region(region)),
+            event(0, Event::Tags(vec!["eu"])),
+            event(0, Event::LanAddresses(lan())),
+        ]));
+        assert_eq!(list.unchanged_updates(), 0);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes.len(), 3);
+        let entry = list.get(ServerId(0)).unwrap();
+        assert_eq!(changes[2], Change::Updated(ServerId(0), entry.clone()));
+        assert_eq!(entry.region, region);
+        assert_eq!(entry.tags, ["eu"]);
+        assert_eq!(entry.lan_addresses, lan());
+
+        let cursor = list.cursor();
+        list.apply(&message(vec![
+            event(0, Event::Region(region)),
+            event(0, Event::Tags(vec!["eu"])),
+            event(0, Event::LanAddresses(lan())),
+        ]));
+        assert_eq!(list.unchanged_updates(), 3);
+        assert_eq!(list.cursor(), cursor);
+
+        list.set_report_unchanged(true);
+        list.apply(&message(vec![
+            event(0, Event::Region(region)),
+            event(0, Event::Tags(vec!["eu"])),
+            event(0, Event::LanAddresses(lan())),
+        ]));
+        assert_eq!(list.unchanged_updates(), 6);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert_eq!(changes, vec![Change::Unchanged(ServerId(0)); 3]);
+    }
+}
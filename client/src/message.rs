@@ -0,0 +1,120 @@
+use bytes::Bytes;
+
+use crate::proto;
+
+/// A message from a meta server that owns its data, for sharing or storing beyond the next
+/// [`Client::recv`](crate::Client::recv)
+///
+/// Holds the message in its compact wire encoding, which is decoded on each call to
+/// [`get`](Self::get). Cloning is cheap, as the encoding is reference-counted.
+#[derive(Debug, Clone)]
+pub struct OwnedMessage {
+    data: Bytes,
+    /// Version of the client protocol the message was encoded for
+    version: u32,
+}
+
+impl OwnedMessage {
+    /// Validate a message encoded for `version` of the client protocol
+    #[cfg(any(feature = "net", feature = "web"))]
+    pub(crate) fn decode(data: Vec<u8>, version: u32) -> Result<Self, bincode::Error> {
+        proto::Message::decode(&data, version)?;
+        Ok(Self {
+            data: data.into(),
+            version,
+        })
+    }
+
+    /// Access the message's contents
+    pub fn get(&self) -> proto::Message<'_> {
+        proto::Message::decode(&self.data, self.version)
+            .expect("message was validated on construction")
+    }
+
+    /// The message's encoding, which data borrowed from [`get`](Self::get) points into
+    pub(crate) fn as_bytes(&self) -> &Bytes {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        net::SocketAddr,
+    };
+
+    use super::*;
+    use crate::{
+        proto::{Event, Message, Server, ServerId},
+        ServerList,
+    };
+
+    /// Counts allocations made by the current thread, so concurrent tests don't interfere
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Number of allocations `f` makes
+    fn allocations(f: impl FnOnce()) -> u64 {
+        let start = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - start
+    }
+
+    /// A message updating servers `0..count`, each with a distinct address and state
+    fn updates(count: u64, state: &[u8]) -> OwnedMessage {
+        let servers = (0..count)
+            .map(|i| Server {
+                id: ServerId(i),
+                event: Event::Update(SocketAddr::from(([192, 0, 2, 1], i as u16)), state),
+            })
+            .collect::<Vec<_>>();
+        let msg = Message {
+            servers,
+            undecodable: Vec::new(),
+        };
+        OwnedMessage {
+            data: msg.encode(3).into(),
+            version: 3,
+        }
+    }
+
+    /// Applying an owned message shares its buffer rather than copying each state, so updating
+    /// every server allocates only to decode the message and share its buffer
+    #[test]
+    fn apply_owned_allocations() {
+        const SERVERS: u64 = 1000;
+        let snapshot = updates(SERVERS, &[1; 100]);
+        let update = updates(SERVERS, &[2; 100]);
+
+        let mut copied = ServerList::new();
+        let mut shared = ServerList::new();
+        let copying = allocations(|| copied.apply(&snapshot.get()));
+        let sharing = allocations(|| shared.apply_owned(&snapshot));
+        // A copy of each state, less the one allocation that makes the buffer shareable
+        assert_eq!(copying - sharing, SERVERS - 1);
+
+        // Two vectors for the decoded message, and one to make the buffer shareable
+        assert_eq!(allocations(|| shared.apply_owned(&update)), 3);
+        assert_eq!(allocations(|| copied.apply(&update.get())), SERVERS + 2);
+        assert_eq!(&shared.get(ServerId(0)).unwrap().state[..], [2; 100]);
+    }
+}
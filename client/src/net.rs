@@ -0,0 +1,1663 @@
+#[cfg(feature = "helpers")]
+use std::{
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+};
+use std::{
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures_util::{
+    future::{self, Either},
+    stream::BoxStream,
+    Stream,
+};
+use quinn::rustls::pki_types::CertificateDer;
+#[cfg(feature = "helpers")]
+use quinn::{crypto::rustls::QuicClientConfig, rustls};
+use thiserror::Error;
+use tokio::sync::{broadcast, watch};
+
+use crate::{proto, record::Recorder, OwnedMessage, ServerEntry, ServerList, Sleep, KEEPALIVE};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("connection lost: {0}")]
+    Connection(#[source] BoxError),
+    #[error("failed to read from server: {0}")]
+    Read(#[source] BoxError),
+    #[error("failed to send to server: {0}")]
+    Write(#[source] BoxError),
+    #[error("server sent malformed data: {0}")]
+    Parse(#[source] BoxError),
+    #[error("recording I/O failed: {0}")]
+    Recording(#[source] io::Error),
+    #[error("end of recording")]
+    EndOfRecording,
+    /// A subscriber fell behind and missed this many messages
+    ///
+    /// Any [`ServerList`](crate::ServerList) maintained from the subscription is now inaccurate,
+    /// and should be rebuilt from a new connection.
+    #[error("missed {0} messages")]
+    Lagged(u64),
+    /// No message arrived within the time passed to [`Client::recv_timeout`]
+    #[error("timed out")]
+    TimedOut,
+    /// The meta server was silent for longer than [`Client::set_max_silence`] allows
+    #[error("meta server stopped responding")]
+    Stalled,
+    /// The meta server predates the feature, e.g. filters before [`proto::PROTOCOL_V3`]
+    #[error("meta server does not support this")]
+    Unsupported,
+    /// See [`proto::Filter::is_valid`]
+    #[error("filter has too many tags, or a tag is empty or too long")]
+    InvalidFilter,
+    /// The meta server refused a query because queries were made too often
+    #[error("rate limited")]
+    RateLimited,
+}
+
+impl Error {
+    fn connection(e: quinn::ConnectionError) -> Self {
+        Self::Connection(e.into())
+    }
+
+    fn read(e: quinn::ReadError) -> Self {
+        match e {
+            quinn::ReadError::ConnectionLost(e) => Self::connection(e),
+            e => Self::Read(e.into()),
+        }
+    }
+
+    fn write(e: quinn::WriteError) -> Self {
+        match e {
+            quinn::WriteError::ConnectionLost(e) => Self::connection(e),
+            e => Self::Write(e.into()),
+        }
+    }
+}
+
+/// Failure to establish a connection to a meta server
+#[cfg(feature = "helpers")]
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("invalid meta server address {0:?}: expected host:port")]
+    InvalidAddress(String),
+    #[error("failed to resolve meta server address: {0}")]
+    Resolve(#[source] std::io::Error),
+    #[error("meta server hostname did not resolve to any addresses")]
+    NoAddresses,
+    #[error("invalid certificate authority: {0}")]
+    InvalidCa(#[source] BoxError),
+    #[error("unusable TLS configuration: {0}")]
+    Tls(#[source] BoxError),
+    #[error("failed to bind local socket: {0}")]
+    Bind(#[source] std::io::Error),
+    #[error("connection failed: {0}")]
+    Connection(#[source] BoxError),
+    /// The meta server's certificate isn't issued by a trusted certificate authority
+    #[error("meta server's certificate is not issued by a trusted authority")]
+    UntrustedRoot,
+    /// The meta server's certificate isn't valid for the host it was connected to by
+    ///
+    /// `presented` are the names it's valid for, if known.
+    #[error("{}", name_mismatch(presented, requested))]
+    NameMismatch {
+        presented: Vec<String>,
+        requested: String,
+    },
+    /// The meta server's certificate has expired, at `not_after` if known
+    #[error("meta server's certificate has expired")]
+    Expired {
+        not_after: Option<std::time::SystemTime>,
+    },
+    #[error(transparent)]
+    Hello(Error),
+}
+
+/// An established connection to a meta server
+///
+/// Only needed to use a connection established by external code. Most applications should use
+/// [`Client::builder`] instead.
+pub struct Connection(quinn::Connection);
+
+impl From<quinn::Connection> for Connection {
+    fn from(x: quinn::Connection) -> Self {
+        Self(x)
+    }
+}
+
+pub struct Client {
+    connection: quinn::Connection,
+    /// Negotiated version of the client protocol
+    version: u32,
+    /// Negotiated ALPN ID
+    alpn: Vec<u8>,
+    /// See [`peer_certificate_fingerprint`](Self::peer_certificate_fingerprint)
+    fingerprint: Option<[u8; 32]>,
+    /// Whether a request has been sent, since meta servers wait for one from clients speaking
+    /// [`proto::PROTOCOL_V3`]
+    requested: Arc<AtomicBool>,
+    /// The meta server's [`proto::Welcome`], once received
+    welcome: Arc<OnceLock<proto::Welcome>>,
+    /// The meta server's latest limits, shared with any background task
+    limits: Arc<watch::Sender<Option<proto::Limits>>>,
+    /// Generation of the latest filter requested
+    filter_generation: AtomicU64,
+    /// Whether any message has been received
+    received: bool,
+    reader: Reader,
+    buffer: Vec<u8>,
+    /// Set once messages are being read by a background task for [`subscribe`](Self::subscribe)
+    subscription: Option<broadcast::Receiver<Arc<OwnedMessage>>>,
+    max_silence: Option<Duration>,
+    /// When the most recent message or keep-alive was received, shared with any background task
+    last_heard: Arc<Mutex<Instant>>,
+    /// Messages received so far, shared with any background task
+    usage: Arc<Mutex<Usage>>,
+    /// Set if the connection was established by a [`Builder`] on an endpoint of its own
+    endpoint: Option<quinn::Endpoint>,
+    /// From [`Builder::cache`]
+    cache: Option<PathBuf>,
+}
+
+impl Client {
+    /// Construct a client from a connection that negotiated one of [`proto::PROTOCOLS`]
+    ///
+    /// Meta servers speaking [`proto::PROTOCOL_V3`] send nothing until the client makes a request,
+    /// so unless one is made first, the first receive makes a request that has no effect.
+    pub fn new(connection: Connection) -> Self {
+        let last_heard = Arc::new(Mutex::new(Instant::now()));
+        let usage = Arc::new(Mutex::new(Usage::new()));
+        let alpn = alpn(&connection.0);
+        let version = version(&alpn);
+        let requested = Arc::new(AtomicBool::new(version < 3));
+        let welcome = Arc::new(OnceLock::new());
+        let limits = Arc::new(watch::Sender::new(None));
+        Self {
+            reader: Reader::new(
+                connection.0.clone(),
+                last_heard.clone(),
+                usage.clone(),
+                requested.clone(),
+                (version >= 3).then(|| welcome.clone()),
+                limits.clone(),
+            ),
+            fingerprint: fingerprint(&connection.0),
+            connection: connection.0,
+            version,
+            alpn,
+            requested,
+            welcome,
+            limits,
+            filter_generation: AtomicU64::new(0),
+            received: false,
+            buffer: Vec::new(),
+            subscription: None,
+            max_silence: None,
+            last_heard,
+            usage,
+            endpoint: None,
+            cache: None,
+        }
+    }
+
+    /// Configure a new connection to a meta server
+    #[cfg(feature = "helpers")]
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Ask the meta server to wait at least `interval` between updates
+    ///
+    /// Useful for bandwidth-constrained clients. Meta servers never send updates more often than
+    /// their own configured interval. Before [`proto::PROTOCOL_V3`], they only honor the first
+    /// request made on a connection.
+    pub async fn request_update_interval(&self, interval: Duration) -> Result<(), Error> {
+        let msg = match self.version {
+            1 | 2 => bincode::serialize(&proto::Hello {
+                update_interval: interval,
+            }),
+            _ => bincode::serialize(&proto::Request::UpdateInterval(interval)),
+        }
+        .unwrap();
+        self.request(&msg).await
+    }
+
+    /// Ask the meta server to only send information about game servers matching `filter`
+    ///
+    /// May be called at any time. Game servers that stop matching, including due to a new filter,
+    /// are reported as shut down. A [`ServerList`](crate::ServerList) reflects the latest filter
+    /// once its [`filter_generation`](crate::ServerList::filter_generation) matches this client's
+    /// [`filter_generation`](Self::filter_generation). Fails with [`Error::Unsupported`] if the
+    /// meta server predates filters.
+    pub async fn set_filter(&self, filter: proto::Filter) -> Result<(), Error> {
+        self.send_subscribe(filter, None).await
+    }
+
+    /// Ask the meta server to send only the first `max` bytes of each game server's state, or to
+    /// stop truncating them if `None`
+    ///
+    /// Useful for bandwidth-constrained clients, alongside
+    /// [`request_update_interval`](Self::request_update_interval). Truncated states are marked by
+    /// [`ServerEntry::truncated`]. Only states sent after the meta server receives the request are
+    /// affected, so call before receiving anything for the initial snapshot to be truncated. Fails
+    /// with [`Error::Unsupported`] if the meta server predates truncation.
+    pub async fn truncate_states(&self, max: Option<u32>) -> Result<(), Error> {
+        if self.version < 3 {
+            return Err(Error::Unsupported);
+        }
+        // Not a request that starts updates, so `requested` is left alone
+        let msg = bincode::serialize(&proto::Request::TruncateStates(max)).unwrap();
+        send(&self.connection, &msg).await
+    }
+
+    async fn send_subscribe(
+        &self,
+        filter: proto::Filter,
+        pacing: Option<proto::Pacing>,
+    ) -> Result<(), Error> {
+        if !filter.is_valid() {
+            return Err(Error::InvalidFilter);
+        }
+        if self.version < 3 {
+            return Err(Error::Unsupported);
+        }
+        let generation = self.filter_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let msg = proto::Request::Subscribe {
+            filter,
+            generation,
+            pacing,
+        };
+        self.request(&bincode::serialize(&msg).unwrap()).await
+    }
+
+    /// Number of filters requested with [`set_filter`](Self::set_filter) so far
+    pub fn filter_generation(&self) -> u64 {
+        self.filter_generation.load(Ordering::Relaxed)
+    }
+
+    /// Ask the meta server to pick a single game server matching `filter`
+    ///
+    /// Returns `None` if no game server matches. Queries don't require receiving messages, and
+    /// meta servers limit how often they may be made, failing others with [`Error::RateLimited`].
+    /// Fails with [`Error::Unsupported`] if the meta server predates queries.
+    pub async fn find_one(
+        &self,
+        filter: proto::Filter,
+        strategy: proto::Strategy,
+    ) -> Result<Option<(proto::ServerId, ServerEntry)>, Error> {
+        if !filter.is_valid() {
+            return Err(Error::InvalidFilter);
+        }
+        let response = self
+            .query(&proto::Request::FindOne { filter, strategy })
+            .await?;
+        match metaserve_proto::decode(&response).map_err(|e| Error::Parse(e.into()))? {
+            proto::Response::FindOne(found) => Ok(found.map(|x| (x.id, found_entry(x)))),
+            proto::Response::RateLimited => Err(Error::RateLimited),
+            _ => Err(Error::Parse("unexpected response".into())),
+        }
+    }
+
+    /// Ask the meta server to tell the game server with ID `server_id` that we want to connect, so
+    /// that both sides can try to traverse NAT
+    ///
+    /// Returns `None` if the game server is unknown or doesn't accept introductions. Otherwise,
+    /// the game server receives the returned token along with our address, and punching through
+    /// NAT is up to the game. Rate-limited like [`find_one`](Self::find_one).
+    pub async fn request_introduction(
+        &self,
+        server_id: proto::ServerId,
+    ) -> Result<Option<proto::Introduction>, Error> {
+        let response = self.query(&proto::Request::Connect { server_id }).await?;
+        match metaserve_proto::decode(&response).map_err(|e| Error::Parse(e.into()))? {
+            proto::Response::Introduced(x) => Ok(x),
+            proto::Response::RateLimited => Err(Error::RateLimited),
+            _ => Err(Error::Parse("unexpected response".into())),
+        }
+    }
+
+    /// Ask the meta server for everything it currently knows about the game server with ID
+    /// `server_id`, e.g. to show full details of a [truncated](ServerEntry::truncated) entry
+    ///
+    /// Returns `None` if the game server is unknown or hidden from us, e.g. by our filter. If its
+    /// state is getting old, the meta server asks it for a fresh one, so asking again shortly may
+    /// return newer details. Rate-limited like [`find_one`](Self::find_one). Fails with
+    /// [`Error::TimedOut`] if no answer arrives within `timeout`.
+    pub async fn get_server(
+        &self,
+        server_id: proto::ServerId,
+        timeout: Duration,
+    ) -> Result<Option<ServerEntry>, Error> {
+        let runtime = quinn::default_runtime()
+            .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+        let timeout = Sleep(runtime.new_timer(Instant::now() + timeout));
+        let request = proto::Request::GetServer { server_id };
+        let query = self.query(&request);
+        let response = match future::select(pin!(query), timeout).await {
+            Either::Left((result, _)) => result?,
+            Either::Right(_) => return Err(Error::TimedOut),
+        };
+        match metaserve_proto::decode(&response).map_err(|e| Error::Parse(e.into()))? {
+            proto::Response::Server(found) => Ok(found.map(found_entry)),
+            proto::Response::RateLimited => Err(Error::RateLimited),
+            _ => Err(Error::Parse("unexpected response".into())),
+        }
+    }
+
+    /// Ask the meta server for a fresh snapshot, e.g. after losing track of the messages received
+    ///
+    /// A [`ServerList`](crate::ServerList) forgets every game server when the snapshot begins.
+    /// Rate-limited like [`find_one`](Self::find_one), but more strictly.
+    pub async fn resync(&self) -> Result<(), Error> {
+        let response = self.query(&proto::Request::Resync).await?;
+        match metaserve_proto::decode(&response).map_err(|e| Error::Parse(e.into()))? {
+            proto::Response::Resyncing => Ok(()),
+            proto::Response::RateLimited => Err(Error::RateLimited),
+            _ => Err(Error::Parse("unexpected response".into())),
+        }
+    }
+
+    /// Make a request on a new bidirectional stream, returning the encoded response
+    async fn query(&self, request: &proto::Request) -> Result<Vec<u8>, Error> {
+        if self.version < 3 {
+            return Err(Error::Unsupported);
+        }
+        let (mut send, mut recv) = self.connection.open_bi().await.map_err(Error::connection)?;
+        let msg = bincode::serialize(request).unwrap();
+        send.write_all(&msg).await.map_err(Error::write)?;
+        let _ = send.finish();
+        recv.read_to_end(MAX_RESPONSE_SIZE)
+            .await
+            .map_err(|e| match e {
+                quinn::ReadToEndError::Read(e) => Error::read(e),
+                e => Error::Read(e.into()),
+            })
+    }
+
+    /// Send an encoded request on a new stream
+    async fn request(&self, msg: &[u8]) -> Result<(), Error> {
+        self.requested.store(true, Ordering::Relaxed);
+        send(&self.connection, msg).await
+    }
+
+    /// Fail with [`Error::Stalled`] if no message or keep-alive arrives for longer than
+    /// `max_silence`
+    ///
+    /// Detects network paths that have silently died sooner than the transport's idle timeout, so
+    /// that the application can reconnect. Only useful with meta servers configured to send
+    /// keep-alives more often than `max_silence`, since otherwise they're silent whenever no game
+    /// server changes. Keep-alives are never returned as messages. Disabled by default.
+    pub fn set_max_silence(&mut self, max_silence: Option<Duration>) {
+        self.max_silence = max_silence;
+    }
+
+    /// Wait for the next message from the meta server
+    ///
+    /// Cancel-safe: if the returned future is dropped, no message is lost.
+    pub async fn recv(&mut self) -> Result<proto::Message<'_>, Error> {
+        self.recv_until(None).await
+    }
+
+    /// Like [`recv`](Self::recv), but fail with [`Error::TimedOut`] if no message arrives within
+    /// `timeout`
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<proto::Message<'_>, Error> {
+        self.recv_until(Some(Instant::now() + timeout)).await
+    }
+
+    async fn recv_until(&mut self, deadline: Option<Instant>) -> Result<proto::Message<'_>, Error> {
+        match self.next(deadline).await? {
+            Received::Data(x) => self.buffer = x,
+            Received::Shared(x) => {
+                self.buffer.clear();
+                self.buffer.extend_from_slice(x.as_bytes());
+                return proto::Message::decode(&self.buffer, self.version)
+                    .map_err(|e| Error::Parse(e.into()));
+            }
+        }
+        let msg = proto::Message::decode(&self.buffer, self.version)
+            .map_err(|e| Error::Parse(e.into()))?;
+        note_limits(&self.limits, &msg);
+        Ok(msg)
+    }
+
+    /// Wait for the next message from the meta server, taking ownership of its data
+    pub async fn recv_owned(&mut self) -> Result<OwnedMessage, Error> {
+        match self.next(None).await? {
+            Received::Data(x) => {
+                let msg =
+                    OwnedMessage::decode(x, self.version).map_err(|e| Error::Parse(e.into()))?;
+                note_limits(&self.limits, &msg.get());
+                Ok(msg)
+            }
+            Received::Shared(x) => Ok(Arc::unwrap_or_clone(x)),
+        }
+    }
+
+    /// Receive messages into `list` until it reflects a complete snapshot under the latest
+    /// [filter](Self::set_filter)
+    ///
+    /// Useful to tell when a server browser is done loading. Returns immediately if `list` is
+    /// already synchronized. `list` must have been fed every message received so far. If none have
+    /// been, [`ServerList::connected`] is called before applying the first.
+    pub async fn synchronized(&mut self, list: &mut ServerList) -> Result<(), Error> {
+        loop {
+            let done = match self.version {
+                // The first message is the snapshot, and filters are unsupported
+                1 | 2 => self.received,
+                _ => list.is_synchronized() && list.filter_generation() == self.filter_generation(),
+            };
+            if done {
+                return Ok(());
+            }
+            let first = !self.received;
+            let msg = self.recv_owned().await?;
+            if first {
+                list.connected(self.instance(), self.table_version());
+            }
+            list.apply_owned(&msg);
+        }
+    }
+
+    /// Wait for the next message, failing at `deadline` or when `max_silence` is exceeded
+    async fn next(&mut self, deadline: Option<Instant>) -> Result<Received, Error> {
+        let result = self.next_inner(deadline).await;
+        self.received |= result.is_ok();
+        result
+    }
+
+    async fn next_inner(&mut self, deadline: Option<Instant>) -> Result<Received, Error> {
+        loop {
+            let timer = match (deadline, self.silence_deadline()) {
+                (Some(x), Some(y)) if y <= x => Some((y, Error::Stalled)),
+                (Some(x), _) => Some((x, Error::TimedOut)),
+                (None, Some(y)) => Some((y, Error::Stalled)),
+                (None, None) => None,
+            };
+            let read = async {
+                match self.subscription {
+                    None => self.reader.next().await.map(Received::Data),
+                    Some(ref mut subscription) => recv_broadcast(subscription, &self.connection)
+                        .await
+                        .map(Received::Shared),
+                }
+            };
+            let Some((time, error)) = timer else {
+                return read.await;
+            };
+            let runtime = quinn::default_runtime()
+                .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+            let timeout = Sleep(runtime.new_timer(time));
+            if let Either::Left((result, _)) = future::select(pin!(read), timeout).await {
+                return result;
+            }
+            // Keep-alives received while we waited push the silence deadline back
+            let extended = self.silence_deadline().is_some_and(|x| x > Instant::now());
+            if !matches!(error, Error::Stalled) || !extended {
+                return Err(error);
+            }
+        }
+    }
+
+    /// When [`Error::Stalled`] is due, if `max_silence` is set
+    fn silence_deadline(&self) -> Option<Instant> {
+        let last_heard = *self
+            .last_heard
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.max_silence.map(|x| last_heard + x)
+    }
+
+    /// Receive messages as a [`Stream`], which ends after the first error
+    pub fn into_stream(self) -> ClientStream {
+        ClientStream(Box::pin(futures_util::stream::unfold(
+            Some(self),
+            |client| async move {
+                let mut client = client?;
+                let result = client.recv_owned().await;
+                let next = result.is_ok().then_some(client);
+                Some((result, next))
+            },
+        )))
+    }
+
+    /// Receive every subsequent message on a channel that can have many consumers
+    ///
+    /// The first call spawns a background task on the async runtime that reads messages from the
+    /// meta server, after which [`record_to`](Self::record_to) has no effect. [`recv`](Self::recv)
+    /// keeps working, as another receiver.
+    ///
+    /// Messages are shared between receivers via `Arc`, and each is kept in memory until every
+    /// receiver has seen it or 64 newer messages have arrived. Receivers that fall further behind
+    /// get a [`RecvError::Lagged`](broadcast::error::RecvError::Lagged) error, and should rebuild
+    /// their view of the server list.
+    ///
+    /// The channel closes when the connection is lost. The background task exits once the
+    /// `Client` and every receiver have been dropped.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<Arc<OwnedMessage>> {
+        if let Some(ref x) = self.subscription {
+            return x.resubscribe();
+        }
+        let (send, recv) = broadcast::channel(BROADCAST_CAPACITY);
+        let connection = self.connection.clone();
+        // Take over any partially read message
+        let mut reader = mem::replace(
+            &mut self.reader,
+            Reader::new(
+                connection.clone(),
+                self.last_heard.clone(),
+                self.usage.clone(),
+                self.requested.clone(),
+                None,
+                self.limits.clone(),
+            ),
+        );
+        let limits = self.limits.clone();
+        let version = self.version;
+        let runtime = quinn::default_runtime()
+            .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+        runtime.spawn(Box::pin(async move {
+            loop {
+                let msg = match reader.next().await {
+                    Ok(x) => x,
+                    Err(_) => break,
+                };
+                let msg = match OwnedMessage::decode(msg, version) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        // Surface the problem to receivers as a closed connection
+                        connection.close(
+                            metaserve_proto::CloseCode::ProtocolViolation.code().into(),
+                            b"malformed message",
+                        );
+                        break;
+                    }
+                };
+                note_limits(&limits, &msg.get());
+                if send.send(Arc::new(msg)).is_err() {
+                    // The `Client` and every receiver have been dropped
+                    break;
+                }
+            }
+        }));
+        let result = recv.resubscribe();
+        self.subscription = Some(recv);
+        result
+    }
+
+    /// Record every subsequently received message to a new file at `path`
+    ///
+    /// Recordings can be played back with [`ReplayClient`](crate::ReplayClient). Replaces any
+    /// previous recording.
+    pub fn record_to(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.reader.recorder = Some(Recorder::create(path.as_ref(), self.version)?);
+        Ok(())
+    }
+
+    /// Save `list` to the path given to [`Builder::cache`], if any, for
+    /// [`Builder::load_cache`] to read next time
+    pub fn save_cache(&self, list: &ServerList) -> io::Result<()> {
+        match self.cache {
+            Some(ref path) => list.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Politely disconnect from the meta server
+    ///
+    /// If the `Client` was established by a [`Builder`] on an endpoint of its own, waits briefly
+    /// for the meta server to be notified. Otherwise, call `quinn::Endpoint::wait_idle` before
+    /// exiting.
+    pub async fn close(self) {
+        self.connection.close(
+            metaserve_proto::CloseCode::Normal.code().into(),
+            b"client closed",
+        );
+        if let Some(endpoint) = self.endpoint {
+            endpoint.wait_idle().await;
+        }
+    }
+
+    /// Transport statistics for the connection to the meta server, and how much it has sent us
+    pub fn stats(&self) -> Stats {
+        Stats::new(
+            &self.connection,
+            &self.usage.lock().unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Address of the meta server
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Local IP address the meta server was contacted from, if known
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.connection.local_ip()
+    }
+
+    /// Version of the client protocol negotiated with the meta server, e.g. 3 for
+    /// [`proto::PROTOCOL_V3`]
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// ALPN ID negotiated with the meta server, one of [`proto::PROTOCOLS`]
+    pub fn negotiated_alpn(&self) -> &[u8] {
+        &self.alpn
+    }
+
+    /// [`spki_fingerprint`] of the certificate the meta server presented, captured when
+    /// connecting
+    pub fn peer_certificate_fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+
+    /// The meta server's description of its version, for debugging
+    ///
+    /// Known once a message has been received, if the meta server speaks [`proto::PROTOCOL_V3`].
+    pub fn peer_version(&self) -> Option<&str> {
+        self.welcome.get().map(|x| &x.version[..])
+    }
+
+    /// The meta server's [`proto::Welcome::instance`], to tell whether [`proto::ServerId`]s from an
+    /// earlier connection still identify the same game servers
+    ///
+    /// Known once a message has been received, if the meta server speaks [`proto::PROTOCOL_V3`].
+    pub fn instance(&self) -> Option<u64> {
+        self.welcome.get().map(|x| x.instance)
+    }
+
+    /// The meta server's [`proto::Welcome::table_version`], to tell whether anything changed since
+    /// an earlier connection
+    ///
+    /// Known once a message has been received, if the meta server speaks [`proto::PROTOCOL_V3`].
+    pub fn table_version(&self) -> Option<u64> {
+        self.welcome.get().map(|x| x.table_version)
+    }
+
+    /// Limits the meta server enforces, e.g. how often it sends updates
+    ///
+    /// Known once a message has been received, if the meta server speaks [`proto::PROTOCOL_V3`].
+    /// Kept up to date as the meta server announces changes in messages received since.
+    pub fn limits(&self) -> Option<proto::Limits> {
+        *self.limits.borrow()
+    }
+
+    /// Watch for changes to [`limits`](Self::limits), e.g. to adapt to a meta server being
+    /// reconfigured
+    pub fn watch_limits(&self) -> watch::Receiver<Option<proto::Limits>> {
+        self.limits.subscribe()
+    }
+}
+
+/// Largest response to a query that we'll read
+const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+/// Number of messages buffered for each receiver returned by [`Client::subscribe`]
+const BROADCAST_CAPACITY: usize = 64;
+
+/// Reads messages from a connection, resumably so that reads can be cancelled without loss
+struct Reader {
+    connection: quinn::Connection,
+    /// Stream carrying the message currently being read, if any
+    stream: Option<quinn::RecvStream>,
+    /// Data read from `stream` so far
+    partial: Vec<u8>,
+    recorder: Option<Recorder>,
+    /// Updated whenever a message or keep-alive is received
+    last_heard: Arc<Mutex<Instant>>,
+    /// See [`Client::usage`]
+    usage: Arc<Mutex<Usage>>,
+    /// See [`Client::requested`]
+    requested: Arc<AtomicBool>,
+    /// Where to store the [`proto::Welcome`], if it's yet to be read
+    welcome: Option<Arc<OnceLock<proto::Welcome>>>,
+    /// See [`Client::limits`]
+    limits: Arc<watch::Sender<Option<proto::Limits>>>,
+}
+
+impl Reader {
+    fn new(
+        connection: quinn::Connection,
+        last_heard: Arc<Mutex<Instant>>,
+        usage: Arc<Mutex<Usage>>,
+        requested: Arc<AtomicBool>,
+        welcome: Option<Arc<OnceLock<proto::Welcome>>>,
+        limits: Arc<watch::Sender<Option<proto::Limits>>>,
+    ) -> Self {
+        Self {
+            connection,
+            stream: None,
+            partial: Vec::new(),
+            recorder: None,
+            last_heard,
+            usage,
+            requested,
+            welcome,
+            limits,
+        }
+    }
+
+    /// Read the next encoded message, skipping keep-alives
+    ///
+    /// Cancel-safe.
+    async fn next(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            let data = self.next_raw().await?;
+            *self
+                .last_heard
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+            if let Some(welcome) = self.welcome.take() {
+                if let Ok(x) = metaserve_proto::decode::<proto::Welcome>(&data) {
+                    self.limits.send_replace(Some(x.limits));
+                    let _ = welcome.set(x);
+                }
+                continue;
+            }
+            if data == KEEPALIVE {
+                continue;
+            }
+            self.usage
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(data.len());
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.record(&data).map_err(Error::Recording)?;
+            }
+            return Ok(data);
+        }
+    }
+
+    async fn next_raw(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.requested.swap(true, Ordering::Relaxed) {
+            // Harmless whether or not it arrives before any other request
+            let msg = bincode::serialize(&proto::Request::UpdateInterval(Duration::ZERO)).unwrap();
+            send(&self.connection, &msg).await?;
+        }
+        loop {
+            if self.stream.is_none() {
+                let stream = self
+                    .connection
+                    .accept_uni()
+                    .await
+                    .map_err(Error::connection)?;
+                self.stream = Some(stream);
+            }
+            let stream = self.stream.as_mut().unwrap();
+            match stream.read_chunk(usize::MAX, true).await {
+                Ok(Some(chunk)) => self.partial.extend_from_slice(&chunk.bytes),
+                Ok(None) => break,
+                Err(e) => {
+                    self.stream = None;
+                    self.partial.clear();
+                    return Err(Error::read(e));
+                }
+            }
+        }
+        self.stream = None;
+        Ok(mem::take(&mut self.partial))
+    }
+}
+
+/// Count of the messages received on a connection
+struct Usage {
+    messages: u64,
+    bytes: u64,
+    /// Estimate of bytes received per second as of `updated`, decaying exponentially over
+    /// [`RATE_WINDOW`](Self::RATE_WINDOW)
+    rate: f64,
+    updated: Instant,
+}
+
+impl Usage {
+    const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self {
+            messages: 0,
+            bytes: 0,
+            rate: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Count a message of `size` bytes, received just now
+    fn record(&mut self, size: usize) {
+        let now = Instant::now();
+        self.rate = self.rate_at(now) + size as f64 / Self::RATE_WINDOW.as_secs_f64();
+        self.updated = now;
+        self.messages += 1;
+        self.bytes += size as u64;
+    }
+
+    /// Estimate of bytes received per second as of `now`
+    fn rate_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.rate * (-elapsed.as_secs_f64() / Self::RATE_WINDOW.as_secs_f64()).exp()
+    }
+}
+
+/// The entry describing a game server found by a query
+fn found_entry(x: proto::Found<'_>) -> ServerEntry {
+    ServerEntry {
+        address: x.address,
+        state: Bytes::copy_from_slice(x.state),
+        region: x.region,
+        tags: x.tags.iter().map(|&x| x.into()).collect(),
+        lan_addresses: x.lan_addresses,
+        truncated: None,
+        user_data: None,
+    }
+}
+
+/// Record any change of limits announced by `msg`
+fn note_limits(limits: &watch::Sender<Option<proto::Limits>>, msg: &proto::Message<'_>) {
+    // Announcements come last
+    for server in msg.servers.iter().rev() {
+        if server.id != proto::ServerId::NONE {
+            break;
+        }
+        if let proto::Event::Limits(x) = server.event {
+            limits.send_replace(Some(x));
+            return;
+        }
+    }
+}
+
+/// Send `msg` on a new stream
+async fn send(connection: &quinn::Connection, msg: &[u8]) -> Result<(), Error> {
+    let mut stream = connection.open_uni().await.map_err(Error::connection)?;
+    stream.write_all(msg).await.map_err(Error::write)?;
+    Ok(())
+}
+
+/// Version of the client protocol negotiated by `connection`
+fn version(alpn: &[u8]) -> u32 {
+    match alpn {
+        proto::PROTOCOL_V3 => 3,
+        proto::PROTOCOL_V2 => 2,
+        _ => 1,
+    }
+}
+
+/// ALPN ID negotiated by `connection`
+fn alpn(connection: &quinn::Connection) -> Vec<u8> {
+    connection
+        .handshake_data()
+        .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|x| x.protocol)
+        .unwrap_or_default()
+}
+
+/// [`spki_fingerprint`] of the certificate presented by `connection`'s peer, if any
+fn fingerprint(connection: &quinn::Connection) -> Option<[u8; 32]> {
+    let chain = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    spki_fingerprint(chain.first()?)
+}
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of a certificate in DER format, or `None` if it can't
+/// be parsed
+///
+/// Depends only on the key, so it stays the same when a certificate is re-issued for the same key,
+/// e.g. on renewal. Matches `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+/// openssl dgst -sha256`.
+pub fn spki_fingerprint(certificate: &[u8]) -> Option<[u8; 32]> {
+    let der = CertificateDer::from(certificate);
+    let cert = webpki::EndEntityCert::try_from(&der).ok()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.subject_public_key_info());
+    digest.as_ref().try_into().ok()
+}
+
+enum Received {
+    Data(Vec<u8>),
+    Shared(Arc<OwnedMessage>),
+}
+
+async fn recv_broadcast(
+    subscription: &mut broadcast::Receiver<Arc<OwnedMessage>>,
+    connection: &quinn::Connection,
+) -> Result<Arc<OwnedMessage>, Error> {
+    match subscription.recv().await {
+        Ok(x) => Ok(x),
+        Err(broadcast::error::RecvError::Lagged(n)) => Err(Error::Lagged(n)),
+        Err(broadcast::error::RecvError::Closed) => Err(match connection.close_reason() {
+            Some(e) => Error::connection(e),
+            // The background task failed to record a message
+            None => Error::Recording(io::Error::other("recording failed")),
+        }),
+    }
+}
+
+/// Messages from a meta server as a [`Stream`], from [`Client::into_stream`]
+pub struct ClientStream(BoxStream<'static, Result<OwnedMessage, Error>>);
+
+impl Stream for ClientStream {
+    type Item = Result<OwnedMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(cx)
+    }
+}
+
+/// Configuration for connecting a [`Client`] to a meta server
+#[cfg(feature = "helpers")]
+pub struct Builder {
+    roots: Vec<Vec<u8>>,
+    webpki_roots: bool,
+    update_interval: Option<Duration>,
+    max_silence: Option<Duration>,
+    filter: Option<proto::Filter>,
+    pacing: Option<proto::Pacing>,
+    truncate_states: Option<u32>,
+    endpoint: Option<quinn::Endpoint>,
+    cache: Option<PathBuf>,
+    tls: Option<rustls::ClientConfig>,
+    address: Option<SocketAddr>,
+    resolver: Option<Arc<Resolver>>,
+    attempt_timeout: Duration,
+}
+
+#[cfg(feature = "helpers")]
+impl Builder {
+    fn new() -> Self {
+        Self {
+            tls: None,
+            address: None,
+            resolver: None,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+            roots: Vec::new(),
+            webpki_roots: true,
+            update_interval: None,
+            max_silence: None,
+            filter: None,
+            pacing: None,
+            truncate_states: None,
+            endpoint: None,
+            cache: None,
+        }
+    }
+
+    /// Trust an additional certificate authority, in DER format
+    pub fn ca(mut self, der: Vec<u8>) -> Self {
+        self.roots.push(der);
+        self
+    }
+
+    /// Connect from `endpoint` rather than a new one, e.g. to share the game's own UDP socket
+    ///
+    /// Only the connection to the meta server uses this crate's TLS and transport configuration;
+    /// `endpoint`'s default client configuration is left alone. [`close`](Client::close) doesn't
+    /// wait for a shared endpoint to become idle.
+    pub fn endpoint(mut self, endpoint: quinn::Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Keep the server list in a file at `path`, so it can be shown before connecting next time
+    ///
+    /// Read it with [`load_cache`](Self::load_cache), and write it with [`Client::save_cache`].
+    pub fn cache(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache = Some(path.into());
+        self
+    }
+
+    /// The server list last saved to the [`cache`](Self::cache), to show until the meta server
+    /// confirms it
+    ///
+    /// Empty if there's no cache, or it can't be read, e.g. because it's corrupt. Pass to
+    /// [`Client::synchronized`] once connected to bring it up to date. See [`ServerList::load`].
+    pub fn load_cache(&self) -> ServerList {
+        self.cache
+            .as_ref()
+            .and_then(|path| ServerList::load(path).ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether to trust the Mozilla root certificates, in addition to any added with
+    /// [`ca`](Self::ca)
+    ///
+    /// Enabled by default.
+    pub fn webpki_roots(mut self, enabled: bool) -> Self {
+        self.webpki_roots = enabled;
+        self
+    }
+
+    /// Secure the connection with `config`, e.g. to verify the meta server's certificate with a
+    /// custom [`ServerCertVerifier`](rustls::client::danger::ServerCertVerifier)
+    ///
+    /// Replaces the configuration built from [`ca`](Self::ca) and
+    /// [`webpki_roots`](Self::webpki_roots). Its ALPN protocols are replaced with
+    /// [`proto::PROTOCOLS`], and it must support TLS 1.3.
+    pub fn tls_config(mut self, config: rustls::ClientConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Trust only a certificate whose public key has this [`spki_fingerprint`], regardless of who
+    /// issued it or which names it's valid for
+    ///
+    /// Shorthand for [`tls_config`](Self::tls_config) with a [`PinnedVerifier`].
+    pub fn pin(self, fingerprint: [u8; 32]) -> Self {
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(fingerprint)))
+            .with_no_client_auth();
+        self.tls_config(config)
+    }
+
+    /// Connect to `address` rather than resolving the host passed to `connect`, which then only
+    /// names the meta server for certificate verification
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Resolve the `host:port` passed to `connect` with `resolver` rather than the system's, e.g.
+    /// to use a DNS client of the game's own
+    ///
+    /// Called on a background thread, so it may block.
+    pub fn resolver(
+        mut self,
+        resolver: impl Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static,
+    ) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Give up on an address that hasn't completed a handshake within `timeout`, and try the
+    /// next
+    ///
+    /// Defaults to 5 seconds.
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = timeout;
+        self
+    }
+
+    /// Ask the meta server to wait at least `interval` between updates
+    ///
+    /// See [`Client::request_update_interval`].
+    pub fn update_interval(mut self, interval: Duration) -> Self {
+        self.update_interval = Some(interval);
+        self
+    }
+
+    /// See [`Client::set_max_silence`]
+    pub fn max_silence(mut self, max_silence: Duration) -> Self {
+        self.max_silence = Some(max_silence);
+        self
+    }
+
+    /// Only receive information about game servers matching `filter`, from the first message on
+    ///
+    /// See [`Client::set_filter`]. Connecting fails with [`Error::Unsupported`] if the meta server
+    /// predates filters.
+    pub fn filter(mut self, filter: proto::Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Ask the meta server to spread the initial snapshot over roughly `duration`, sending game
+    /// servers in `order`
+    ///
+    /// Progress is reported by
+    /// [`ServerList::snapshot_progress`](crate::ServerList::snapshot_progress). Connecting fails
+    /// with [`Error::Unsupported`] if the meta server predates pacing.
+    pub fn paced_snapshot(mut self, duration: Duration, order: proto::Strategy) -> Self {
+        self.pacing = Some(proto::Pacing { duration, order });
+        self
+    }
+
+    /// Ask the meta server to send only the first `max` bytes of each game server's state, from
+    /// the first message on
+    ///
+    /// See [`Client::truncate_states`]. Connecting fails with [`Error::Unsupported`] if the meta
+    /// server predates truncation.
+    pub fn truncate_states(mut self, max: u32) -> Self {
+        self.truncate_states = Some(max);
+        self
+    }
+
+    /// Connect to the meta server at `server`, given as `host:port`
+    ///
+    /// `server` is resolved afresh on each call, and each address it resolves to is tried in turn
+    /// until one completes a handshake, so reconnecting with a new builder follows changes to DNS,
+    /// e.g. after a failover. Addresses that failed in the last minute are tried last, and an
+    /// unreachable address delays the next by the [`attempt_timeout`](Self::attempt_timeout).
+    pub async fn connect(self, server: &str) -> Result<Client, ConnectError> {
+        let hostname = hostname(server)?;
+        let addrs = match self.address {
+            Some(x) => vec![x],
+            None => resolve(server, self.resolver).await?,
+        };
+
+        let mut rejections = None;
+        let mut client_crypto = match self.tls {
+            Some(x) => x,
+            None => {
+                let mut roots = rustls::RootCertStore::empty();
+                if self.webpki_roots {
+                    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                }
+                for der in self.roots {
+                    roots
+                        .add(CertificateDer::from(der))
+                        .map_err(|e| ConnectError::InvalidCa(e.into()))?;
+                }
+                let verifier = Arc::new(Verifier {
+                    inner: rustls::client::WebPkiServerVerifier::builder(Arc::new(roots))
+                        .build()
+                        .map_err(|e| ConnectError::Tls(e.into()))?,
+                    rejected: Mutex::new(None),
+                });
+                let config = rustls::ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier.clone())
+                    .with_no_client_auth();
+                rejections = Some(verifier);
+                config
+            }
+        };
+        client_crypto.alpn_protocols = proto::PROTOCOLS.iter().map(|&x| x.into()).collect();
+        let client_crypto =
+            QuicClientConfig::try_from(client_crypto).map_err(|e| ConnectError::Tls(e.into()))?;
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        let mut transport = quinn::TransportConfig::default();
+        transport
+            .keep_alive_interval(Some(Duration::from_secs(5)))
+            .max_concurrent_bidi_streams(0u32.into())
+            .max_concurrent_uni_streams(1u32.into());
+        client_config.transport_config(Arc::new(transport));
+
+        let shared = self.endpoint.is_some();
+        let (endpoint, conn) = connect_any(
+            self.endpoint,
+            &addrs,
+            client_config,
+            hostname,
+            rejections.as_deref(),
+            self.attempt_timeout,
+        )
+        .await?;
+        let mut client = Client::new(Connection(conn));
+        client.endpoint = (!shared).then_some(endpoint);
+        client.cache = self.cache;
+        client.set_max_silence(self.max_silence);
+        // Before any request that starts updates, so the snapshot is truncated too
+        if let Some(max) = self.truncate_states {
+            client
+                .truncate_states(Some(max))
+                .await
+                .map_err(ConnectError::Hello)?;
+        }
+        if self.filter.is_some() || self.pacing.is_some() {
+            client
+                .send_subscribe(self.filter.unwrap_or_default(), self.pacing)
+                .await
+                .map_err(ConnectError::Hello)?;
+        }
+        if let Some(interval) = self.update_interval {
+            client
+                .request_update_interval(interval)
+                .await
+                .map_err(ConnectError::Hello)?;
+        }
+        Ok(client)
+    }
+}
+
+/// Verifies certificates as webpki does, remembering why the last one was rejected so that
+/// [`Builder::connect`] can say
+#[cfg(feature = "helpers")]
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<rustls::client::WebPkiServerVerifier>,
+    rejected: Mutex<Option<rustls::CertificateError>>,
+}
+
+#[cfg(feature = "helpers")]
+impl Verifier {
+    /// Describe the last rejection of a certificate for `requested`, if it's one of the common
+    /// mistakes given its own [`ConnectError`] variant
+    fn rejection(&self, requested: &str) -> Option<ConnectError> {
+        use rustls::CertificateError::*;
+        let error = self
+            .rejected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()?;
+        Some(match error {
+            UnknownIssuer => ConnectError::UntrustedRoot,
+            NotValidForName => ConnectError::NameMismatch {
+                presented: Vec::new(),
+                requested: requested.into(),
+            },
+            NotValidForNameContext { presented, .. } => ConnectError::NameMismatch {
+                presented: presented.into_iter().map(presented_name).collect(),
+                requested: requested.into(),
+            },
+            Expired => ConnectError::Expired { not_after: None },
+            ExpiredContext { not_after, .. } => ConnectError::Expired {
+                not_after: Some(std::time::UNIX_EPOCH + Duration::from_secs(not_after.as_secs())),
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(feature = "helpers")]
+impl rustls::client::danger::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if let Err(rustls::Error::InvalidCertificate(ref e)) = result {
+            *self.rejected.lock().unwrap_or_else(PoisonError::into_inner) = Some(e.clone());
+        }
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Trusts certificates by the [`spki_fingerprint`] of their public key, regardless of who issued
+/// them or which names they're valid for, remembering the last one presented
+///
+/// Pass to [`Builder::tls_config`], or use [`Builder::pin`].
+#[cfg(feature = "helpers")]
+#[derive(Debug)]
+pub struct PinnedVerifier {
+    /// `None` to trust any certificate
+    fingerprint: Option<[u8; 32]>,
+    presented: Mutex<Option<CertificateDer<'static>>>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+#[cfg(feature = "helpers")]
+impl PinnedVerifier {
+    /// Trust only a certificate whose public key has `fingerprint`
+    pub fn new(fingerprint: [u8; 32]) -> Self {
+        Self::with_fingerprint(Some(fingerprint))
+    }
+
+    /// Trust any certificate at all
+    ///
+    /// Offers no protection against impersonation, so only suits fetching a meta server's
+    /// certificate with [`presented`](Self::presented) to check its fingerprint out of band before
+    /// pinning it.
+    pub fn trust_any() -> Self {
+        Self::with_fingerprint(None)
+    }
+
+    fn with_fingerprint(fingerprint: Option<[u8; 32]>) -> Self {
+        Self {
+            fingerprint,
+            presented: Mutex::new(None),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+
+    /// The certificate the meta server presented most recently, in DER format, whether or not it
+    /// was trusted
+    pub fn presented(&self) -> Option<Vec<u8>> {
+        let presented = self
+            .presented
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        presented.as_ref().map(|x| x.to_vec())
+    }
+}
+
+#[cfg(feature = "helpers")]
+impl rustls::client::danger::ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        *self
+            .presented
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(end_entity.clone().into_owned());
+        let trusted = match self.fingerprint {
+            None => true,
+            Some(x) => spki_fingerprint(end_entity) == Some(x),
+        };
+        if !trusted {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Strip the type from a name as webpki reports it, e.g. `DnsName("example.com")`
+#[cfg(feature = "helpers")]
+fn presented_name(name: String) -> String {
+    let inner = name
+        .strip_prefix("DnsName(\"")
+        .and_then(|x| x.strip_suffix("\")"))
+        .or_else(|| {
+            name.strip_prefix("IpAddress(")
+                .and_then(|x| x.strip_suffix(')'))
+        });
+    match inner {
+        Some(x) => x.into(),
+        None => name,
+    }
+}
+
+/// Describe a certificate that isn't valid for the name it was requested by
+#[cfg(feature = "helpers")]
+fn name_mismatch(presented: &[String], requested: &str) -> String {
+    let mut message = if presented.is_empty() {
+        format!("meta server's certificate is not valid for {}", requested)
+    } else {
+        format!(
+            "meta server's certificate is valid for {}, not {}",
+            presented.join(", "),
+            requested
+        )
+    };
+    if requested.parse::<IpAddr>().is_ok() {
+        message.push_str("; certificates are usually issued for hostnames, so connect by one");
+    }
+    message
+}
+
+/// Connect to the first of `addrs` that completes a handshake within `timeout`, from `endpoint`
+/// if given, or else from a new one bound for each address family tried
+///
+/// Addresses that recently failed are tried last. Gives up early if `rejections` says the meta
+/// server's certificate was rejected, since every address for the same name should present the
+/// same one.
+#[cfg(feature = "helpers")]
+async fn connect_any(
+    mut endpoint: Option<quinn::Endpoint>,
+    addrs: &[SocketAddr],
+    config: quinn::ClientConfig,
+    hostname: &str,
+    rejections: Option<&Verifier>,
+    timeout: Duration,
+) -> Result<(quinn::Endpoint, quinn::Connection), ConnectError> {
+    let runtime = quinn::default_runtime()
+        .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+    let mut addrs = addrs.to_vec();
+    prefer_working(&mut addrs, Instant::now());
+    let shared = endpoint.is_some();
+    let mut error = ConnectError::NoAddresses;
+    for addr in addrs {
+        let reusable = endpoint
+            .as_ref()
+            .is_some_and(|x| shared || x.local_addr().is_ok_and(|x| x.is_ipv4() == addr.is_ipv4()));
+        if !reusable {
+            let bind = match addr {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => "[::]:0",
+            };
+            endpoint =
+                Some(quinn::Endpoint::client(bind.parse().unwrap()).map_err(ConnectError::Bind)?);
+        }
+        let endpoint = endpoint.as_ref().unwrap();
+        let connecting = match endpoint.connect_with(config.clone(), addr, hostname) {
+            Ok(x) => x,
+            Err(e) => {
+                record_attempt(addr, false);
+                error = ConnectError::Connection(e.into());
+                continue;
+            }
+        };
+        let mut connecting = pin!(connecting);
+        let mut expired = Sleep(runtime.new_timer(Instant::now() + timeout));
+        let result = poll_fn(|cx| {
+            if let Poll::Ready(x) = connecting.as_mut().poll(cx) {
+                return Poll::Ready(Some(x));
+            }
+            Pin::new(&mut expired).poll(cx).map(|()| None)
+        })
+        .await;
+        record_attempt(addr, matches!(result, Some(Ok(_))));
+        match result {
+            Some(Ok(conn)) => return Ok((endpoint.clone(), conn)),
+            Some(Err(e)) => {
+                if let Some(rejection) = rejections.and_then(|x| x.rejection(hostname)) {
+                    return Err(rejection);
+                }
+                error = ConnectError::Connection(e.into());
+            }
+            None => {
+                error = ConnectError::Connection(format!("no response from {addr}").into());
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Addresses that recently failed to complete a handshake, and when, so that
+/// [`connect_any`] tries others first
+#[cfg(feature = "helpers")]
+static FAILED: Mutex<BTreeMap<SocketAddr, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Order `addrs` to try those that haven't failed in the last [`FAILURE_MEMORY`] first, in their
+/// original order, then those that failed longest ago
+#[cfg(feature = "helpers")]
+fn prefer_working(addrs: &mut [SocketAddr], now: Instant) {
+    let mut failed = FAILED.lock().unwrap_or_else(PoisonError::into_inner);
+    failed.retain(|_, &mut x| now.saturating_duration_since(x) < FAILURE_MEMORY);
+    addrs.sort_by_key(|x| failed.get(x).copied());
+}
+
+/// Remember whether a handshake with `addr` completed
+#[cfg(feature = "helpers")]
+fn record_attempt(addr: SocketAddr, ok: bool) {
+    let mut failed = FAILED.lock().unwrap_or_else(PoisonError::into_inner);
+    if ok {
+        failed.remove(&addr);
+    } else {
+        failed.insert(addr, Instant::now());
+    }
+}
+
+/// Addresses each meta server last resolved to, so that [`note_resolved`] can tell when they
+/// change
+#[cfg(feature = "helpers")]
+static RESOLVED: Mutex<BTreeMap<String, Vec<SocketAddr>>> = Mutex::new(BTreeMap::new());
+
+/// Log if `server` resolved to other addresses than it did last time, returning whether it did
+#[cfg(feature = "helpers")]
+fn note_resolved(server: &str, addrs: &[SocketAddr]) -> bool {
+    let mut addrs = addrs.to_vec();
+    addrs.sort_unstable();
+    addrs.dedup();
+    let mut resolved = RESOLVED.lock().unwrap_or_else(PoisonError::into_inner);
+    match resolved.insert(server.into(), addrs) {
+        Some(old) if old != resolved[server] => {
+            tracing::info!(server, ?old, new = ?resolved[server], "meta server addresses changed");
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Resolve `server` with `resolver`, or else the system's, on a background thread, so as not to
+/// block the async runtime
+#[cfg(feature = "helpers")]
+async fn resolve(
+    server: &str,
+    resolver: Option<Arc<Resolver>>,
+) -> Result<Vec<SocketAddr>, ConnectError> {
+    let (send, recv) = futures_channel::oneshot::channel();
+    use std::net::ToSocketAddrs;
+
+    let owned = server.to_owned();
+    std::thread::spawn(move || {
+        let _ = send.send(match resolver {
+            Some(resolver) => resolver(&owned),
+            None => owned.to_socket_addrs().map(|x| x.collect::<Vec<_>>()),
+        });
+    });
+    let addrs = recv
+        .await
+        .expect("resolver thread panicked")
+        .map_err(ConnectError::Resolve)?;
+    if addrs.is_empty() {
+        return Err(ConnectError::NoAddresses);
+    }
+    note_resolved(server, &addrs);
+    Ok(addrs)
+}
+
+/// See [`Builder::resolver`]
+#[cfg(feature = "helpers")]
+type Resolver = dyn Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync;
+
+/// How long an address that failed to complete a handshake is tried after others
+#[cfg(feature = "helpers")]
+const FAILURE_MEMORY: Duration = Duration::from_secs(60);
+/// Default for [`Builder::attempt_timeout`]
+#[cfg(feature = "helpers")]
+const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Extract the host from `host:port`, stripping brackets from IPv6 literals
+#[cfg(feature = "helpers")]
+fn hostname(server: &str) -> Result<&str, ConnectError> {
+    let (host, _) = server
+        .rsplit_once(':')
+        .ok_or_else(|| ConnectError::InvalidAddress(server.into()))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(ConnectError::InvalidAddress(server.into()));
+    }
+    Ok(host)
+}
+
+/// Snapshot of a connection's transport statistics
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Current best estimate of the round-trip time
+    pub rtt: Duration,
+    /// Current congestion window, in bytes
+    pub cwnd: u64,
+    /// Number of congestion events, e.g. due to packet loss
+    pub congestion_events: u64,
+    /// Total UDP payload bytes sent
+    pub bytes_sent: u64,
+    /// Total UDP payload bytes received
+    pub bytes_received: u64,
+    /// Number of packets deemed lost
+    pub lost_packets: u64,
+    /// Number of messages received from the meta server, excluding keep-alives
+    pub messages_received: u64,
+    /// Total encoded size of those messages, in bytes
+    pub message_bytes_received: u64,
+    /// Bytes of messages received per second, averaged over roughly the last 10 seconds
+    pub message_rate: f64,
+}
+
+impl Stats {
+    fn new(connection: &quinn::Connection, usage: &Usage) -> Self {
+        let x = connection.stats();
+        Self {
+            messages_received: usage.messages,
+            message_bytes_received: usage.bytes,
+            message_rate: usage.rate_at(Instant::now()),
+            rtt: x.path.rtt,
+            cwnd: x.path.cwnd,
+            congestion_events: x.path.congestion_events,
+            bytes_sent: x.udp_tx.bytes,
+            bytes_received: x.udp_rx.bytes,
+            lost_packets: x.path.lost_packets,
+        }
+    }
+}
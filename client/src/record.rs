@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use metaserve_proto::record::{MAGIC, VERSION};
+
+use crate::{proto, Error, Sleep};
+
+/// Appends received messages to a recording
+pub(crate) struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Begin a recording of messages encoded for `version` of the client protocol
+    pub(crate) fn create(path: &Path, version: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(&version.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(&mut self, msg: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(msg.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        let time = self.start.elapsed().as_micros() as u64;
+        self.file.write_all(&time.to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(msg)?;
+        // Flush every message so that recordings survive crashes
+        self.file.flush()
+    }
+}
+
+/// Plays back a recording made with [`Client::record_to`](crate::Client::record_to)
+///
+/// Messages are delivered with the same timing at which they were originally received, relative to
+/// the first call to [`recv`](Self::recv), optionally scaled by [`speed`](Self::speed).
+pub struct ReplayClient {
+    file: BufReader<File>,
+    runtime: Arc<dyn quinn::Runtime>,
+    speed: f64,
+    start: Option<Instant>,
+    buffer: Vec<u8>,
+    /// Version of the client protocol the messages were encoded for
+    protocol: u32,
+}
+
+impl ReplayClient {
+    /// Open the recording at `path`
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut header = [0; MAGIC.len() + 4];
+        file.read_exact(&mut header)?;
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a metaserve recording",
+            ));
+        }
+        let version = u32::from_le_bytes(header[MAGIC.len()..].try_into().unwrap());
+        let protocol = match version {
+            // Predates per-entry encoding
+            1 => 2,
+            VERSION => {
+                let mut protocol = [0; 4];
+                file.read_exact(&mut protocol)?;
+                u32::from_le_bytes(protocol)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported recording version {}", version),
+                ))
+            }
+        };
+        Ok(Self {
+            file,
+            runtime: quinn::default_runtime()
+                .expect("no async runtime found; enable the tokio, smol, or async-std feature"),
+            speed: 1.0,
+            start: None,
+            buffer: Vec::new(),
+            protocol,
+        })
+    }
+
+    /// Play back `speed` times faster than the recording was made
+    ///
+    /// Pass `f64::INFINITY` to deliver messages as quickly as they are requested.
+    pub fn speed(mut self, speed: f64) -> Self {
+        assert!(speed > 0.0, "playback speed must be positive");
+        self.speed = speed;
+        self
+    }
+
+    /// Wait for the next message in the recording
+    ///
+    /// Returns [`Error::EndOfRecording`] once every message has been delivered.
+    pub async fn recv(&mut self) -> Result<proto::Message<'_>, Error> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let mut header = [0; 12];
+        read_record(&mut self.file, &mut header)?;
+        let time = Duration::from_micros(u64::from_le_bytes(header[..8].try_into().unwrap()));
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+        self.buffer.resize(len as usize, 0);
+        read_record(&mut self.file, &mut self.buffer)?;
+
+        Sleep(self.runtime.new_timer(start + time.div_f64(self.speed))).await;
+        proto::Message::decode(&self.buffer, self.protocol).map_err(|e| Error::Parse(e.into()))
+    }
+}
+
+fn read_record(file: &mut impl Read, buf: &mut [u8]) -> Result<(), Error> {
+    file.read_exact(buf).map_err(|e| match e.kind() {
+        // Includes truncated recordings
+        io::ErrorKind::UnexpectedEof => Error::EndOfRecording,
+        _ => Error::Recording(e),
+    })
+}
@@ -0,0 +1,183 @@
+//! Receiving server lists in a browser, through a WebTransport gateway
+
+use std::time::Duration;
+
+use js_sys::{Promise, Reflect, Uint8Array};
+use thiserror::Error;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{ReadableStream, ReadableStreamDefaultReader, WritableStream};
+
+use crate::{proto, OwnedMessage, ServerList, KEEPALIVE};
+
+/// Version of the client protocol spoken through the gateway
+const VERSION: u32 = 3;
+
+/// Errors that can occur while talking to a meta server through a WebTransport gateway
+#[derive(Debug, Error)]
+pub enum WebError {
+    /// The browser's WebTransport API failed, e.g. because the gateway is unreachable
+    #[error("WebTransport error: {0}")]
+    Transport(String),
+    #[error("connection closed")]
+    Closed,
+    #[error("malformed message: {0}")]
+    Malformed(#[source] bincode::Error),
+}
+
+impl From<JsValue> for WebError {
+    fn from(x: JsValue) -> Self {
+        let message = x
+            .dyn_ref::<js_sys::Error>()
+            .map(|e| String::from(e.message()))
+            .unwrap_or_else(|| format!("{:?}", x));
+        Self::Transport(message)
+    }
+}
+
+/// Connection to a meta server from a browser, through a WebTransport gateway
+///
+/// The gateway must relay version 3 of the client protocol stream for stream: each message from
+/// the meta server, starting with the [`proto::Welcome`], arrives on its own unidirectional stream,
+/// and each request from the client leaves on one. Only the server list is supported; queries like
+/// [`Client::find_one`](crate::Client::find_one) need bidirectional streams.
+pub struct WebTransportClient {
+    transport: WebTransport,
+    /// Yields each unidirectional stream the gateway opens
+    streams: ReadableStreamDefaultReader,
+    welcome: Option<proto::Welcome>,
+    /// Whether a message has been returned by [`recv_owned`](Self::recv_owned)
+    received: bool,
+}
+
+impl WebTransportClient {
+    /// Connect to the gateway at `url`, e.g. `https://meta.example.com:4433/`
+    pub async fn connect(url: &str) -> Result<Self, WebError> {
+        let transport = WebTransport::new(url)?;
+        JsFuture::from(transport.ready()).await?;
+        let streams = transport
+            .incoming_unidirectional_streams()
+            .get_reader()
+            .unchecked_into();
+        let mut client = Self {
+            transport,
+            streams,
+            welcome: None,
+            received: false,
+        };
+        // Ask for updates as often as the meta server allows, as `Client` does
+        let msg = bincode::serialize(&proto::Request::UpdateInterval(Duration::ZERO)).unwrap();
+        client.send(&msg).await?;
+        let welcome = client.next_raw().await?;
+        client.welcome = Some(metaserve_proto::decode(&welcome).map_err(WebError::Malformed)?);
+        Ok(client)
+    }
+
+    /// Wait for the next message from the meta server
+    ///
+    /// Not cancel-safe: if the returned future is dropped, a message may be lost.
+    pub async fn recv_owned(&mut self) -> Result<OwnedMessage, WebError> {
+        loop {
+            let data = self.next_raw().await?;
+            if data == KEEPALIVE {
+                continue;
+            }
+            let msg = OwnedMessage::decode(data, VERSION).map_err(WebError::Malformed)?;
+            self.received = true;
+            return Ok(msg);
+        }
+    }
+
+    /// Receive messages into `list` until it reflects a complete snapshot
+    ///
+    /// As [`Client::synchronized`](crate::Client::synchronized).
+    pub async fn synchronized(&mut self, list: &mut ServerList) -> Result<(), WebError> {
+        while !list.is_synchronized() {
+            let first = !self.received;
+            let msg = self.recv_owned().await?;
+            if first {
+                list.connected(self.instance(), self.table_version());
+            }
+            list.apply_owned(&msg);
+        }
+        Ok(())
+    }
+
+    /// See [`Client::instance`](crate::Client::instance)
+    pub fn instance(&self) -> Option<u64> {
+        self.welcome.as_ref().map(|x| x.instance)
+    }
+
+    /// See [`Client::table_version`](crate::Client::table_version)
+    pub fn table_version(&self) -> Option<u64> {
+        self.welcome.as_ref().map(|x| x.table_version)
+    }
+
+    /// See [`Client::limits`](crate::Client::limits)
+    pub fn limits(&self) -> Option<proto::Limits> {
+        self.welcome.as_ref().map(|x| x.limits)
+    }
+
+    /// Close the connection
+    pub fn close(self) {
+        self.transport.close();
+    }
+
+    /// Read the next unidirectional stream to its end
+    async fn next_raw(&mut self) -> Result<Vec<u8>, WebError> {
+        let stream = read(&self.streams)
+            .await?
+            .ok_or(WebError::Closed)?
+            .unchecked_into::<ReadableStream>();
+        let reader = stream
+            .get_reader()
+            .unchecked_into::<ReadableStreamDefaultReader>();
+        let mut data = Vec::new();
+        while let Some(chunk) = read(&reader).await? {
+            data.extend_from_slice(&chunk.unchecked_into::<Uint8Array>().to_vec());
+        }
+        Ok(data)
+    }
+
+    /// Send `msg` on a new unidirectional stream
+    async fn send(&self, msg: &[u8]) -> Result<(), WebError> {
+        let stream = JsFuture::from(self.transport.create_unidirectional_stream())
+            .await?
+            .unchecked_into::<WritableStream>();
+        let writer = stream.get_writer()?;
+        JsFuture::from(writer.write_with_chunk(&Uint8Array::from(msg))).await?;
+        JsFuture::from(writer.close()).await?;
+        Ok(())
+    }
+}
+
+/// The next chunk from `reader`, or `None` at the end of the stream
+async fn read(reader: &ReadableStreamDefaultReader) -> Result<Option<JsValue>, WebError> {
+    let result = JsFuture::from(reader.read()).await?;
+    if Reflect::get(&result, &"done".into())?.is_truthy() {
+        return Ok(None);
+    }
+    Ok(Some(Reflect::get(&result, &"value".into())?))
+}
+
+// web-sys only exposes WebTransport with `--cfg=web_sys_unstable_apis`, which dependents would
+// all have to set, so bind the little that's needed directly
+#[wasm_bindgen]
+extern "C" {
+    type WebTransport;
+
+    #[wasm_bindgen(constructor, catch)]
+    fn new(url: &str) -> Result<WebTransport, JsValue>;
+
+    #[wasm_bindgen(method, getter)]
+    fn ready(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method, getter, js_name = incomingUnidirectionalStreams)]
+    fn incoming_unidirectional_streams(this: &WebTransport) -> ReadableStream;
+
+    #[wasm_bindgen(method, js_name = createUnidirectionalStream)]
+    fn create_unidirectional_stream(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method)]
+    fn close(this: &WebTransport);
+}
@@ -0,0 +1,108 @@
+//! `ServerList` reconciliation, run natively and, to check the wasm build, under Node with
+//! `CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner cargo test
+//! -p metaserve-client --no-default-features --target wasm32-unknown-unknown --test list`
+
+use std::net::SocketAddr;
+
+use metaserve_client::{
+    proto::{Event, Message, Region, Server, ServerId},
+    Change, ServerList,
+};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test as test;
+
+fn message(servers: Vec<Server<'_>>) -> Message<'_> {
+    Message {
+        servers,
+        undecodable: Vec::new(),
+    }
+}
+
+fn server(id: u64, event: Event<'_>) -> Server<'_> {
+    Server {
+        id: ServerId(id),
+        event,
+    }
+}
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([192, 0, 2, 1], port))
+}
+
+#[test]
+fn apply_updates_and_shutdowns() {
+    let mut list = ServerList::new();
+    list.apply(&message(vec![
+        server(0, Event::Update(addr(1000), b"a")),
+        server(0, Event::Region(Some(Region(*b"DE")))),
+        server(0, Event::Tags(vec!["eu"])),
+        server(1, Event::Update(addr(1001), b"b")),
+        server(ServerId::NONE.0, Event::Synchronized),
+    ]));
+    assert!(list.is_synchronized());
+    assert_eq!(list.len(), 2);
+    let entry = list.get(ServerId(0)).unwrap();
+    assert_eq!(entry.address, addr(1000));
+    assert_eq!(&entry.state[..], b"a");
+    assert_eq!(entry.region, Some(Region(*b"DE")));
+    assert_eq!(entry.tags, ["eu"]);
+    assert_eq!(entry.truncated, None);
+
+    list.apply(&message(vec![
+        server(0, Event::Shutdown),
+        server(1, Event::Update(addr(1001), b"b2")),
+        server(1, Event::Truncated(100)),
+    ]));
+    assert!(list.get(ServerId(0)).is_none());
+    let entry = list.get(ServerId(1)).unwrap();
+    assert_eq!(&entry.state[..], b"b2");
+    assert_eq!(entry.truncated, Some(100));
+}
+
+#[test]
+fn reset_forgets_everything() {
+    let mut list = ServerList::new();
+    list.apply(&message(vec![
+        server(0, Event::Update(addr(1000), b"a")),
+        server(ServerId::NONE.0, Event::Synchronized),
+    ]));
+    list.apply(&message(vec![
+        server(1, Event::Update(addr(1001), b"b")),
+        server(ServerId::NONE.0, Event::Reset),
+        server(ServerId::NONE.0, Event::Synchronized),
+    ]));
+    assert_eq!(
+        list.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+        [ServerId(1)]
+    );
+}
+
+/// A message with a corrupt entry is applied but for that entry, which is reported
+#[test]
+fn corrupt_entry_is_reported() {
+    let data = include_bytes!("../../proto/fixtures/client-v3-message-corrupt-entry.bin");
+    let msg = Message::decode(data, 3).unwrap();
+    let mut list = ServerList::new();
+    list.set_change_log_capacity(16);
+    let cursor = list.cursor();
+    list.apply(&msg);
+
+    assert_eq!(
+        list.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+        [ServerId(5), ServerId(7)]
+    );
+    assert_eq!(&list.get(ServerId(7)).unwrap().state[..], b"after");
+    let (changes, _) = list.changes_since(cursor).unwrap();
+    let undecodable = changes
+        .iter()
+        .filter_map(|x| match *x {
+            Change::Undecodable { id, ref bytes } => Some((id, bytes.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(undecodable.len(), 1);
+    assert_eq!(undecodable[0].0, Some(ServerId(6)));
+    // Server 6's ID, then an event number no version defines
+    assert_eq!(&undecodable[0].1[..], b"\x06\0\0\0\0\0\0\0\xef\xbe\xad\xde");
+    assert_eq!(changes.len(), 3);
+}
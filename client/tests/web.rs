@@ -0,0 +1,113 @@
+//! `WebTransportClient` against a fake WebTransport gateway, run under Node with
+//! `CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner cargo test
+//! -p metaserve-client --no-default-features --features web --target wasm32-unknown-unknown --test
+//! web`
+
+#![cfg(all(target_arch = "wasm32", feature = "web"))]
+
+use std::{net::SocketAddr, time::Duration};
+
+use js_sys::{Array, Reflect, Uint8Array};
+use metaserve_client::{
+    proto::{self, Event, Message, Server, ServerId},
+    ServerList, WebError, WebTransportClient,
+};
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+/// Stands in for the browser's WebTransport API, as if the gateway opened a stream carrying each
+/// element of `globalThis.incoming` and then closed, recording what's written to each stream the
+/// client opens in `globalThis.outgoing`
+const FAKE_WEBTRANSPORT: &str = r#"
+globalThis.outgoing = [];
+globalThis.WebTransport = class {
+    constructor(url) {
+        this.ready = Promise.resolve();
+        const incoming = globalThis.incoming;
+        this.incomingUnidirectionalStreams = new ReadableStream({
+            start(streams) {
+                for (const data of incoming) {
+                    streams.enqueue(new ReadableStream({
+                        start(chunks) {
+                            chunks.enqueue(data);
+                            chunks.close();
+                        },
+                    }));
+                }
+                streams.close();
+            },
+        });
+    }
+    createUnidirectionalStream() {
+        return Promise.resolve(new WritableStream({
+            write(chunk) {
+                globalThis.outgoing.push(chunk);
+            },
+        }));
+    }
+    close() {}
+};
+"#;
+
+#[wasm_bindgen_test]
+async fn receive_through_gateway() {
+    let welcome = proto::Welcome {
+        version: "test".into(),
+        limits: proto::Limits {
+            max_state_size: 1024,
+            heartbeat_min_interval: Duration::from_secs(1),
+            client_update_interval: Duration::from_secs(1),
+            max_message_size: 1024,
+        },
+        instance: 7,
+        table_version: 3,
+    };
+    let address = SocketAddr::from(([192, 0, 2, 1], 1000));
+    let snapshot = Message {
+        servers: vec![
+            Server {
+                id: ServerId(0),
+                event: Event::Update(address, b"state"),
+            },
+            Server {
+                id: ServerId::NONE,
+                event: Event::Synchronized,
+            },
+        ],
+        undecodable: Vec::new(),
+    };
+    // The welcome, a keep-alive, and the snapshot
+    let incoming = [
+        bincode::serialize(&welcome).unwrap(),
+        vec![0; 8],
+        snapshot.encode(3),
+    ];
+    let incoming = incoming
+        .iter()
+        .map(|x| JsValue::from(Uint8Array::from(&x[..])))
+        .collect::<Array>();
+    let global = js_sys::global();
+    Reflect::set(&global, &"incoming".into(), &incoming).unwrap();
+    js_sys::eval(FAKE_WEBTRANSPORT).unwrap();
+
+    let mut client = WebTransportClient::connect("https://gateway.invalid/")
+        .await
+        .unwrap();
+    assert_eq!(client.instance(), Some(7));
+    assert_eq!(client.table_version(), Some(3));
+    assert_eq!(client.limits(), Some(welcome.limits));
+    let mut list = ServerList::new();
+    client.synchronized(&mut list).await.unwrap();
+    assert_eq!(list.len(), 1);
+    let entry = list.get(ServerId(0)).unwrap();
+    assert_eq!(entry.address, address);
+    assert_eq!(&entry.state[..], b"state");
+    assert!(matches!(client.recv_owned().await, Err(WebError::Closed)));
+    client.close();
+
+    // The client asked for updates as soon as it connected
+    let outgoing = Array::from(&Reflect::get(&global, &"outgoing".into()).unwrap());
+    let request = bincode::serialize(&proto::Request::UpdateInterval(Duration::ZERO)).unwrap();
+    assert_eq!(outgoing.length(), 1);
+    assert_eq!(Uint8Array::new(&outgoing.get(0)).to_vec(), request);
+}
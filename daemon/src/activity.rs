@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// Counts of what happened on a single connection, for identifying misbehaving peers
+pub struct Activity {
+    start: Instant,
+    streams: AtomicU64,
+    /// Streams we opened that are still being sent
+    unfinished: AtomicU64,
+    /// Most that were ever being sent at once
+    max_unfinished: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    parse_failures: AtomicU64,
+    throttled: AtomicU64,
+    coalesced: AtomicU64,
+    over_budget: AtomicU64,
+    stale: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            streams: AtomicU64::new(0),
+            unfinished: AtomicU64::new(0),
+            max_unfinished: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            parse_failures: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            over_budget: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+        }
+    }
+
+    /// A stream carrying `bytes` was received
+    pub fn read(&self, bytes: usize) {
+        self.streams.fetch_add(1, Relaxed);
+        self.bytes_read.fetch_add(bytes as u64, Relaxed);
+    }
+
+    /// A stream carrying `bytes` was sent
+    pub fn wrote(&self, bytes: usize) {
+        self.streams.fetch_add(1, Relaxed);
+        self.bytes_written.fetch_add(bytes as u64, Relaxed);
+    }
+
+    /// A stream we opened is being sent until the returned guard is dropped
+    pub fn sending(&self) -> Sending<'_> {
+        let unfinished = self.unfinished.fetch_add(1, Relaxed) + 1;
+        self.max_unfinished.fetch_max(unfinished, Relaxed);
+        Sending(self)
+    }
+
+    /// The peer sent a message that couldn't be decoded
+    pub fn parse_failure(&self) {
+        self.parse_failures.fetch_add(1, Relaxed);
+    }
+
+    /// A message was delayed by rate limiting
+    pub fn throttled(&self) {
+        self.throttled.fetch_add(1, Relaxed);
+    }
+
+    /// An update was held back because the peer hadn't yet received the previous one
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Relaxed);
+    }
+
+    /// A message exceeded the connection's bandwidth budget, delaying later ones
+    pub fn over_budget(&self) {
+        self.over_budget.fetch_add(1, Relaxed);
+    }
+
+    /// A message was ignored for being older than one already received
+    pub fn stale(&self) {
+        self.stale.fetch_add(1, Relaxed);
+    }
+
+    /// Log a summary of the connection, which should have ended as described by `ending`
+    pub fn log(&self, protocol: &str, ending: Ending) {
+        macro_rules! summary {
+            ($level:ident) => {
+                $level!(
+                    protocol,
+                    ending = ending.as_str(),
+                    duration = ?self.start.elapsed(),
+                    streams = self.streams.load(Relaxed),
+                    max_unfinished_streams = self.max_unfinished.load(Relaxed),
+                    bytes_read = self.bytes_read.load(Relaxed),
+                    bytes_written = self.bytes_written.load(Relaxed),
+                    parse_failures = self.parse_failures.load(Relaxed),
+                    throttled = self.throttled.load(Relaxed),
+                    coalesced = self.coalesced.load(Relaxed),
+                    over_budget = self.over_budget.load(Relaxed),
+                    stale = self.stale.load(Relaxed),
+                    "connection summary"
+                )
+            };
+        }
+        match ending {
+            Ending::Clean | Ending::Expected => summary!(debug),
+            Ending::Abnormal => summary!(info),
+        }
+    }
+}
+
+/// A stream being sent, from [`Activity::sending`]
+pub struct Sending<'a>(&'a Activity);
+
+impl Drop for Sending<'_> {
+    fn drop(&mut self) {
+        self.0.unfinished.fetch_sub(1, Relaxed);
+    }
+}
+
+/// How a connection ended, for deciding how loudly to report it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Ending {
+    /// The peer said goodbye
+    Clean,
+    /// Routine in normal operation, e.g. we closed it deliberately, or an idle client went away
+    Expected,
+    /// Something went wrong, e.g. a transport error or protocol violation
+    Abnormal,
+}
+
+impl Ending {
+    /// Classify the end of `conn`, which is from a game client if `client` is set
+    pub fn of(conn: &quinn::Connection, client: bool) -> Self {
+        Self::from_reason(conn.close_reason().as_ref(), client)
+    }
+
+    /// Classify the end of a connection that closed for `reason`, if it's closed at all
+    fn from_reason(reason: Option<&quinn::ConnectionError>, client: bool) -> Self {
+        use quinn::ConnectionError::*;
+        match reason {
+            Some(ApplicationClosed(x))
+                if x.error_code == metaserve_proto::CloseCode::Normal.code().into() =>
+            {
+                Self::Clean
+            }
+            // Connections we close ourselves are logged with the reason when we do so
+            Some(LocallyClosed) => Self::Expected,
+            // Players routinely close games, and lose connectivity, without ceremony
+            Some(TimedOut) if client => Self::Expected,
+            _ => Self::Abnormal,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Clean => "clean",
+            Self::Expected => "expected",
+            Self::Abnormal => "abnormal",
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use quinn::{ConnectionError, TransportErrorCode};
+
+    use super::*;
+
+    /// Each kind of connection error, named for the variant it is, with the close codes that
+    /// matter
+    pub(crate) fn every_error() -> Vec<(&'static str, ConnectionError)> {
+        let application = |code: metaserve_proto::CloseCode| {
+            ConnectionError::ApplicationClosed(quinn::ApplicationClose {
+                error_code: code.code().into(),
+                reason: Default::default(),
+            })
+        };
+        let errors = vec![
+            ("version mismatch", ConnectionError::VersionMismatch),
+            (
+                "transport error",
+                ConnectionError::TransportError(TransportErrorCode::PROTOCOL_VIOLATION.into()),
+            ),
+            (
+                "connection closed",
+                ConnectionError::ConnectionClosed(quinn::ConnectionClose {
+                    error_code: TransportErrorCode::crypto(120),
+                    frame_type: None,
+                    reason: Default::default(),
+                }),
+            ),
+            ("normal", application(metaserve_proto::CloseCode::Normal)),
+            (
+                "rejected",
+                application(metaserve_proto::CloseCode::Rejected),
+            ),
+            ("reset", ConnectionError::Reset),
+            ("timed out", ConnectionError::TimedOut),
+            ("locally closed", ConnectionError::LocallyClosed),
+            ("cids exhausted", ConnectionError::CidsExhausted),
+        ];
+        // Fails to compile if quinn adds a variant, so that it's added to the table too
+        for (_, e) in &errors {
+            match e {
+                ConnectionError::VersionMismatch
+                | ConnectionError::TransportError(_)
+                | ConnectionError::ConnectionClosed(_)
+                | ConnectionError::ApplicationClosed(_)
+                | ConnectionError::Reset
+                | ConnectionError::TimedOut
+                | ConnectionError::LocallyClosed
+                | ConnectionError::CidsExhausted => {}
+            }
+        }
+        errors
+    }
+
+    #[test]
+    fn unfinished_high_water_mark() {
+        let activity = Activity::new();
+        let first = activity.sending();
+        let second = activity.sending();
+        drop(first);
+        let third = activity.sending();
+        assert_eq!(activity.max_unfinished.load(Relaxed), 2);
+        drop((second, third));
+        assert_eq!(activity.unfinished.load(Relaxed), 0);
+        drop(activity.sending());
+        assert_eq!(activity.max_unfinished.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn endings() {
+        use Ending::*;
+        let expected = [
+            ("version mismatch", Abnormal, Abnormal),
+            ("transport error", Abnormal, Abnormal),
+            ("connection closed", Abnormal, Abnormal),
+            ("normal", Clean, Clean),
+            ("rejected", Abnormal, Abnormal),
+            ("reset", Abnormal, Abnormal),
+            ("timed out", Abnormal, Expected),
+            ("locally closed", Expected, Expected),
+            ("cids exhausted", Abnormal, Abnormal),
+        ];
+        let errors = every_error();
+        assert_eq!(errors.len(), expected.len());
+        for ((name, e), (expected_name, server, client)) in errors.iter().zip(expected) {
+            assert_eq!(*name, expected_name);
+            assert_eq!(
+                Ending::from_reason(Some(e), false),
+                server,
+                "{name} from a server"
+            );
+            assert_eq!(
+                Ending::from_reason(Some(e), true),
+                client,
+                "{name} from a client"
+            );
+        }
+        // Still open, e.g. when a handler gives up on its own
+        assert_eq!(Ending::from_reason(None, false), Abnormal);
+    }
+}
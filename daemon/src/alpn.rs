@@ -0,0 +1,173 @@
+//! Diagnosing connections that fail to negotiate a protocol
+
+use std::{
+    cell::RefCell,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use metaserve_proto as ms;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+thread_local! {
+    /// Protocols offered by the client hello most recently processed on this thread
+    static OFFERED: RefCell<Option<Offered>> = const { RefCell::new(None) };
+}
+
+/// Wraps a certificate resolver to record the protocols offered by each client hello
+///
+/// Hellos are processed synchronously by `quinn::Incoming::accept`, so [`take_offered`] called
+/// right after it finds the connection's protocols.
+#[derive(Debug)]
+pub struct RecordOffered(pub Arc<dyn ResolvesServerCert>);
+
+impl ResolvesServerCert for RecordOffered {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let offered = Offered(hello.alpn().into_iter().flatten().map(Vec::from).collect());
+        OFFERED.with(|x| *x.borrow_mut() = Some(offered));
+        self.0.resolve(hello)
+    }
+}
+
+/// Protocols offered by the client hello most recently processed on this thread, if not yet taken
+pub fn take_offered() -> Option<Offered> {
+    OFFERED.with(|x| x.borrow_mut().take())
+}
+
+/// ALPN IDs offered by a peer
+#[derive(Debug, Default)]
+pub struct Offered(Vec<Vec<u8>>);
+
+impl Offered {
+    /// Whether both game and client protocols are offered, suggesting a confused peer
+    pub fn is_mixed(&self) -> bool {
+        let game = self.0.iter().any(|x| ms::game::PROTOCOLS.contains(&&x[..]));
+        let client = self
+            .0
+            .iter()
+            .any(|x| ms::client::PROTOCOLS.contains(&&x[..]));
+        game && client
+    }
+}
+
+impl fmt::Display for Offered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("none");
+        }
+        for (i, protocol) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            match name(protocol) {
+                Some(x) => f.write_str(x)?,
+                None => write!(f, "unknown {:?}", String::from_utf8_lossy(protocol))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Human-readable name of a protocol we speak
+fn name(protocol: &[u8]) -> Option<&'static str> {
+    Some(match protocol {
+        ms::game::PROTOCOL => "game v1",
+        ms::game::PROTOCOL_V2 => "game v2",
+        ms::game::PROTOCOL_V3 => "game v3",
+        ms::client::PROTOCOL => "client v1",
+        ms::client::PROTOCOL_V2 => "client v2",
+        ms::client::PROTOCOL_V3 => "client v3",
+        _ => return None,
+    })
+}
+
+/// Why a handshake failed
+#[derive(Debug, Copy, Clone)]
+pub enum Failure {
+    /// The peer offered none of our protocols
+    NoProtocol,
+    /// Some other TLS failure, e.g. the peer rejected our certificate
+    Tls,
+    TimedOut,
+    Other,
+}
+
+impl Failure {
+    pub fn of(e: &quinn::ConnectionError) -> Self {
+        let code = match e {
+            quinn::ConnectionError::TimedOut => return Self::TimedOut,
+            quinn::ConnectionError::TransportError(x) => x.code,
+            quinn::ConnectionError::ConnectionClosed(x) => x.error_code,
+            _ => return Self::Other,
+        };
+        if code == quinn::TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL) {
+            Self::NoProtocol
+        } else if (0x100..0x200).contains(&u64::from(code)) {
+            Self::Tls
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoProtocol => "no common protocol",
+            Self::Tls => "TLS",
+            Self::TimedOut => "timed out",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// TLS alert sent when ALPN fails, carried in the QUIC crypto error range starting at 0x100
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// Running totals of handshake failures by [`Failure`]
+#[derive(Default)]
+pub struct Failures([AtomicU64; 4]);
+
+impl Failures {
+    /// Count a failure, returning the total of its kind so far
+    pub fn record(&self, failure: Failure) -> u64 {
+        self.0[failure as usize].fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::tests::every_error;
+
+    #[test]
+    fn failures() {
+        let expected = [
+            ("version mismatch", "other"),
+            ("transport error", "other"),
+            ("connection closed", "no common protocol"),
+            ("normal", "other"),
+            ("rejected", "other"),
+            ("reset", "other"),
+            ("timed out", "timed out"),
+            ("locally closed", "other"),
+            ("cids exhausted", "other"),
+        ];
+        let errors = every_error();
+        assert_eq!(errors.len(), expected.len());
+        for ((name, e), (expected_name, failure)) in errors.iter().zip(expected) {
+            assert_eq!(*name, expected_name);
+            assert_eq!(Failure::of(e).as_str(), failure, "{name}");
+        }
+        // Any other TLS alert, e.g. bad_certificate
+        let tls = quinn::TransportErrorCode::crypto(42);
+        assert_eq!(
+            Failure::of(&quinn::ConnectionError::TransportError(tls.into())).as_str(),
+            "TLS"
+        );
+    }
+}
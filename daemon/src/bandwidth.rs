@@ -0,0 +1,106 @@
+//! Limiting the rate at which bytes are exchanged with a peer
+
+use tokio::time::{Duration, Instant};
+
+/// A token bucket that may go into debt, so that messages larger than its capacity still get
+/// through eventually
+pub struct Bucket {
+    /// Bytes regained per second
+    rate: f64,
+    /// Most bytes that can be saved up
+    capacity: f64,
+    /// Bytes available as of `updated`, or owed if negative
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Allow `per_minute` bytes per minute, in bursts of up to that much
+    pub fn new(per_minute: u64) -> Self {
+        Self {
+            rate: per_minute as f64 / 60.0,
+            capacity: per_minute as f64,
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Apply a new limit of `per_minute` bytes per minute, or none, to `bucket`, keeping any debt
+    pub fn reconfigure(bucket: &mut Option<Self>, per_minute: Option<u64>) {
+        match (bucket.as_mut(), per_minute) {
+            (Some(x), Some(per_minute)) => {
+                // Settle what was regained at the old rate first
+                x.spend(0);
+                x.rate = per_minute as f64 / 60.0;
+                x.capacity = per_minute as f64;
+                x.tokens = x.tokens.min(x.capacity);
+            }
+            _ => *bucket = per_minute.map(Self::new),
+        }
+    }
+
+    /// Spend `bytes`, returning how long until the bucket is out of debt
+    pub fn spend(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - bytes as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Duration, expected: Duration) {
+        let error = actual.abs_diff(expected);
+        assert!(
+            error < Duration::from_millis(1),
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill() {
+        // 100 bytes per second
+        let mut bucket = Bucket::new(6000);
+        assert_eq!(bucket.spend(6000), Duration::ZERO);
+        assert_near(bucket.spend(600), Duration::from_secs(6));
+
+        // Debt is paid off at the configured rate
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_near(bucket.spend(0), Duration::from_secs(3));
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert_eq!(bucket.spend(0), Duration::ZERO);
+
+        // Savings are capped at a minute's worth
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert_eq!(bucket.spend(6000), Duration::ZERO);
+        assert_near(bucket.spend(1), Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfigure() {
+        let mut bucket = Some(Bucket::new(6000));
+        bucket.as_mut().unwrap().spend(6600);
+
+        // Debt survives a change of rate, and is paid off at the new one
+        Bucket::reconfigure(&mut bucket, Some(12000));
+        assert_near(bucket.as_mut().unwrap().spend(0), Duration::from_secs(3));
+
+        // Lowering the limit caps savings
+        tokio::time::advance(Duration::from_secs(600)).await;
+        Bucket::reconfigure(&mut bucket, Some(60));
+        assert_near(bucket.as_mut().unwrap().spend(61), Duration::from_secs(1));
+
+        Bucket::reconfigure(&mut bucket, None);
+        assert!(bucket.is_none());
+        Bucket::reconfigure(&mut bucket, Some(60));
+        assert_eq!(bucket.as_mut().unwrap().spend(60), Duration::ZERO);
+    }
+}
@@ -0,0 +1,894 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use clap::{ArgEnum, Parser};
+use metaserve_proto as ms;
+use serde::{Deserialize, Serialize};
+
+use crate::error::DaemonError;
+
+// Each option may also be set in the config file under its long name, or by a `METASERVE_*`
+// environment variable. Precedence is config file < environment < command line, so flags take an
+// optional value, e.g. `--dev=false`, to turn off what a config file turned on.
+#[derive(Parser, Debug, Clone)]
+#[clap(name = "metaserve", version = crate::VERSION)]
+pub struct Opt {
+    /// Configuration file in TOML format, with keys named after long options
+    #[clap(parse(from_os_str), long = "config", env = "METASERVE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Validate the configuration, print it in full as TOML, and exit
+    #[clap(long = "check-config")]
+    pub check_config: bool,
+    /// Load TLS material and bind the listen address, then exit without serving
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+    /// Check that the daemon at this host:port completes a client handshake, then exit
+    #[clap(long = "healthcheck")]
+    pub healthcheck: Option<String>,
+    /// Diagnose handshakes with the daemon at this host:port as a client and as a game server,
+    /// then exit
+    #[clap(long = "selftest")]
+    pub selftest: Option<String>,
+    /// Certificate authority in DER format to trust for --healthcheck and --selftest; may be
+    /// repeated
+    #[clap(parse(from_os_str), long = "ca")]
+    pub ca: Vec<PathBuf>,
+    /// With --selftest, write the certificate the daemon presents to this path in DER format,
+    /// whether or not it's trusted, e.g. to pass to --ca once its fingerprint has been checked
+    #[clap(parse(from_os_str), long = "save-cert", requires = "selftest")]
+    pub save_cert: Option<PathBuf>,
+
+    /// TLS private key in DER format
+    #[clap(parse(from_os_str), short = 'k', long = "key", env = "METASERVE_KEY")]
+    private_key: Option<PathBuf>,
+    /// TLS certificate in DER format
+    #[clap(parse(from_os_str), short = 'c', long = "cert", env = "METASERVE_CERT")]
+    certificate: Option<PathBuf>,
+    /// Write the certificate to this path on startup and reload, for peers to fetch out of band;
+    /// in PEM format if the path ends in .pem, and DER otherwise
+    #[clap(
+        parse(from_os_str),
+        long = "export-cert",
+        env = "METASERVE_EXPORT_CERT"
+    )]
+    export_cert: Option<PathBuf>,
+
+    /// Maximum size of server state to accept [default: 8192]
+    #[clap(short = 's', long = "state-size", env = "METASERVE_STATE_SIZE")]
+    state_size: Option<usize>,
+    /// Maximum total size of all server states to store
+    #[clap(
+        long = "max-total-state-bytes",
+        env = "METASERVE_MAX_TOTAL_STATE_BYTES"
+    )]
+    max_total_state_bytes: Option<usize>,
+    /// What to do when --max-total-state-bytes would be exceeded [default: reject]
+    #[clap(
+        long = "state-budget-policy",
+        arg_enum,
+        env = "METASERVE_STATE_BUDGET_POLICY"
+    )]
+    state_budget_policy: Option<BudgetPolicy>,
+    /// Disconnect game servers whose state isn't UTF-8 text
+    #[clap(
+        long = "require-utf8-state",
+        env = "METASERVE_REQUIRE_UTF8_STATE",
+        parse(try_from_str = flag),
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    require_utf8_state: Option<bool>,
+    /// Disconnect game servers whose state isn't a JSON value
+    #[clap(
+        long = "require-json-state",
+        env = "METASERVE_REQUIRE_JSON_STATE",
+        parse(try_from_str = flag),
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    require_json_state: Option<bool>,
+
+    /// Bytes per minute to accept from each game server; heartbeats beyond this are delayed, and
+    /// game servers that keep exceeding it are disconnected
+    #[clap(
+        long = "max-heartbeat-bandwidth",
+        env = "METASERVE_MAX_HEARTBEAT_BANDWIDTH"
+    )]
+    max_heartbeat_bandwidth: Option<u64>,
+    /// Bytes per minute to send each client; updates beyond this are delayed
+    #[clap(long = "max-client-bandwidth", env = "METASERVE_MAX_CLIENT_BANDWIDTH")]
+    max_client_bandwidth: Option<u64>,
+    /// Make game servers find a hash with this many leading zero bits before registering them,
+    /// refusing those too old to; game servers with client certificates are exempt
+    #[clap(long = "challenge-difficulty", env = "METASERVE_CHALLENGE_DIFFICULTY")]
+    challenge_difficulty: Option<u8>,
+    /// Only challenge game servers while more than this many register per minute
+    #[clap(long = "challenge-above", env = "METASERVE_CHALLENGE_ABOVE")]
+    challenge_above: Option<u64>,
+    /// Seconds game servers have to answer a challenge [default: 10]
+    #[clap(long = "challenge-timeout", env = "METASERVE_CHALLENGE_TIMEOUT")]
+    challenge_timeout: Option<f64>,
+
+    /// Minimum seconds between requests for a game server to send a fresh heartbeat [default: 5]
+    #[clap(long = "refresh-interval", env = "METASERVE_REFRESH_INTERVAL")]
+    refresh_interval: Option<u64>,
+    /// Seconds to keep showing a game server whose connection was lost, rather than closed
+    /// gracefully, so that if it reconnects from the same address clients see no interruption
+    #[clap(long = "removal-grace", env = "METASERVE_REMOVAL_GRACE")]
+    removal_grace: Option<f64>,
+
+    /// Seconds between updates sent to each client, before jitter [default: 1]
+    #[clap(
+        long = "client-update-interval",
+        env = "METASERVE_CLIENT_UPDATE_INTERVAL"
+    )]
+    client_update_interval: Option<f64>,
+    /// Minimum seconds between heartbeats accepted from each game server [default: 1]
+    #[clap(
+        long = "heartbeat-min-interval",
+        env = "METASERVE_HEARTBEAT_MIN_INTERVAL"
+    )]
+    heartbeat_min_interval: Option<f64>,
+    /// Randomly vary each client's update interval by up to this fraction, so that clients which
+    /// connected together don't receive updates in lockstep [default: 0.2]
+    #[clap(long = "update-jitter", env = "METASERVE_UPDATE_JITTER")]
+    update_jitter: Option<f64>,
+    /// Minimum seconds between queries answered for each client; faster ones are refused
+    /// [default: 0.1]
+    #[clap(
+        long = "client-query-interval",
+        env = "METASERVE_CLIENT_QUERY_INTERVAL"
+    )]
+    client_query_interval: Option<f64>,
+    /// Send each client an empty update after this many seconds without a real one, so that
+    /// clients can detect dead connections promptly
+    #[clap(long = "client-keepalive", env = "METASERVE_CLIENT_KEEPALIVE")]
+    client_keepalive: Option<f64>,
+    /// Disconnect clients that haven't received an update this many seconds after it was sent
+    #[clap(long = "client-send-timeout", env = "METASERVE_CLIENT_SEND_TIMEOUT")]
+    client_send_timeout: Option<f64>,
+    /// Clients to notify of game server changes at a time, yielding to other tasks in between
+    /// [default: 256]
+    #[clap(long = "fanout-batch", env = "METASERVE_FANOUT_BATCH")]
+    fanout_batch: Option<usize>,
+
+    /// Seconds to wait for connections to end after SIGUSR2 before exiting anyway [default: 300]
+    #[clap(long = "drain-timeout", env = "METASERVE_DRAIN_TIMEOUT")]
+    drain_timeout: Option<f64>,
+    /// Seconds between releasing memory left over from past load peaks [default: 60]
+    #[clap(long = "maintenance-interval", env = "METASERVE_MAINTENANCE_INTERVAL")]
+    maintenance_interval: Option<f64>,
+
+    /// Address to listen on, accepting the peers chosen by --accept [default: [::]:4433, unless
+    /// the config file lists other listeners]
+    #[clap(long = "listen", env = "METASERVE_LISTEN")]
+    listen: Option<SocketAddr>,
+    /// Kinds of peer to accept on --listen, e.g. only clients for a read replica fed by a mirror.
+    /// Others see a handshake failure for lack of a common protocol, rather than a Rejected close
+    /// [default: both]
+    #[clap(long = "accept", arg_enum, env = "METASERVE_ACCEPT")]
+    accept: Option<Accept>,
+    /// MaxMind GeoIP2 or GeoLite2 database to look up game servers' countries in
+    #[cfg(feature = "geoip")]
+    #[clap(parse(from_os_str), long = "geoip-db", env = "METASERVE_GEOIP_DB")]
+    geoip_db: Option<PathBuf>,
+    /// Which log messages and spans to record, as tracing filter directives, e.g.
+    /// "info,[server]=debug" for debug output from game server connections only [default: the
+    /// RUST_LOG environment variable, or "info"]
+    #[clap(long = "log-filter", env = "METASERVE_LOG_FILTER")]
+    log_filter: Option<String>,
+    /// OTLP/HTTP endpoint to export tracing spans to, e.g. http://localhost:4318/v1/traces
+    #[cfg(feature = "otel")]
+    #[clap(long = "otlp-endpoint", env = "METASERVE_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Enable options that are only suitable for development
+    #[clap(
+        long = "dev",
+        env = "METASERVE_DEV",
+        parse(try_from_str = flag),
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    dev: Option<bool>,
+    /// Advertise this many synthetic game servers, for testing clients (requires --dev)
+    #[clap(long = "fake-servers", env = "METASERVE_FAKE_SERVERS")]
+    fake_servers: Option<usize>,
+    /// Mean seconds between updates to each synthetic game server [default: 10]
+    #[clap(long = "fake-update-interval", env = "METASERVE_FAKE_UPDATE_INTERVAL")]
+    fake_update_interval: Option<f64>,
+    /// Mean seconds before each synthetic game server is replaced by a new one [default: 300]
+    #[clap(long = "fake-lifetime", env = "METASERVE_FAKE_LIFETIME")]
+    fake_lifetime: Option<f64>,
+}
+
+/// Effective configuration, after merging the command line, environment, and config file
+///
+/// See [`Opt`] for documentation.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(rename = "key")]
+    pub private_key: Option<PathBuf>,
+    #[serde(rename = "cert")]
+    pub certificate: Option<PathBuf>,
+    pub export_cert: Option<PathBuf>,
+    pub state_size: usize,
+    pub max_total_state_bytes: Option<usize>,
+    pub state_budget_policy: BudgetPolicy,
+    pub require_utf8_state: bool,
+    pub require_json_state: bool,
+    pub max_heartbeat_bandwidth: Option<u64>,
+    pub max_client_bandwidth: Option<u64>,
+    pub challenge_difficulty: Option<u8>,
+    pub challenge_above: Option<u64>,
+    pub challenge_timeout: f64,
+    pub refresh_interval: u64,
+    pub removal_grace: Option<f64>,
+    pub client_update_interval: f64,
+    pub heartbeat_min_interval: f64,
+    pub update_jitter: f64,
+    pub client_query_interval: f64,
+    pub client_keepalive: Option<f64>,
+    pub client_send_timeout: Option<f64>,
+    pub fanout_batch: usize,
+    pub drain_timeout: f64,
+    pub maintenance_interval: f64,
+    pub listen: Option<SocketAddr>,
+    pub accept: Accept,
+    /// Further addresses to listen on, each with its own policy; config file only
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerPolicy>,
+    pub log_filter: Option<String>,
+    #[cfg(feature = "geoip")]
+    pub geoip_db: Option<PathBuf>,
+    #[cfg(feature = "otel")]
+    pub otlp_endpoint: Option<String>,
+    pub dev: bool,
+    pub fake_servers: Option<usize>,
+    pub fake_update_interval: f64,
+    pub fake_lifetime: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            private_key: None,
+            certificate: None,
+            export_cert: None,
+            state_size: 8192,
+            max_total_state_bytes: None,
+            state_budget_policy: BudgetPolicy::Reject,
+            require_utf8_state: false,
+            require_json_state: false,
+            max_heartbeat_bandwidth: None,
+            max_client_bandwidth: None,
+            challenge_difficulty: None,
+            challenge_above: None,
+            challenge_timeout: 10.0,
+            refresh_interval: 5,
+            removal_grace: None,
+            client_update_interval: 1.0,
+            heartbeat_min_interval: 1.0,
+            update_jitter: 0.2,
+            client_query_interval: 0.1,
+            client_keepalive: None,
+            client_send_timeout: None,
+            fanout_batch: 256,
+            drain_timeout: 300.0,
+            maintenance_interval: 60.0,
+            listen: None,
+            accept: Accept::Both,
+            listeners: Vec::new(),
+            log_filter: None,
+            #[cfg(feature = "geoip")]
+            geoip_db: None,
+            #[cfg(feature = "otel")]
+            otlp_endpoint: None,
+            dev: false,
+            fake_servers: None,
+            fake_update_interval: 10.0,
+            fake_lifetime: 300.0,
+        }
+    }
+}
+
+impl Config {
+    /// Determine the configuration from `opt` and any config file it names
+    pub fn load(opt: Opt) -> Result<Self, DaemonError> {
+        let mut config = match opt.config {
+            Some(ref path) => {
+                let text = fs::read_to_string(path).map_err(|source| DaemonError::ConfigRead {
+                    path: path.clone(),
+                    source,
+                })?;
+                toml::from_str(&text).map_err(|source| DaemonError::ConfigParse {
+                    path: path.clone(),
+                    source,
+                })?
+            }
+            None => Self::default(),
+        };
+        config.merge(opt);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings with those given in `opt`
+    fn merge(&mut self, opt: Opt) {
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(if let Some(x) = opt.$field {
+                    self.$field = x;
+                })*
+            };
+        }
+        merge!(
+            state_size,
+            state_budget_policy,
+            challenge_timeout,
+            refresh_interval,
+            client_update_interval,
+            heartbeat_min_interval,
+            update_jitter,
+            client_query_interval,
+            fanout_batch,
+            drain_timeout,
+            maintenance_interval,
+            accept,
+            fake_update_interval,
+            fake_lifetime
+        );
+        macro_rules! merge_optional {
+            ($($field:ident),*) => {
+                $(if opt.$field.is_some() {
+                    self.$field = opt.$field;
+                })*
+            };
+        }
+        merge_optional!(
+            private_key,
+            certificate,
+            export_cert,
+            max_total_state_bytes,
+            max_heartbeat_bandwidth,
+            max_client_bandwidth,
+            challenge_difficulty,
+            challenge_above,
+            removal_grace,
+            client_keepalive,
+            client_send_timeout,
+            listen,
+            log_filter,
+            fake_servers
+        );
+        #[cfg(feature = "geoip")]
+        merge_optional!(geoip_db);
+        #[cfg(feature = "otel")]
+        merge_optional!(otlp_endpoint);
+        merge!(require_utf8_state, require_json_state, dev);
+    }
+
+    fn validate(&self) -> Result<(), DaemonError> {
+        if self.private_key.is_none() {
+            return Err(DaemonError::config_invalid(
+                "key",
+                "must be given, e.g. with --key",
+            ));
+        }
+        if self.certificate.is_none() {
+            return Err(DaemonError::config_invalid(
+                "cert",
+                "must be given, e.g. with --cert",
+            ));
+        }
+        if self.fake_servers.is_some() && !self.dev {
+            return Err(DaemonError::config_invalid(
+                "fake-servers",
+                "requires dev to be enabled",
+            ));
+        }
+        // Advertised to peers as a u32, with room for framing
+        if self.state_size > u32::MAX as usize - ms::game::MAX_UPDATE_OVERHEAD {
+            return Err(DaemonError::config_invalid("state-size", "too large"));
+        }
+        if self.max_heartbeat_bandwidth == Some(0) {
+            return Err(DaemonError::config_invalid(
+                "max-heartbeat-bandwidth",
+                "must be positive",
+            ));
+        }
+        if self.max_client_bandwidth == Some(0) {
+            return Err(DaemonError::config_invalid(
+                "max-client-bandwidth",
+                "must be positive",
+            ));
+        }
+        if self
+            .challenge_difficulty
+            .is_some_and(|x| x > ms::game::MAX_DIFFICULTY)
+        {
+            return Err(DaemonError::config_invalid(
+                "challenge-difficulty",
+                format_args!("must be at most {}", ms::game::MAX_DIFFICULTY),
+            ));
+        }
+        if self.challenge_above.is_some() && self.challenge_difficulty.is_none() {
+            return Err(DaemonError::config_invalid(
+                "challenge-above",
+                "requires challenge-difficulty",
+            ));
+        }
+        if self.challenge_above == Some(0) {
+            return Err(DaemonError::config_invalid(
+                "challenge-above",
+                "must be positive",
+            ));
+        }
+        check("challenge-timeout", positive(self.challenge_timeout))?;
+        check(
+            "client-update-interval",
+            interval(self.client_update_interval),
+        )?;
+        check(
+            "heartbeat-min-interval",
+            interval(self.heartbeat_min_interval),
+        )?;
+        check("update-jitter", fraction(self.update_jitter))?;
+        check(
+            "client-query-interval",
+            positive(self.client_query_interval),
+        )?;
+        if let Some(x) = self.removal_grace {
+            check("removal-grace", positive(x))?;
+        }
+        if let Some(x) = self.client_keepalive {
+            check("client-keepalive", positive(x))?;
+        }
+        if let Some(x) = self.client_send_timeout {
+            check("client-send-timeout", positive(x))?;
+        }
+        if self.fanout_batch == 0 {
+            return Err(DaemonError::config_invalid(
+                "fanout-batch",
+                "must be positive",
+            ));
+        }
+        check("drain-timeout", positive(self.drain_timeout))?;
+        check("maintenance-interval", positive(self.maintenance_interval))?;
+        check("fake-update-interval", positive(self.fake_update_interval))?;
+        check("fake-lifetime", positive(self.fake_lifetime))?;
+        if let Some(ref x) = self.log_filter {
+            tracing_subscriber::EnvFilter::try_new(x)
+                .map_err(|e| DaemonError::config_invalid("log-filter", e))?;
+        }
+        for listener in &self.listeners {
+            let field = || format!("listener {}", listener.address);
+            if listener.accept.is_empty() {
+                return Err(DaemonError::config_invalid(field(), "accepts nothing"));
+            }
+            if listener.filter.as_ref().is_some_and(|x| !x.is_valid()) {
+                return Err(DaemonError::config_invalid(field(), "invalid filter"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo changes to settings that only take effect on startup, returning their names
+    ///
+    /// Used when reloading, with `old` being the configuration in effect.
+    pub fn keep_static(&mut self, old: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(if self.$field != old.$field {
+                    changed.push(stringify!($field));
+                    self.$field = old.$field.clone();
+                })*
+            };
+        }
+        keep!(
+            private_key,
+            certificate,
+            state_size,
+            require_utf8_state,
+            require_json_state,
+            maintenance_interval,
+            listen,
+            accept,
+            listeners,
+            dev,
+            fake_servers,
+            fake_update_interval,
+            fake_lifetime
+        );
+        #[cfg(feature = "geoip")]
+        keep!(geoip_db);
+        #[cfg(feature = "otel")]
+        keep!(otlp_endpoint);
+        changed
+    }
+
+    /// Every address to listen on, with its policy
+    pub fn listeners(&self) -> Vec<ListenerPolicy> {
+        let default = match self.listen {
+            Some(x) => Some(x),
+            None if self.listeners.is_empty() => Some("[::]:4433".parse().unwrap()),
+            None => None,
+        };
+        default
+            .map(|address| ListenerPolicy {
+                accept: self.accept.roles(),
+                ..ListenerPolicy::open(address)
+            })
+            .into_iter()
+            .chain(self.listeners.iter().cloned())
+            .collect()
+    }
+}
+
+/// An address to listen on, and how to treat peers that connect to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ListenerPolicy {
+    pub address: SocketAddr,
+    /// Certificate authority in DER format that peers must present a certificate from
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Kinds of peer to accept
+    #[serde(default = "Role::all")]
+    pub accept: Vec<Role>,
+    /// Criteria that game servers must also meet to be visible to clients, whatever their own
+    /// filters
+    #[serde(default)]
+    pub filter: Option<ms::client::Filter>,
+    /// Send clients every game server's LAN addresses, rather than only those of game servers
+    /// sharing their public address, e.g. for monitoring from inside the game servers' network
+    #[serde(default)]
+    pub all_lan_addresses: bool,
+}
+
+impl ListenerPolicy {
+    /// Accept every peer on `address`
+    pub fn open(address: SocketAddr) -> Self {
+        Self {
+            address,
+            client_ca: None,
+            accept: Role::all(),
+            filter: None,
+            all_lan_addresses: false,
+        }
+    }
+
+    /// Whether peers speaking protocols for `role` are accepted
+    pub fn accepts(&self, role: Role) -> bool {
+        self.accept.contains(&role)
+    }
+
+    /// Combine a client's `filter` with this listener's
+    pub fn restrict(&self, mut filter: ms::client::Filter) -> ms::client::Filter {
+        if let Some(ref x) = self.filter {
+            filter.required.extend_from_slice(&x.required);
+            filter.excluded.extend_from_slice(&x.excluded);
+        }
+        filter
+    }
+
+    /// Whether clients may see a game server with `tags` in `region`
+    pub fn shows(&self, tags: &[String], region: Option<ms::client::Region>) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|x| x.matches_in(tags, region))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Game servers sending heartbeats
+    Game,
+    /// Clients receiving the server list
+    Client,
+}
+
+impl Role {
+    fn all() -> Vec<Self> {
+        vec![Self::Game, Self::Client]
+    }
+}
+
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BudgetPolicy {
+    /// Disconnect game servers that would exceed the budget, and refuse new ones while it's full
+    Reject,
+    /// Disconnect the game servers that heartbeated least recently to make room
+    Evict,
+}
+
+/// Kinds of peer to accept on `--listen`
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Accept {
+    Both,
+    /// Game servers only, e.g. for a private registration tier
+    Servers,
+    /// Clients only, e.g. for a read replica
+    Clients,
+}
+
+impl Accept {
+    fn roles(self) -> Vec<Role> {
+        match self {
+            Self::Both => Role::all(),
+            Self::Servers => vec![Role::Game],
+            Self::Clients => vec![Role::Client],
+        }
+    }
+}
+
+/// Parse the optional value of a flag like `--dev`, also accepting the spellings clap accepts for
+/// flags set in the environment
+fn flag(x: &str) -> Result<bool, String> {
+    match &*x.to_ascii_lowercase() {
+        "y" | "yes" | "t" | "true" | "on" | "1" => Ok(true),
+        "" | "n" | "no" | "f" | "false" | "off" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, not {x:?}")),
+    }
+}
+
+/// Name the offending option in a validation failure
+fn check(option: &str, result: Result<(), &str>) -> Result<(), DaemonError> {
+    result.map_err(|e| DaemonError::config_invalid(option, e))
+}
+
+fn positive(x: f64) -> Result<(), &'static str> {
+    if x > 0.0 {
+        Ok(())
+    } else {
+        Err("must be a positive number")
+    }
+}
+
+fn fraction(x: f64) -> Result<(), &'static str> {
+    if (0.0..1.0).contains(&x) {
+        Ok(())
+    } else {
+        Err("must be at least 0 and less than 1")
+    }
+}
+
+fn interval(x: f64) -> Result<(), &'static str> {
+    if (0.1..=60.0).contains(&x) {
+        Ok(())
+    } else {
+        Err("must be a number of seconds between 0.1 and 60")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_order() {
+        let path = std::env::temp_dir().join(format!("metaserve-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "state-size = 100\n\
+             refresh-interval = 7\n\
+             dev = true\n\
+             require-utf8-state = true\n\
+             require-json-state = true\n",
+        )
+        .unwrap();
+        let load = |args: &[&str]| {
+            let opt = Opt::try_parse_from(
+                ["metaserve", "-k", "key.der", "-c", "cert.der", "--config"]
+                    .into_iter()
+                    .chain([path.to_str().unwrap()])
+                    .chain(args.iter().copied()),
+            )
+            .unwrap();
+            Config::load(opt).unwrap()
+        };
+
+        // The file overrides the defaults
+        let config = load(&[]);
+        assert_eq!(config.state_size, 100);
+        assert_eq!(config.refresh_interval, 7);
+        assert!(config.dev && config.require_utf8_state && config.require_json_state);
+
+        // The environment overrides the file, including turning flags off
+        std::env::set_var("METASERVE_STATE_SIZE", "150");
+        std::env::set_var("METASERVE_DEV", "false");
+        let config = load(&[]);
+        assert_eq!(config.state_size, 150);
+        assert_eq!(config.refresh_interval, 7);
+        assert!(!config.dev && config.require_utf8_state);
+
+        // The command line overrides both
+        let config = load(&[
+            "--state-size=200",
+            "--dev",
+            "--require-utf8-state=false",
+            "--require-json-state",
+        ]);
+        std::env::remove_var("METASERVE_STATE_SIZE");
+        std::env::remove_var("METASERVE_DEV");
+        assert_eq!(config.state_size, 200);
+        assert_eq!(config.refresh_interval, 7);
+        assert!(config.dev && !config.require_utf8_state && config.require_json_state);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flag_values() {
+        for x in ["true", "1", "yes", "On"] {
+            assert_eq!(flag(x), Ok(true));
+        }
+        for x in ["false", "0", "no", "OFF", ""] {
+            assert_eq!(flag(x), Ok(false));
+        }
+        assert!(flag("maybe").is_err());
+    }
+
+    #[test]
+    fn challenge_difficulty_bounded() {
+        let config = |difficulty| Config {
+            private_key: Some("key.der".into()),
+            certificate: Some("cert.der".into()),
+            challenge_difficulty: Some(difficulty),
+            ..Config::default()
+        };
+        config(ms::game::MAX_DIFFICULTY).validate().unwrap();
+        let e = config(ms::game::MAX_DIFFICULTY + 1).validate().unwrap_err();
+        let DaemonError::ConfigInvalid { field, .. } = e else {
+            panic!("unexpected {e}");
+        };
+        assert_eq!(field, "challenge-difficulty");
+    }
+
+    #[test]
+    fn validate_ranges() {
+        let valid = || Config {
+            private_key: Some("key.der".into()),
+            certificate: Some("cert.der".into()),
+            ..Config::default()
+        };
+        valid().validate().unwrap();
+        let check = |field: &str, ok: bool, change: &dyn Fn(&mut Config)| {
+            let mut config = valid();
+            change(&mut config);
+            match config.validate() {
+                Ok(()) => assert!(ok, "{field} accepted"),
+                Err(DaemonError::ConfigInvalid { field: x, reason }) => {
+                    assert!(!ok, "{field} rejected: {reason}");
+                    assert_eq!(x, field);
+                }
+                Err(e) => panic!("{field}: unexpected {e}"),
+            }
+        };
+        check("key", false, &|x| x.private_key = None);
+        check("cert", false, &|x| x.certificate = None);
+        check("fake-servers", false, &|x| x.fake_servers = Some(1));
+        check("fake-servers", true, &|x| {
+            x.fake_servers = Some(1);
+            x.dev = true;
+        });
+        let max_state = u32::MAX as usize - ms::game::MAX_UPDATE_OVERHEAD;
+        check("state-size", true, &|x| x.state_size = max_state);
+        check("state-size", false, &|x| x.state_size = max_state + 1);
+        type Field = fn(&mut Config) -> &mut f64;
+        let intervals: [(&str, Field); 2] = [
+            ("client-update-interval", |x| &mut x.client_update_interval),
+            ("heartbeat-min-interval", |x| &mut x.heartbeat_min_interval),
+        ];
+        for (interval, field) in intervals {
+            for (value, ok) in [(0.1, true), (60.0, true), (0.09, false), (60.1, false)] {
+                check(interval, ok, &|x| *field(x) = value);
+            }
+            check(interval, false, &|x| *field(x) = f64::NAN);
+        }
+        for (value, ok) in [(0.0, true), (0.99, true), (1.0, false), (-0.1, false)] {
+            check("update-jitter", ok, &|x| x.update_jitter = value);
+        }
+        for (value, ok) in [
+            (0.001, true),
+            (0.0, false),
+            (-1.0, false),
+            (f64::NAN, false),
+        ] {
+            check("challenge-timeout", ok, &|x| x.challenge_timeout = value);
+            check("client-query-interval", ok, &|x| {
+                x.client_query_interval = value
+            });
+            check("removal-grace", ok, &|x| x.removal_grace = Some(value));
+            check("client-keepalive", ok, &|x| {
+                x.client_keepalive = Some(value)
+            });
+            check("client-send-timeout", ok, &|x| {
+                x.client_send_timeout = Some(value)
+            });
+            check("drain-timeout", ok, &|x| x.drain_timeout = value);
+            check("maintenance-interval", ok, &|x| {
+                x.maintenance_interval = value
+            });
+            check("fake-update-interval", ok, &|x| {
+                x.fake_update_interval = value
+            });
+            check("fake-lifetime", ok, &|x| x.fake_lifetime = value);
+        }
+        for (value, ok) in [(1, true), (0, false)] {
+            check("max-heartbeat-bandwidth", ok, &|x| {
+                x.max_heartbeat_bandwidth = Some(value)
+            });
+            check("max-client-bandwidth", ok, &|x| {
+                x.max_client_bandwidth = Some(value)
+            });
+            check("fanout-batch", ok, &|x| x.fanout_batch = value as usize);
+        }
+        check("challenge-above", false, &|x| x.challenge_above = Some(1));
+        check("challenge-above", false, &|x| {
+            x.challenge_above = Some(0);
+            x.challenge_difficulty = Some(8);
+        });
+        check("challenge-above", true, &|x| {
+            x.challenge_above = Some(1);
+            x.challenge_difficulty = Some(8);
+        });
+        check("log-filter", false, &|x| x.log_filter = Some("[".into()));
+        check("log-filter", true, &|x| x.log_filter = Some("debug".into()));
+    }
+
+    #[test]
+    fn state_validators() {
+        use crate::validate::{self, Json, StateValidator, Utf8};
+
+        assert_eq!(Utf8.validate(b"{\"players\": 3}"), Ok(()));
+        assert_eq!(Utf8.validate(b""), Ok(()));
+        assert_eq!(
+            Utf8.validate(b"ok\xff"),
+            Err("invalid utf-8 sequence of 1 bytes from index 2".into())
+        );
+
+        assert_eq!(Json.validate(b"{\"players\": 3}"), Ok(()));
+        assert_eq!(Json.validate(b" 42 "), Ok(()));
+        assert_eq!(
+            Json.validate(b""),
+            Err("EOF while parsing a value at line 1 column 0".into())
+        );
+        assert_eq!(
+            Json.validate(b"{} {}"),
+            Err("trailing characters at line 1 column 4".into())
+        );
+        assert_eq!(
+            Json.validate(b"{\"players\": }"),
+            Err("expected value at line 1 column 13".into())
+        );
+
+        // Each flag enables its validator, and states must pass every enabled one
+        let check = |utf8, json, state: &[u8]| {
+            let config = Config {
+                require_utf8_state: utf8,
+                require_json_state: json,
+                ..Config::default()
+            };
+            let validators = validate::from_config(&config);
+            assert_eq!(validators.len(), usize::from(utf8) + usize::from(json));
+            validators.iter().find_map(|x| x.validate(state).err())
+        };
+        assert_eq!(check(false, false, b"\xff"), None);
+        assert!(check(true, false, b"\xff").is_some());
+        assert_eq!(check(true, false, b"not json"), None);
+        assert!(check(false, true, b"not json").is_some());
+        assert!(check(true, true, b"\xff").is_some());
+        assert_eq!(check(true, true, b"[]"), None);
+    }
+}
@@ -0,0 +1,1975 @@
+//! The game servers and clients the daemon knows, and every transition between their states
+//!
+//! Nothing here does I/O or reads the clock. Connections are only compared and closed, through
+//! [`Connection`], and the time is passed in, so that tests can drive a [`Core`] through any
+//! interleaving of events. The daemon's connection handlers drive one behind a lock.
+
+use std::{
+    cmp, mem,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use indexmap::IndexSet;
+use metaserve_proto as ms;
+use rand::{seq::IteratorRandom, Rng};
+use tokio::{
+    sync::{mpsc, Notify},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+use crate::{
+    config::{BudgetPolicy, ListenerPolicy},
+    fanout::Histogram,
+    table::{ClientId, ServerId, Table},
+};
+
+/// Most shutdowns to queue for a client before sending it a fresh snapshot instead
+const MAX_LOST: usize = 4096;
+
+/// Most events not about any particular server in one of [`Core::take_update`]'s messages
+const META_EVENTS: usize = 5;
+
+/// Room to allow for an encoded entry carrying any event other than a server's update, enough
+/// for all but `LanAddresses` with several addresses
+const EVENT_ENTRY_SIZE: usize = 64;
+
+/// Room to allow for a server's encoded entries besides its state and tags, enough for an IPv6
+/// address and a `Truncated` entry
+const ENTRIES_OVERHEAD: usize = 128;
+
+/// A game server's connection, as far as a [`Core`] is concerned
+pub trait Connection: Clone {
+    /// Distinguishes the connection from others open at the same time
+    fn stable_id(&self) -> usize;
+    /// Close the connection, telling the game server why
+    fn close(&self, code: ms::CloseCode, reason: &[u8]);
+}
+
+/// A game server's heartbeat, as [`Core::update_server`] applies it
+pub struct Heartbeat {
+    /// Where game clients should connect
+    pub address: SocketAddr,
+    pub state: Vec<u8>,
+    /// Looked up from `address`
+    pub region: Option<ms::client::Region>,
+    /// Fraction of player slots in use, if known from `state`
+    pub load: Option<f64>,
+}
+
+/// Every game server and client, and what each client has yet to be told
+pub struct Core<C> {
+    pub servers: Table<ServerId, Server<C>>,
+    pub clients: Table<ClientId, Client>,
+    /// Total size of all servers' `state`
+    pub state_bytes: usize,
+    /// Incremented by each change to the game servers, for [`ms::client::Welcome::table_version`]
+    pub table_version: u64,
+    /// Time from a game server changing to clients being sent the change, since the last
+    /// [`maintain`](Self::maintain)
+    pub update_latency: Histogram,
+}
+
+impl<C: Connection> Core<C> {
+    pub fn new() -> Self {
+        Self {
+            servers: Table::new(),
+            clients: Table::new(),
+            state_bytes: 0,
+            table_version: 0,
+            update_latency: Histogram::new(),
+        }
+    }
+
+    /// Register a game server that just connected over `connection`, or `None` for a fake server
+    ///
+    /// The server isn't visible to clients until its first [heartbeat](Self::update_server).
+    pub fn add_server(
+        &mut self,
+        refresh: Arc<Notify>,
+        connection: Option<C>,
+        now: Instant,
+    ) -> ServerId {
+        self.servers.insert(Server::new(refresh, connection, now))
+    }
+
+    /// If another game server is registered at `addr`, assume it's a stale instance of the game
+    /// server registered as `id` and replace it, returning the ID now in use
+    ///
+    /// The stale entry keeps its ID and state until the replacement's first heartbeat, so clients
+    /// see a single update rather than a shutdown followed by a new server. This includes entries
+    /// kept after their connection was [lost](Self::lose_server).
+    pub fn claim_address(&mut self, id: ServerId, addr: SocketAddr, now: Instant) -> ServerId {
+        let stale = self.servers.iter().find(|&(other, x)| {
+            other != id
+                && !x.evicted
+                && (x.connection.is_some() || x.lost_at.is_some())
+                && x.address == Some(addr)
+        });
+        let Some((stale, _)) = stale else {
+            return id;
+        };
+        info!(%stale, "replacing server with the same address");
+        let new = self.servers.remove(id);
+        self.forget_server(id, None, now);
+        let server = &mut self.servers[stale];
+        if let Some(old) = server.connection.replace(new.connection.unwrap()) {
+            old.close(ms::CloseCode::Superseded, b"superseded");
+        }
+        server.lost_at = None;
+        server.refresh = new.refresh;
+        server.last_heartbeat = now;
+        stale
+    }
+
+    /// Note that visible server `id`, which `conn` serves, is now reachable at `addr`, which is in
+    /// `region`, returning whether clients must be told
+    ///
+    /// Fails if the server's entry has been taken over.
+    pub fn move_server(
+        &mut self,
+        id: ServerId,
+        conn: Option<&C>,
+        addr: SocketAddr,
+        region: Option<ms::client::Region>,
+        now: Instant,
+    ) -> Result<bool> {
+        let Some(server) = self.server_mut(id, conn) else {
+            bail!("superseded");
+        };
+        // Servers that haven't sent a heartbeat yet aren't visible, so needn't be updated
+        match server.address {
+            Some(old) if old != addr => {
+                info!(%old, new = %addr, "address changed");
+                server.address = Some(addr);
+                let old_region = mem::replace(&mut server.region, region);
+                if old_region == region {
+                    self.mark_dirty(id, now);
+                } else {
+                    let tags = server.tags.clone();
+                    self.retag(id, &tags, old_region, now);
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Store the latest state of server `id`, returning whether clients must be told
+    ///
+    /// `conn` is the connection the heartbeat arrived on, or `None` for fake servers. `budget` is
+    /// the most state to hold from all servers together, if limited, and what to do about a
+    /// server that would exceed it. Fails if the server's entry has been taken over or evicted, or
+    /// if the state can't be stored within the budget.
+    pub fn update_server(
+        &mut self,
+        id: ServerId,
+        conn: Option<&C>,
+        heartbeat: Heartbeat,
+        budget: Option<(usize, BudgetPolicy)>,
+        now: Instant,
+    ) -> Result<bool> {
+        let Heartbeat {
+            address: addr,
+            state,
+            region,
+            load,
+        } = heartbeat;
+        // Borrow only `servers`, leaving `state_bytes` accessible
+        let server = self.servers.get_mut(id).filter(|x| x.is_served_by(conn));
+        let Some(server) = server else {
+            bail!("superseded");
+        };
+        if server.evicted {
+            bail!("evicted");
+        }
+        server.last_heartbeat = now;
+        let digest = seahash::hash(&state);
+        // Comparing digests first is cheap, and a match is confirmed in case of collision
+        let dirty =
+            digest != server.digest || state != server.state || Some(addr) != server.address;
+        if !dirty {
+            return Ok(false);
+        }
+        let old_len = server.state.len();
+        let total = self.state_bytes - old_len + state.len();
+        if let Some((max, policy)) = budget.filter(|&(x, _)| total > x) {
+            match policy {
+                BudgetPolicy::Reject => bail!("rejected: state budget exhausted"),
+                BudgetPolicy::Evict => self.evict(id, total - max, now)?,
+            }
+        }
+        // Eviction already deducted the states it freed
+        self.state_bytes = self.state_bytes - old_len + state.len();
+        let total = self.state_bytes;
+        let server = &mut self.servers[id];
+        if let Some(old) = server.address.filter(|&x| x != addr) {
+            info!(%old, new = %addr, "address changed");
+        }
+        debug!(
+            digest = %format_args!("{:016x}", digest),
+            total_state_bytes = total,
+            "state changed"
+        );
+        server.state = state;
+        server.digest = digest;
+        let was_visible = server.address.replace(addr).is_some();
+        let old_region = mem::replace(&mut server.region, region);
+        server.load = load;
+        // Clients whose filters name a region may need to start or stop seeing the server
+        if was_visible && old_region != region {
+            let tags = server.tags.clone();
+            self.retag(id, &tags, old_region, now);
+        } else {
+            self.mark_dirty(id, now);
+        }
+        Ok(true)
+    }
+
+    /// Replace the tags of server `id`, showing or hiding it from clients whose filters it now
+    /// matches or no longer matches, and returning whether clients must be told
+    ///
+    /// Fails if the server's entry has been taken over by a connection other than `conn`.
+    pub fn set_tags(
+        &mut self,
+        id: ServerId,
+        conn: Option<&C>,
+        tags: Vec<String>,
+        now: Instant,
+    ) -> Result<bool> {
+        let Some(server) = self.server_mut(id, conn) else {
+            bail!("superseded");
+        };
+        if server.tags == tags {
+            return Ok(false);
+        }
+        debug!(?tags, "tags changed");
+        let old = mem::replace(&mut server.tags, tags);
+        // Servers that haven't sent a heartbeat yet will be filtered when they do
+        let visible = server.address.is_some();
+        let region = server.region;
+        if visible {
+            self.retag(id, &old, region, now);
+        }
+        Ok(visible)
+    }
+
+    /// Keep showing server `id`, whose connection `conn` was lost, in case it reconnects and
+    /// [claims](Self::claim_address) its entry, returning whether it's kept
+    ///
+    /// Servers that never became visible have nothing to keep. Those that are kept must be
+    /// [expired](Self::expire_server) later, with `now` as when they were lost.
+    pub fn lose_server(&mut self, id: ServerId, conn: &C, now: Instant) -> bool {
+        let Some(server) = self
+            .server_mut(id, Some(conn))
+            .filter(|x| x.address.is_some())
+        else {
+            return false;
+        };
+        server.connection = None;
+        server.introductions = None;
+        server.lost_at = Some(now);
+        true
+    }
+
+    /// Remove server `id` if it's still the entry [lost](Self::lose_server) at `lost_at`, rather
+    /// than claimed by a reconnection, returning whether it was removed
+    pub fn expire_server(&mut self, id: ServerId, lost_at: Instant, now: Instant) -> bool {
+        let expired = self
+            .servers
+            .get(id)
+            .is_some_and(|x| x.lost_at == Some(lost_at));
+        expired && self.remove_server(id, None, now)
+    }
+
+    /// Forget server `id`, telling clients that could see it that it shut down, and returning
+    /// whether it was removed
+    ///
+    /// Does nothing if the server's entry has been taken over by a connection other than `conn`.
+    pub fn remove_server(&mut self, id: ServerId, conn: Option<&C>, now: Instant) -> bool {
+        if self.server_mut(id, conn).is_none() {
+            return false;
+        }
+        let server = self.servers.remove(id);
+        self.state_bytes -= server.state.len();
+        self.forget_server(
+            id,
+            server
+                .address
+                .is_some()
+                .then_some((&server.tags[..], server.region)),
+            now,
+        );
+        true
+    }
+
+    /// Disconnect the least recently heartbeating servers other than `keep` to free at least
+    /// `needed` bytes of state, or fail without doing anything if that's impossible
+    ///
+    /// Evicted servers are hidden from clients immediately, and removed when their connection
+    /// handler exits, or their removal grace period ends.
+    fn evict(&mut self, keep: ServerId, needed: usize, now: Instant) -> Result<()> {
+        let mut candidates = self
+            .servers
+            .iter()
+            .filter(|&(id, x)| {
+                id != keep
+                    && !x.evicted
+                    && !x.state.is_empty()
+                    && (x.connection.is_some() || x.lost_at.is_some())
+            })
+            .map(|(id, x)| (x.last_heartbeat, id, x.state.len()))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        let mut freed = 0;
+        let victims = candidates
+            .into_iter()
+            .take_while(|&(_, _, len)| {
+                let done = freed >= needed;
+                freed += len;
+                !done
+            })
+            .map(|(_, id, _)| id)
+            .collect::<Vec<_>>();
+        if freed < needed {
+            bail!("rejected: state budget exhausted");
+        }
+        info!(
+            count = victims.len(),
+            "evicting servers to stay within state budget"
+        );
+        for id in victims {
+            let server = &mut self.servers[id];
+            server.evicted = true;
+            self.state_bytes -= server.state.len();
+            server.state = Vec::new();
+            server.encoded = None;
+            let visible = server.address.take().is_some();
+            let tags = mem::take(&mut server.tags);
+            let region = server.region;
+            if let Some(ref conn) = server.connection {
+                conn.close(ms::CloseCode::Rejected, b"evicted");
+            }
+            self.forget_server(id, visible.then_some((&tags, region)), now);
+        }
+        Ok(())
+    }
+
+    /// Report update latency and state budget usage, release memory left over from past peaks in
+    /// the number of servers and clients, and in debug builds, check invariants
+    ///
+    /// `max_state_bytes` is the configured `--max-total-state-bytes`, if any.
+    pub fn maintain(&mut self, max_state_bytes: Option<usize>) {
+        if self.update_latency.len() > 0 {
+            info!(
+                updates = self.update_latency.len(),
+                p50 = ?self.update_latency.quantile(0.5).unwrap(),
+                p99 = ?self.update_latency.quantile(0.99).unwrap(),
+                "update latency"
+            );
+            self.update_latency.clear();
+        }
+        info!(
+            servers = self.servers.len(),
+            state_bytes = self.state_bytes,
+            max_state_bytes,
+            "state budget"
+        );
+        self.servers.shrink_to_fit();
+        self.clients.shrink_to_fit();
+        for (_, client) in &mut self.clients {
+            client.dirty.shrink_to_fit();
+            client.lost.shrink_to_fit();
+            client.snapshot.shrink_to_fit();
+        }
+        if cfg!(debug_assertions) {
+            self.check_invariants();
+        }
+    }
+
+    /// Panic if the state budget or any client's pending updates are inconsistent with the server
+    /// table
+    pub fn check_invariants(&self) {
+        if let Some(problem) = self.inconsistency() {
+            panic!("{}", problem);
+        }
+    }
+
+    /// How the state budget or some client's pending updates are inconsistent with the server
+    /// table, if they are
+    pub fn inconsistency(&self) -> Option<&'static str> {
+        let state_bytes = self
+            .servers
+            .iter()
+            .map(|(_, x)| x.state.len())
+            .sum::<usize>();
+        if state_bytes != self.state_bytes {
+            return Some("state budget out of sync");
+        }
+        for (_, client) in &self.clients {
+            if !client.dirty.iter().all(|&x| self.servers.contains(x)) {
+                return Some("client has update pending for nonexistent server");
+            }
+            if !client
+                .lost
+                .iter()
+                .filter(|&&x| client.dirty.contains(&x))
+                .all(|&x| self.servers[x].address.is_some())
+            {
+                return Some("client has update pending for a server it will be told shut down");
+            }
+            if !client.snapshot.iter().all(|&x| self.servers.contains(x)) {
+                return Some("client has snapshot pending for nonexistent server");
+            }
+            if !client.snapshot.iter().all(|x| !client.dirty.contains(x)) {
+                return Some("client has server both in snapshot and dirty");
+            }
+            if !(client.subscribed || client.dirty.is_empty() && client.lost.is_empty()) {
+                return Some("unsubscribed client has updates pending");
+            }
+        }
+        None
+    }
+
+    /// Restore the invariants a transition may have broken by panicking partway through
+    ///
+    /// Recounts the state budget, re-encodes servers, and forgets what clients have pending.
+    /// Subscribed clients that understand resets are sent a fresh snapshot, since what they were
+    /// already told may be inconsistent too; others are sent every server matching their filter.
+    pub fn repair(&mut self) {
+        self.state_bytes = self.servers.iter().map(|(_, x)| x.state.len()).sum();
+        for (_, server) in &mut self.servers {
+            server.encoded = None;
+        }
+        let ids = self.clients.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        for id in ids {
+            let client = &mut self.clients[id];
+            if !client.subscribed {
+                client.dirty.clear();
+                client.lost.clear();
+                client.snapshot.clear();
+                continue;
+            }
+            if client.version >= 3 {
+                self.reset(id);
+                continue;
+            }
+            client.lost.clear();
+            client.snapshot.clear();
+            client.dirty = visible(&self.servers, &client.filter)
+                .map(|(id, _)| id)
+                .collect();
+        }
+    }
+
+    /// Take everything pending for client `id`, which speaks `version` of the client protocol
+    /// from `client_ip` and is sent updates every `interval`, as its next message
+    ///
+    /// Returns the number of entries in the message, and its encoding. `snapshot` is whether the
+    /// initial snapshot is still incomplete, and is cleared once the message completes it.
+    pub fn take_update(
+        &mut self,
+        id: ClientId,
+        version: u32,
+        client_ip: IpAddr,
+        interval: Duration,
+        snapshot: &mut bool,
+        now: Instant,
+    ) -> (usize, Vec<u8>) {
+        let client = &mut self.clients[id];
+        let max_state = client.max_state;
+        let batch = match client.pacing {
+            Some(x) if !x.duration.is_zero() => {
+                let share = interval.as_secs_f64() / x.duration.as_secs_f64();
+                ((client.snapshot_total as f64 * share).ceil() as usize).max(1)
+            }
+            _ => client.snapshot.len(),
+        };
+        let batch = batch.min(client.snapshot.len());
+        let progress = client
+            .pacing
+            .is_some()
+            .then(|| ms::client::Event::SnapshotProgress {
+                delivered: client.snapshot_total - (client.snapshot.len() - batch) as u64,
+                total: client.snapshot_total,
+            });
+        let reset = mem::take(&mut client.reset);
+        let synchronized = (*snapshot || reset) && batch == client.snapshot.len();
+        if synchronized {
+            client.pacing = None;
+        }
+        if let Some(since) = client.pending_since.take() {
+            self.update_latency
+                .record(now.saturating_duration_since(since));
+        }
+        // Each with its position, to sort by ID without reordering parts with the same ID
+        let mut parts =
+            Vec::with_capacity(client.lost.len() + client.dirty.len() + batch + META_EVENTS);
+        for id in client.lost.drain(..) {
+            let part = Part::Event(ms::client::Event::Shutdown);
+            parts.push((id.wire(), parts.len(), part));
+        }
+        for id in client.dirty.drain(..).chain(client.snapshot.drain(..batch)) {
+            let x = &self.servers[id];
+            if x.address.is_none() {
+                continue;
+            }
+            parts.push((id.wire(), parts.len(), Part::Server(id)));
+            let lan = x.lan_addresses_for(client_ip, &client.policy);
+            if version >= 3 && !lan.is_empty() {
+                let event = ms::client::Event::LanAddresses(lan.to_vec());
+                parts.push((id.wire(), parts.len(), Part::Event(event)));
+            }
+        }
+        let meta = [
+            client.generation.take().map(ms::client::Event::Subscribed),
+            progress,
+            client.limits.take().map(ms::client::Event::Limits),
+            reset.then_some(ms::client::Event::Reset),
+            (synchronized && version >= 3).then_some(ms::client::Event::Synchronized),
+        ];
+        for event in meta.into_iter().flatten() {
+            parts.push((ms::client::ServerId::NONE, parts.len(), Part::Event(event)));
+        }
+        *snapshot &= !synchronized;
+        // Stably, so a shutdown precedes the update of a server visible under the same ID again,
+        // whether a new server reusing it or one that matches the client's filter again. Unlike
+        // `sort_by_key`, needs no scratch space.
+        parts.sort_unstable_by_key(|&(id, position, _)| (id, position));
+
+        if version < 3 {
+            let servers = &self.servers;
+            let msg = ms::client::Message {
+                servers: parts
+                    .into_iter()
+                    .flat_map(|(wire, _, part)| {
+                        let (event, region) = match part {
+                            Part::Event(event) => (event, None),
+                            Part::Server(id) => {
+                                let x = &servers[id];
+                                let update =
+                                    ms::client::Event::Update(x.address.unwrap(), &x.state);
+                                let region = ms::client::Event::Region(x.region);
+                                (update, (version >= 2).then_some(region))
+                            }
+                        };
+                        [Some(event), region]
+                            .into_iter()
+                            .flatten()
+                            .map(move |event| ms::client::Server { id: wire, event })
+                    })
+                    .collect(),
+                undecodable: Vec::new(),
+            };
+            return (msg.servers.len(), msg.encode(version));
+        }
+        // Sized up front, so that encoding allocates once
+        let mut size = 0;
+        for (_, _, part) in &parts {
+            size += match *part {
+                Part::Event(_) => EVENT_ENTRY_SIZE,
+                Part::Server(id) => match max_state {
+                    Some(max) if self.servers[id].state.len() > max => {
+                        self.servers[id].encoded_size(max)
+                    }
+                    _ => self.servers[id].encoded(id).len(),
+                },
+            };
+        }
+        let mut count = 0;
+        let mut entries = Vec::with_capacity(size);
+        for (wire, _, part) in parts {
+            match part {
+                Part::Event(event) => {
+                    ms::client::Server { id: wire, event }.encode_entry(&mut entries);
+                    count += 1;
+                }
+                Part::Server(id) => {
+                    let server = &mut self.servers[id];
+                    match max_state {
+                        Some(max) if server.state.len() > max => {
+                            server.encode_truncated(id, max, &mut entries);
+                            count += Server::<C>::ENCODED_ENTRIES + 1;
+                        }
+                        _ => {
+                            entries.extend_from_slice(server.encoded(id));
+                            count += Server::<C>::ENCODED_ENTRIES;
+                        }
+                    }
+                }
+            }
+        }
+        (count, ms::client::Message::encode_entries(count, &entries))
+    }
+
+    /// Server `id`, if its entry hasn't been taken over by a connection other than `conn`
+    pub fn server_mut(&mut self, id: ServerId, conn: Option<&C>) -> Option<&mut Server<C>> {
+        self.servers.get_mut(id).filter(|x| x.is_served_by(conn))
+    }
+
+    /// Register a new client, which isn't sent updates until it [subscribes](Self::subscribe)
+    pub fn add_client(&mut self, version: u32, policy: Arc<ListenerPolicy>) -> ClientId {
+        self.clients.insert(Client {
+            version,
+            dirty: IndexSet::new(),
+            lost: IndexSet::new(),
+            filter: policy.restrict(ms::client::Filter::default()),
+            policy,
+            subscribed: false,
+            generation: None,
+            pacing: None,
+            snapshot: IndexSet::new(),
+            snapshot_total: 0,
+            reset: false,
+            limits: None,
+            pending_since: None,
+            max_state: None,
+        })
+    }
+
+    /// Forget client `id`, which disconnected
+    pub fn remove_client(&mut self, id: ClientId) {
+        self.clients.remove(id);
+    }
+
+    /// Start sending updates to client `id`, the first of which will be a snapshot of every
+    /// visible server matching its filter
+    ///
+    /// Because the snapshot is taken under the same lock as the subscription, every later change
+    /// to the server table is reflected in the client's `dirty` or `lost` sets, with nothing
+    /// missed or sent twice. If the client asked for pacing, the snapshot goes into its `snapshot`
+    /// queue instead, to be sent a batch at a time.
+    pub fn subscribe(&mut self, id: ClientId) {
+        let client = &mut self.clients[id];
+        client.subscribed = true;
+        let mut visible = visible(&self.servers, &client.filter).collect::<Vec<_>>();
+        match client.pacing {
+            None => client.dirty = visible.into_iter().map(|(id, _)| id).collect(),
+            Some(pacing) => {
+                if pacing.order == ms::client::Strategy::LeastLoaded {
+                    visible.sort_by(|(_, x), (_, y)| by_load(x.load, y.load));
+                }
+                client.snapshot = visible.into_iter().map(|(id, _)| id).collect();
+                client.snapshot_total = client.snapshot.len() as u64;
+            }
+        }
+        for (_, server) in &self.servers {
+            server.refresh.notify_one();
+        }
+    }
+
+    /// Replace everything pending for client `id` with a fresh snapshot, telling it to forget the
+    /// servers it knows first
+    pub fn reset(&mut self, id: ClientId) {
+        let client = &mut self.clients[id];
+        client.lost.clear();
+        client.snapshot.clear();
+        client.snapshot_total = 0;
+        client.pacing = None;
+        client.reset = true;
+        client.dirty = visible(&self.servers, &client.filter)
+            .map(|(id, _)| id)
+            .collect();
+    }
+
+    /// Tell clients that understand it about new `limits` in their next update
+    pub fn announce_limits(&mut self, limits: ms::Limits) {
+        for (_, client) in self.clients.iter_mut().filter(|(_, x)| x.version >= 3) {
+            client.limits = Some(limits);
+        }
+    }
+
+    /// [Reset](Self::reset) clients that understand resets and have too many shutdowns pending
+    fn reset_overflowed(&mut self) {
+        let overflowed = self
+            .clients
+            .iter()
+            .filter(|(_, x)| x.version >= 3 && x.lost.len() > MAX_LOST)
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        for id in overflowed {
+            debug!(client = %id, "too many shutdowns pending; resetting");
+            self.reset(id);
+        }
+    }
+
+    /// A visible server matching `filter`, picked according to `strategy`
+    pub fn find_one(
+        &self,
+        filter: &ms::client::Filter,
+        strategy: ms::client::Strategy,
+        rng: &mut impl Rng,
+    ) -> Option<ServerId> {
+        let candidates = self
+            .servers
+            .iter()
+            .filter(|(_, x)| x.address.is_some() && x.matches(filter));
+        match strategy {
+            ms::client::Strategy::Random => candidates.map(|(id, _)| id).choose(rng),
+            ms::client::Strategy::LeastLoaded => candidates
+                .min_by(|(_, x), (_, y)| by_load(x.load, y.load))
+                .map(|(id, _)| id),
+        }
+    }
+
+    /// Send the current state of server `id` in the next update of each subscribed client whose
+    /// filter it matches
+    fn mark_dirty(&mut self, id: ServerId, now: Instant) {
+        self.table_version += 1;
+        let server = &mut self.servers[id];
+        server.encoded = None;
+        for (_, client) in self.clients.iter_mut().filter(|(_, x)| x.subscribed) {
+            // Servers still queued for a paced snapshot will be sent in their turn
+            if server.matches(&client.filter) && !client.snapshot.contains(&id) {
+                client.dirty.insert(id);
+                client.pending_since.get_or_insert(now);
+            }
+        }
+    }
+
+    /// Stop sending updates about server `id` to clients, telling those that could see it that it
+    /// shut down
+    ///
+    /// `seen` is the server's tags and region if it was visible, or `None` if it wasn't.
+    fn forget_server(
+        &mut self,
+        id: ServerId,
+        seen: Option<(&[String], Option<ms::client::Region>)>,
+        now: Instant,
+    ) {
+        self.table_version += 1;
+        for (_, client) in self.clients.iter_mut().filter(|(_, x)| x.subscribed) {
+            client.dirty.remove(&id);
+            if client.unqueue(id) {
+                continue;
+            }
+            if seen.is_some_and(|(tags, region)| client.filter.matches_in(tags, region)) {
+                client.lost.insert(id);
+                client.pending_since.get_or_insert(now);
+            }
+        }
+        self.reset_overflowed();
+    }
+
+    /// Re-evaluate clients' filters against visible server `id`, whose tags were `old_tags` and
+    /// whose region was `old_region`
+    ///
+    /// Clients that can still see the server get its new tags and region, clients that newly match
+    /// get the whole server, and clients that no longer match are told it shut down.
+    fn retag(
+        &mut self,
+        id: ServerId,
+        old_tags: &[String],
+        old_region: Option<ms::client::Region>,
+        now: Instant,
+    ) {
+        self.table_version += 1;
+        let server = &mut self.servers[id];
+        server.encoded = None;
+        for (_, client) in self.clients.iter_mut().filter(|(_, x)| x.subscribed) {
+            if server.matches(&client.filter) {
+                if !client.snapshot.contains(&id) {
+                    client.dirty.insert(id);
+                    client.pending_since.get_or_insert(now);
+                }
+            } else if client.filter.matches_in(old_tags, old_region) && !client.unqueue(id) {
+                client.dirty.remove(&id);
+                client.lost.insert(id);
+                client.pending_since.get_or_insert(now);
+            }
+        }
+        self.reset_overflowed();
+    }
+
+    /// Replace client `id`'s filter, showing or hiding each visible server accordingly, and
+    /// acknowledging it as `generation` in the next update
+    pub fn set_filter(&mut self, id: ClientId, filter: ms::client::Filter, generation: u64) {
+        let client = &mut self.clients[id];
+        let filter = client.policy.restrict(filter);
+        client.generation = Some(generation);
+        if !client.subscribed {
+            client.filter = filter;
+            return;
+        }
+        for (server_id, server) in &self.servers {
+            if server.address.is_none() {
+                continue;
+            }
+            match (server.matches(&client.filter), server.matches(&filter)) {
+                (false, true) => {
+                    client.dirty.insert(server_id);
+                }
+                (true, false) => {
+                    if client.unqueue(server_id) {
+                        continue;
+                    }
+                    client.dirty.remove(&server_id);
+                    client.lost.insert(server_id);
+                }
+                _ => {}
+            }
+        }
+        client.filter = filter;
+        if client.version >= 3 && client.lost.len() > MAX_LOST {
+            debug!("too many shutdowns pending; resetting");
+            self.reset(id);
+        }
+    }
+}
+
+/// Servers that clients with `filter` can see
+fn visible<'a, C>(
+    servers: &'a Table<ServerId, Server<C>>,
+    filter: &'a ms::client::Filter,
+) -> impl Iterator<Item = (ServerId, &'a Server<C>)> + 'a {
+    // Servers aren't visible until their first heartbeat sets their address
+    servers
+        .iter()
+        .filter(|(_, x)| x.address.is_some() && filter.matches_in(&x.tags, x.region))
+}
+
+/// Order servers by load, with unknown loads last
+fn by_load(x: Option<f64>, y: Option<f64>) -> cmp::Ordering {
+    match (x, y) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (x, y) => y.is_some().cmp(&x.is_some()),
+    }
+}
+
+pub struct Server<C> {
+    pub address: Option<SocketAddr>,
+    pub state: Vec<u8>,
+    /// Hash of `state`, for cheap change detection
+    pub digest: u64,
+    pub last_heartbeat: Instant,
+    /// Signaled to ask the game server for a fresh heartbeat
+    pub refresh: Arc<Notify>,
+    /// Looked up from `address`
+    pub region: Option<ms::client::Region>,
+    /// Matched against clients' filters
+    pub tags: Vec<String>,
+    /// Fraction of player slots in use, if known from `state`
+    pub load: Option<f64>,
+    /// `None` for fake servers
+    pub connection: Option<C>,
+    /// Introductions to forward to the game server, if it accepts them
+    pub introductions: Option<mpsc::Sender<ms::game::Introduction>>,
+    /// Offered to clients that seem to share the game server's network
+    pub lan_addresses: Vec<SocketAddr>,
+    /// Whether the server was disconnected to stay within the state budget
+    pub evicted: bool,
+    /// When the connection was lost, if the entry is being kept in case the server reconnects
+    pub lost_at: Option<Instant>,
+    /// Cache for [`encoded`](Self::encoded), cleared whenever clients must be told of a change
+    pub encoded: Option<Vec<u8>>,
+}
+
+impl<C: Connection> Server<C> {
+    /// Number of entries in [`encoded`](Self::encoded)
+    const ENCODED_ENTRIES: usize = 3;
+
+    fn new(refresh: Arc<Notify>, connection: Option<C>, now: Instant) -> Self {
+        Self {
+            address: None,
+            state: Vec::new(),
+            digest: seahash::hash(&[]),
+            last_heartbeat: now,
+            refresh,
+            region: None,
+            tags: Vec::new(),
+            load: None,
+            connection,
+            introductions: None,
+            lan_addresses: Vec::new(),
+            evicted: false,
+            lost_at: None,
+            encoded: None,
+        }
+    }
+
+    /// This server's `Update`, `Region`, and `Tags` entries, as encoded for clients since
+    /// [`ms::client::PROTOCOL_V3`], shared by every client's next update
+    ///
+    /// The server must be visible, and is known to clients as `id`.
+    fn encoded(&mut self, id: ServerId) -> &[u8] {
+        if self.encoded.is_none() {
+            let mut encoded = Vec::with_capacity(self.encoded_size(self.state.len()));
+            self.encode_entries(id, &self.state, &mut encoded);
+            self.encoded = Some(encoded);
+        }
+        self.encoded.as_deref().unwrap()
+    }
+
+    /// Room to allow for this server's encoded entries, reporting `state_size` bytes of state
+    fn encoded_size(&self, state_size: usize) -> usize {
+        let tags = self.tags.iter().map(|x| 8 + x.len()).sum::<usize>();
+        state_size + tags + ENTRIES_OVERHEAD
+    }
+
+    /// Like [`encoded`](Self::encoded), but with the state cut short at `max` bytes and followed
+    /// by an `Event::Truncated` entry, for a client that asked for truncation
+    fn encode_truncated(&self, id: ServerId, max: usize, out: &mut Vec<u8>) {
+        self.encode_entries(id, &self.state[..max], out);
+        ms::client::Server {
+            id: id.wire(),
+            event: ms::client::Event::Truncated(self.state.len() as u32),
+        }
+        .encode_entry(out);
+    }
+
+    /// Append this server's `Update`, `Region`, and `Tags` entries to `out`, reporting `state`
+    fn encode_entries(&self, id: ServerId, state: &[u8], out: &mut Vec<u8>) {
+        let events = [
+            ms::client::Event::Update(self.address.unwrap(), state),
+            ms::client::Event::Region(self.region),
+            ms::client::Event::Tags(self.tags.iter().map(|x| &x[..]).collect()),
+        ];
+        for event in events {
+            ms::client::Server {
+                id: id.wire(),
+                event,
+            }
+            .encode_entry(out);
+        }
+    }
+
+    /// Everything a query's answer says about this server, which is visible and known to clients
+    /// as `id`, to a client at `client_ip` on a listener with `policy`
+    pub fn found(
+        &self,
+        id: ServerId,
+        client_ip: IpAddr,
+        policy: &ListenerPolicy,
+    ) -> ms::client::Found<'_> {
+        ms::client::Found {
+            id: id.wire(),
+            address: self.address.unwrap(),
+            state: &self.state,
+            region: self.region,
+            tags: self.tags.iter().map(|x| &x[..]).collect(),
+            lan_addresses: self.lan_addresses_for(client_ip, policy).to_vec(),
+        }
+    }
+
+    /// LAN addresses to offer a client at `ip` on a listener with `policy`, which are only useful
+    /// if it's behind the same NAT, unless the listener is for the game servers' own network
+    fn lan_addresses_for(&self, ip: IpAddr, policy: &ListenerPolicy) -> &[SocketAddr] {
+        match self.address {
+            _ if policy.all_lan_addresses => &self.lan_addresses,
+            Some(x) if x.ip().to_canonical() == ip.to_canonical() => &self.lan_addresses,
+            _ => &[],
+        }
+    }
+
+    /// Whether clients with `filter` can see this server, if it's visible at all
+    pub fn matches(&self, filter: &ms::client::Filter) -> bool {
+        filter.matches_in(&self.tags, self.region)
+    }
+
+    /// Whether `conn` is the current connection for this server
+    fn is_served_by(&self, conn: Option<&C>) -> bool {
+        self.connection.as_ref().map(|x| x.stable_id()) == conn.map(|x| x.stable_id())
+    }
+}
+
+pub struct Client {
+    /// Negotiated version of the client protocol
+    pub version: u32,
+    /// Policy of the listener the client connected to
+    pub policy: Arc<ListenerPolicy>,
+    /// Visible servers whose latest state hasn't been sent yet
+    pub dirty: IndexSet<ServerId>,
+    /// Servers that shut down, or stopped matching `filter`, since the last update
+    ///
+    /// A set, so that it's bounded by the size of the server table even if server IDs are reused
+    /// many times between updates to a slow client.
+    pub lost: IndexSet<ServerId>,
+    /// The client's own filter, [restricted](ListenerPolicy::restrict) by `policy`
+    pub filter: ms::client::Filter,
+    /// Whether the client is sent updates, rather than only making queries
+    pub subscribed: bool,
+    /// Generation of a filter that took effect since the last update
+    pub generation: Option<u64>,
+    /// How to send the initial snapshot, until it's complete
+    pub pacing: Option<ms::client::Pacing>,
+    /// Visible servers not yet sent in a paced snapshot, in the order they'll be sent
+    ///
+    /// Disjoint from `dirty`, since changes to these servers are sent in their turn.
+    pub snapshot: IndexSet<ServerId>,
+    /// Number of servers in the paced snapshot, excluding those that since left it
+    pub snapshot_total: u64,
+    /// Whether the client must forget every server before applying the next update, which
+    /// carries a fresh snapshot in `dirty`
+    pub reset: bool,
+    /// Limits to announce in the next update, having changed since the client's welcome
+    pub limits: Option<ms::Limits>,
+    /// When the earliest game server change not yet sent to the client happened
+    pub pending_since: Option<Instant>,
+    /// Longest state to send in full, as the client requested
+    pub max_state: Option<usize>,
+}
+
+/// Part of a client's next update, in [`Core::take_update`]
+enum Part {
+    Event(ms::client::Event<'static>),
+    /// Everything clients are told about a visible server, other than LAN addresses
+    Server(ServerId),
+}
+
+impl Client {
+    /// Drop server `id` from a paced snapshot, returning whether it was still queued, and hence
+    /// never sent
+    fn unqueue(&mut self, id: ServerId) -> bool {
+        let queued = self.snapshot.shift_remove(&id);
+        if queued {
+            self.snapshot_total -= 1;
+        }
+        queued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        collections::BTreeMap,
+        rc::Rc,
+    };
+
+    use metaserve_client::ServerList;
+    use proptest::{
+        prelude::*,
+        sample::Index,
+        test_runner::{Config, RngSeed},
+    };
+
+    use super::*;
+
+    const CLIENT_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 1));
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Stands in for a game server's connection, remembering whether it was closed
+    #[derive(Clone)]
+    struct FakeConnection {
+        id: usize,
+        closed: Rc<Cell<bool>>,
+    }
+
+    impl Connection for FakeConnection {
+        fn stable_id(&self) -> usize {
+            self.id
+        }
+
+        fn close(&self, _: ms::CloseCode, _: &[u8]) {
+            self.closed.set(true);
+        }
+    }
+
+    /// Something that happens to a [`Core`]
+    ///
+    /// Indices pick among the game servers, lost entries, or clients present at the time, and the
+    /// operation is skipped if there are none.
+    #[derive(Debug, Clone)]
+    enum Op {
+        /// A game server connects, claiming the entry of any other on the same port
+        Connect {
+            port: u16,
+        },
+        /// A game server heartbeats, having changed its port if `port` is set
+        Heartbeat {
+            server: Index,
+            state: Vec<u8>,
+            port: Option<u16>,
+        },
+        /// A game server's connection migrates to `port`
+        Move {
+            server: Index,
+            port: u16,
+        },
+        Retag {
+            server: Index,
+            tagged: bool,
+        },
+        /// A game server disconnects cleanly
+        Disconnect {
+            server: Index,
+        },
+        /// A game server's connection is lost, so its entry is kept in case it reconnects
+        Lose {
+            server: Index,
+        },
+        /// The grace period of a lost entry ends
+        Expire {
+            lost: Index,
+        },
+        /// A client connects and subscribes with filter number `filter`, maybe asking for pacing
+        Subscribe {
+            filter: u8,
+            paced: bool,
+        },
+        SetFilter {
+            client: Index,
+            filter: u8,
+        },
+        Resync {
+            client: Index,
+        },
+        /// A client is sent its next update
+        Deliver {
+            client: Index,
+        },
+        /// A client disconnects
+        Leave {
+            client: Index,
+        },
+        Elapse {
+            millis: u64,
+        },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let port = 0..4u16;
+        prop_oneof![
+            2 => port.clone().prop_map(|port| Op::Connect { port }),
+            4 => (
+                any::<Index>(),
+                prop::collection::vec(any::<u8>(), 0..4),
+                prop::option::weighted(0.1, port.clone()),
+            )
+                .prop_map(|(server, state, port)| Op::Heartbeat {
+                    server,
+                    state,
+                    port
+                }),
+            1 => (any::<Index>(), port).prop_map(|(server, port)| Op::Move { server, port }),
+            1 => (any::<Index>(), any::<bool>())
+                .prop_map(|(server, tagged)| Op::Retag { server, tagged }),
+            1 => any::<Index>().prop_map(|server| Op::Disconnect { server }),
+            1 => any::<Index>().prop_map(|server| Op::Lose { server }),
+            1 => any::<Index>().prop_map(|lost| Op::Expire { lost }),
+            1 => (0..3u8, any::<bool>())
+                .prop_map(|(filter, paced)| Op::Subscribe { filter, paced }),
+            1 => (any::<Index>(), 0..3u8)
+                .prop_map(|(client, filter)| Op::SetFilter { client, filter }),
+            1 => any::<Index>().prop_map(|client| Op::Resync { client }),
+            4 => any::<Index>().prop_map(|client| Op::Deliver { client }),
+            1 => any::<Index>().prop_map(|client| Op::Leave { client }),
+            1 => (0..5000u64).prop_map(|millis| Op::Elapse { millis }),
+        ]
+    }
+
+    /// A state budget small enough to be hit often, if any
+    fn budget() -> impl Strategy<Value = Option<(usize, BudgetPolicy)>> {
+        let policy = prop_oneof![Just(BudgetPolicy::Reject), Just(BudgetPolicy::Evict)];
+        prop::option::of((0..8usize, policy))
+    }
+
+    proptest! {
+        #![proptest_config(Config {
+            rng_seed: RngSeed::Fixed(0x6d65_7461),
+            failure_persistence: None,
+            ..Config::default()
+        })]
+
+        /// However events interleave, clients' lists converge on what they can see, no server is
+        /// sent as updated after being reported shut down, and nothing outlives its removal
+        #[test]
+        fn model(budget in budget(), ops in prop::collection::vec(op(), 1..200)) {
+            let mut model = Model::new(budget);
+            for op in ops {
+                model.apply(op);
+                model.core.check_invariants();
+            }
+            model.settle();
+            model.tear_down();
+        }
+    }
+
+    /// A game server connected to the model
+    struct Peer {
+        conn: FakeConnection,
+        id: ServerId,
+        port: u16,
+    }
+
+    /// A client connected to the model, and the list it reconstructed from its updates
+    struct Viewer {
+        id: ClientId,
+        list: ServerList,
+        /// Whether the initial snapshot is still incomplete, as for [`Core::take_update`]
+        snapshot: bool,
+        generation: u64,
+    }
+
+    struct Model {
+        core: Core<FakeConnection>,
+        now: Instant,
+        budget: Option<(usize, BudgetPolicy)>,
+        peers: Vec<Peer>,
+        /// Entries kept after their connection was lost, with when that happened
+        lost: Vec<(ServerId, Instant)>,
+        viewers: Vec<Viewer>,
+        connections: usize,
+    }
+
+    impl Model {
+        fn new(budget: Option<(usize, BudgetPolicy)>) -> Self {
+            Self {
+                core: Core::new(),
+                now: Instant::now(),
+                budget,
+                peers: Vec::new(),
+                lost: Vec::new(),
+                viewers: Vec::new(),
+                connections: 0,
+            }
+        }
+
+        fn apply(&mut self, op: Op) {
+            let now = self.now;
+            match op {
+                Op::Connect { port } => {
+                    self.connections += 1;
+                    let conn = FakeConnection {
+                        id: self.connections,
+                        closed: Rc::default(),
+                    };
+                    let id = self
+                        .core
+                        .add_server(Arc::default(), Some(conn.clone()), now);
+                    let id = self.core.claim_address(id, address(port), now);
+                    self.peers.push(Peer { conn, id, port });
+                }
+                Op::Heartbeat {
+                    server,
+                    state,
+                    port,
+                } => {
+                    let Some(peer) = pick(&mut self.peers, server) else {
+                        return;
+                    };
+                    peer.port = port.unwrap_or(peer.port);
+                    let heartbeat = Heartbeat {
+                        address: address(peer.port),
+                        state,
+                        region: None,
+                        load: None,
+                    };
+                    let result = self.core.update_server(
+                        peer.id,
+                        Some(&peer.conn),
+                        heartbeat,
+                        self.budget,
+                        now,
+                    );
+                    fail(&peer.conn, result);
+                }
+                Op::Move { server, port } => {
+                    let Some(peer) = pick(&mut self.peers, server) else {
+                        return;
+                    };
+                    peer.port = port;
+                    let result =
+                        self.core
+                            .move_server(peer.id, Some(&peer.conn), address(port), None, now);
+                    fail(&peer.conn, result);
+                }
+                Op::Retag { server, tagged } => {
+                    let Some(peer) = pick(&mut self.peers, server) else {
+                        return;
+                    };
+                    let tags = if tagged { vec!["a".into()] } else { Vec::new() };
+                    let result = self.core.set_tags(peer.id, Some(&peer.conn), tags, now);
+                    fail(&peer.conn, result);
+                }
+                Op::Disconnect { server } => {
+                    if let Some(peer) = take(&mut self.peers, server) {
+                        self.core.remove_server(peer.id, Some(&peer.conn), now);
+                    }
+                }
+                Op::Lose { server } => {
+                    let Some(peer) = take(&mut self.peers, server) else {
+                        return;
+                    };
+                    if self.core.lose_server(peer.id, &peer.conn, now) {
+                        self.lost.push((peer.id, now));
+                    }
+                    // The connection handler exits either way
+                    self.core.remove_server(peer.id, Some(&peer.conn), now);
+                }
+                Op::Expire { lost } => {
+                    if let Some((id, lost_at)) = take(&mut self.lost, lost) {
+                        self.core.expire_server(id, lost_at, now);
+                    }
+                }
+                Op::Subscribe { filter, paced } => {
+                    let policy = ListenerPolicy::open(([127, 0, 0, 1], 0).into());
+                    let id = self.core.add_client(3, Arc::new(policy));
+                    // As requested before subscribing
+                    self.core.clients[id].pacing = paced.then_some(ms::client::Pacing {
+                        duration: Duration::from_secs(1),
+                        order: ms::client::Strategy::LeastLoaded,
+                    });
+                    self.core.set_filter(id, self::filter(filter), 1);
+                    self.core.subscribe(id);
+                    self.viewers.push(Viewer {
+                        id,
+                        list: ServerList::new(),
+                        snapshot: true,
+                        generation: 1,
+                    });
+                }
+                Op::SetFilter { client, filter } => {
+                    if let Some(viewer) = pick(&mut self.viewers, client) {
+                        viewer.generation += 1;
+                        let filter = self::filter(filter);
+                        self.core.set_filter(viewer.id, filter, viewer.generation);
+                    }
+                }
+                Op::Resync { client } => {
+                    if let Some(viewer) = pick(&mut self.viewers, client) {
+                        self.core.reset(viewer.id);
+                    }
+                }
+                Op::Deliver { client } => {
+                    if !self.viewers.is_empty() {
+                        self.deliver(client.index(self.viewers.len()));
+                    }
+                }
+                Op::Leave { client } => {
+                    if let Some(viewer) = take(&mut self.viewers, client) {
+                        self.core.remove_client(viewer.id);
+                    }
+                }
+                Op::Elapse { millis } => self.now += Duration::from_millis(millis),
+            }
+            // Connections that were closed, by eviction, replacement, or failure, end their
+            // handlers, which remove their entries if they're still theirs
+            let (closed, open) = mem::take(&mut self.peers)
+                .into_iter()
+                .partition::<Vec<_>, _>(|x| x.conn.closed.get());
+            self.peers = open;
+            for peer in closed {
+                self.core.remove_server(peer.id, Some(&peer.conn), now);
+            }
+        }
+
+        /// Send the `i`th client its next update, checking it against the server table
+        fn deliver(&mut self, i: usize) {
+            let viewer = &mut self.viewers[i];
+            let (_, data) = self.core.take_update(
+                viewer.id,
+                3,
+                CLIENT_IP,
+                INTERVAL,
+                &mut viewer.snapshot,
+                self.now,
+            );
+            let msg = ms::client::Message::decode(&data, 3).unwrap();
+            assert!(msg.undecodable.is_empty());
+            // In ascending order of ID, with a shutdown ahead of anything else for the same ID
+            for pair in msg.servers.windows(2) {
+                assert!(pair[0].id <= pair[1].id, "servers out of order");
+                if pair[0].id == pair[1].id {
+                    assert!(!matches!(pair[1].event, ms::client::Event::Shutdown));
+                }
+            }
+            // Whether each server was last reported updated, or shut down
+            let mut last = BTreeMap::new();
+            for x in &msg.servers {
+                match x.event {
+                    ms::client::Event::Update(..) => last.insert(x.id, true),
+                    ms::client::Event::Shutdown => last.insert(x.id, false),
+                    _ => None,
+                };
+            }
+            let filter = &self.core.clients[viewer.id].filter;
+            for (id, updated) in last {
+                let visible = ServerId::from_wire(id)
+                    .and_then(|x| self.core.servers.get(x))
+                    .is_some_and(|x| x.address.is_some() && x.matches(filter));
+                assert_eq!(updated, visible, "server {} misreported", id);
+            }
+            viewer.list.apply(&msg);
+        }
+
+        /// Deliver updates until every client is told everything, then check that each client's
+        /// list matches what it can see
+        fn settle(&mut self) {
+            for i in 0..self.viewers.len() {
+                for _ in 0..1000 {
+                    let client = &self.core.clients[self.viewers[i].id];
+                    let pending = !client.dirty.is_empty()
+                        || !client.lost.is_empty()
+                        || !client.snapshot.is_empty()
+                        || client.reset
+                        || client.generation.is_some()
+                        || self.viewers[i].snapshot;
+                    if !pending {
+                        break;
+                    }
+                    self.now += INTERVAL;
+                    self.deliver(i);
+                }
+                let viewer = &self.viewers[i];
+                let filter = &self.core.clients[viewer.id].filter;
+                let expected = visible(&self.core.servers, filter)
+                    .map(|(id, x)| {
+                        let entry = (x.address.unwrap(), x.state.clone(), x.tags.clone());
+                        (id.wire(), entry)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                let actual = viewer
+                    .list
+                    .iter()
+                    .map(|(id, x)| (id, (x.address, x.state.to_vec(), x.tags.clone())))
+                    .collect::<BTreeMap<_, _>>();
+                assert_eq!(actual, expected, "client {} diverged", viewer.id);
+                assert!(viewer.list.is_synchronized());
+            }
+        }
+
+        /// Disconnect everything, checking that clients are told every server shut down, and that
+        /// no trace of anything remains
+        fn tear_down(mut self) {
+            let now = self.now;
+            for peer in mem::take(&mut self.peers) {
+                self.core.remove_server(peer.id, Some(&peer.conn), now);
+            }
+            for (id, lost_at) in mem::take(&mut self.lost) {
+                self.core.expire_server(id, lost_at, now);
+            }
+            self.settle();
+            for viewer in mem::take(&mut self.viewers) {
+                assert_eq!(viewer.list.len(), 0);
+                self.core.remove_client(viewer.id);
+            }
+            assert_eq!(self.core.servers.len(), 0, "server entries leaked");
+            assert_eq!(self.core.clients.len(), 0, "client entries leaked");
+            assert_eq!(self.core.state_bytes, 0);
+        }
+    }
+
+    /// Counts allocations made by the current thread, so concurrent tests don't interfere
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Number of allocations `f` makes
+    fn allocations<T>(f: impl FnOnce() -> T) -> (u64, T) {
+        let start = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (ALLOCATIONS.with(Cell::get) - start, result)
+    }
+
+    /// Each changed server is encoded once, with one allocation, for every client to share, and a
+    /// client's update otherwise allocates the same regardless of how much changed
+    #[test]
+    fn take_update_allocations() {
+        const SERVERS: u16 = 1000;
+        let now = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let heartbeat = |port, state| Heartbeat {
+            address: address(port),
+            state: vec![state; 100],
+            region: None,
+            load: None,
+        };
+        let ids = (0..SERVERS)
+            .map(|port| {
+                let id = core.add_server(Arc::default(), None, now);
+                core.update_server(id, None, heartbeat(port, 0), None, now)
+                    .unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        let clients = [
+            core.add_client(3, policy.clone()),
+            core.add_client(3, policy),
+        ];
+        let take = |core: &mut Core<_>, client| {
+            let mut snapshot = false;
+            let (n, (count, _)) = allocations(|| {
+                core.take_update(client, 3, CLIENT_IP, INTERVAL, &mut snapshot, now)
+            });
+            (n, count / Server::<FakeConnection>::ENCODED_ENTRIES)
+        };
+        // The message's parts, entries, and encoding
+        const BASE: u64 = 3;
+
+        core.subscribe(clients[0]);
+        core.subscribe(clients[1]);
+        let snapshot = (BASE + u64::from(SERVERS), usize::from(SERVERS));
+        assert_eq!(take(&mut core, clients[0]), snapshot);
+        assert_eq!(take(&mut core, clients[1]), (BASE, usize::from(SERVERS)));
+
+        for changed in [1, 10, 100] {
+            for (port, &id) in ids[..changed].iter().enumerate() {
+                let heartbeat = heartbeat(port as u16, changed as u8);
+                core.update_server(id, None, heartbeat, None, now).unwrap();
+            }
+            let first = (BASE + changed as u64, changed);
+            assert_eq!(take(&mut core, clients[0]), first);
+            assert_eq!(take(&mut core, clients[1]), (BASE, changed));
+        }
+    }
+
+    /// Servers are sent in ascending order of ID, whatever order they changed in, and a shutdown
+    /// comes before the update for a new server given the same ID
+    #[test]
+    fn update_order() {
+        let now = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let heartbeat = |port| Heartbeat {
+            address: address(port),
+            state: vec![port as u8],
+            region: None,
+            load: None,
+        };
+        let add = |core: &mut Core<_>, port| {
+            let id = core.add_server(Arc::default(), None, now);
+            core.update_server(id, None, heartbeat(port), None, now)
+                .unwrap();
+            id
+        };
+        let ids = [0, 1, 2].map(|port| add(&mut core, port));
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        let client = core.add_client(3, policy);
+        let take = |core: &mut Core<_>| {
+            let mut snapshot = false;
+            let (_, data) = core.take_update(client, 3, CLIENT_IP, INTERVAL, &mut snapshot, now);
+            let msg = ms::client::Message::decode(&data, 3).unwrap();
+            // Whether each update or shutdown is a shutdown, leaving out supplementary events
+            msg.servers
+                .iter()
+                .filter_map(|x| match x.event {
+                    ms::client::Event::Update(..) => Some((x.id, false)),
+                    ms::client::Event::Shutdown => Some((x.id, true)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let wire = |id: ServerId| id.wire();
+
+        // Registered in reverse order of ID, the snapshot is still in order
+        core.remove_server(ids[0], None, now);
+        core.remove_server(ids[1], None, now);
+        let reused = [add(&mut core, 3), add(&mut core, 4)];
+        let mut expected = [ids[2], reused[0], reused[1]];
+        expected.sort_unstable();
+        core.subscribe(client);
+        let snapshot = take(&mut core);
+        assert_eq!(
+            snapshot,
+            expected
+                .iter()
+                .map(|&x| (wire(x), false))
+                .collect::<Vec<_>>()
+        );
+
+        // Changed in reverse order of ID
+        for &id in expected.iter().rev() {
+            core.update_server(id, None, heartbeat(5), None, now)
+                .unwrap();
+        }
+        let update = take(&mut core);
+        assert_eq!(
+            update,
+            expected
+                .iter()
+                .map(|&x| (wire(x), false))
+                .collect::<Vec<_>>()
+        );
+
+        // A server shuts down and its ID is reused before the client hears of it
+        let gone = expected[0];
+        core.remove_server(gone, None, now);
+        core.update_server(expected[2], None, heartbeat(6), None, now)
+            .unwrap();
+        let replacement = add(&mut core, 7);
+        assert_eq!(wire(replacement), wire(gone));
+        let update = take(&mut core);
+        assert_eq!(
+            update,
+            [
+                (wire(gone), true),
+                (wire(gone), false),
+                (wire(expected[2]), false)
+            ]
+        );
+    }
+
+    /// Only the message completing a snapshot, whether sent at once, paced over several, or
+    /// following a reset, carries the synchronized marker
+    #[test]
+    fn synchronized_marker() {
+        const SERVERS: u16 = 10;
+        let now = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let ids = (0..SERVERS)
+            .map(|port| {
+                let id = core.add_server(Arc::default(), None, now);
+                let heartbeat = Heartbeat {
+                    address: address(port),
+                    state: Vec::new(),
+                    region: None,
+                    load: None,
+                };
+                core.update_server(id, None, heartbeat, None, now).unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        // Applies the client's next message to `list`, returning whether it was marked
+        let take =
+            |core: &mut Core<_>, client, version, snapshot: &mut bool, list: &mut ServerList| {
+                let (_, data) =
+                    core.take_update(client, version, CLIENT_IP, INTERVAL, snapshot, now);
+                let msg = ms::client::Message::decode(&data, version).unwrap();
+                list.apply(&msg);
+                msg.servers
+                    .iter()
+                    .any(|x| matches!(x.event, ms::client::Event::Synchronized))
+            };
+        let touch = |core: &mut Core<_>| {
+            let heartbeat = Heartbeat {
+                address: address(0),
+                state: vec![1],
+                region: None,
+                load: None,
+            };
+            core.update_server(ids[0], None, heartbeat, None, now)
+                .unwrap();
+        };
+
+        // All at once
+        let client = core.add_client(3, policy.clone());
+        core.subscribe(client);
+        let (mut snapshot, mut list) = (true, ServerList::new());
+        assert!(take(&mut core, client, 3, &mut snapshot, &mut list));
+        assert!(list.is_synchronized());
+        assert_eq!(list.len(), usize::from(SERVERS));
+        touch(&mut core);
+        assert!(!take(&mut core, client, 3, &mut snapshot, &mut list));
+        core.remove_client(client);
+
+        // Paced over five messages
+        let client = core.add_client(3, policy.clone());
+        core.clients[client].pacing = Some(ms::client::Pacing {
+            duration: INTERVAL * 5,
+            order: ms::client::Strategy::LeastLoaded,
+        });
+        core.subscribe(client);
+        let (mut snapshot, mut list) = (true, ServerList::new());
+        for _ in 0..4 {
+            assert!(!take(&mut core, client, 3, &mut snapshot, &mut list));
+            assert!(!list.is_synchronized());
+        }
+        assert!(take(&mut core, client, 3, &mut snapshot, &mut list));
+        assert!(list.is_synchronized());
+        assert_eq!(list.len(), usize::from(SERVERS));
+        touch(&mut core);
+        assert!(!take(&mut core, client, 3, &mut snapshot, &mut list));
+
+        // Reset after the initial snapshot, marked again
+        core.reset(client);
+        assert!(take(&mut core, client, 3, &mut snapshot, &mut list));
+        assert!(list.is_synchronized());
+        assert_eq!(list.len(), usize::from(SERVERS));
+        touch(&mut core);
+        assert!(!take(&mut core, client, 3, &mut snapshot, &mut list));
+        core.remove_client(client);
+
+        // Older clients have no such marker
+        let client = core.add_client(2, policy);
+        core.subscribe(client);
+        let (mut snapshot, mut list) = (true, ServerList::new());
+        assert!(!take(&mut core, client, 2, &mut snapshot, &mut list));
+        assert_eq!(list.len(), usize::from(SERVERS));
+    }
+
+    /// A lost server that reconnects within its grace period keeps its entry and ID, seen by
+    /// clients only as an update, while one that doesn't is eventually seen to shut down
+    #[test]
+    fn reclaim_and_expiry() {
+        let start = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let connect = |core: &mut Core<_>, n| {
+            let conn = FakeConnection {
+                id: n,
+                closed: Rc::default(),
+            };
+            let id = core.add_server(Arc::default(), Some(conn.clone()), start);
+            let id = core.claim_address(id, address(0), start);
+            (id, conn)
+        };
+        let heartbeat = |state: u8| Heartbeat {
+            address: address(0),
+            state: vec![state],
+            region: None,
+            load: None,
+        };
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        let client = core.add_client(3, policy);
+        core.subscribe(client);
+        // Each update or shutdown sent to the client
+        let take = |core: &mut Core<_>| {
+            let mut snapshot = false;
+            let (_, data) = core.take_update(client, 3, CLIENT_IP, INTERVAL, &mut snapshot, start);
+            let msg = ms::client::Message::decode(&data, 3).unwrap();
+            msg.servers
+                .iter()
+                .filter_map(|x| match x.event {
+                    ms::client::Event::Update(_, state) => Some((x.id, state.to_vec())),
+                    ms::client::Event::Shutdown => Some((x.id, Vec::new())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (id, conn) = connect(&mut core, 0);
+        core.update_server(id, Some(&conn), heartbeat(1), None, start)
+            .unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), vec![1])]);
+
+        // Lost, then reclaimed before its grace period ends
+        let lost_at = start + Duration::from_secs(1);
+        assert!(core.lose_server(id, &conn, lost_at));
+        assert!(take(&mut core).is_empty());
+        let (reclaimed, conn) = connect(&mut core, 1);
+        assert_eq!(reclaimed, id);
+        assert!(take(&mut core).is_empty());
+        core.update_server(id, Some(&conn), heartbeat(2), None, lost_at)
+            .unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), vec![2])]);
+        // The grace period ending changes nothing
+        assert!(!core.expire_server(id, lost_at, start + Duration::from_secs(2)));
+        assert_eq!(core.servers.len(), 1);
+        assert!(take(&mut core).is_empty());
+
+        // Lost again, and the earlier grace period's end still doesn't apply
+        let lost_again = start + Duration::from_secs(3);
+        assert!(core.lose_server(id, &conn, lost_again));
+        assert!(!core.expire_server(id, lost_at, lost_again));
+        assert!(take(&mut core).is_empty());
+        assert!(core.expire_server(id, lost_again, start + Duration::from_secs(4)));
+        assert_eq!(take(&mut core), [(id.wire(), Vec::new())]);
+        assert_eq!(core.servers.len(), 0);
+        assert_eq!(core.state_bytes, 0);
+    }
+
+    /// A client with more shutdowns pending than it's allowed is sent a fresh snapshot instead,
+    /// leaving its list just as if it had been sent every change
+    #[test]
+    fn lost_overflow() {
+        const SERVERS: u16 = MAX_LOST as u16 + 100;
+        let now = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let heartbeat = |port: u16, state: u8| Heartbeat {
+            address: address(port),
+            state: vec![state],
+            region: None,
+            load: None,
+        };
+        let ids = (0..SERVERS)
+            .map(|port| {
+                let id = core.add_server(Arc::default(), None, now);
+                core.update_server(id, None, heartbeat(port, 0), None, now)
+                    .unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        // A client that understands resets, and one that must be sent every shutdown
+        let mut clients = [3, 2].map(|version| {
+            let id = core.add_client(version, policy.clone());
+            core.subscribe(id);
+            (id, version, ServerList::new())
+        });
+        // Applies each client's next message to its list, returning whether it was a reset
+        let take = |core: &mut Core<_>, clients: &mut [(ClientId, u32, ServerList)]| {
+            clients
+                .iter_mut()
+                .map(|(id, version, list)| {
+                    let mut snapshot = false;
+                    let (_, data) =
+                        core.take_update(*id, *version, CLIENT_IP, INTERVAL, &mut snapshot, now);
+                    let msg = ms::client::Message::decode(&data, *version).unwrap();
+                    list.apply(&msg);
+                    msg.servers
+                        .iter()
+                        .any(|x| matches!(x.event, ms::client::Event::Reset))
+                })
+                .collect::<Vec<_>>()
+        };
+        // Whether `list` holds exactly the servers visible in `core`, with their latest states
+        let matches = |core: &Core<_>, list: &ServerList| {
+            let visible = core
+                .servers
+                .iter()
+                .filter(|(_, x)| x.address.is_some())
+                .collect::<Vec<_>>();
+            list.len() == visible.len()
+                && visible.iter().all(|(id, x)| {
+                    list.get(id.wire())
+                        .is_some_and(|entry| entry.state == x.state[..])
+                })
+        };
+        assert_eq!(take(&mut core, &mut clients), [false, false]);
+
+        // A new filter hiding too many at once
+        let filter = ms::client::Filter {
+            required: vec!["absent".into()],
+            excluded: Vec::new(),
+        };
+        core.set_filter(clients[0].0, filter, 1);
+        assert_eq!(take(&mut core, &mut clients[..1]), [true]);
+        assert_eq!(clients[0].2.len(), 0);
+        assert_eq!(clients[0].2.filter_generation(), 1);
+        core.set_filter(clients[0].0, ms::client::Filter::default(), 2);
+        assert_eq!(take(&mut core, &mut clients[..1]), [false]);
+        assert!(matches(&core, &clients[0].2));
+
+        // One shutdown too many, alongside other changes
+        for &id in &ids[..=MAX_LOST] {
+            core.remove_server(id, None, now);
+        }
+        let port = MAX_LOST as u16 + 1;
+        core.update_server(ids[MAX_LOST + 1], None, heartbeat(port, 1), None, now)
+            .unwrap();
+        let added = core.add_server(Arc::default(), None, now);
+        core.update_server(added, None, heartbeat(SERVERS, 2), None, now)
+            .unwrap();
+        assert!(core.clients[clients[0].0].lost.is_empty());
+        assert_eq!(core.clients[clients[1].0].lost.len(), MAX_LOST + 1);
+        assert_eq!(take(&mut core, &mut clients), [true, false]);
+        for (_, _, list) in &clients {
+            assert!(matches(&core, list));
+        }
+        assert!(clients[0].2.is_synchronized());
+
+        // Deltas resume afterwards
+        core.remove_server(ids[MAX_LOST + 2], None, now);
+        assert_eq!(take(&mut core, &mut clients), [false, false]);
+        for (_, _, list) in &clients {
+            assert!(matches(&core, list));
+        }
+    }
+
+    /// Clients filtering by region start and stop seeing a game server as its address moves it
+    /// between regions
+    #[test]
+    fn region_filter() {
+        let now = Instant::now();
+        let mut core = Core::<FakeConnection>::new();
+        let region = |code: &[u8; 2]| Some(ms::client::Region(*code));
+        let heartbeat = |port: u16, region| Heartbeat {
+            address: address(port),
+            state: vec![0],
+            region,
+            load: None,
+        };
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        let client = core.add_client(3, policy);
+        core.set_filter(
+            client,
+            ms::client::Filter {
+                required: vec!["region:DE".into()],
+                excluded: Vec::new(),
+            },
+            0,
+        );
+        core.subscribe(client);
+        // Whether each server the client is sent is an update, rather than a shutdown
+        let take = |core: &mut Core<_>| {
+            let mut snapshot = false;
+            let (_, data) = core.take_update(client, 3, CLIENT_IP, INTERVAL, &mut snapshot, now);
+            let msg = ms::client::Message::decode(&data, 3).unwrap();
+            msg.servers
+                .iter()
+                .filter_map(|x| match x.event {
+                    ms::client::Event::Update(..) => Some((x.id, true)),
+                    ms::client::Event::Shutdown => Some((x.id, false)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        take(&mut core);
+
+        let id = core.add_server(Arc::default(), None, now);
+        core.update_server(id, None, heartbeat(0, region(b"DE")), None, now)
+            .unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), true)]);
+        // A tag can't stand in for the region
+        core.set_tags(id, None, vec!["region:DE".into()], now)
+            .unwrap();
+        take(&mut core);
+        core.update_server(id, None, heartbeat(1, region(b"FR")), None, now)
+            .unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), false)]);
+        core.move_server(id, None, address(2), region(b"DE"), now)
+            .unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), true)]);
+        core.move_server(id, None, address(3), None, now).unwrap();
+        assert_eq!(take(&mut core), [(id.wire(), false)]);
+        // Moving within a region the client can't see tells it nothing
+        core.move_server(id, None, address(4), region(b"FR"), now)
+            .unwrap();
+        assert!(take(&mut core).is_empty());
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], 27015 + port))
+    }
+
+    /// One of a few filters on the single tag game servers may have
+    fn filter(n: u8) -> ms::client::Filter {
+        let tag = || vec!["a".into()];
+        match n {
+            0 => ms::client::Filter::default(),
+            1 => ms::client::Filter {
+                required: tag(),
+                excluded: Vec::new(),
+            },
+            _ => ms::client::Filter {
+                required: Vec::new(),
+                excluded: tag(),
+            },
+        }
+    }
+
+    /// Close `conn` if an operation on its game server failed, as its handler would on exiting
+    fn fail<T>(conn: &FakeConnection, result: Result<T>) {
+        if result.is_err() {
+            conn.close(ms::CloseCode::Rejected, b"");
+        }
+    }
+
+    fn pick<T>(xs: &mut [T], i: Index) -> Option<&mut T> {
+        (!xs.is_empty()).then(|| &mut xs[i.index(xs.len())])
+    }
+
+    fn take<T>(xs: &mut Vec<T>, i: Index) -> Option<T> {
+        (!xs.is_empty()).then(|| xs.swap_remove(i.index(xs.len())))
+    }
+}
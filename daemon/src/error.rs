@@ -0,0 +1,123 @@
+//! Failures to start, classified so that supervisors can tell them apart by exit code
+
+use std::{io, net::SocketAddr, path::PathBuf};
+
+use thiserror::Error;
+
+/// Why the daemon couldn't start
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error("failed to read {}", path.display())]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to parse {}", path.display())]
+    ConfigParse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+    /// A setting is missing or has an unusable value
+    #[error("invalid {field}: {reason}")]
+    ConfigInvalid { field: String, reason: String },
+    /// The private key, certificate, or a client certificate authority couldn't be read or parsed
+    #[error("failed to load {}", path.display())]
+    TlsLoad {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// The TLS material loaded, but doesn't make a usable configuration, e.g. because the private
+    /// key doesn't match the certificate
+    #[error("invalid TLS configuration")]
+    TlsConfig(#[source] anyhow::Error),
+    #[error("failed to listen on {addr}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl DaemonError {
+    pub fn config_invalid(field: impl Into<String>, reason: impl ToString) -> Self {
+        Self::ConfigInvalid {
+            field: field.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn tls_load(path: impl Into<PathBuf>, source: impl Into<anyhow::Error>) -> Self {
+        Self::TlsLoad {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+
+    /// Status to exit with
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Self::ConfigRead { .. } | Self::ConfigParse { .. } | Self::ConfigInvalid { .. } => 2,
+            Self::TlsLoad { .. } | Self::TlsConfig(_) => 3,
+            Self::Bind { .. } => 4,
+        }
+    }
+}
+
+/// Status to exit with after `error`: that of the [`DaemonError`] in its chain, if any, or else 1
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|x| x.downcast_ref::<DaemonError>())
+        .map_or(1, DaemonError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let config = DaemonError::config_invalid("state-size", "too large");
+        let read = DaemonError::ConfigRead {
+            path: "metaserve.toml".into(),
+            source: io::ErrorKind::NotFound.into(),
+        };
+        let parse = DaemonError::ConfigParse {
+            path: "metaserve.toml".into(),
+            source: toml::from_str::<toml::Table>("=").unwrap_err(),
+        };
+        let tls = DaemonError::tls_load("key.der", anyhow::anyhow!("not a key"));
+        let tls_config = DaemonError::TlsConfig(anyhow::anyhow!("key doesn't match"));
+        let bind = DaemonError::Bind {
+            addr: "[::]:4433".parse().unwrap(),
+            source: io::ErrorKind::AddrInUse.into(),
+        };
+        for (error, code) in [
+            (config, 2),
+            (read, 2),
+            (parse, 2),
+            (tls, 3),
+            (tls_config, 3),
+            (bind, 4),
+        ] {
+            assert_eq!(error.exit_code(), code, "{error}");
+            // Wherever it is in the chain
+            let error = anyhow::Error::from(error);
+            assert_eq!(exit_code(&error), code);
+            let error = error.context("failed to start");
+            assert_eq!(exit_code(&error), code);
+        }
+
+        let other = anyhow::anyhow!("something else");
+        assert_eq!(exit_code(&other), 1);
+        let io = Err::<(), _>(io::Error::from(io::ErrorKind::Other))
+            .context("failed to export certificate")
+            .unwrap_err();
+        assert_eq!(exit_code(&io), 1);
+    }
+}
@@ -0,0 +1,111 @@
+//! Synthetic game servers for exercising clients without running real game servers
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::{ServerId, State};
+
+/// Networks reserved for documentation by RFC 5737, so fake addresses can't reach anything real
+const TEST_NETS: [[u8; 3]; 3] = [[192, 0, 2], [198, 51, 100], [203, 0, 113]];
+
+const ADJECTIVES: &[&str] = &[
+    "Casual", "Hardcore", "Friendly", "Ancient", "Frozen", "Burning", "Silent", "Crimson",
+];
+const NOUNS: &[&str] = &[
+    "Arena",
+    "Outpost",
+    "Citadel",
+    "Playground",
+    "Frontier",
+    "Depot",
+];
+const MAPS: &[&str] = &["docks", "canyon", "station", "village", "reactor", "summit"];
+/// Each fake server has each of these tags with even odds
+const TAGS: &[&str] = &["ranked", "modded", "hardcore"];
+
+/// How often the simulation advances
+const TICK: Duration = Duration::from_millis(100);
+
+/// Maintain `count` fake servers that update, leave, and are replaced at random
+pub async fn run(state: Arc<State>, count: usize) {
+    let mut rng = StdRng::from_entropy();
+    let update_chance = (TICK.as_secs_f64() / state.options().fake_update_interval).min(1.0);
+    let leave_chance = (TICK.as_secs_f64() / state.options().fake_lifetime).min(1.0);
+    let mut servers = (0..count)
+        .map(|_| FakeServer::new(&state, &mut rng))
+        .collect::<Vec<_>>();
+    info!(count, "simulating fake servers");
+
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        for server in &mut servers {
+            if rng.gen_bool(leave_chance) {
+                state.remove_server(server.id, None);
+                *server = FakeServer::new(&state, &mut rng);
+            } else if rng.gen_bool(update_chance) {
+                server.players = rng.gen_range(0..=server.max_players);
+                server.publish(&state);
+            }
+        }
+    }
+}
+
+struct FakeServer {
+    id: ServerId,
+    address: SocketAddr,
+    name: String,
+    map: &'static str,
+    players: u32,
+    max_players: u32,
+}
+
+impl FakeServer {
+    fn new(state: &State, rng: &mut impl Rng) -> Self {
+        let id = state
+            .lock()
+            .add_server(Arc::default(), None, Instant::now());
+        let [a, b, c] = *TEST_NETS.choose(rng).unwrap();
+        let max_players = *[8, 16, 24, 32].choose(rng).unwrap();
+        let server = Self {
+            id,
+            address: SocketAddr::new(
+                Ipv4Addr::new(a, b, c, rng.gen_range(1..255)).into(),
+                rng.gen_range(27000..28000),
+            ),
+            name: format!(
+                "{} {} #{}",
+                ADJECTIVES.choose(rng).unwrap(),
+                NOUNS.choose(rng).unwrap(),
+                rng.gen_range(1..100)
+            ),
+            map: MAPS.choose(rng).unwrap(),
+            players: rng.gen_range(0..=max_players),
+            max_players,
+        };
+        let tags = TAGS
+            .iter()
+            .filter(|_| rng.gen_bool(0.5))
+            .map(|&x| x.into())
+            .collect();
+        let _ = state.set_tags(id, None, tags);
+        server.publish(state);
+        server
+    }
+
+    fn publish(&self, state: &State) {
+        let info = format!(
+            r#"{{"name":"{}","map":"{}","players":{},"max_players":{}}}"#,
+            self.name, self.map, self.players, self.max_players
+        );
+        if let Err(e) = state.update_server(self.id, None, self.address, info.into_bytes()) {
+            debug!(id = %self.id, error = %e, "fake server update failed");
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Telling client tasks about game server changes a batch at a time, so that thousands of them
+//! don't all contend for the shared state at once
+
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use slab::Slab;
+use tokio::sync::{futures::Notified, Notify};
+
+/// Wakes registered [`Waiter`]s whenever [`notify`](Self::notify) is called, in batches
+pub struct Fanout {
+    waiters: Mutex<Slab<Arc<Notify>>>,
+    /// Wakes [`run`](Self::run)
+    changed: Notify,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Self {
+            waiters: Mutex::new(Slab::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Wake every waiter soon
+    ///
+    /// Calls made while waiters are being woken cause them all to be woken again afterwards.
+    pub fn notify(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Start receiving notifications, until the [`Waiter`] is dropped
+    pub fn register(self: &Arc<Self>) -> Waiter {
+        let notify = Arc::new(Notify::new());
+        let key = self.lock().insert(notify.clone());
+        Waiter {
+            fanout: self.clone(),
+            key,
+            notify,
+        }
+    }
+
+    /// Wake waiters after each call to [`notify`](Self::notify), `batch()` at a time
+    ///
+    /// Yields between batches, so woken tasks can run before the next batch is woken. Each pass
+    /// starts a batch further along than the last, so that no waiter is always woken last.
+    pub async fn run(&self, batch: impl Fn() -> usize) {
+        let mut start = 0;
+        loop {
+            self.changed.notified().await;
+            let waiters = self
+                .lock()
+                .iter()
+                .map(|(_, x)| x.clone())
+                .collect::<Vec<_>>();
+            if waiters.is_empty() {
+                continue;
+            }
+            let batch = batch();
+            start = (start + batch) % waiters.len();
+            let (later, first) = waiters.split_at(start);
+            for chunk in first.chunks(batch).chain(later.chunks(batch)) {
+                for waiter in chunk {
+                    waiter.notify_one();
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slab<Arc<Notify>>> {
+        self.waiters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A task's registration with a [`Fanout`]
+pub struct Waiter {
+    fanout: Arc<Fanout>,
+    key: usize,
+    notify: Arc<Notify>,
+}
+
+impl Waiter {
+    /// Wait for the next notification
+    ///
+    /// A notification sent while nobody is waiting is kept for the next call.
+    pub fn notified(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.fanout.lock().remove(self.key);
+    }
+}
+
+/// Distribution of durations, for reporting percentiles
+///
+/// Buckets are a quarter of a power of two wide, so percentiles are accurate to within 19%.
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 4 * 64],
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let micros = (duration.as_micros() as u64).max(1);
+        let exponent = 63 - micros.leading_zeros();
+        let fraction = match exponent {
+            0 | 1 => 0,
+            _ => (micros >> (exponent - 2)) & 3,
+        };
+        self.counts[(exponent * 4) as usize + fraction as usize] += 1;
+        self.total += 1;
+    }
+
+    /// Number of durations recorded
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    /// Upper bound of the bucket containing the `q`th quantile, if anything was recorded
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = ((self.total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let (exponent, fraction) = ((i / 4) as u32, (i % 4) as u64);
+                let upper = match exponent {
+                    0 | 1 => 1 << (exponent + 1),
+                    _ => (4 + fraction + 1) << (exponent - 2),
+                };
+                return Some(Duration::from_micros(upper));
+            }
+        }
+        None
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+        self.total = 0;
+    }
+}
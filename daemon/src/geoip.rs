@@ -0,0 +1,64 @@
+//! Locating game servers by IP address, using a MaxMind database
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context, Result};
+use maxminddb::geoip2;
+use metaserve_proto::client::Region;
+
+/// Number of lookup results to remember before starting afresh
+const MAX_CACHED: usize = 4096;
+
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+    /// Results of previous lookups, since game servers rarely change address
+    cache: Mutex<HashMap<IpAddr, Option<Region>>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("failed to open GeoIP database {}", path.display()))?;
+        Ok(Self {
+            reader,
+            cache: Mutex::default(),
+        })
+    }
+
+    /// Find the country `ip` is in
+    pub fn lookup(&self, ip: IpAddr) -> Option<Region> {
+        let ip = ip.to_canonical();
+        if let Some(&region) = self.cache().get(&ip) {
+            return region;
+        }
+        let region = self.lookup_uncached(ip);
+        let mut cache = self.cache();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(ip, region);
+        region
+    }
+
+    /// Access the cache, even if a panicking task poisoned the lock, since a partial update
+    /// leaves it usable
+    fn cache(&self) -> MutexGuard<'_, HashMap<IpAddr, Option<Region>>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lookup_uncached(&self, ip: IpAddr) -> Option<Region> {
+        let record = self
+            .reader
+            .lookup(ip)
+            .ok()?
+            .decode::<geoip2::Country>()
+            .ok()??;
+        let code = record.country.iso_code?.as_bytes().try_into().ok()?;
+        Some(Region(code))
+    }
+}
@@ -1,75 +1,282 @@
 use std::{
-    fs,
-    net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    collections::HashMap,
+    fs, future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak,
+    },
+    thread,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
-use futures_util::StreamExt;
-use indexmap::IndexSet;
 use metaserve_proto as ms;
-use slab::Slab;
-use tokio::sync::Notify;
-use tracing::{debug, error, info, Instrument};
+use quinn::crypto::rustls::QuicServerConfig;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinSet,
+    time::{Duration, Instant, MissedTickBehavior},
+};
+use tracing::{debug, error, field::Empty, info, warn, Instrument};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
-#[derive(Parser, Debug)]
-#[clap(name = "metaserve")]
-struct Opt {
-    /// TLS private key in DER format
-    #[clap(parse(from_os_str), short = 'k', long = "key")]
-    private_key: PathBuf,
-    /// TLS certificate in DER format
-    #[clap(parse(from_os_str), short = 'c', long = "cert")]
-    certificate: PathBuf,
+use crate::core::{Connection, Core, Heartbeat};
+use activity::{Activity, Ending};
+use bandwidth::Bucket;
+use config::{BudgetPolicy, Config, ListenerPolicy, Opt, Role};
+use error::DaemonError;
+use fanout::Fanout;
+use table::{ClientId, Key, ServerId};
+use validate::StateValidator;
 
-    /// Maximum size of server state to accept
-    #[clap(short = 's', long = "state-size", default_value = "8192")]
-    state_size: usize,
+mod activity;
+mod alpn;
+mod bandwidth;
+mod config;
+mod core;
+mod error;
+mod fake;
+mod fanout;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "otel")]
+mod otel;
+mod selftest;
+mod table;
+mod validate;
 
-    /// Address to listen on
-    #[clap(long = "listen", default_value = "[::]:4433")]
-    listen: SocketAddr,
-}
+/// Description of this build, e.g. "0.1.0 (1a2b3c4, 2024-01-31)"
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("METASERVE_GIT_HASH"),
+    ", ",
+    env!("METASERVE_BUILD_DATE"),
+    ")"
+);
+
+/// Minimum time between updates sent to a client when a server has shut down
+const SHUTDOWN_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// How often to check whether game servers' connections have migrated
+const ADDRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest client hello or request that we'll read
+const MAX_CLIENT_REQUEST_SIZE: usize = 4096;
+/// Largest solution to a challenge that we'll read
+const MAX_SOLUTION_SIZE: usize = 64;
+/// Most introductions waiting to be forwarded to each game server
+const MAX_QUEUED_INTRODUCTIONS: usize = 4;
+/// Minimum time between resyncs requested by each client
+const RESYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// Minimum time between introductions forwarded to each game server
+const INTRODUCTION_INTERVAL: Duration = Duration::from_millis(100);
+/// Longest a game server may stay over `--max-heartbeat-bandwidth` before being disconnected
+const MAX_BANDWIDTH_DEBT: Duration = Duration::from_secs(60);
+/// How long `--healthcheck` waits for a handshake
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
-async fn run(options: Opt) -> Result<()> {
+async fn run(
+    options: Config,
+    opt: Opt,
+    set_log_filter: LogFilterSetter,
+    dry_run: bool,
+) -> Result<()> {
+    let key_path = options
+        .private_key
+        .as_ref()
+        .expect("checked by Config::validate");
+    let key = fs::read(key_path).map_err(|e| DaemonError::tls_load(key_path, e))?;
     let key =
-        rustls::PrivateKey(fs::read(&options.private_key).context("failed to read private key")?);
-    let cert_chain = vec![rustls::Certificate(
-        fs::read(&options.certificate).context("failed to read certificate")?,
+        PrivateKeyDer::try_from(key).map_err(|e| DaemonError::tls_load(key_path, anyhow!(e)))?;
+    let cert_path = options
+        .certificate
+        .as_ref()
+        .expect("checked by Config::validate");
+    let cert_chain = vec![CertificateDer::from(
+        fs::read(cert_path).map_err(|e| DaemonError::tls_load(cert_path, e))?,
     )];
-    let mut server_crypto = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key)?;
-    server_crypto.alpn_protocols = vec![ms::client::PROTOCOL.into(), ms::game::PROTOCOL.into()];
+    let mut listeners = Vec::new();
+    for policy in options.listeners() {
+        let endpoint = listen(&options, &policy, cert_chain.clone(), key.clone_key())?;
+        listeners.push((endpoint, Arc::new(policy)));
+    }
+    let instance = rand::random::<u64>();
+    // Every version up to the newest is supported
+    info!(
+        version = VERSION,
+        instance = %format_args!("{:016x}", instance),
+        game_protocol = ms::game::PROTOCOLS.len(),
+        client_protocol = ms::client::PROTOCOLS.len(),
+        "starting"
+    );
+    for (endpoint, policy) in &listeners {
+        debug!(address = %endpoint.local_addr()?, accept = ?policy.accept, "listening");
+    }
+
+    if let Some(ref path) = options.export_cert {
+        export_certificate(path, &cert_chain)
+            .with_context(|| format!("failed to export certificate to {}", path.display()))?;
+    }
+
+    let state = Arc::new(State::new(
+        options,
+        opt,
+        instance,
+        cert_chain,
+        set_log_filter,
+    )?);
+    if dry_run {
+        info!("dry run succeeded");
+        return Ok(());
+    }
+    state.run(listeners).await
+}
+
+/// Write `cert_chain` to `path`, as PEM if its extension is `pem`, or otherwise the leaf as DER
+fn export_certificate(path: &Path, cert_chain: &[CertificateDer<'_>]) -> Result<()> {
+    use base64::Engine;
+
+    if path.extension().is_none_or(|x| x != "pem") {
+        return Ok(fs::write(path, &cert_chain[0])?);
+    }
+    let mut pem = String::new();
+    for der in cert_chain {
+        pem.push_str("-----BEGIN CERTIFICATE-----\n");
+        let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+    }
+    Ok(fs::write(path, pem)?)
+}
+
+/// Accept connections as configured by `policy`, identifying ourselves with `cert_chain` and `key`
+fn listen(
+    options: &Config,
+    policy: &ListenerPolicy,
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<quinn::Endpoint, DaemonError> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match policy.client_ca {
+        Some(ref path) => {
+            let der = fs::read(path).map_err(|e| DaemonError::tls_load(path, e))?;
+            let mut roots = rustls::RootCertStore::empty();
+            roots
+                .add(CertificateDer::from(der))
+                .map_err(|e| DaemonError::tls_load(path, e))?;
+            builder.with_client_cert_verifier(
+                rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| DaemonError::TlsConfig(e.into()))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_crypto = builder
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| DaemonError::TlsConfig(e.into()))?;
+    let mut protocols = Vec::new();
+    if policy.accepts(Role::Client) {
+        protocols.extend_from_slice(ms::client::PROTOCOLS);
+    }
+    if policy.accepts(Role::Game) {
+        protocols.extend_from_slice(ms::game::PROTOCOLS);
+    }
+    server_crypto.alpn_protocols = protocols.into_iter().map(Vec::from).collect();
+    server_crypto.cert_resolver = Arc::new(alpn::RecordOffered(server_crypto.cert_resolver));
+    let server_crypto =
+        QuicServerConfig::try_from(server_crypto).map_err(|e| DaemonError::TlsConfig(e.into()))?;
     let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
-    server_config.use_retry(true);
     Arc::get_mut(&mut server_config.transport)
         .unwrap()
         .max_concurrent_uni_streams(1u32.into())
-        .max_concurrent_bidi_streams(0u32.into())
+        // For client queries, answered one at a time
+        .max_concurrent_bidi_streams(1u32.into())
+        // Enough for any valid message from a game server
         .stream_receive_window(
-            options
-                .state_size
+            (options.state_size + ms::game::MAX_UPDATE_OVERHEAD)
                 .try_into()
-                .context("failed to set stream window size")?,
+                .expect("checked by Config::validate"),
         );
-    let (endpoint, incoming) = quinn::Endpoint::server(server_config, options.listen)?;
-    debug!("listening on {}", endpoint.local_addr()?);
+    quinn::Endpoint::server(server_config, policy.address).map_err(|source| DaemonError::Bind {
+        addr: policy.address,
+        source,
+    })
+}
 
-    let state = Arc::new(State::new(options));
-    state.run(incoming).await
+/// Connect to the daemon at `server` as a client would, trusting the certificate authorities in
+/// `ca`
+#[tokio::main]
+async fn healthcheck(server: &str, ca: &[PathBuf]) -> Result<()> {
+    let mut builder = metaserve_client::Client::builder();
+    for path in ca {
+        let der = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        builder = builder.ca(der);
+    }
+    let client = tokio::time::timeout(HEALTHCHECK_TIMEOUT, builder.connect(server))
+        .await
+        .context("timed out")??;
+    client.close().await;
+    Ok(())
 }
 
 fn main() {
-    use tracing_subscriber::{
-        filter, fmt, layer::SubscriberExt, registry, util::SubscriberInitExt,
-    };
+    use tracing_subscriber::{fmt, layer::SubscriberExt, registry, util::SubscriberInitExt};
 
     let opt = Opt::parse();
+    if let Some(ref server) = opt.healthcheck {
+        if let Err(e) = healthcheck(server, &opt.ca) {
+            eprintln!("ERROR: {:#}", e);
+            ::std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(ref server) = opt.selftest {
+        if let Err(e) = selftest::run(server, &opt.ca, opt.save_cert.as_deref()) {
+            eprintln!("ERROR: {:#}", e);
+            ::std::process::exit(1);
+        }
+        return;
+    }
+    let check_config = opt.check_config;
+    let dry_run = opt.dry_run;
+    let config = match Config::load(opt.clone()) {
+        Ok(x) => x,
+        Err(e) => {
+            let code = e.exit_code();
+            eprintln!("ERROR: {:#}", anyhow::Error::from(e));
+            ::std::process::exit(code);
+        }
+    };
+    if check_config {
+        print!("{}", toml::to_string(&config).unwrap());
+        return;
+    }
+    #[cfg(feature = "otel")]
+    let exporter = match config
+        .otlp_endpoint
+        .as_deref()
+        .map(otel::Exporter::new)
+        .transpose()
+    {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("ERROR: {:#}", e);
+            ::std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "otel")]
+    let otel = exporter.as_ref().map(otel::Exporter::layer);
+    #[cfg(not(feature = "otel"))]
+    let otel = None::<tracing_subscriber::layer::Identity>;
     let journald = tracing_journald::layer();
     let (stdout, stdout_guard) = tracing_appender::non_blocking(std::io::stdout());
     let fmt = if journald.is_err() || std::env::var_os("INVOCATION_ID").is_none() {
@@ -77,218 +284,3407 @@ fn main() {
     } else {
         None
     };
-    let registry = registry().with(fmt).with(
-        filter::EnvFilter::from_default_env()
-            .add_directive(tracing_subscriber::filter::LevelFilter::INFO.into()),
-    );
+    let (filter, filter_handle) =
+        tracing_subscriber::reload::Layer::new(log_filter(config.log_filter.as_deref()));
+    let set_log_filter: LogFilterSetter = Box::new(move |filter| {
+        filter_handle
+            .reload(filter)
+            .map_err(|e| anyhow!("failed to replace log filter: {}", e))
+    });
+    let registry = registry().with(otel).with(fmt).with(filter);
     match journald {
         Ok(layer) => {
             registry.with(layer).init();
         }
-        Err(_e) => {
+        Err(e) => {
             registry.init();
-            info!("couldn't connect to journald: {}", _e);
+            info!(error = %e, "couldn't connect to journald");
         }
     }
-    let code = match run(opt) {
+    let code = match run(config, opt, set_log_filter, dry_run) {
         Err(e) => {
-            error!("{}", e);
-            1
+            error!("{:#}", e);
+            error::exit_code(&e)
         }
         Ok(()) => 0,
     };
+    #[cfg(feature = "otel")]
+    if let Some(exporter) = exporter {
+        exporter.shutdown();
+    }
     drop(stdout_guard);
     ::std::process::exit(code);
 }
 
+/// Tracing filter for `directives`, or else for the `RUST_LOG` environment variable
+fn log_filter(directives: Option<&str>) -> EnvFilter {
+    match directives {
+        Some(x) => EnvFilter::new(x),
+        None => EnvFilter::from_default_env().add_directive(LevelFilter::INFO.into()),
+    }
+}
+
+/// Replaces the tracing filter installed in `main`
+type LogFilterSetter = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
 struct State {
-    options: Opt,
-    dirty: Notify,
-    inner: Mutex<Inner>,
+    /// Replaced when the configuration is [reloaded](Self::reload)
+    options: watch::Sender<Arc<Config>>,
+    /// Command line, reapplied over the config file when reloading
+    opt: Opt,
+    set_log_filter: LogFilterSetter,
+    /// Checks applied to every heartbeat
+    validators: Vec<Box<dyn StateValidator>>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<geoip::GeoIp>,
+    /// Sent to clients as [`ms::client::Welcome::instance`]
+    instance: u64,
+    /// The certificate chain we present, for `--export-cert`
+    cert_chain: Vec<CertificateDer<'static>>,
+    /// Notified when clients may have updates to send
+    dirty: Arc<Fanout>,
+    /// Set once we've stopped accepting new connections, to exit when existing ones end
+    draining: AtomicBool,
+    /// Notified when `draining` is set
+    drain: Notify,
+    handshake_failures: alpn::Failures,
+    /// Game servers registering, counted against `challenge_above`
+    registrations: Mutex<Option<Bucket>>,
+    inner: Mutex<Core<quinn::Connection>>,
 }
 
 impl State {
-    fn new(options: Opt) -> Self {
-        Self {
-            options,
-            dirty: Notify::new(),
-            inner: Mutex::new(Inner {
-                clients: Slab::new(),
-                servers: Slab::new(),
-            }),
+    fn new(
+        options: Config,
+        opt: Opt,
+        instance: u64,
+        cert_chain: Vec<CertificateDer<'static>>,
+        set_log_filter: LogFilterSetter,
+    ) -> Result<Self> {
+        Ok(Self {
+            validators: validate::from_config(&options),
+            #[cfg(feature = "geoip")]
+            geoip: options
+                .geoip_db
+                .as_deref()
+                .map(geoip::GeoIp::open)
+                .transpose()?,
+            registrations: Mutex::new(options.challenge_above.map(Bucket::new)),
+            options: watch::Sender::new(Arc::new(options)),
+            opt,
+            set_log_filter,
+            instance,
+            cert_chain,
+            dirty: Arc::new(Fanout::new()),
+            draining: AtomicBool::new(false),
+            drain: Notify::new(),
+            handshake_failures: alpn::Failures::default(),
+            inner: Mutex::new(Core::new()),
+        })
+    }
+
+    /// The configuration currently in effect
+    fn options(&self) -> Arc<Config> {
+        self.options.borrow().clone()
+    }
+
+    /// Re-read the configuration, applying any changes that don't require a restart
+    fn reload(&self) {
+        match Config::load(self.opt.clone()) {
+            Ok(x) => self.apply(x),
+            Err(e) => error!(error = %format_args!("{:#}", e), "failed to reload configuration"),
+        }
+    }
+
+    /// Switch to `options`, except for changes that require a restart
+    fn apply(&self, mut options: Config) {
+        let old = self.options();
+        for option in options.keep_static(&old) {
+            warn!(option, "ignoring change that requires a restart");
+        }
+        if options.log_filter != old.log_filter {
+            let filter = log_filter(options.log_filter.as_deref());
+            let directives = filter.to_string();
+            match (self.set_log_filter)(filter) {
+                Ok(()) => info!(%directives, "changed log filter"),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+        if let Some(ref path) = options.export_cert {
+            if let Err(e) = export_certificate(path, &self.cert_chain) {
+                error!(path = %path.display(), error = %e, "failed to export certificate");
+            }
+        }
+        Bucket::reconfigure(
+            &mut self
+                .registrations
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            options.challenge_above,
+        );
+        self.options.send_replace(Arc::new(options));
+        info!("reloaded configuration");
+        if self.limits(0) != limits_of(&old, 0) {
+            let limits = self.limits(MAX_CLIENT_REQUEST_SIZE);
+            self.lock().announce_limits(limits);
+            self.dirty.notify();
+        }
+    }
+
+    /// The region a game server at `ip` is in, if known
+    fn region(&self, ip: IpAddr) -> Option<ms::client::Region> {
+        #[cfg(feature = "geoip")]
+        if let Some(ref geoip) = self.geoip {
+            return geoip.lookup(ip);
+        }
+        let _ = ip;
+        None
+    }
+
+    /// Access shared state, [recovering](Self::recover) it if a panicking task poisoned the lock
+    fn lock(&self) -> MutexGuard<'_, Core<quinn::Connection>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|e| self.recover(e.into_inner()))
+    }
+
+    /// Repair state that a task panicked while holding, then clear the poison if that worked
+    ///
+    /// Connection handlers clean up after themselves when panicking, but may have left a
+    /// transition half done. If the state is still inconsistent, the lock stays poisoned for the
+    /// [watchdog](Self::check_health) to notice.
+    fn recover<'a>(
+        &self,
+        mut inner: MutexGuard<'a, Core<quinn::Connection>>,
+    ) -> MutexGuard<'a, Core<quinn::Connection>> {
+        inner.repair();
+        match inner.inconsistency() {
+            None => {
+                warn!("repaired state after a panic");
+                self.inner.clear_poison();
+            }
+            Some(problem) => error!(problem, "state inconsistent after a panic"),
+        }
+        inner
+    }
+
+    /// Check that the state lock can be taken within `timeout`, recovering the state if
+    /// necessary, or describe why not
+    fn check_health(&self, timeout: std::time::Duration) -> Result<(), &'static str> {
+        let start = std::time::Instant::now();
+        loop {
+            match self.inner.try_lock() {
+                Ok(_) => return Ok(()),
+                Err(TryLockError::Poisoned(e)) => {
+                    drop(self.recover(e.into_inner()));
+                    if self.inner.is_poisoned() {
+                        return Err("state poisoned beyond repair");
+                    }
+                    return Ok(());
+                }
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                    thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(TryLockError::WouldBlock) => return Err("state lock held too long"),
+            }
         }
     }
 
-    async fn run(self: Arc<Self>, mut incoming: quinn::Incoming) -> Result<()> {
-        while let Some(conn) = incoming.next().await {
-            tokio::spawn(self.clone().dispatch(conn));
+    async fn run(
+        self: Arc<Self>,
+        listeners: Vec<(quinn::Endpoint, Arc<ListenerPolicy>)>,
+    ) -> Result<()> {
+        if let Some(count) = self.options().fake_servers {
+            tokio::spawn(fake::run(self.clone(), count));
+        }
+        let state = self.clone();
+        tokio::spawn(async move { state.dirty.run(|| state.options().fanout_batch).await });
+        // On a thread of its own, so a wedged runtime can't stop it
+        let state = Arc::downgrade(&self);
+        thread::Builder::new()
+            .name("watchdog".into())
+            .spawn(move || watchdog(state))?;
+        // Gather incoming connections from every listener, closing once they've all shut down
+        let (accepted_send, mut accepted) = mpsc::channel(1);
+        for (endpoint, policy) in &listeners {
+            let (endpoint, policy, send) =
+                (endpoint.clone(), policy.clone(), accepted_send.clone());
+            tokio::spawn(async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    if send.send((incoming, policy.clone())).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(accepted_send);
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut drain = signal(SignalKind::user_defined2())?;
+            let state = self.clone();
+            tokio::spawn(async move {
+                drain.recv().await;
+                state.start_drain();
+            });
+            let mut reload = signal(SignalKind::hangup())?;
+            let state = self.clone();
+            tokio::spawn(async move {
+                while reload.recv().await.is_some() {
+                    state.reload();
+                }
+            });
+        }
+        let mut tasks = JoinSet::new();
+        let mut spans = HashMap::new();
+        let mut drain_deadline = None;
+        let mut maintenance =
+            tokio::time::interval(Duration::from_secs_f64(self.options().maintenance_interval));
+        maintenance.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            if drain_deadline.is_some() && tasks.is_empty() {
+                info!("drained");
+                break;
+            }
+            let drain_timeout = async {
+                match drain_deadline {
+                    Some(x) => tokio::time::sleep_until(x).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = self.drain.notified(), if drain_deadline.is_none() => {
+                    info!(connections = tasks.len(), "draining");
+                    drain_deadline = Some(
+                        Instant::now() + Duration::from_secs_f64(self.options().drain_timeout),
+                    );
+                }
+                _ = drain_timeout => {
+                    info!(connections = tasks.len(), "drain timed out");
+                    break;
+                }
+                _ = maintenance.tick() => {
+                    let max_state_bytes = self.options().max_total_state_bytes;
+                    self.lock().maintain(max_state_bytes);
+                }
+                incoming = accepted.recv() => {
+                    let Some((incoming, policy)) = incoming else { break; };
+                    // Require address validation before committing any resources
+                    if !incoming.remote_address_validated() {
+                        incoming.retry().unwrap();
+                        continue;
+                    }
+                    // Identifies the connection if its handler panics
+                    let remote = incoming.remote_address();
+                    let span = tracing::error_span!("connection", %remote);
+                    let task = tasks.spawn(self.clone().dispatch(incoming, policy));
+                    spans.insert(task.id(), span);
+                }
+                Some(result) = tasks.join_next_with_id() => {
+                    let e = match result {
+                        Ok((id, ())) => {
+                            spans.remove(&id);
+                            continue;
+                        }
+                        Err(e) => e,
+                    };
+                    let span = spans.remove(&e.id()).unwrap_or_else(tracing::Span::none);
+                    if let Ok(panic) = e.try_into_panic() {
+                        let msg = panic
+                            .downcast_ref::<&str>()
+                            .copied()
+                            .or_else(|| panic.downcast_ref::<String>().map(|x| &x[..]))
+                            .unwrap_or("unknown cause");
+                        error!(parent: &span, panic = msg, "connection handler panicked");
+                    }
+                }
+            }
+        }
+        if drain_deadline.is_some() {
+            for (endpoint, _) in &listeners {
+                endpoint.close(close_code(ms::CloseCode::Draining), b"draining");
+            }
+            for (endpoint, _) in &listeners {
+                endpoint.wait_idle().await;
+            }
         }
         Ok(())
     }
 
-    async fn dispatch(self: Arc<Self>, conn: quinn::Connecting) {
-        match conn.await {
+    /// Stop accepting new connections, and exit once existing ones end or `drain_timeout` passes
+    fn start_drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.drain.notify_one();
+    }
+
+    /// Serve a connection accepted by a listener with `policy`
+    async fn dispatch(self: Arc<Self>, incoming: quinn::Incoming, policy: Arc<ListenerPolicy>) {
+        let address = incoming.remote_address();
+        let connecting = incoming.accept();
+        // Must be taken before yielding, as other connections may be accepted on this thread
+        let offered = alpn::take_offered().unwrap_or_default();
+        if offered.is_mixed() {
+            info!(%address, %offered, "peer offered both game and client protocols");
+        }
+        // Failure to agree on a protocol is detected immediately
+        let result = match connecting {
+            Ok(x) => x.await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(conn) if self.draining.load(Ordering::Relaxed) => {
+                debug!(%address, "refusing connection while draining");
+                conn.close(close_code(ms::CloseCode::Draining), b"draining");
+            }
             Ok(conn) => {
                 let hs = conn
-                    .connection
                     .handshake_data()
                     .unwrap()
                     .downcast::<quinn::crypto::rustls::HandshakeData>()
                     .unwrap();
                 match hs.protocol.as_ref().map(|x| &x[..]).unwrap() {
-                    ms::game::PROTOCOL => self.handle_server(conn).await,
-                    ms::client::PROTOCOL => self.handle_client(conn).await,
+                    ms::game::PROTOCOL => self.handle_server(conn, 1).await,
+                    ms::game::PROTOCOL_V2 => self.handle_server(conn, 2).await,
+                    ms::game::PROTOCOL_V3 => self.handle_server(conn, 3).await,
+                    ms::client::PROTOCOL => self.handle_client(conn, 1, policy).await,
+                    ms::client::PROTOCOL_V2 => self.handle_client(conn, 2, policy).await,
+                    ms::client::PROTOCOL_V3 => self.handle_client(conn, 3, policy).await,
                     _ => unreachable!(),
                 }
             }
             Err(e) => {
-                info!("handshake failed: {}", e);
+                let failure = alpn::Failure::of(&e);
+                let count = self.handshake_failures.record(failure);
+                info!(
+                    %address,
+                    %offered,
+                    reason = failure.as_str(),
+                    count,
+                    error = %e,
+                    "handshake failed"
+                );
             }
         }
     }
 
-    async fn handle_server(self: Arc<Self>, conn: quinn::NewConnection) {
-        let id = self.inner.lock().unwrap().servers.insert(Server {
-            state: Vec::new(),
-            address: None,
-        });
-        let span = tracing::error_span!("server", id);
+    /// Serve a game server speaking `version` of the game protocol
+    async fn handle_server(self: Arc<Self>, conn: quinn::Connection, version: u32) {
+        let refresh = Arc::new(Notify::new());
+        let id = self
+            .lock()
+            .add_server(refresh.clone(), Some(conn.clone()), Instant::now());
+        let span = tracing::error_span!("server", %id, version, peer_certificate = Empty);
+        if let Some(x) = fingerprint(&conn) {
+            span.record("peer_certificate", x);
+        }
         async move {
-            info!(address = %conn.connection.remote_address(), "connected");
-            if let Err(e) = self.server_inner(conn, id).await {
-                info!("connection lost: {}", e);
-                {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.servers.remove(id);
-                    for (_, client) in &mut inner.clients {
-                        client.dirty.remove(&id);
-                        client.lost.push(id);
-                    }
+            info!(address = %conn.remote_address(), "connected");
+            let activity = Activity::new();
+            let mut guard = ServerGuard {
+                state: &self,
+                conn: &conn,
+                id,
+            };
+            if let Err(e) = self
+                .server_inner(&conn, version, &mut guard.id, &refresh, &activity)
+                .await
+            {
+                let ending = Ending::of(&conn, false);
+                log_disconnect(&e, ending);
+                if ending == Ending::Abnormal {
+                    self.suspect_server(guard.id, &conn);
                 }
-                self.dirty.notify_waiters();
             }
+            drop(guard);
+            activity.log("game", Ending::of(&conn, false));
         }
         .instrument(span)
         .await;
     }
 
-    async fn server_inner(&self, mut conn: quinn::NewConnection, id: usize) -> Result<()> {
-        let hello = match conn.uni_streams.next().await {
-            Some(x) => x?,
-            None => return Ok(()),
+    /// Keep showing server `id`, whose connection `conn` was lost, for `removal_grace` in case it
+    /// reconnects and [claims](Self::claim_address) its entry
+    ///
+    /// Afterwards, or if no grace period is configured, the entry is removed as usual.
+    fn suspect_server(self: &Arc<Self>, id: ServerId, conn: &quinn::Connection) {
+        let Some(grace) = self.options().removal_grace else {
+            return;
         };
-        let hello = hello.read_to_end(self.options.state_size).await?;
-        let hello = bincode::deserialize::<ms::game::Hello>(&hello).context("decoding hello")?;
+        let lost_at = Instant::now();
+        if !self.lock().lose_server(id, conn, lost_at) {
+            return;
+        }
+        debug!(grace, "keeping lost server in case it reconnects");
+        let state = self.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(Duration::from_secs_f64(grace)).await;
+                let expired = state.lock().expire_server(id, lost_at, Instant::now());
+                if expired {
+                    info!("lost server didn't reconnect");
+                    state.dirty.notify();
+                }
+            }
+            .in_current_span(),
+        );
+    }
 
-        while let Some(stream) = conn.uni_streams.next().await {
-            let stream = stream?;
-            let state = stream.read_to_end(self.options.state_size).await?;
-            let addr = SocketAddr::new(conn.connection.remote_address().ip(), hello.port);
-            let dirty = {
-                let mut inner = self.inner.lock().unwrap();
-                let server = &mut inner.servers[id];
-                let dirty = state != server.state || Some(addr) != server.address;
-                if dirty {
-                    server.state = state;
-                    server.address = Some(addr);
-                    for (_, client) in &mut inner.clients {
-                        client.dirty.insert(id);
+    /// Serve a game server's connection, updating `id` if it takes over another server's entry
+    async fn server_inner(
+        &self,
+        conn: &quinn::Connection,
+        version: u32,
+        id: &mut ServerId,
+        refresh: &Notify,
+        activity: &Activity,
+    ) -> Result<()> {
+        let mut hello = conn.accept_uni().await?;
+        let hello = hello
+            .read_to_end(self.options().state_size + ms::game::MAX_UPDATE_OVERHEAD)
+            .await?;
+        activity.read(hello.len());
+        let hello = match version {
+            1 => ms::decode::<ms::game::Hello>(&hello).map(|x| ms::game::HelloV2 {
+                port: x.port,
+                tags: Vec::new(),
+                introductions: false,
+                lan_addresses: Vec::new(),
+            }),
+            _ => ms::decode::<ms::game::HelloV2>(&hello),
+        }
+        .inspect_err(|_| activity.parse_failure())
+        .context("decoding hello")?;
+        let addr = advertised_address(conn, hello.port);
+        if let Err(reason) = validate_address(addr).and(validate_hello(&hello)) {
+            conn.close(
+                close_code(ms::CloseCode::ProtocolViolation),
+                reason.as_bytes(),
+            );
+            bail!("invalid hello: {}", reason);
+        }
+        if let Some(difficulty) = self.challenge_difficulty(conn) {
+            if version < 3 {
+                conn.close(
+                    close_code(ms::CloseCode::Rejected),
+                    b"proof of work required",
+                );
+                bail!("rejected: too old to be challenged");
+            }
+            self.challenge(conn, difficulty, activity).await?;
+        }
+        let placeholder = *id;
+        *id = self.claim_address(*id, addr);
+        // Replacements are exempt, since they should be roughly the same size
+        if *id == placeholder && self.options().state_budget_policy == BudgetPolicy::Reject {
+            let full = self
+                .options()
+                .max_total_state_bytes
+                .is_some_and(|max| self.lock().state_bytes >= max);
+            if full {
+                conn.close(
+                    close_code(ms::CloseCode::Rejected),
+                    b"state budget exhausted",
+                );
+                bail!("rejected: state budget exhausted");
+            }
+        }
+        self.set_tags(*id, Some(conn), hello.tags)?;
+        let (send, introductions) = mpsc::channel(MAX_QUEUED_INTRODUCTIONS);
+        if let Some(server) = self.lock().server_mut(*id, Some(conn)) {
+            server.introductions = hello.introductions.then_some(send);
+            server.lan_addresses = hello.lan_addresses;
+        }
+        if version >= 2 {
+            let welcome = ms::game::Welcome {
+                version: VERSION.into(),
+                limits: self.limits(limit_for(version, self.options().state_size)),
+            };
+            let msg = match version {
+                2 => bincode::serialize(&welcome),
+                _ => bincode::serialize(&ms::game::Greeting::Welcome(welcome)),
+            };
+            welcome_peer(conn, &msg.unwrap(), activity).await?;
+        }
+
+        let port = AtomicU16::new(hello.port);
+        tokio::select! {
+            result = self.read_heartbeats(conn, version, *id, &port, activity) => result,
+            result = self.send_refreshes(conn, refresh, activity) => result,
+            result = self.send_introductions(conn, introductions, activity) => result,
+            result = self.watch_address(conn, *id, &port) => result,
+            result = self.send_observed_addresses(conn, version, *id, activity) => result,
+            result = self.send_limits(conn, version, activity) => result,
+        }
+    }
+
+    /// Difficulty of the challenge a game server registering over `conn` must solve, if any
+    fn challenge_difficulty(&self, conn: &quinn::Connection) -> Option<u8> {
+        let difficulty = self.options().challenge_difficulty?;
+        let busy = match *self
+            .registrations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(ref mut x) => x.spend(1) > Duration::ZERO,
+            None => true,
+        };
+        // A client certificate is proof enough
+        (busy && difficulty > 0 && conn.peer_identity().is_none()).then_some(difficulty)
+    }
+
+    /// Make a game server prove work before it's registered, closing the connection if it doesn't
+    async fn challenge(
+        &self,
+        conn: &quinn::Connection,
+        difficulty: u8,
+        activity: &Activity,
+    ) -> Result<()> {
+        let challenge = ms::game::Challenge {
+            prefix: rand::random(),
+            difficulty,
+            timeout: Duration::from_secs_f64(self.options().challenge_timeout),
+        };
+        debug!(difficulty, "challenging");
+        let msg = bincode::serialize(&ms::game::Greeting::Challenge(challenge)).unwrap();
+        welcome_peer(conn, &msg, activity).await?;
+        let solution = tokio::time::timeout(challenge.timeout, async {
+            let mut stream = conn.accept_uni().await?;
+            let msg = stream.read_to_end(MAX_SOLUTION_SIZE).await?;
+            activity.read(msg.len());
+            ms::decode::<ms::game::Solution>(&msg)
+                .inspect_err(|_| activity.parse_failure())
+                .context("decoding solution")
+        })
+        .await;
+        let Ok(solution) = solution else {
+            conn.close(close_code(ms::CloseCode::Rejected), b"challenge timed out");
+            bail!("rejected: challenge timed out");
+        };
+        if !solves(&challenge, solution?.nonce) {
+            conn.close(close_code(ms::CloseCode::Rejected), b"wrong solution");
+            bail!("rejected: wrong solution to challenge");
+        }
+        Ok(())
+    }
+
+    /// Read a game server's heartbeats and, since version 2, other updates
+    ///
+    /// Returns once the game server says goodbye.
+    async fn read_heartbeats(
+        &self,
+        conn: &quinn::Connection,
+        version: u32,
+        id: ServerId,
+        port: &AtomicU16,
+        activity: &Activity,
+    ) -> Result<()> {
+        let limit = limit_for(version, self.options().state_size);
+        // Updates received too soon after the last one are held back, with later ones replacing
+        // earlier ones. The connection is still read meanwhile, so that its loss is noticed.
+        let mut next = Instant::now();
+        let mut state = None;
+        let mut seq = Sequence::default();
+        let mut bandwidth = None;
+        let mut tags = None;
+        loop {
+            // Reread every time, in case the configuration was reloaded
+            let options = self.options();
+            Bucket::reconfigure(&mut bandwidth, options.max_heartbeat_bandwidth);
+            let held = state.is_some() || tags.is_some();
+            let ready = async {
+                if held {
+                    tokio::time::sleep_until(next).await
+                } else {
+                    future::pending().await
+                }
+            };
+            tokio::select! {
+                stream = conn.accept_uni() => {
+                    let data = stream?.read_to_end(limit).await?;
+                    activity.read(data.len());
+                    if let Some(ref mut bandwidth) = bandwidth {
+                        let debt = bandwidth.spend(data.len());
+                        if debt > MAX_BANDWIDTH_DEBT {
+                            conn.close(close_code(ms::CloseCode::Rejected), b"bandwidth exceeded");
+                            bail!("bandwidth exceeded");
+                        }
+                        if debt > Duration::ZERO {
+                            // Stretch the interval until the game server is back within budget
+                            activity.over_budget();
+                            next = next.max(Instant::now() + debt);
+                        }
                     }
+                    let update = match version {
+                        1 => Ok(ms::game::Update::State(&data)),
+                        _ => ms::decode(&data),
+                    };
+                    match update {
+                        Ok(ms::game::Update::State(x)) if x.len() <= options.state_size => {
+                            state = Some(x.to_vec());
+                        }
+                        Ok(ms::game::Update::SequencedState { seq: n, state: x })
+                            if x.len() <= options.state_size =>
+                        {
+                            if !seq.advance(n) {
+                                debug!(seq = n, "ignoring stale state");
+                                activity.stale();
+                                continue;
+                            }
+                            state = Some(x.to_vec());
+                        }
+                        Ok(ms::game::Update::Tags(x)) if ms::game::tags_valid(&x) => {
+                            tags = Some(x);
+                        }
+                        // Applied immediately, like a migration
+                        Ok(ms::game::Update::PortChange(x)) if x != 0 => {
+                            info!(old = port.load(Ordering::Relaxed), new = x, "port changed");
+                            port.store(x, Ordering::Relaxed);
+                            self.check_address(conn, id, x)?;
+                            continue;
+                        }
+                        Ok(ms::game::Update::Goodbye { reason }) => {
+                            info!(reason, "goodbye");
+                            conn.close(close_code(ms::CloseCode::Normal), b"goodbye");
+                            return Ok(());
+                        }
+                        _ => {
+                            activity.parse_failure();
+                            conn.close(
+                                close_code(ms::CloseCode::ProtocolViolation),
+                                b"malformed update",
+                            );
+                            bail!("malformed update");
+                        }
+                    }
+                    if Instant::now() < next {
+                        activity.throttled();
+                        continue;
+                    }
+                }
+                _ = ready => {}
+            }
+            if let Some(tags) = tags.take() {
+                self.set_tags(id, Some(conn), tags)?;
+            }
+            if let Some(state) = state.take() {
+                let _span = tracing::info_span!("heartbeat", bytes = state.len()).entered();
+                #[cfg(test)]
+                inject_panic(&state);
+                if let Some(e) = self
+                    .validators
+                    .iter()
+                    .find_map(|x| x.validate(&state).err())
+                {
+                    activity.parse_failure();
+                    conn.close(close_code(ms::CloseCode::Rejected), b"invalid state");
+                    bail!("invalid state: {}", e);
+                }
+                let addr = advertised_address(conn, port.load(Ordering::Relaxed));
+                if let Err(e) = self.update_server(id, Some(conn), addr, state) {
+                    conn.close(
+                        close_code(ms::CloseCode::Rejected),
+                        b"state budget exhausted",
+                    );
+                    return Err(e);
                 }
-                dirty
+            }
+            next = Instant::now() + Duration::from_secs_f64(options.heartbeat_min_interval);
+        }
+    }
+
+    /// Forward refresh requests to a game server, at a limited rate
+    async fn send_refreshes(
+        &self,
+        conn: &quinn::Connection,
+        refresh: &Notify,
+        activity: &Activity,
+    ) -> Result<()> {
+        let msg = bincode::serialize(&ms::game::Control::RefreshRequest).unwrap();
+        loop {
+            // Requests made while we're sleeping are coalesced into a single stored permit
+            refresh.notified().await;
+            // Blocks indefinitely if the game server doesn't read control streams, which is fine
+            let sending = activity.sending();
+            let mut stream = conn.open_uni().await?;
+            stream.write_all(&msg).await?;
+            drop((stream, sending));
+            activity.wrote(msg.len());
+            tokio::time::sleep(Duration::from_secs(self.options().refresh_interval)).await;
+        }
+    }
+
+    /// Forward introductions to a game server, at a limited rate
+    async fn send_introductions(
+        &self,
+        conn: &quinn::Connection,
+        mut queue: mpsc::Receiver<ms::game::Introduction>,
+        activity: &Activity,
+    ) -> Result<()> {
+        while let Some(x) = queue.recv().await {
+            let msg = bincode::serialize(&ms::game::Control::Introduce(x)).unwrap();
+            // Blocks indefinitely if the game server doesn't read control streams, which is fine
+            let sending = activity.sending();
+            let mut stream = conn.open_uni().await?;
+            stream.write_all(&msg).await?;
+            drop((stream, sending));
+            activity.wrote(msg.len());
+            tokio::time::sleep(INTRODUCTION_INTERVAL).await;
+        }
+        // The game server doesn't accept introductions
+        future::pending().await
+    }
+
+    /// Notice when a game server's connection migrates, rather than waiting for its next heartbeat
+    async fn watch_address(
+        &self,
+        conn: &quinn::Connection,
+        id: ServerId,
+        port: &AtomicU16,
+    ) -> Result<()> {
+        let mut interval = tokio::time::interval(ADDRESS_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check_address(conn, id, port.load(Ordering::Relaxed))?;
+        }
+    }
+
+    /// Tell a game server once its first heartbeat is stored, and the address game clients are
+    /// told to connect to whenever it changes
+    async fn send_observed_addresses(
+        &self,
+        conn: &quinn::Connection,
+        version: u32,
+        id: ServerId,
+        activity: &Activity,
+    ) -> Result<()> {
+        if version < 2 {
+            return future::pending().await;
+        }
+        let mut interval = tokio::time::interval(ADDRESS_POLL_INTERVAL);
+        let mut sent = None;
+        loop {
+            interval.tick().await;
+            // Unset until the first heartbeat
+            let address = self
+                .lock()
+                .server_mut(id, Some(conn))
+                .and_then(|x| x.address);
+            let Some(address) = address.filter(|&x| Some(x) != sent) else {
+                continue;
             };
-            if dirty {
-                self.dirty.notify_waiters();
+            let mut msgs = vec![ms::game::Control::ObservedAddress(address)];
+            if sent.is_none() {
+                let advertisement = ms::game::Advertisement {
+                    id: id.wire(),
+                    address,
+                };
+                msgs.insert(0, ms::game::Control::Advertised(advertisement));
+            }
+            for msg in msgs {
+                let msg = bincode::serialize(&msg).unwrap();
+                // Blocks indefinitely if the game server doesn't read control streams, which is
+                // fine
+                let sending = activity.sending();
+                let mut stream = conn.open_uni().await?;
+                stream.write_all(&msg).await?;
+                drop((stream, sending));
+                activity.wrote(msg.len());
+            }
+            sent = Some(address);
+        }
+    }
+
+    /// Tell a game server whenever reloading the configuration changes its limits
+    async fn send_limits(
+        &self,
+        conn: &quinn::Connection,
+        version: u32,
+        activity: &Activity,
+    ) -> Result<()> {
+        if version < 2 {
+            return future::pending().await;
+        }
+        let mut options = self.options.subscribe();
+        let max_message_size = limit_for(version, options.borrow().state_size);
+        // As sent in the welcome, give or take a reload in the meantime
+        let mut sent = limits_of(&options.borrow_and_update(), max_message_size);
+        loop {
+            options.changed().await?;
+            let limits = limits_of(&options.borrow_and_update(), max_message_size);
+            if limits == sent {
+                continue;
             }
-            // Read at most one heartbeat per second
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            let msg = bincode::serialize(&ms::game::Control::Limits(limits)).unwrap();
+            // Blocks indefinitely if the game server doesn't read control streams, which is fine
+            let sending = activity.sending();
+            let mut stream = conn.open_uni().await?;
+            stream.write_all(&msg).await?;
+            drop((stream, sending));
+            activity.wrote(msg.len());
+            sent = limits;
         }
+    }
 
+    /// Forward any change in game server `id`'s address, given the port it advertises
+    fn check_address(&self, conn: &quinn::Connection, id: ServerId, port: u16) -> Result<()> {
+        let addr = advertised_address(conn, port);
+        // Looked up before locking, as it may be slow
+        let region = self.region(addr.ip());
+        let changed = self
+            .lock()
+            .move_server(id, Some(conn), addr, region, Instant::now())?;
+        if changed {
+            self.dirty.notify();
+        }
         Ok(())
     }
 
-    async fn handle_client(self: Arc<Self>, conn: quinn::NewConnection) {
-        let id = {
-            let mut inner = self.inner.lock().unwrap();
-            let client = Client {
-                dirty: inner.servers.iter().map(|(id, _)| id).collect(),
-                lost: Vec::new(),
-            };
-            inner.clients.insert(client)
+    /// If another game server is registered at `addr`, replace it with the game server
+    /// registered as `id`, returning the ID now in use
+    ///
+    /// See [`Core::claim_address`].
+    fn claim_address(&self, id: ServerId, addr: SocketAddr) -> ServerId {
+        self.lock().claim_address(id, addr, Instant::now())
+    }
+
+    /// Store the latest state of server `id`, notifying clients if it changed
+    ///
+    /// `conn` is the connection the update arrived on, or `None` for fake servers. Fails if the
+    /// server's entry has been taken over or evicted, or if the state can't be stored within the
+    /// state budget.
+    fn update_server(
+        &self,
+        id: ServerId,
+        conn: Option<&quinn::Connection>,
+        addr: SocketAddr,
+        state: Vec<u8>,
+    ) -> Result<()> {
+        // Computed before locking, as they may be slow
+        let heartbeat = Heartbeat {
+            address: addr,
+            region: self.region(addr.ip()),
+            load: load(&state),
+            state,
         };
-        let span = tracing::error_span!("client", id);
+        let options = self.options();
+        let budget = options
+            .max_total_state_bytes
+            .map(|x| (x, options.state_budget_policy));
+        let dirty = self
+            .lock()
+            .update_server(id, conn, heartbeat, budget, Instant::now())?;
+        if dirty {
+            self.dirty.notify();
+        }
+        Ok(())
+    }
+
+    /// Replace the tags of server `id`, showing or hiding it from clients whose filters it now
+    /// matches or no longer matches
+    ///
+    /// Fails if the server's entry has been taken over by a connection other than `conn`.
+    fn set_tags(
+        &self,
+        id: ServerId,
+        conn: Option<&quinn::Connection>,
+        tags: Vec<String>,
+    ) -> Result<()> {
+        let visible = self.lock().set_tags(id, conn, tags, Instant::now())?;
+        if visible {
+            self.dirty.notify();
+        }
+        Ok(())
+    }
+
+    /// Forget server `id`, notifying clients of its shutdown
+    ///
+    /// Does nothing if the server's entry has been taken over by a connection other than `conn`.
+    fn remove_server(&self, id: ServerId, conn: Option<&quinn::Connection>) {
+        if self.lock().remove_server(id, conn, Instant::now()) {
+            self.dirty.notify();
+        }
+    }
+
+    /// Serve a client speaking `version` of the client protocol
+    async fn handle_client(
+        self: Arc<Self>,
+        conn: quinn::Connection,
+        version: u32,
+        policy: Arc<ListenerPolicy>,
+    ) {
+        let id = self.lock().add_client(version, policy);
+        let span = tracing::error_span!("client", %id, version, peer_certificate = Empty);
+        if let Some(x) = fingerprint(&conn) {
+            span.record("peer_certificate", x);
+        }
         async move {
-            info!(address = %conn.connection.remote_address(), "connected");
-            if let Err(e) = self.client_inner(conn, id).await {
-                info!("connection lost: {}", e);
-                {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.clients.remove(id);
-                }
+            info!(address = %conn.remote_address(), "connected");
+            let activity = Activity::new();
+            let guard = ClientGuard { state: &self, id };
+            if let Err(e) = self.client_inner(&conn, id, version, &activity).await {
+                log_disconnect(&e, Ending::of(&conn, true));
             }
+            drop(guard);
+            activity.log("client", Ending::of(&conn, true));
         }
         .instrument(span)
         .await;
     }
 
-    async fn client_inner(&self, mut conn: quinn::NewConnection, id: usize) -> Result<()> {
-        loop {
-            let mut stream = conn.connection.open_uni().await?;
-            let msg = {
-                let inner = &mut *self.inner.lock().unwrap();
-                let client = &mut inner.clients[id];
-                let msg = ms::client::Message {
-                    servers: client
-                        .lost
-                        .drain(..)
-                        .map(|id| ms::client::Server {
-                            id: id as u64,
-                            event: ms::client::Event::Shutdown,
-                        })
-                        .chain(client.dirty.drain(..).map(|id| {
-                            let x = &inner.servers[id];
-                            ms::client::Server {
-                                id: id as u64,
-                                event: ms::client::Event::Update(
-                                    x.address.expect("dirty server without addr"),
-                                    &x.state,
-                                ),
-                            }
-                        }))
-                        .collect(),
-                };
-                bincode::serialize(&msg).unwrap()
-            };
-            stream.write_all(&msg).await?;
-            drop(stream);
+    async fn client_inner(
+        &self,
+        conn: &quinn::Connection,
+        id: ClientId,
+        version: u32,
+        activity: &Activity,
+    ) -> Result<()> {
+        if version < 3 {
+            return self.send_updates(conn, id, version, activity).await;
+        }
+        tokio::select! {
+            result = self.send_updates(conn, id, version, activity) => result,
+            result = self.answer_queries(conn, id, activity) => result,
+        }
+    }
 
-            // Update at most once per second
-            let dirty = self.dirty.notified();
-            let should_transmit = async move {
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                dirty.await;
+    /// Answer a client's queries, each made on its own bidirectional stream, at a limited rate
+    async fn answer_queries(
+        &self,
+        conn: &quinn::Connection,
+        id: ClientId,
+        activity: &Activity,
+    ) -> Result<()> {
+        let mut rng = StdRng::from_entropy();
+        let mut prev = None;
+        let mut prev_resync = None::<Instant>;
+        loop {
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let query = recv.read_to_end(MAX_CLIENT_REQUEST_SIZE).await?;
+            activity.read(query.len());
+            let query = ms::decode::<ms::client::Request>(&query)
+                .ok()
+                .filter(|x| match x {
+                    ms::client::Request::FindOne { filter, .. } => filter.is_valid(),
+                    ms::client::Request::Connect { .. }
+                    | ms::client::Request::Resync
+                    | ms::client::Request::GetServer { .. } => true,
+                    _ => false,
+                });
+            let Some(query) = query else {
+                activity.parse_failure();
+                conn.close(
+                    close_code(ms::CloseCode::ProtocolViolation),
+                    b"malformed query",
+                );
+                bail!("malformed query");
             };
-            tokio::select! {
-                _ = should_transmit => {}
-                Some(Err(e)) = conn.bi_streams.next() => {
-                    return Err(e.into());
+            // Reread every time, in case the configuration was reloaded
+            let min_interval = Duration::from_secs_f64(self.options().client_query_interval);
+            let now = Instant::now();
+            let response = if prev.is_some_and(|x| now - x < min_interval)
+                || matches!(query, ms::client::Request::Resync)
+                    && prev_resync.is_some_and(|x| now - x < RESYNC_INTERVAL)
+            {
+                activity.throttled();
+                bincode::serialize(&ms::client::Response::RateLimited)
+            } else {
+                prev = Some(now);
+                match query {
+                    ms::client::Request::FindOne { filter, strategy } => {
+                        #[cfg(test)]
+                        filter
+                            .required
+                            .iter()
+                            .for_each(|x| inject_panic(x.as_bytes()));
+                        let inner = self.lock();
+                        let policy = &inner.clients[id].policy;
+                        let filter = policy.restrict(filter);
+                        let found = inner
+                            .find_one(&filter, strategy, &mut rng)
+                            .map(|x| inner.servers[x].found(x, conn.remote_address().ip(), policy));
+                        debug!(?filter, ?strategy, found = ?found.as_ref().map(|x| x.id), "query");
+                        bincode::serialize(&ms::client::Response::FindOne(found))
+                    }
+                    ms::client::Request::Connect { server_id } => {
+                        bincode::serialize(&self.introduce(conn, id, server_id, &mut rng))
+                    }
+                    ms::client::Request::Resync => {
+                        prev_resync = Some(now);
+                        let mut inner = self.lock();
+                        // Unsubscribed clients haven't been sent anything to resync
+                        if inner.clients[id].subscribed {
+                            debug!("resync requested");
+                            inner.reset(id);
+                            drop(inner);
+                            self.dirty.notify();
+                        }
+                        bincode::serialize(&ms::client::Response::Resyncing)
+                    }
+                    ms::client::Request::GetServer { server_id } => {
+                        let refresh_after = Duration::from_secs(self.options().refresh_interval);
+                        let inner = self.lock();
+                        // The live table, as the client's own view may lag behind
+                        let client = &inner.clients[id];
+                        let found = ServerId::from_wire(server_id)
+                            .and_then(|id| Some((id, inner.servers.get(id)?)))
+                            .filter(|(_, x)| x.address.is_some() && x.matches(&client.filter))
+                            .map(|(id, x)| {
+                                if x.last_heartbeat.elapsed() >= refresh_after {
+                                    x.refresh.notify_one();
+                                }
+                                x.found(id, conn.remote_address().ip(), &client.policy)
+                            });
+                        debug!(%server_id, found = found.is_some(), "server queried");
+                        bincode::serialize(&ms::client::Response::Server(found))
+                    }
+                    _ => unreachable!(),
                 }
             }
+            .unwrap();
+            send.write_all(&response).await?;
+            send.finish()?;
+            activity.wrote(response.len());
         }
     }
-}
 
-struct Inner {
-    servers: Slab<Server>,
-    clients: Slab<Client>,
-}
+    /// Tell game server `server_id` that client `client_id` on `conn` wants to connect, if it
+    /// accepts introductions and the client may see it
+    fn introduce(
+        &self,
+        conn: &quinn::Connection,
+        client_id: ClientId,
+        server_id: ms::client::ServerId,
+        rng: &mut impl Rng,
+    ) -> ms::client::Response<'static> {
+        let inner = self.lock();
+        let policy = &inner.clients[client_id].policy;
+        let target = ServerId::from_wire(server_id)
+            .and_then(|id| inner.servers.get(id))
+            .filter(|x| x.address.is_some() && policy.shows(&x.tags, x.region))
+            .and_then(|x| Some((x.connection.as_ref()?, x.introductions.as_ref()?)));
+        let Some((server, queue)) = target else {
+            debug!(%server_id, "introduction refused");
+            return ms::client::Response::Introduced(None);
+        };
+        let token = rng.gen();
+        let introduction = ms::game::Introduction {
+            address: canonical(conn.remote_address()),
+            token,
+        };
+        if queue.try_send(introduction).is_err() {
+            debug!(%server_id, "introduction queue full");
+            return ms::client::Response::RateLimited;
+        }
+        debug!(%server_id, "introduced");
+        ms::client::Response::Introduced(Some(ms::client::Introduction {
+            address: canonical(server.remote_address()),
+            token,
+        }))
+    }
 
-struct Server {
-    address: Option<SocketAddr>,
-    state: Vec<u8>,
-}
+    /// Limits to advertise to a peer whose messages are read up to `max_message_size` bytes
+    fn limits(&self, max_message_size: usize) -> ms::Limits {
+        limits_of(&self.options(), max_message_size)
+    }
 
-struct Client {
-    dirty: IndexSet<usize>,
-    lost: Vec<usize>,
+    /// Send a client updates about the servers it can see, acting on any requests it makes
+    async fn send_updates(
+        &self,
+        conn: &quinn::Connection,
+        id: ClientId,
+        version: u32,
+        activity: &Activity,
+    ) -> Result<()> {
+        // Longest interval the client asked for
+        let mut requested_interval = Duration::ZERO;
+        let request = read_client_request(conn, version, activity);
+        tokio::pin!(request);
+        let mut requests_done = false;
+        // Seeded by ID so that behavior is reproducible
+        let mut rng = StdRng::seed_from_u64(id.index() as u64);
+        let mut bandwidth = None;
+        if version >= 3 {
+            let welcome = ms::client::Welcome {
+                version: VERSION.into(),
+                limits: self.limits(MAX_CLIENT_REQUEST_SIZE),
+                instance: self.instance,
+                table_version: self.lock().table_version,
+            };
+            welcome_peer(conn, &bincode::serialize(&welcome).unwrap(), activity).await?;
+        }
+        if version >= 3 {
+            // Wait for the client's first request, so that any filter applies to the snapshot, and
+            // so that clients that only make queries aren't sent updates. Truncation may be asked
+            // for beforehand, so that it applies to the snapshot too.
+            loop {
+                tokio::select! {
+                    result = &mut request => {
+                        let truncation =
+                            matches!(result, Ok(ms::client::Request::TruncateStates(_)));
+                        let more = self.handle_request(
+                            conn,
+                            id,
+                            version,
+                            result,
+                            &mut requested_interval,
+                        )?;
+                        if more {
+                            request.set(read_client_request(conn, version, activity));
+                        } else {
+                            requests_done = true;
+                        }
+                        if !truncation || requests_done {
+                            break;
+                        }
+                    }
+                    e = conn.closed() => {
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        let waiter = self.dirty.register();
+        self.lock().subscribe(id);
+        // The first message carries every visible server, unless the client asked for pacing
+        let mut snapshot = true;
+        loop {
+            // Reread every time, in case the configuration was reloaded
+            let options = self.options();
+            // Clients may slow updates down, but not speed them up
+            let interval =
+                Duration::from_secs_f64(options.client_update_interval).max(requested_interval);
+            let jitter = options.update_jitter;
+            let keepalive = options.client_keepalive.map(Duration::from_secs_f64);
+            let send_timeout = options.client_send_timeout.map(Duration::from_secs_f64);
+            Bucket::reconfigure(&mut bandwidth, options.max_client_bandwidth);
+            // Rechecked every time, in case the client migrated
+            let client_ip = conn.remote_address().ip();
+            let span = tracing::info_span!("update", servers = Empty, bytes = Empty);
+            let msg = span.in_scope(|| {
+                let inner = &mut *self.lock();
+                let (servers, msg) = inner.take_update(
+                    id,
+                    version,
+                    client_ip,
+                    interval,
+                    &mut snapshot,
+                    Instant::now(),
+                );
+                span.record("servers", servers);
+                span.record("bytes", msg.len());
+                msg
+            });
+            activity.wrote(msg.len());
+            let debt = bandwidth
+                .as_mut()
+                .map_or(Duration::ZERO, |x| x.spend(msg.len()));
+            if debt > Duration::ZERO {
+                activity.over_budget();
+            }
+            // Must finish before the next message is sent, so that clients on slow links get fewer,
+            // larger updates rather than an ever-growing backlog
+            let mut sending = Some(Box::pin(transmit(conn, msg, activity)));
+            let sent = Instant::now();
+            // Nothing more is sent until the client is back within its budget
+            let earliest = sent + debt;
+            let scale = 1.0 + rng.gen_range(-jitter..=jitter);
+
+            // Wait until there's something to send. Regular updates are paced by `interval`, but
+            // shutdowns are forwarded promptly so clients don't try to join dead servers. Absent
+            // either, a keep-alive is sent if enabled.
+            let mut held = false;
+            let mut overdue = false;
+            loop {
+                // Register for notifications before checking, so none are missed
+                let notified = waiter.notified();
+                let deadline = {
+                    let client = &self.lock().clients[id];
+                    if !client.lost.is_empty() {
+                        Some(sent + SHUTDOWN_UPDATE_INTERVAL.min(interval))
+                    } else if !client.dirty.is_empty()
+                        || !client.snapshot.is_empty()
+                        || client.generation.is_some()
+                        || client.reset
+                        || client.limits.is_some()
+                    {
+                        Some(sent + interval.mul_f64(scale))
+                    } else {
+                        None
+                    }
+                };
+                let deadline = deadline.map(|x| x.max(earliest));
+                held |= deadline.is_some_and(|x| x > Instant::now());
+                let deadline = deadline.or(keepalive.map(|x| (sent + x).max(earliest)));
+                let ready = async {
+                    match deadline {
+                        Some(x) => tokio::time::sleep_until(x).await,
+                        None => future::pending().await,
+                    }
+                };
+                let timed_out = async {
+                    match send_timeout {
+                        Some(x) => tokio::time::sleep_until(sent + x).await,
+                        None => future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = ready, if !overdue => {
+                        if sending.is_none() {
+                            break;
+                        }
+                        // Changes accumulate in the meantime, to be sent together
+                        overdue = true;
+                        activity.coalesced();
+                    }
+                    result = async { sending.as_mut().unwrap().await }, if sending.is_some() => {
+                        result?;
+                        sending = None;
+                        if overdue {
+                            break;
+                        }
+                    }
+                    _ = timed_out, if sending.is_some() => {
+                        conn.close(close_code(ms::CloseCode::Rejected), b"too slow");
+                        bail!("client too slow to receive updates");
+                    }
+                    _ = notified => {}
+                    result = &mut request, if !requests_done => {
+                        let more = self.handle_request(
+                            conn,
+                            id,
+                            version,
+                            result,
+                            &mut requested_interval,
+                        )?;
+                        if more {
+                            request.set(read_client_request(conn, version, activity));
+                        } else {
+                            requests_done = true;
+                        }
+                    }
+                    e = conn.closed() => {
+                        return Err(e.into());
+                    }
+                }
+            }
+            if held {
+                activity.throttled();
+            }
+        }
+    }
+
+    /// Act on a request read from client `id`, returning whether to read another
+    fn handle_request(
+        &self,
+        conn: &quinn::Connection,
+        id: ClientId,
+        version: u32,
+        result: Result<ms::client::Request>,
+        requested_interval: &mut Duration,
+    ) -> Result<bool> {
+        let request = match result {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(error = %format_args!("{:#}", e), "failed to read request");
+                return Ok(false);
+            }
+        };
+        match request {
+            ms::client::Request::UpdateInterval(x) => {
+                *requested_interval = x;
+                debug!(interval = ?x, "client requested update interval");
+            }
+            ms::client::Request::FindOne { .. }
+            | ms::client::Request::Connect { .. }
+            | ms::client::Request::Resync
+            | ms::client::Request::GetServer { .. } => {
+                debug!("ignoring query made on a unidirectional stream");
+            }
+            ms::client::Request::TruncateStates(x) => {
+                debug!(max = ?x, "client requested state truncation");
+                self.lock().clients[id].max_state = x.map(|x| x as usize);
+            }
+            ms::client::Request::Subscribe {
+                filter,
+                generation,
+                pacing,
+            } => {
+                if !filter.is_valid() {
+                    conn.close(
+                        close_code(ms::CloseCode::ProtocolViolation),
+                        b"invalid filter",
+                    );
+                    bail!("invalid filter");
+                }
+                debug!(?filter, generation, ?pacing, "client set filter");
+                let inner = &mut *self.lock();
+                // Only the initial snapshot is paced
+                let client = &mut inner.clients[id];
+                if !client.subscribed {
+                    client.pacing = pacing;
+                }
+                inner.set_filter(id, filter, generation);
+            }
+        }
+        // Earlier versions only have a single hello
+        Ok(version >= 3)
+    }
+}
+
+/// Removes a game server's entry when its connection handler exits, even by panicking
+struct ServerGuard<'a> {
+    state: &'a State,
+    conn: &'a quinn::Connection,
+    id: ServerId,
+}
+
+impl Drop for ServerGuard<'_> {
+    fn drop(&mut self) {
+        self.state.remove_server(self.id, Some(self.conn));
+    }
+}
+
+/// Removes a client's entry when its connection handler exits, even by panicking
+struct ClientGuard<'a> {
+    state: &'a State,
+    id: ClientId,
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lock().remove_client(self.id);
+    }
+}
+
+/// Panic if `payload` asks to, so that tests can check how connection handlers clean up
+#[cfg(test)]
+fn inject_panic(payload: &[u8]) {
+    assert_ne!(payload, b"panic!", "injected panic");
+}
+
+/// Log the failure of a connection handler, more loudly the less expected it was
+fn log_disconnect(e: &anyhow::Error, ending: Ending) {
+    let ending_str = ending.as_str();
+    match ending {
+        Ending::Clean => debug!(ending = ending_str, "disconnected"),
+        Ending::Expected => info!(ending = ending_str, error = %e, "connection closed"),
+        Ending::Abnormal => warn!(ending = ending_str, error = %e, "connection lost"),
+    }
+}
+
+/// Largest message accepted from a game server speaking `version` of the game protocol
+fn limit_for(version: u32, state_size: usize) -> usize {
+    match version {
+        1 => state_size,
+        _ => state_size + ms::game::MAX_UPDATE_OVERHEAD,
+    }
+}
+
+/// Send a welcome message, which must be the first stream we open
+async fn welcome_peer(conn: &quinn::Connection, msg: &[u8], activity: &Activity) -> Result<()> {
+    let _sending = activity.sending();
+    let mut stream = conn.open_uni().await?;
+    stream.write_all(msg).await?;
+    stream.finish()?;
+    activity.wrote(msg.len());
+    Ok(())
+}
+
+/// Send `msg` on a new stream, finishing once the peer has received all of it
+async fn transmit(conn: &quinn::Connection, msg: Vec<u8>, activity: &Activity) -> Result<()> {
+    let _sending = activity.sending();
+    let mut stream = conn.open_uni().await?;
+    stream.write_all(&msg).await?;
+    stream.finish()?;
+    stream.stopped().await?;
+    Ok(())
+}
+
+/// Read a request from a client speaking `version` of the client protocol
+///
+/// Before version 3, this is the optional hello, expressed as the equivalent request.
+async fn read_client_request(
+    conn: &quinn::Connection,
+    version: u32,
+    activity: &Activity,
+) -> Result<ms::client::Request> {
+    let mut stream = conn.accept_uni().await?;
+    let request = stream.read_to_end(MAX_CLIENT_REQUEST_SIZE).await?;
+    activity.read(request.len());
+    match version {
+        1 | 2 => ms::decode::<ms::client::Hello>(&request)
+            .map(|x| ms::client::Request::UpdateInterval(x.update_interval)),
+        _ => ms::decode(&request),
+    }
+    .inspect_err(|_| activity.parse_failure())
+    .context("decoding request")
+}
+
+/// Limits to advertise under `options` to a peer whose messages are read up to
+/// `max_message_size` bytes
+fn limits_of(options: &Config, max_message_size: usize) -> ms::Limits {
+    ms::Limits {
+        max_state_size: options.state_size.try_into().unwrap_or(u32::MAX),
+        heartbeat_min_interval: Duration::from_secs_f64(options.heartbeat_min_interval),
+        client_update_interval: Duration::from_secs_f64(options.client_update_interval),
+        max_message_size: max_message_size.try_into().unwrap_or(u32::MAX),
+    }
+}
+
+/// The address game clients should use to reach the game server on `conn`, which said it's
+/// listening on `port`
+fn advertised_address(conn: &quinn::Connection, port: u16) -> SocketAddr {
+    SocketAddr::new(conn.remote_address().ip(), port)
+}
+
+/// Check that game clients could plausibly connect to `addr`
+fn validate_address(addr: SocketAddr) -> Result<(), &'static str> {
+    if addr.port() == 0 {
+        return Err("port 0");
+    }
+    let ip = addr.ip();
+    let ip = match ip {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    };
+    if ip.is_unspecified() || ip.is_multicast() || ip == IpAddr::V4(Ipv4Addr::BROADCAST) {
+        return Err("unusable address");
+    }
+    Ok(())
+}
+
+/// Fraction of player slots in use on a game server with `state`, if it's a JSON object with
+/// numeric `players` and `max_players` fields
+fn load(state: &[u8]) -> Option<f64> {
+    #[derive(Deserialize)]
+    struct Players {
+        players: u32,
+        max_players: u32,
+    }
+    let x = serde_json::from_slice::<Players>(state).ok()?;
+    (x.max_players > 0).then(|| f64::from(x.players) / f64::from(x.max_players))
+}
+
+/// `addr`, with any IPv4-mapped IPv6 address converted to IPv4
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Check the parts of a game server's hello that aren't covered by [`validate_address`]
+fn validate_hello(hello: &ms::game::HelloV2) -> Result<(), &'static str> {
+    if !ms::game::tags_valid(&hello.tags) {
+        return Err("invalid tags");
+    }
+    if hello.lan_addresses.len() > ms::game::MAX_LAN_ADDRESSES {
+        return Err("too many LAN addresses");
+    }
+    Ok(())
+}
+
+/// Whether `nonce` solves `challenge`, as described by [`ms::game::Challenge`]
+fn solves(challenge: &ms::game::Challenge, nonce: u64) -> bool {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(&challenge.prefix);
+    context.update(&nonce.to_le_bytes());
+    let mut zeros = 0;
+    for &byte in context.finish().as_ref() {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros >= u32::from(challenge.difficulty)
+}
+
+fn close_code(code: ms::CloseCode) -> quinn::VarInt {
+    code.code().into()
+}
+
+impl Connection for quinn::Connection {
+    fn stable_id(&self) -> usize {
+        self.stable_id()
+    }
+
+    fn close(&self, code: ms::CloseCode, reason: &[u8]) {
+        self.close(close_code(code), reason);
+    }
+}
+
+/// [`certificate_fingerprint`] of the certificate `conn`'s peer presented, if any
+fn fingerprint(conn: &quinn::Connection) -> Option<String> {
+    let chain = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    certificate_fingerprint(chain.first()?)
+}
+
+/// Hex-encoded SHA-256 hash of the SubjectPublicKeyInfo of a certificate in DER format, as
+/// reported by `peer_certificate_fingerprint` in the client and heartbeat libraries, or `None` if
+/// it can't be parsed
+fn certificate_fingerprint(der: &[u8]) -> Option<String> {
+    let der = CertificateDer::from(der);
+    let cert = webpki::EndEntityCert::try_from(&der).ok()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.subject_public_key_info());
+    let hex = digest
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    Some(hex)
+}
+
+/// Exit, for a supervisor to restart us, if `state`'s lock is wedged or poisoned beyond repair
+///
+/// Returns once `state` is dropped.
+fn watchdog(state: Weak<State>) {
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        if let Err(problem) = state.check_health(WEDGED_AFTER) {
+            error!(problem, "exiting to be restarted");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// How often the [`watchdog`] checks the state
+const WATCHDOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long the state lock may be held before the [`watchdog`] deems it wedged
+const WEDGED_AFTER: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Highest sequence number of any state received on a game server connection
+#[derive(Default)]
+struct Sequence(Option<u64>);
+
+impl Sequence {
+    /// Whether a state numbered `n` is newer than every one received before it, in which case it
+    /// becomes the newest
+    fn advance(&mut self, n: u64) -> bool {
+        if self.0.is_some_and(|prev| n <= prev) {
+            return false;
+        }
+        self.0 = Some(n);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Accept;
+
+    /// A `State` with `configure`d options and no listeners or TLS material
+    fn state(configure: impl FnOnce(&mut Config)) -> State {
+        let mut options = Config::default();
+        configure(&mut options);
+        let opt = Opt::parse_from(["metaserve"]);
+        State::new(options, opt, 0, Vec::new(), Box::new(|_| Ok(()))).unwrap()
+    }
+
+    /// Private key and certificate for `localhost`, self-signed and valid until 2126
+    const KEY: &[u8] = include_bytes!("../fixtures/localhost.key.der");
+    const CERT: &[u8] = include_bytes!("../fixtures/localhost.cert.der");
+    /// Certificate for `localhost` with the same key, self-signed and valid only during 2000
+    const EXPIRED_CERT: &[u8] = include_bytes!("../fixtures/expired.cert.der");
+    /// Certificate for `unrelated`, self-signed with an unrelated key
+    const UNRELATED_CERT: &[u8] = include_bytes!("../fixtures/unrelated.cert.der");
+
+    /// Serve each of `options`' listeners in the background, returning their addresses
+    fn serve(options: Config) -> (Arc<State>, Vec<SocketAddr>) {
+        serve_with_certificate(options, CERT)
+    }
+
+    /// [`serve`], presenting `cert` rather than [`CERT`]
+    fn serve_with_certificate(options: Config, cert: &[u8]) -> (Arc<State>, Vec<SocketAddr>) {
+        let cert_chain = vec![CertificateDer::from(cert.to_vec())];
+        let key = PrivateKeyDer::try_from(KEY).unwrap();
+        let listeners = options
+            .listeners()
+            .into_iter()
+            .map(|policy| {
+                let endpoint = listen(&options, &policy, cert_chain.clone(), key.clone_key());
+                (endpoint.unwrap(), Arc::new(policy))
+            })
+            .collect::<Vec<_>>();
+        let addresses = listeners
+            .iter()
+            .map(|(x, _)| x.local_addr().unwrap())
+            .collect();
+        let opt = Opt::parse_from(["metaserve"]);
+        let state = State::new(
+            options,
+            opt,
+            rand::random(),
+            cert_chain,
+            Box::new(|_| Ok(())),
+        )
+        .unwrap();
+        let state = Arc::new(state);
+        tokio::spawn(state.clone().run(listeners));
+        (state, addresses)
+    }
+
+    /// Configuration for connecting to `localhost` with [`CERT`], offering only the `alpn` protocol
+    fn client_config(alpn: &[u8]) -> quinn::ClientConfig {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(CERT)).unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![alpn.to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+        quinn::ClientConfig::new(Arc::new(crypto))
+    }
+
+    /// Complete a handshake with the daemon at `address`, offering only the `alpn` protocol
+    async fn handshake(
+        address: SocketAddr,
+        alpn: &[u8],
+    ) -> Result<quinn::Connection, quinn::ConnectionError> {
+        let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        endpoint.set_default_client_config(client_config(alpn));
+        endpoint.connect(address, "localhost").unwrap().await
+    }
+
+    /// Whether a handshake offering `alpn` failed for lack of a common protocol
+    fn no_common_protocol(result: &Result<quinn::Connection, quinn::ConnectionError>) -> bool {
+        // TLS alert 120, no_application_protocol, as a QUIC crypto error
+        let code = quinn::TransportErrorCode::crypto(120);
+        matches!(result, Err(quinn::ConnectionError::ConnectionClosed(x)) if x.error_code == code)
+    }
+
+    /// Handshakes offering each game and client protocol in turn, on a daemon listening as
+    /// `--accept accept` would have it, succeed only for the kinds of peer it accepts
+    async fn check_accept(accept: Accept, games: bool, clients: bool) {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            accept,
+            ..Config::default()
+        });
+        let protocols = ms::game::PROTOCOLS
+            .iter()
+            .map(|x| (x, games))
+            .chain(ms::client::PROTOCOLS.iter().map(|x| (x, clients)));
+        for (alpn, accepted) in protocols {
+            let result = handshake(addresses[0], alpn).await;
+            if accepted {
+                result.unwrap().close(0u32.into(), b"");
+            } else {
+                assert!(no_common_protocol(&result), "{:?}", result);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_both() {
+        check_accept(Accept::Both, true, true).await;
+    }
+
+    #[tokio::test]
+    async fn accept_servers() {
+        check_accept(Accept::Servers, true, false).await;
+    }
+
+    #[tokio::test]
+    async fn accept_clients() {
+        check_accept(Accept::Clients, false, true).await;
+    }
+
+    /// Connect to the daemon at `address` as a client
+    async fn client(address: SocketAddr) -> metaserve_client::Client {
+        metaserve_client::Client::builder()
+            .ca(CERT.to_vec())
+            .webpki_roots(false)
+            .address(address)
+            .connect("localhost:0")
+            .await
+            .unwrap()
+    }
+
+    /// Connect to the daemon at `address` as a client, and wait for the initial snapshot
+    async fn synchronized_client(
+        address: SocketAddr,
+    ) -> (metaserve_client::Client, metaserve_client::ServerList) {
+        let mut client = client(address).await;
+        let mut list = metaserve_client::ServerList::new();
+        client.synchronized(&mut list).await.unwrap();
+        (client, list)
+    }
+
+    /// Register a game server reachable on `port` with the daemon at `address`
+    async fn game_server(address: SocketAddr, port: u16) -> metaserve_heartbeat::Heartbeat {
+        metaserve_heartbeat::Heartbeat::builder()
+            .ca(CERT.to_vec())
+            .webpki_roots(false)
+            .address(address)
+            .connect("localhost:0", port)
+            .await
+            .unwrap()
+    }
+
+    /// Register a game server reachable on `port` with the daemon at `address`, speaking the
+    /// protocol directly rather than through the heartbeat crate, which paces itself
+    async fn raw_game_server(
+        address: SocketAddr,
+        port: u16,
+    ) -> (quinn::Connection, ms::game::Welcome) {
+        let conn = handshake(address, ms::game::PROTOCOL_V3).await.unwrap();
+        let hello = ms::game::HelloV2 {
+            port,
+            tags: Vec::new(),
+            introductions: false,
+            lan_addresses: Vec::new(),
+        };
+        let mut stream = conn.open_uni().await.unwrap();
+        stream
+            .write_all(&bincode::serialize(&hello).unwrap())
+            .await
+            .unwrap();
+        drop(stream);
+        let greeting = conn
+            .accept_uni()
+            .await
+            .unwrap()
+            .read_to_end(1024)
+            .await
+            .unwrap();
+        let ms::game::Greeting::Welcome(welcome) = ms::decode(&greeting).unwrap() else {
+            panic!("expected a welcome");
+        };
+        (conn, welcome)
+    }
+
+    /// Send a heartbeat carrying `state` over a [`raw_game_server`]'s connection
+    async fn send_state(conn: quinn::Connection, seq: u64, state: &[u8]) {
+        send_update(&conn, &ms::game::Update::SequencedState { seq, state }).await;
+    }
+
+    /// Send `update` over a [`raw_game_server`]'s connection
+    async fn send_update(conn: &quinn::Connection, update: &ms::game::Update<'_>) {
+        let mut stream = conn.open_uni().await.unwrap();
+        stream
+            .write_all(&bincode::serialize(update).unwrap())
+            .await
+            .unwrap();
+    }
+
+    /// Wait for `done` to hold, checking every 10ms and failing after 10 seconds
+    async fn poll_until(mut done: impl FnMut() -> bool) {
+        let poll = async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), poll)
+            .await
+            .unwrap();
+    }
+
+    /// Apply messages from `client` to `list` until `done` holds, failing after 10 seconds
+    async fn receive_until(
+        client: &mut metaserve_client::Client,
+        list: &mut metaserve_client::ServerList,
+        done: impl Fn(&metaserve_client::ServerList) -> bool,
+    ) {
+        while !done(list) {
+            let msg = client.recv_timeout(Duration::from_secs(10)).await.unwrap();
+            list.apply(&msg);
+        }
+    }
+
+    /// Each kind of update a game server sends is applied as it says, and version 1 game servers'
+    /// unframed states still are
+    #[tokio::test]
+    async fn update_dispatch() {
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            heartbeat_min_interval: 0.0,
+            ..Config::default()
+        });
+        let server = || {
+            let inner = state.lock();
+            let (_, x) = inner.servers.iter().next()?;
+            Some((x.address.map(|x| x.port()), x.state.clone(), x.tags.clone()))
+        };
+        let closed = |conn: quinn::Connection| async move {
+            match conn.closed().await {
+                quinn::ConnectionError::ApplicationClosed(x) => (x.error_code, x.reason),
+                e => panic!("unexpected {e}"),
+            }
+        };
+
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_update(&conn, &ms::game::Update::State(b"one")).await;
+        poll_until(|| server().is_some_and(|x| x.1 == b"one")).await;
+        send_update(
+            &conn,
+            &ms::game::Update::SequencedState {
+                seq: 5,
+                state: b"two",
+            },
+        )
+        .await;
+        poll_until(|| server().is_some_and(|x| x.1 == b"two")).await;
+        // Stale, whichever is read first
+        send_update(
+            &conn,
+            &ms::game::Update::SequencedState {
+                seq: 4,
+                state: b"old",
+            },
+        )
+        .await;
+        send_update(
+            &conn,
+            &ms::game::Update::SequencedState {
+                seq: 6,
+                state: b"new",
+            },
+        )
+        .await;
+        poll_until(|| server().is_some_and(|x| x.1 == b"new")).await;
+        send_update(&conn, &ms::game::Update::Tags(vec!["a".into()])).await;
+        poll_until(|| server().is_some_and(|x| x.2 == ["a"])).await;
+        send_update(&conn, &ms::game::Update::PortChange(1001)).await;
+        poll_until(|| server().is_some_and(|x| x.0 == Some(1001))).await;
+        assert_eq!(server().unwrap().1, b"new");
+        let goodbye = ms::game::Update::Goodbye {
+            reason: Some("done"),
+        };
+        send_update(&conn, &goodbye).await;
+        let (code, reason) = closed(conn).await;
+        assert_eq!(code, close_code(ms::CloseCode::Normal));
+        assert_eq!(reason, &b"goodbye"[..]);
+        poll_until(|| server().is_none()).await;
+
+        // Invalid updates end the connection
+        let invalid = [
+            ms::game::Update::PortChange(0),
+            ms::game::Update::Tags(vec![String::new()]),
+        ];
+        for update in &invalid {
+            let (conn, _) = raw_game_server(addresses[0], 1000).await;
+            send_update(&conn, update).await;
+            let (code, reason) = closed(conn).await;
+            assert_eq!(code, close_code(ms::CloseCode::ProtocolViolation));
+            assert_eq!(reason, &b"malformed update"[..]);
+        }
+        poll_until(|| server().is_none()).await;
+
+        // Version 1 sends bare states
+        let conn = handshake(addresses[0], ms::game::PROTOCOL).await.unwrap();
+        let hello = bincode::serialize(&ms::game::Hello { port: 1002 }).unwrap();
+        for data in [&hello[..], b"bare"] {
+            let mut stream = conn.open_uni().await.unwrap();
+            stream.write_all(data).await.unwrap();
+            stream.finish().unwrap();
+        }
+        poll_until(|| server().is_some_and(|x| x == (Some(1002), b"bare".to_vec(), vec![]))).await;
+    }
+
+    /// States of exactly the maximum size are accepted however they're sent, and one byte more
+    /// never is, while the advertised limits agree
+    #[tokio::test]
+    async fn state_size_boundaries() {
+        const MAX: usize = 1000;
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            state_size: MAX,
+            heartbeat_min_interval: 0.0,
+            ..Config::default()
+        });
+        let server = || {
+            let inner = state.lock();
+            inner.servers.iter().next().map(|(_, x)| x.state.len())
+        };
+
+        // Through the heartbeat crate, which knows the limit
+        let mut heartbeat = metaserve_heartbeat::Heartbeat::builder()
+            .ca(CERT.to_vec())
+            .webpki_roots(false)
+            .address(addresses[0])
+            .tags(vec!["t".repeat(ms::game::MAX_TAG_LEN); ms::game::MAX_TAGS])
+            .connect("localhost:0", 1000)
+            .await
+            .unwrap();
+        let limits = heartbeat.limits().unwrap();
+        assert_eq!(limits.max_state_size as usize, MAX);
+        assert_eq!(
+            limits.max_message_size as usize,
+            MAX + ms::game::MAX_UPDATE_OVERHEAD
+        );
+        assert!(matches!(
+            heartbeat.send(&[0; MAX + 1]).await,
+            Err(metaserve_heartbeat::Error::StateTooLarge)
+        ));
+        heartbeat.send(&[0; MAX]).await.unwrap();
+        poll_until(|| server() == Some(MAX)).await;
+        assert!(heartbeat.is_connected());
+        heartbeat.close().await;
+        poll_until(|| server().is_none()).await;
+
+        // Framed, within the message size but past the state size
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_update(&conn, &ms::game::Update::State(&[0; MAX])).await;
+        poll_until(|| server() == Some(MAX)).await;
+        send_update(&conn, &ms::game::Update::State(&[0; MAX + 1])).await;
+        let quinn::ConnectionError::ApplicationClosed(close) = conn.closed().await else {
+            panic!("expected the daemon to close the connection");
+        };
+        assert_eq!(close.reason, &b"malformed update"[..]);
+        poll_until(|| server().is_none()).await;
+
+        // Past the message size too
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        let mut stream = conn.open_uni().await.unwrap();
+        let _ = stream
+            .write_all(&[0; MAX + ms::game::MAX_UPDATE_OVERHEAD + 1])
+            .await;
+        let _ = stream.finish();
+        tokio::time::timeout(Duration::from_secs(10), conn.closed())
+            .await
+            .unwrap();
+
+        // Unframed, with the state size the whole message
+        for (len, accepted) in [(MAX, true), (MAX + 1, false)] {
+            let conn = handshake(addresses[0], ms::game::PROTOCOL).await.unwrap();
+            let hello = bincode::serialize(&ms::game::Hello { port: 1000 }).unwrap();
+            for data in [&hello[..], &vec![0; len]] {
+                let mut stream = conn.open_uni().await.unwrap();
+                let _ = stream.write_all(data).await;
+                let _ = stream.finish();
+            }
+            if accepted {
+                poll_until(|| server() == Some(len)).await;
+                conn.close(0u32.into(), b"");
+                poll_until(|| server().is_none()).await;
+            } else {
+                tokio::time::timeout(Duration::from_secs(10), conn.closed())
+                    .await
+                    .unwrap();
+                poll_until(|| server().is_none()).await;
+            }
+        }
+    }
+
+    /// A game server whose connection is lost keeps its entry for the grace period, so clients see
+    /// nothing but an update if it reconnects, and a shutdown only once the period ends otherwise
+    #[tokio::test]
+    async fn removal_grace() {
+        const GRACE: Duration = Duration::from_secs(1);
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            removal_grace: Some(GRACE.as_secs_f64()),
+            client_update_interval: 0.1,
+            heartbeat_min_interval: 0.0,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        // As far as the daemon can tell, the game server crashed
+        let lose = |conn: quinn::Connection| conn.close(0xDEADu32.into(), b"");
+        // Apply messages until `done`, failing on any shutdown
+        async fn no_shutdown_until(
+            client: &mut metaserve_client::Client,
+            list: &mut metaserve_client::ServerList,
+            done: impl Fn(&metaserve_client::ServerList) -> bool,
+        ) {
+            while !done(list) {
+                let msg = client.recv_timeout(Duration::from_secs(10)).await.unwrap();
+                let shutdown = msg
+                    .servers
+                    .iter()
+                    .any(|x| matches!(x.event, ms::client::Event::Shutdown));
+                assert!(!shutdown);
+                list.apply(&msg);
+            }
+        }
+
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_state(conn.clone(), 0, b"one").await;
+        receive_until(&mut client, &mut list, |x| x.len() == 1).await;
+        let (id, _) = list.iter().next().unwrap();
+
+        // Reclaimed
+        lose(conn);
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_state(conn.clone(), 0, b"two").await;
+        no_shutdown_until(&mut client, &mut list, |x| {
+            x.get(id).is_some_and(|x| x.state == b"two"[..])
+        })
+        .await;
+        assert_eq!(list.len(), 1);
+        // Nor is a shutdown sent once the original grace period is over
+        let over = tokio::time::sleep(GRACE * 2);
+        tokio::pin!(over);
+        let wait = no_shutdown_until(&mut client, &mut list, |_| false);
+        tokio::select! {
+            _ = wait => unreachable!(),
+            _ = &mut over => {}
+        }
+
+        // Expired
+        lose(conn);
+        let lost = Instant::now();
+        receive_until(&mut client, &mut list, |x| x.is_empty()).await;
+        assert!(lost.elapsed() > GRACE - Duration::from_millis(100));
+
+        // Gracefully closed servers are removed immediately
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_state(conn.clone(), 0, b"three").await;
+        receive_until(&mut client, &mut list, |x| x.len() == 1).await;
+        let goodbye = ms::game::Update::Goodbye { reason: None };
+        send_update(&conn, &goodbye).await;
+        let left = Instant::now();
+        receive_until(&mut client, &mut list, |x| x.is_empty()).await;
+        assert!(left.elapsed() < GRACE / 2);
+    }
+
+    /// Clients learn which daemon they're connected to and how far its table has changed, so that
+    /// a saved list keeps its IDs only when reconnecting to the same daemon
+    #[tokio::test]
+    async fn instance_and_table_version() {
+        let config = || Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        };
+        let (_, addresses) = serve(config());
+        let (mut first, mut list) = synchronized_client(addresses[0]).await;
+        let instance = first.instance().unwrap();
+        let before = first.table_version().unwrap();
+
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        heartbeat.send(b"state").await.unwrap();
+        let id = heartbeat.advertised().await.unwrap().id;
+        receive_until(&mut first, &mut list, |x| x.len() == 1).await;
+        let (second, _) = synchronized_client(addresses[0]).await;
+        assert_eq!(second.instance(), Some(instance));
+        assert!(second.table_version().unwrap() > before);
+
+        let path = std::env::temp_dir().join(format!("metaserve-{}.list", std::process::id()));
+        list.save(&path).unwrap();
+        let mut same = client(addresses[0]).await;
+        let mut loaded = metaserve_client::ServerList::load(&path).unwrap();
+        same.synchronized(&mut loaded).await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get(id).unwrap().state, b"state"[..]);
+
+        // Another daemon, which has the same game server under its own ID
+        let (_, addresses) = serve(config());
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        heartbeat.send(b"elsewhere").await.unwrap();
+        let other = heartbeat.advertised().await.unwrap().id;
+        let mut other_client = client(addresses[0]).await;
+        let mut loaded = metaserve_client::ServerList::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        other_client.synchronized(&mut loaded).await.unwrap();
+        assert_ne!(other_client.instance(), Some(instance));
+        assert_eq!(loaded.len(), 1);
+        let entry = loaded.get(other).unwrap();
+        assert_eq!(entry.address.port(), 1000);
+        assert_eq!(entry.state, b"elsewhere"[..]);
+    }
+
+    /// The usual mistakes in trusting a daemon's certificate are reported as such by both the
+    /// client and heartbeat crates
+    #[tokio::test]
+    async fn certificate_rejections() {
+        let config = || Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        };
+        let (_, valid) = serve(config());
+        let (_, expired) = serve_with_certificate(config(), EXPIRED_CERT);
+        // Connects a client and a game server to `address` by `name`, trusting `ca`
+        let connect = |address, name: &'static str, ca: &'static [u8]| async move {
+            let client = metaserve_client::Client::builder()
+                .ca(ca.to_vec())
+                .webpki_roots(false)
+                .address(address)
+                .connect(&format!("{name}:0"))
+                .await
+                .map(drop);
+            let heartbeat = metaserve_heartbeat::Heartbeat::builder()
+                .ca(ca.to_vec())
+                .webpki_roots(false)
+                .address(address)
+                .connect(&format!("{name}:0"), 1000)
+                .await
+                .map(drop);
+            (client.unwrap_err(), heartbeat.unwrap_err())
+        };
+
+        let (client, heartbeat) = connect(valid[0], "example.com", CERT).await;
+        match client {
+            metaserve_client::ConnectError::NameMismatch {
+                presented,
+                requested,
+            } => {
+                assert_eq!(presented, ["localhost"]);
+                assert_eq!(requested, "example.com");
+            }
+            e => panic!("unexpected {e}"),
+        }
+        match heartbeat {
+            metaserve_heartbeat::ConnectError::NameMismatch {
+                presented,
+                requested,
+            } => {
+                assert_eq!(presented, ["localhost"]);
+                assert_eq!(requested, "example.com");
+            }
+            e => panic!("unexpected {e}"),
+        }
+
+        let (client, heartbeat) = connect(valid[0], "localhost", UNRELATED_CERT).await;
+        assert!(
+            matches!(client, metaserve_client::ConnectError::UntrustedRoot),
+            "{client}"
+        );
+        assert!(
+            matches!(heartbeat, metaserve_heartbeat::ConnectError::UntrustedRoot),
+            "{heartbeat}"
+        );
+
+        let end = std::time::UNIX_EPOCH + Duration::from_secs(978_307_200);
+        let (client, heartbeat) = connect(expired[0], "localhost", EXPIRED_CERT).await;
+        match client {
+            metaserve_client::ConnectError::Expired { not_after } => {
+                assert_eq!(not_after, Some(end));
+            }
+            e => panic!("unexpected {e}"),
+        }
+        match heartbeat {
+            metaserve_heartbeat::ConnectError::Expired { not_after } => {
+                assert_eq!(not_after, Some(end));
+            }
+            e => panic!("unexpected {e}"),
+        }
+    }
+
+    /// The client and heartbeat crates work without tokio, driven by smol
+    #[test]
+    fn smol_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (_, addresses) = {
+            let _guard = runtime.enter();
+            serve(Config {
+                listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+                client_update_interval: 0.1,
+                ..Config::default()
+            })
+        };
+        smol::block_on(async {
+            assert!(tokio::runtime::Handle::try_current().is_err());
+            let mut heartbeat = game_server(addresses[0], 1000).await;
+            heartbeat.send(b"state").await.unwrap();
+            let id = heartbeat.advertised().await.unwrap().id;
+            let (mut client, mut list) = synchronized_client(addresses[0]).await;
+            receive_until(&mut client, &mut list, |x| x.get(id).is_some()).await;
+            assert_eq!(list.get(id).unwrap().state, b"state"[..]);
+            // Timers too
+            assert!(matches!(
+                client.recv_timeout(Duration::from_millis(100)).await,
+                Err(metaserve_client::Error::TimedOut)
+            ));
+            heartbeat.close().await;
+            client.close().await;
+        });
+    }
+
+    /// The Bevy plugin keeps its server browser up to date, reporting each change as a message
+    #[tokio::test]
+    async fn bevy_plugin() {
+        use metaserve_bevy::{ConnectionStatus, ServerBrowser, ServerEvent};
+
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+        let address = addresses[0];
+        let mut heartbeat = game_server(address, 1000).await;
+        heartbeat.send(b"state").await.unwrap();
+        let id = heartbeat.advertised().await.unwrap().id;
+
+        let mut app = bevy_app::App::new();
+        app.add_plugins(
+            metaserve_bevy::MetaservePlugin::new("localhost:0").builder(move || {
+                metaserve_client::Client::builder()
+                    .ca(CERT.to_vec())
+                    .webpki_roots(false)
+                    .address(address)
+            }),
+        );
+        let messages = app
+            .world()
+            .resource::<bevy_ecs::message::Messages<ServerEvent>>();
+        let mut cursor = messages.get_cursor();
+        // Every message written so far
+        let mut events = Vec::new();
+        let mut update = |app: &mut bevy_app::App| {
+            app.update();
+            let messages = app.world().resource();
+            events.extend(cursor.read(messages).cloned());
+            events.clone()
+        };
+
+        poll_until(|| !update(&mut app).is_empty()).await;
+        assert_eq!(
+            *app.world().resource::<ConnectionStatus>(),
+            ConnectionStatus::Connected
+        );
+        let browser = app.world().resource::<ServerBrowser>();
+        assert_eq!(browser.get(id).unwrap().state, b"state"[..]);
+        assert!(matches!(
+            update(&mut app)[..],
+            [ServerEvent::Added(x, ref entry)] if x == id && entry.state == b"state"[..]
+        ));
+
+        heartbeat.close().await;
+        poll_until(|| update(&mut app).len() == 2).await;
+        assert!(matches!(update(&mut app)[1], ServerEvent::Removed(x, _) if x == id));
+        assert!(app.world().resource::<ServerBrowser>().is_empty());
+    }
+
+    /// Filters changed faster than the daemon can answer leave a client's list reflecting the last
+    #[tokio::test]
+    async fn rapid_filter_changes() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+        let mut servers = Vec::new();
+        for (port, tags) in [
+            (1000, &["red"][..]),
+            (1001, &["blue"]),
+            (1002, &["red", "blue"]),
+        ] {
+            let mut heartbeat = metaserve_heartbeat::Heartbeat::builder()
+                .ca(CERT.to_vec())
+                .webpki_roots(false)
+                .address(addresses[0])
+                .tags(tags.iter().map(|&x| x.into()).collect())
+                .connect("localhost:0", port)
+                .await
+                .unwrap();
+            heartbeat.send(b"state").await.unwrap();
+            servers.push(heartbeat);
+        }
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        receive_until(&mut client, &mut list, |list| list.len() == 3).await;
+
+        let filter = |required: &[&str], excluded: &[&str]| ms::client::Filter {
+            required: required.iter().map(|&x| x.into()).collect(),
+            excluded: excluded.iter().map(|&x| x.into()).collect(),
+        };
+        let filters = [
+            filter(&["red"], &[]),
+            filter(&["blue"], &[]),
+            filter(&["red"], &["blue"]),
+            filter(&[], &[]),
+            filter(&["blue"], &["red"]),
+        ];
+        for filter in &filters {
+            client.set_filter(filter.clone()).await.unwrap();
+        }
+        assert_eq!(client.filter_generation(), filters.len() as u64);
+        tokio::time::timeout(Duration::from_secs(10), client.synchronized(&mut list))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(list.filter_generation(), filters.len() as u64);
+        let ports = list
+            .iter()
+            .map(|(_, entry)| entry.address.port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [1001]);
+
+        // Later changes are still honored
+        client.set_filter(filter(&["red"], &[])).await.unwrap();
+        client.synchronized(&mut list).await.unwrap();
+        let mut ports = list
+            .iter()
+            .map(|(_, entry)| entry.address.port())
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        assert_eq!(ports, [1000, 1002]);
+    }
+
+    /// Client and heartbeat connections report what they negotiated with the daemon, and the
+    /// certificate it presented
+    #[tokio::test]
+    async fn negotiated_parameters() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+        let fingerprint = metaserve_client::spki_fingerprint(CERT).unwrap();
+
+        let (client, _) = synchronized_client(addresses[0]).await;
+        assert_eq!(client.protocol_version(), 3);
+        assert_eq!(client.negotiated_alpn(), ms::client::PROTOCOL_V3);
+        assert_eq!(client.peer_certificate_fingerprint(), Some(fingerprint));
+
+        let heartbeat = game_server(addresses[0], 1000).await;
+        assert_eq!(heartbeat.protocol_version(), 3);
+        assert_eq!(heartbeat.negotiated_alpn(), ms::game::PROTOCOL_V3);
+        assert_eq!(heartbeat.peer_certificate_fingerprint(), Some(fingerprint));
+    }
+
+    /// Pinning the daemon's fingerprint trusts its key alone, whichever certificate carries it
+    #[tokio::test]
+    async fn pinned_fingerprint() {
+        let config = || Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        };
+        // Issued separately for the same key, and since expired
+        let (_, reissued) = serve_with_certificate(config(), EXPIRED_CERT);
+        let fingerprint = metaserve_client::spki_fingerprint(CERT).unwrap();
+        assert_eq!(
+            metaserve_client::spki_fingerprint(EXPIRED_CERT),
+            Some(fingerprint)
+        );
+        let connect = |fingerprint| {
+            metaserve_client::Client::builder()
+                .pin(fingerprint)
+                .address(reissued[0])
+                .connect("example.com:0")
+        };
+
+        let client = connect(fingerprint).await.unwrap();
+        assert_eq!(client.peer_certificate_fingerprint(), Some(fingerprint));
+        client.close().await;
+
+        let unrelated = metaserve_client::spki_fingerprint(UNRELATED_CERT).unwrap();
+        assert_ne!(unrelated, fingerprint);
+        assert!(connect(unrelated).await.is_err());
+    }
+
+    /// A heartbeat is acknowledged once the daemon stores it
+    #[tokio::test]
+    async fn heartbeat_acknowledged() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        assert_eq!(heartbeat.last_ack(), None);
+        let sent = std::time::Instant::now();
+        let report = heartbeat.send(b"state").await.unwrap();
+        assert!(!report.throttled);
+        heartbeat.advertised().await.unwrap();
+        // Recorded as the event receiver decodes the acknowledgement, before it's handed over
+        let acked = heartbeat.last_ack().unwrap();
+        assert!(acked >= sent && acked <= std::time::Instant::now());
+    }
+
+    /// Clients and heartbeats resolve the daemon's name afresh when connecting, skip addresses
+    /// that don't answer, and try those that recently failed last
+    #[tokio::test]
+    async fn connect_follows_resolver() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+        let live = addresses[0];
+        let attempt_timeout = Duration::from_millis(500);
+        // Neither crate remembers failures of the other's, so each gets its own unresponsive
+        // address
+        for heartbeat in [false, true] {
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let silent = socket.local_addr().unwrap();
+            let answer = Arc::new(std::sync::Mutex::new(vec![silent]));
+            let connect = || {
+                let answer = answer.clone();
+                let resolver = move |_: &str| Ok(answer.lock().unwrap().clone());
+                async move {
+                    if heartbeat {
+                        metaserve_heartbeat::Heartbeat::builder()
+                            .ca(CERT.to_vec())
+                            .webpki_roots(false)
+                            .resolver(resolver)
+                            .attempt_timeout(attempt_timeout)
+                            .connect("localhost:0", 1000)
+                            .await
+                            .map(|x| x.remote_address())
+                            .map_err(|e| e.to_string())
+                    } else {
+                        metaserve_client::Client::builder()
+                            .ca(CERT.to_vec())
+                            .webpki_roots(false)
+                            .resolver(resolver)
+                            .attempt_timeout(attempt_timeout)
+                            .connect("localhost:0")
+                            .await
+                            .map(|x| x.remote_address())
+                            .map_err(|e| e.to_string())
+                    }
+                }
+            };
+
+            // Only the unresponsive address is known, so connecting gives up on it
+            let start = Instant::now();
+            let e = connect().await.unwrap_err();
+            assert!(e.ends_with(&format!("no response from {silent}")), "{e}");
+            assert!(start.elapsed() >= attempt_timeout);
+
+            // The name fails over, but the old address is still listed first. Having just
+            // failed, it's tried last, so connecting doesn't wait for it.
+            *answer.lock().unwrap() = vec![silent, live];
+            let start = Instant::now();
+            assert_eq!(connect().await.unwrap(), live);
+            assert!(start.elapsed() < attempt_timeout);
+        }
+    }
+
+    /// A client sharing the game's endpoint coexists with the game's own connections, and
+    /// leaves them, and the endpoint's configuration, alone
+    #[tokio::test]
+    async fn shared_endpoint() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..Config::default()
+        });
+
+        // Stands in for some other server the game talks to, e.g. for gameplay
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(CERT)],
+                PrivateKeyDer::try_from(KEY).unwrap(),
+            )
+            .unwrap();
+        crypto.alpn_protocols = vec![b"game".to_vec()];
+        let crypto = QuicServerConfig::try_from(crypto).unwrap();
+        let game = quinn::Endpoint::server(
+            quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+            (Ipv4Addr::LOCALHOST, 0).into(),
+        )
+        .unwrap();
+        let game_address = game.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = game.accept().await {
+                let conn = incoming.await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                        let data = recv.read_to_end(64).await.unwrap();
+                        send.write_all(&data).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+        let exchange = |conn: quinn::Connection| async move {
+            let (mut send, mut recv) = conn.open_bi().await.unwrap();
+            send.write_all(b"ping").await.unwrap();
+            send.finish().unwrap();
+            assert_eq!(recv.read_to_end(64).await.unwrap(), b"ping");
+        };
+
+        let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        endpoint.set_default_client_config(client_config(b"game"));
+        let conn = endpoint.connect(game_address, "localhost").unwrap();
+        let conn = conn.await.unwrap();
+
+        let mut client = metaserve_client::Client::builder()
+            .ca(CERT.to_vec())
+            .webpki_roots(false)
+            .address(addresses[0])
+            .endpoint(endpoint.clone())
+            .connect("localhost:0")
+            .await
+            .unwrap();
+        let mut list = metaserve_client::ServerList::new();
+        client.synchronized(&mut list).await.unwrap();
+        exchange(conn.clone()).await;
+
+        // The game's default configuration, offering its own protocol, still applies
+        let other = endpoint.connect(game_address, "localhost").unwrap();
+        let other = other.await.unwrap();
+        assert_eq!(
+            other
+                .handshake_data()
+                .unwrap()
+                .downcast::<quinn::crypto::rustls::HandshakeData>()
+                .unwrap()
+                .protocol
+                .unwrap(),
+            b"game"
+        );
+        other.close(0u32.into(), b"");
+
+        client.close().await;
+        exchange(conn.clone()).await;
+        assert!(conn.close_reason().is_none());
+    }
+
+    /// A public listener and an internal one treat peers differently, as their policies say
+    #[tokio::test]
+    async fn listener_policies() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let public = ListenerPolicy {
+            accept: vec![Role::Client],
+            filter: Some(ms::client::Filter {
+                required: vec!["public".into()],
+                excluded: Vec::new(),
+            }),
+            ..ListenerPolicy::open(localhost)
+        };
+        let internal = ListenerPolicy {
+            all_lan_addresses: true,
+            ..ListenerPolicy::open(localhost)
+        };
+        let (state, addresses) = serve(Config {
+            listeners: vec![public, internal],
+            ..Config::default()
+        });
+        let [public, internal] = addresses[..] else {
+            panic!("expected two listeners, got {addresses:?}");
+        };
+
+        // Only the internal listener accepts game servers
+        assert!(no_common_protocol(
+            &handshake(public, ms::game::PROTOCOLS[0]).await
+        ));
+        let conn = handshake(internal, ms::game::PROTOCOLS[0]).await.unwrap();
+        conn.close(0u32.into(), b"");
+
+        // Behind a NAT other than the clients', so its LAN addresses are useless to them
+        let lan = SocketAddr::from(([10, 0, 0, 2], 4433));
+        let mut ids = Vec::new();
+        for (port, tags) in [(1, vec!["public".into()]), (2, Vec::new())] {
+            let id = {
+                let inner = &mut *state.lock();
+                let id = inner.add_server(Arc::default(), None, Instant::now());
+                let server = &mut inner.servers[id];
+                server.tags = tags;
+                server.lan_addresses = vec![lan];
+                id
+            };
+            let addr = SocketAddr::from(([192, 0, 2, 1], port));
+            state.update_server(id, None, addr, Vec::new()).unwrap();
+            ids.push(id.wire());
+        }
+
+        // The public listener hides untagged game servers, from the list and from queries, and
+        // offers no LAN addresses
+        let (client, list) = synchronized_client(public).await;
+        assert_eq!(list.len(), 1);
+        assert!(list.get(ids[0]).unwrap().lan_addresses.is_empty());
+        let hidden = client.get_server(ids[1], Duration::from_secs(5)).await;
+        assert!(hidden.unwrap().is_none());
+
+        // The internal one shows everything
+        let (client, list) = synchronized_client(internal).await;
+        assert_eq!(list.len(), 2);
+        for &id in &ids {
+            assert_eq!(list.get(id).unwrap().lan_addresses, [lan]);
+        }
+        let found = client.get_server(ids[1], Duration::from_secs(5)).await;
+        assert_eq!(found.unwrap().unwrap().lan_addresses, [lan]);
+    }
+
+    /// A state failing a validator closes the heartbeat connection as rejected, without the state
+    /// ever reaching the list
+    #[tokio::test]
+    async fn invalid_state_rejected() {
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            require_json_state: true,
+            ..Config::default()
+        });
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        heartbeat.send(b"{\"players\": 3}").await.unwrap();
+        let closed = heartbeat.closed();
+        heartbeat.send(b"not json").await.unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+        {
+            metaserve_heartbeat::Closed::ByMetaServer { code, reason } => {
+                assert_eq!(code, Some(ms::CloseCode::Rejected));
+                assert_eq!(reason, b"invalid state");
+            }
+            e => panic!("unexpected {e}"),
+        }
+        let inner = state.lock();
+        assert!(inner.servers.iter().all(|(_, x)| x.state != b"not json"));
+    }
+
+    /// Changing the heartbeat and query intervals at runtime applies them to existing
+    /// connections, and tells game servers, without disconnecting anyone
+    #[tokio::test]
+    async fn intervals_reloaded() {
+        let options = |heartbeat_min_interval, client_query_interval| Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            heartbeat_min_interval,
+            client_query_interval,
+            ..Config::default()
+        };
+        let (state, addresses) = serve(options(0.1, 60.0));
+
+        // A game server speaking the protocol directly, so it isn't held back on its own side
+        let (conn, welcome) = raw_game_server(addresses[0], 1000).await;
+        assert_eq!(
+            welcome.limits.heartbeat_min_interval,
+            Duration::from_millis(100)
+        );
+        let mut seq = 0;
+        let mut send = |state: &'static [u8]| {
+            seq += 1;
+            send_state(conn.clone(), seq - 1, state)
+        };
+        let stored = |expected: &[u8]| {
+            let inner = state.lock();
+            inner.servers.iter().any(|(_, x)| x.state == expected)
+        };
+        let wait_for = |expected: &'static [u8]| async move {
+            while !stored(expected) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        send(b"a").await;
+        wait_for(b"a").await;
+
+        let (client, _) = synchronized_client(addresses[0]).await;
+        let query = || client.find_one(Default::default(), ms::client::Strategy::Random);
+        query().await.unwrap();
+        assert!(matches!(
+            query().await,
+            Err(metaserve_client::Error::RateLimited)
+        ));
+
+        state.apply(options(1.5, 0.001));
+
+        // Game servers are told
+        let limits = loop {
+            let msg = conn
+                .accept_uni()
+                .await
+                .unwrap()
+                .read_to_end(1024)
+                .await
+                .unwrap();
+            if let Ok(ms::game::Control::Limits(x)) = ms::decode(&msg) {
+                break x;
+            }
+        };
+        assert_eq!(limits.heartbeat_min_interval, Duration::from_millis(1500));
+
+        // Heartbeats are held back for the new interval
+        send(b"b").await;
+        wait_for(b"b").await;
+        let applied = Instant::now();
+        send(b"c").await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(stored(b"b"));
+        wait_for(b"c").await;
+        assert!(applied.elapsed() >= Duration::from_millis(1400));
+
+        // Queries may be made more often
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        query().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        query().await.unwrap();
+
+        assert!(conn.close_reason().is_none());
+    }
+
+    /// A game server shutting down reaches clients well before the next regular update would
+    #[tokio::test]
+    async fn shutdown_propagates_promptly() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 2.0,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        heartbeat.send(b"state").await.unwrap();
+        receive_until(&mut client, &mut list, |x| x.len() == 1).await;
+
+        // Just after a regular update, so the next is about 2 seconds off
+        let start = Instant::now();
+        heartbeat.close().await;
+        receive_until(&mut client, &mut list, |x| x.is_empty()).await;
+        assert!(
+            start.elapsed() < Duration::from_millis(500),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    /// Clients are sent updates at the configured interval, or a longer one they ask for, and
+    /// heartbeats are applied at most once per the configured minimum interval
+    ///
+    /// The client and heartbeat crates keep time by the system clock, so both sides are driven
+    /// directly, leaving the daemon's own pacing the only timing under test.
+    #[tokio::test(start_paused = true)]
+    async fn pacing() {
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 3.0,
+            heartbeat_min_interval: 0.5,
+            update_jitter: 0.0,
+            ..Config::default()
+        });
+
+        // Heartbeats five times as often as the daemon applies them
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(100));
+            for seq in 0u64.. {
+                interval.tick().await;
+                send_state(conn.clone(), seq, &seq.to_le_bytes()).await;
+            }
+        });
+        let mut applied = Vec::new();
+        while applied.len() < 10 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let inner = state.lock();
+            let Some((_, server)) = inner.servers.iter().next() else {
+                continue;
+            };
+            let Ok(seq) = server.state[..].try_into() else {
+                continue;
+            };
+            let seq = u64::from_le_bytes(seq);
+            if applied.last() != Some(&seq) {
+                applied.push(seq);
+            }
+        }
+        // Each applied state is the latest sent half a second after the last
+        for pair in applied.windows(2) {
+            assert!((4..=6).contains(&(pair[1] - pair[0])), "{applied:?}");
+        }
+
+        for requested in [None, Some(Duration::from_secs(7))] {
+            let conn = handshake(addresses[0], ms::client::PROTOCOL_V3).await;
+            let mut client = metaserve_client::Client::new(conn.unwrap().into());
+            if let Some(x) = requested {
+                client.request_update_interval(x).await.unwrap();
+            }
+            let expected = requested.unwrap_or(Duration::from_secs(3));
+            // The snapshot is sent immediately
+            client.recv().await.unwrap();
+            let mut prev = Instant::now();
+            for _ in 0..3 {
+                client.recv().await.unwrap();
+                // Give or take delivery, which takes time as the clock skips ahead
+                let gap = prev.elapsed();
+                let slack = Duration::from_millis(250);
+                assert!(gap > expected - slack && gap < expected + slack, "{gap:?}");
+                prev = Instant::now();
+            }
+        }
+    }
+
+    /// A game server that restarts before its old connection is noticed to be gone replaces its
+    /// old entry, which clients see as a single update
+    #[tokio::test]
+    async fn fast_restart_replaces() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        list.set_change_log_capacity(64);
+        let mut old = game_server(addresses[0], 1000).await;
+        old.send(b"old").await.unwrap();
+        let id = old.advertised().await.unwrap().id;
+        receive_until(&mut client, &mut list, |x| x.get(id).is_some()).await;
+        let cursor = list.cursor();
+
+        // The old instance's connection lingers, as it would until its idle timeout
+        let closed = old.closed();
+        let mut new = game_server(addresses[0], 1000).await;
+        match tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+        {
+            metaserve_heartbeat::Closed::ByMetaServer { code, .. } => {
+                assert_eq!(code, Some(ms::CloseCode::Superseded));
+            }
+            e => panic!("unexpected {e}"),
+        }
+        new.send(b"new").await.unwrap();
+        assert_eq!(new.advertised().await.unwrap().id, id);
+        receive_until(&mut client, &mut list, |x| {
+            x.get(id).is_some_and(|x| x.state == b"new"[..])
+        })
+        .await;
+        assert_eq!(list.len(), 1);
+        let (changes, _) = list.changes_since(cursor).unwrap();
+        assert!(
+            changes
+                .iter()
+                .all(|x| matches!(x, metaserve_client::Change::Updated(x, _) if *x == id)),
+            "{changes:?}"
+        );
+    }
+
+    /// A game server whose connection moves to another address is advertised at the new one,
+    /// without waiting for its state to change
+    #[tokio::test]
+    async fn rebind_moves_server() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        let endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let mut heartbeat = metaserve_heartbeat::Heartbeat::builder()
+            .ca(CERT.to_vec())
+            .webpki_roots(false)
+            .address(addresses[0])
+            .endpoint(endpoint.clone())
+            .connect("localhost:0", 1000)
+            .await
+            .unwrap();
+        heartbeat.send(b"state").await.unwrap();
+        let id = heartbeat.advertised().await.unwrap().id;
+        let old = SocketAddr::from((Ipv4Addr::LOCALHOST, 1000));
+        receive_until(&mut client, &mut list, |x| {
+            x.get(id).is_some_and(|x| x.address == old)
+        })
+        .await;
+
+        // Another loopback address, as if the game server's NAT mapping changed
+        let new = SocketAddr::from(([127, 0, 0, 2], 1000));
+        let socket = std::net::UdpSocket::bind((new.ip(), 0)).unwrap();
+        endpoint.rebind(socket).unwrap();
+        // Tags carry no address, so only the path they arrive on reveals the new one
+        heartbeat.set_tags(vec!["moved".into()]).await.unwrap();
+        receive_until(&mut client, &mut list, |x| {
+            x.get(id).is_some_and(|x| x.address == new)
+        })
+        .await;
+        assert_eq!(list.get(id).unwrap().state, b"state"[..]);
+    }
+
+    /// Clients connecting while a game server has registered but not yet sent its first heartbeat
+    /// don't see it until it does
+    #[tokio::test]
+    async fn connect_before_first_heartbeat() {
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let (mut early, mut early_list) = synchronized_client(addresses[0]).await;
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        assert_eq!(state.lock().servers.len(), 1);
+
+        // Slow to send its first heartbeat
+        let (mut late, mut late_list) = synchronized_client(addresses[0]).await;
+        assert!(late_list.is_empty());
+        // Nor is it sent later, while it still has no heartbeat
+        if let Ok(msg) = late.recv_timeout(Duration::from_millis(500)).await {
+            late_list.apply(&msg);
+        }
+        assert!(late_list.is_empty());
+
+        heartbeat.send(b"state").await.unwrap();
+        let id = heartbeat.advertised().await.unwrap().id;
+        for (client, list) in [(&mut early, &mut early_list), (&mut late, &mut late_list)] {
+            receive_until(client, list, |x| x.len() == 1).await;
+            assert_eq!(list.get(id).unwrap().state, b"state"[..]);
+        }
+    }
+
+    /// Clients connecting while game servers register all end up with the same, complete list
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_convergence() {
+        const N: u16 = 100;
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let address = addresses[0];
+        let (done, _) = tokio::sync::broadcast::channel::<()>(1);
+        let mut servers = tokio::task::JoinSet::new();
+        for i in 0..N {
+            let mut done = done.subscribe();
+            servers.spawn(async move {
+                let mut heartbeat = game_server(address, 1000 + i).await;
+                heartbeat.send(&i.to_le_bytes()).await.unwrap();
+                // Stay registered until every client has seen everyone
+                let _ = done.recv().await;
+            });
+        }
+        let mut clients = tokio::task::JoinSet::new();
+        for _ in 0..N {
+            clients.spawn(async move {
+                let (mut client, mut list) = synchronized_client(address).await;
+                receive_until(&mut client, &mut list, |x| x.len() == usize::from(N)).await;
+                let mut servers = list
+                    .iter()
+                    .map(|(id, x)| (id, x.address, x.state.clone()))
+                    .collect::<Vec<_>>();
+                servers.sort_by_key(|&(_, address, _)| address);
+                servers
+            });
+        }
+        let expected = clients.join_next().await.unwrap().unwrap();
+        for (i, (_, address, state)) in (0..N).zip(&expected) {
+            assert_eq!(address.port(), 1000 + i);
+            assert_eq!(state[..], i.to_le_bytes());
+        }
+        while let Some(result) = clients.join_next().await {
+            assert_eq!(result.unwrap(), expected);
+        }
+        done.send(()).unwrap();
+        while let Some(result) = servers.join_next().await {
+            result.unwrap();
+        }
+    }
+
+    /// A game server disconnecting while a heartbeat is held back by the minimum interval is
+    /// noticed at once, rather than when the interval is up
+    #[tokio::test]
+    async fn disconnect_while_throttled() {
+        let (_, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            heartbeat_min_interval: 10.0,
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        let (conn, _) = raw_game_server(addresses[0], 1000).await;
+        send_state(conn.clone(), 0, b"first").await;
+        receive_until(&mut client, &mut list, |x| x.len() == 1).await;
+        send_state(conn.clone(), 1, b"held back").await;
+
+        let start = Instant::now();
+        conn.close(0u32.into(), b"");
+        receive_until(&mut client, &mut list, |x| x.is_empty()).await;
+        assert!(
+            start.elapsed() < Duration::from_millis(500),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    /// A game server that isn't sending heartbeats still learns promptly that the daemon is
+    /// going away, and why
+    #[tokio::test]
+    async fn heartbeat_notices_daemon_exit() {
+        // Drained, and told so
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            drain_timeout: 0.1,
+            ..Config::default()
+        });
+        let heartbeat = game_server(addresses[0], 1000).await;
+        let closed = heartbeat.closed();
+        let start = Instant::now();
+        state.start_drain();
+        match tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+        {
+            metaserve_heartbeat::Closed::ByMetaServer { code, reason } => {
+                assert_eq!(code, Some(ms::CloseCode::Draining));
+                assert_eq!(reason, b"draining");
+            }
+            e => panic!("unexpected {e}"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!heartbeat.is_connected());
+
+        // Killed without a word, so only silence gives it away. The connection is set up by hand
+        // to time out sooner than the heartbeat crate's own would.
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (_, addresses) = {
+            let _guard = runtime.enter();
+            serve(Config {
+                listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+                ..Config::default()
+            })
+        };
+        let mut config = client_config(ms::game::PROTOCOL_V3);
+        let mut transport = quinn::TransportConfig::default();
+        transport
+            .keep_alive_interval(Some(Duration::from_millis(100)))
+            .max_idle_timeout(Some(Duration::from_secs(1).try_into().unwrap()));
+        config.transport_config(Arc::new(transport));
+        let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        endpoint.set_default_client_config(config);
+        let conn = endpoint.connect(addresses[0], "localhost").unwrap();
+        let conn = metaserve_heartbeat::Connection::from(conn.await.unwrap());
+        let heartbeat = metaserve_heartbeat::Heartbeat::new(conn, 1000)
+            .await
+            .unwrap();
+        let closed = heartbeat.closed();
+        let start = Instant::now();
+        runtime.shutdown_background();
+        let closed = tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap();
+        assert!(
+            matches!(closed, metaserve_heartbeat::Closed::TimedOut),
+            "{closed}"
+        );
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        assert!(!heartbeat.is_connected());
+    }
+
+    /// Register a server that recently lost its connection, making it eligible for eviction,
+    /// and publish `size` bytes of state for it
+    fn add_server(state: &State, port: u16, size: usize) -> (ServerId, Result<()>) {
+        let id = {
+            let inner = &mut *state.lock();
+            let id = inner.add_server(Arc::default(), None, Instant::now());
+            inner.servers[id].lost_at = Some(Instant::now());
+            id
+        };
+        let addr = SocketAddr::from(([192, 0, 2, 1], port));
+        (id, state.update_server(id, None, addr, vec![0; size]))
+    }
+
+    /// Register a client that has subscribed to updates with the default filter
+    fn subscribed_client(state: &State) -> ClientId {
+        let mut inner = state.lock();
+        let policy = Arc::new(ListenerPolicy::open(([127, 0, 0, 1], 0).into()));
+        let id = inner.add_client(3, policy);
+        inner.subscribe(id);
+        id
+    }
+
+    /// Check that the state budget accounts for exactly the states held, and stays within `max`
+    fn check_budget(state: &State, max: usize) -> usize {
+        let inner = state.lock();
+        let held = inner
+            .servers
+            .iter()
+            .map(|(_, x)| x.state.len())
+            .sum::<usize>();
+        assert_eq!(inner.state_bytes, held);
+        assert!(held <= max);
+        held
+    }
+
+    #[test]
+    fn budget_reject() {
+        let state = state(|x| {
+            x.max_total_state_bytes = Some(1000);
+            x.state_budget_policy = BudgetPolicy::Reject;
+        });
+        let mut accepted = Vec::new();
+        for port in 0..100 {
+            let (id, result) = add_server(&state, port, 30);
+            if result.is_ok() {
+                accepted.push(id);
+            }
+        }
+        assert_eq!(accepted.len(), 1000 / 30);
+        assert_eq!(check_budget(&state, 1000), accepted.len() * 30);
+
+        // Shrinking and regrowing within the budget is fine, but growing past it isn't
+        let addr = SocketAddr::from(([192, 0, 2, 1], 0));
+        state
+            .update_server(accepted[0], None, addr, vec![1; 20])
+            .unwrap();
+        state
+            .update_server(accepted[0], None, addr, vec![1; 40])
+            .unwrap();
+        assert!(state
+            .update_server(accepted[0], None, addr, vec![1; 41])
+            .is_err());
+        check_budget(&state, 1000);
+        assert!(state.lock().servers.iter().all(|(_, x)| !x.evicted));
+        state.lock().maintain(Some(1000));
+    }
+
+    #[test]
+    fn budget_evict() {
+        let state = state(|x| {
+            x.max_total_state_bytes = Some(1000);
+            x.state_budget_policy = BudgetPolicy::Evict;
+        });
+        let mut ids = Vec::new();
+        for port in 0..100 {
+            let (id, result) = add_server(&state, port, 30);
+            result.unwrap();
+            ids.push(id);
+            check_budget(&state, 1000);
+        }
+        // The most recent servers survive, and everything older was evicted
+        let inner = state.lock();
+        let live = ids.iter().filter(|&&id| !inner.servers[id].evicted).count();
+        assert_eq!(live, 1000 / 30);
+        assert!(ids[ids.len() - live..]
+            .iter()
+            .all(|&id| !inner.servers[id].evicted));
+        drop(inner);
+        assert_eq!(check_budget(&state, 1000), live * 30);
+
+        // A single state larger than the budget can't be made to fit
+        let (_, result) = add_server(&state, 100, 1001);
+        assert!(result.is_err());
+        check_budget(&state, 1000);
+        state.lock().maintain(Some(1000));
+    }
+
+    /// Months of registrations coming and going, with a subscribed client that's never sent
+    /// anything, must not grow any collection beyond the number of servers live at once
+    #[tokio::test(start_paused = true)]
+    async fn churn_stays_bounded() {
+        const LIVE: usize = 64;
+        let state = state(|_| {});
+        let client = subscribed_client(&state);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut live = Vec::new();
+        let start = Instant::now();
+        for i in 0..200_000u32 {
+            if live.len() == LIVE || !live.is_empty() && rng.gen_bool(0.5) {
+                let id = live.swap_remove(rng.gen_range(0..live.len()));
+                state.remove_server(id, None);
+            } else {
+                let id = state
+                    .lock()
+                    .add_server(Arc::default(), None, Instant::now());
+                let addr = SocketAddr::from(([192, 0, 2, 1], rng.gen()));
+                state
+                    .update_server(id, None, addr, i.to_le_bytes().to_vec())
+                    .unwrap();
+                live.push(id);
+            }
+            tokio::time::advance(Duration::from_secs(30)).await;
+            if i % 1000 == 0 {
+                let mut inner = state.lock();
+                inner.maintain(None);
+                let client = &inner.clients[client];
+                assert!(client.dirty.len() <= LIVE);
+                assert!(client.lost.len() <= LIVE);
+                assert!(inner.servers.len() <= LIVE);
+            }
+        }
+        assert!(start.elapsed() > Duration::from_secs(60 * 60 * 24 * 60));
+        let inner = state.lock();
+        assert_eq!(inner.servers.len(), live.len());
+        assert_eq!(inner.state_bytes, live.len() * 4);
+    }
+
+    /// A game server connecting, heartbeating, and disconnecting as fast as possible while a
+    /// client drains its updates must never let the client see an update describing a server
+    /// after that server's shutdown
+    #[test]
+    fn no_update_after_shutdown() {
+        const GENERATIONS: u32 = 20_000;
+        let state = Arc::new(state(|_| {}));
+        let client = subscribed_client(&state);
+        let churn = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                let addr = SocketAddr::from(([192, 0, 2, 1], 1000));
+                for generation in 0..GENERATIONS {
+                    let id = state
+                        .lock()
+                        .add_server(Arc::default(), None, Instant::now());
+                    for heartbeat in 0..3u32 {
+                        let data = [generation.to_le_bytes(), heartbeat.to_le_bytes()].concat();
+                        state.update_server(id, None, addr, data).unwrap();
+                    }
+                    state.remove_server(id, None);
+                }
+            }
+        });
+
+        // The generation each server ID currently describes, as far as the client knows
+        let mut live = HashMap::<ms::client::ServerId, u32>::new();
+        // Generations below this have been reported shut down
+        let mut retired = 0;
+        let mut updates = 0;
+        loop {
+            let finished = churn.is_finished();
+            let (_, data) = state.lock().take_update(
+                client,
+                3,
+                Ipv4Addr::LOCALHOST.into(),
+                Duration::ZERO,
+                &mut false,
+                Instant::now(),
+            );
+            let msg = ms::client::Message::decode(&data, 3).unwrap();
+            assert!(msg.undecodable.is_empty());
+            for server in &msg.servers {
+                match server.event {
+                    ms::client::Event::Shutdown => {
+                        // Possibly of a generation the client was never told about
+                        if let Some(generation) = live.remove(&server.id) {
+                            retired = generation + 1;
+                        }
+                    }
+                    ms::client::Event::Update(_, data) => {
+                        let generation = u32::from_le_bytes(data[..4].try_into().unwrap());
+                        assert!(
+                            generation >= retired,
+                            "update for generation {generation} after its shutdown"
+                        );
+                        if let Some(old) = live.insert(server.id, generation) {
+                            assert_eq!(old, generation, "new generation without a shutdown");
+                        }
+                        updates += 1;
+                    }
+                    _ => {}
+                }
+            }
+            if finished {
+                break;
+            }
+        }
+        churn.join().unwrap();
+        assert!(live.is_empty());
+        assert!(updates > 0);
+        state.lock().maintain(None);
+    }
+
+    #[test]
+    fn stale_states_ignored() {
+        let mut seq = Sequence::default();
+        let accepted = [5, 3, 5, 6, 0, 8, 7, 9]
+            .into_iter()
+            .filter(|&n| seq.advance(n))
+            .collect::<Vec<_>>();
+        assert_eq!(accepted, [5, 6, 8, 9]);
+
+        // The first state is accepted whatever its number, and nothing follows the last
+        let mut seq = Sequence::default();
+        assert!(seq.advance(u64::MAX));
+        assert!(!seq.advance(0));
+        assert!(!seq.advance(u64::MAX));
+    }
+
+    #[test]
+    fn proof_of_work() {
+        let prefix = [7; 16];
+        let challenge = |difficulty| ms::game::Challenge {
+            prefix,
+            difficulty,
+            timeout: Duration::from_secs(10),
+        };
+        for nonce in 0..4096u64 {
+            // Leading zero bits of the hash, counted independently of `solves`
+            let data = [&prefix[..], &nonce.to_le_bytes()].concat();
+            let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+            let zeros = u128::from_be_bytes(digest.as_ref()[..16].try_into().unwrap())
+                .leading_zeros() as u8;
+            assert!(solves(&challenge(0), nonce));
+            assert!(solves(&challenge(zeros), nonce));
+            assert!(!solves(&challenge(zeros + 1), nonce));
+            assert!(!solves(&challenge(ms::game::MAX_DIFFICULTY), nonce));
+        }
+    }
+
+    /// A task that panics while holding the state lock mustn't wedge the daemon, or leave the
+    /// state inconsistent
+    #[tokio::test]
+    async fn lock_survives_panic() {
+        let state = Arc::new(state(|_| {}));
+        let (id, result) = add_server(&state, 1000, 10);
+        result.unwrap();
+        let (other, result) = add_server(&state, 1001, 10);
+        result.unwrap();
+        let client = subscribed_client(&state);
+        // Partway through removing a server, leaving the budget and the client's pending updates
+        // referring to it
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let mut inner = state.lock();
+                inner.servers.remove(other);
+                panic!("handler bug");
+            }
+        });
+        assert!(task.await.unwrap_err().is_panic());
+        assert!(state.inner.is_poisoned());
+
+        // The watchdog is first to notice, and repairs the state rather than giving up
+        assert_eq!(state.check_health(Duration::ZERO), Ok(()));
+        assert!(!state.inner.is_poisoned());
+        {
+            let inner = state.lock();
+            assert_eq!(inner.inconsistency(), None);
+            assert_eq!(inner.state_bytes, 10);
+            // What the client was told may be inconsistent too, so it's sent a fresh snapshot
+            let client = &inner.clients[client];
+            assert!(client.reset);
+            assert_eq!(client.dirty.iter().copied().collect::<Vec<_>>(), [id]);
+        }
+
+        let addr = SocketAddr::from(([192, 0, 2, 1], 1000));
+        state.update_server(id, None, addr, vec![1; 20]).unwrap();
+        let (_, result) = add_server(&state, 1002, 30);
+        result.unwrap();
+        state.remove_server(id, None);
+        assert_eq!(check_budget(&state, usize::MAX), 30);
+        assert_eq!(state.lock().servers.len(), 1);
+        state.lock().maintain(None);
+    }
+
+    /// A connection handler that panics still removes its game server or client, and the daemon
+    /// carries on serving others
+    #[tokio::test]
+    async fn handler_panic_cleans_up() {
+        let (state, addresses) = serve(Config {
+            listen: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            client_update_interval: 0.1,
+            ..Config::default()
+        });
+        let (mut client, mut list) = synchronized_client(addresses[0]).await;
+        let mut heartbeat = game_server(addresses[0], 1000).await;
+        heartbeat.send(b"state").await.unwrap();
+        let id = heartbeat.advertised().await.unwrap().id;
+        receive_until(&mut client, &mut list, |x| x.get(id).is_some()).await;
+
+        // Other clients are told the game server is gone, as if it had disconnected
+        let closed = heartbeat.closed();
+        heartbeat.send(b"panic!").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap();
+        receive_until(&mut client, &mut list, |x| x.is_empty()).await;
+        assert_eq!(state.lock().servers.len(), 0);
+
+        let (panicking, _) = synchronized_client(addresses[0]).await;
+        assert_eq!(state.lock().clients.len(), 2);
+        let filter = ms::client::Filter {
+            required: vec!["panic!".into()],
+            excluded: Vec::new(),
+        };
+        let result = panicking
+            .find_one(filter, ms::client::Strategy::Random)
+            .await;
+        assert!(result.is_err());
+        assert_eq!(state.lock().clients.len(), 1);
+
+        assert!(!state.inner.is_poisoned());
+        assert_eq!(state.lock().inconsistency(), None);
+        let (_, list) = synchronized_client(addresses[0]).await;
+        assert!(list.is_empty());
+    }
+
+    /// The watchdog gives up on a lock that's held for too long
+    #[test]
+    fn watchdog_detects_wedged_lock() {
+        let state = state(|_| {});
+        assert_eq!(state.check_health(Duration::ZERO), Ok(()));
+        let inner = state.lock();
+        let health = thread::scope(|s| {
+            let check = s.spawn(|| state.check_health(Duration::from_millis(50)));
+            check.join().unwrap()
+        });
+        assert_eq!(health, Err("state lock held too long"));
+        drop(inner);
+        assert_eq!(state.check_health(Duration::ZERO), Ok(()));
+    }
 }
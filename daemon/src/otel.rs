@@ -0,0 +1,43 @@
+//! Exporting tracing spans to an OpenTelemetry collector
+
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+pub struct Exporter(SdkTracerProvider);
+
+impl Exporter {
+    /// Export to the OTLP/HTTP collector at `endpoint`, in batches on a background thread
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .context("failed to configure OTLP exporter")?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("metaserve").build())
+            .build();
+        Ok(Self(provider))
+    }
+
+    /// A layer feeding spans to this exporter
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, Tracer>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.0.tracer("metaserve"))
+    }
+
+    /// Export any remaining spans
+    pub fn shutdown(self) {
+        let _ = self.0.shutdown();
+    }
+}
@@ -0,0 +1,553 @@
+//! `--selftest`: diagnosing why clients or game servers can't connect to a daemon
+
+use std::{
+    fmt, fs,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use metaserve_proto as ms;
+use quinn::crypto::rustls::QuicClientConfig;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use tokio::time::{Duration, Instant};
+
+/// How long to wait for each handshake
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handshake with the daemon at `server` as a client and as a game server, trusting the
+/// certificate authorities in `ca` and the usual public ones, and describe what happened
+///
+/// Fails if the certificate isn't trusted for `server`'s host name, or if neither handshake
+/// completes. If `save` is given, the certificate is written there regardless.
+#[tokio::main]
+pub async fn run(server: &str, ca: &[PathBuf], save: Option<&Path>) -> Result<()> {
+    let (host, _) = server
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("{:?} isn't of the form host:port", server))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|_| anyhow!("{:?} isn't a valid host name or IP address", host))?;
+    let addr = server
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {}", server))?
+        .next()
+        .ok_or_else(|| anyhow!("{} has no addresses", host))?;
+    println!("{} resolved to {}", host, addr);
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for path in ca {
+        let der = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        roots
+            .add(CertificateDer::from(der))
+            .with_context(|| format!("{} isn't a DER certificate", path.display()))?;
+    }
+    let verifier = Arc::new(Inspect {
+        inner: WebPkiServerVerifier::builder(Arc::new(roots)).build()?,
+        seen: Mutex::new(None),
+    });
+    let bind = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let endpoint = quinn::Endpoint::client(bind.parse().unwrap())?;
+
+    let mut connected = false;
+    for (role, protocols) in [
+        ("client", ms::client::PROTOCOLS),
+        ("game server", ms::game::PROTOCOLS),
+    ] {
+        match handshake(&endpoint, &verifier, protocols, addr, &name).await {
+            Ok((alpn, elapsed, rtt)) => {
+                connected = true;
+                println!(
+                    "as a {}: negotiated {} in {:?} (round trip {:?})",
+                    role,
+                    describe_alpn(&alpn),
+                    elapsed,
+                    rtt
+                );
+            }
+            Err(e) => println!("as a {}: {}", role, explain(&e, role)),
+        }
+    }
+    endpoint.wait_idle().await;
+
+    let (chain, verdict) = verifier
+        .seen
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .ok_or_else(|| anyhow!("no handshake completed"))?;
+    println!("certificate chain:");
+    let mut leaf = None;
+    for (i, der) in chain.iter().enumerate() {
+        match Certificate::parse(der) {
+            Some(cert) => {
+                println!("  {}: {}", i, cert.subject);
+                println!("     issuer: {}", cert.issuer);
+                println!("     valid: {} to {}", cert.not_before, cert.not_after);
+                if !cert.names.is_empty() {
+                    println!("     names: {}", cert.names.join(", "));
+                }
+                if let Some(x) = crate::certificate_fingerprint(der) {
+                    println!("     SPKI SHA-256: {}", x);
+                }
+                if i == 0 {
+                    leaf = Some(cert);
+                }
+            }
+            None => println!("  {}: unparseable ({} bytes)", i, der.len()),
+        }
+    }
+    if let Some(path) = save {
+        fs::write(path, &chain[0])
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        println!("saved certificate to {}", path.display());
+    }
+    match verdict {
+        Ok(()) => {
+            println!("certificate: trusted for {}", host);
+            if let Some(leaf) = leaf {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |x| x.as_secs() as i64);
+                let days = (leaf.not_after.unix - now) / (24 * 60 * 60);
+                if days < 14 {
+                    println!("WARNING: certificate expires in {} days", days);
+                }
+            }
+        }
+        Err(e) => bail!("{}", explain_certificate(&e, host, leaf.as_ref(), ca)),
+    }
+    if !connected {
+        bail!("no handshake completed");
+    }
+    Ok(())
+}
+
+/// Connect offering `protocols`, returning the negotiated protocol, the handshake's duration, and
+/// the measured round-trip time
+async fn handshake(
+    endpoint: &quinn::Endpoint,
+    verifier: &Arc<Inspect>,
+    protocols: &[&[u8]],
+    addr: SocketAddr,
+    name: &ServerName<'static>,
+) -> Result<(Vec<u8>, Duration, Duration), Failure> {
+    let mut crypto = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    crypto.alpn_protocols = protocols.iter().map(|&x| x.into()).collect();
+    let crypto = QuicClientConfig::try_from(crypto).map_err(|e| Failure::Other(e.into()))?;
+    let start = Instant::now();
+    let connecting = endpoint
+        .connect_with(
+            quinn::ClientConfig::new(Arc::new(crypto)),
+            addr,
+            &name.to_str(),
+        )
+        .map_err(|e| Failure::Other(e.into()))?;
+    let conn = tokio::time::timeout(TIMEOUT, connecting)
+        .await
+        .map_err(|_| Failure::Timeout)?
+        .map_err(Failure::Connection)?;
+    let elapsed = start.elapsed();
+    // A server checks any client certificate only after the client considers the handshake done
+    if let Ok(e) = tokio::time::timeout(elapsed, conn.closed()).await {
+        return Err(Failure::Connection(e));
+    }
+    let alpn = conn
+        .handshake_data()
+        .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|x| x.protocol)
+        .unwrap_or_default();
+    let rtt = conn.rtt();
+    conn.close(0u32.into(), b"selftest");
+    Ok((alpn, elapsed, rtt))
+}
+
+enum Failure {
+    Timeout,
+    Connection(quinn::ConnectionError),
+    Other(anyhow::Error),
+}
+
+/// TLS alert sent when a client certificate is required but wasn't presented
+const CERTIFICATE_REQUIRED: u8 = 116;
+/// TLS alert sent when no offered ALPN is acceptable
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// Describe `failure` to connect as `role` with a suggestion of what to check
+fn explain(failure: &Failure, role: &str) -> String {
+    use quinn::{ConnectionError::*, TransportErrorCode};
+    let e = match *failure {
+        Failure::Timeout => {
+            return format!(
+                "no response within {:?}; check that the daemon is running and that UDP traffic \
+                 to its port isn't blocked",
+                TIMEOUT
+            )
+        }
+        Failure::Connection(ref e) => e,
+        Failure::Other(ref e) => return format!("{:#}", e),
+    };
+    let code = match *e {
+        TransportError(ref e) => e.code,
+        ConnectionClosed(ref e) => e.error_code,
+        _ => return e.to_string(),
+    };
+    if code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL) {
+        format!(
+            "refused every {} protocol version; the listener may not accept {}s (see `accept` in \
+             its configuration), or the daemon may be too old or too new for this one",
+            role, role
+        )
+    } else if code == TransportErrorCode::crypto(CERTIFICATE_REQUIRED) {
+        "refused to connect without a client certificate; the listener requires one \
+         (`client_ca`), which this check can't present"
+            .into()
+    } else {
+        e.to_string()
+    }
+}
+
+/// Describe why the certificate wasn't trusted, with a suggestion of how to fix it
+fn explain_certificate(
+    error: &rustls::Error,
+    host: &str,
+    leaf: Option<&Certificate>,
+    ca: &[PathBuf],
+) -> String {
+    use CertificateError::*;
+    let rustls::Error::InvalidCertificate(ref error) = *error else {
+        return format!("certificate rejected: {}", error);
+    };
+    match *error {
+        NotValidForName | NotValidForNameContext { .. } => match leaf {
+            Some(leaf) if leaf.names.is_empty() => format!(
+                "certificate has no subject alternative names, so isn't valid for {}; reissue it \
+                 with a DNS name or IP address matching the one clients connect to",
+                host
+            ),
+            Some(leaf) => format!(
+                "certificate has no subject alternative name matching {} (it has {}); connect \
+                 using one of those names or reissue the certificate",
+                host,
+                leaf.names.join(", ")
+            ),
+            None => format!("certificate isn't valid for {}", host),
+        },
+        Expired | ExpiredContext { .. } => match leaf {
+            Some(leaf) => format!(
+                "certificate expired at {}; renew it, or check this machine's clock",
+                leaf.not_after
+            ),
+            None => "certificate has expired; renew it, or check this machine's clock".into(),
+        },
+        NotValidYet | NotValidYetContext { .. } => match leaf {
+            Some(leaf) => format!(
+                "certificate isn't valid until {}; check this machine's clock",
+                leaf.not_before
+            ),
+            None => "certificate isn't valid yet; check this machine's clock".into(),
+        },
+        UnknownIssuer if ca.is_empty() => "certificate isn't signed by a publicly trusted \
+                                           authority; pass the authority that signed it with --ca"
+            .into(),
+        UnknownIssuer => "certificate isn't signed by a publicly trusted authority or one given \
+                          with --ca; check that the daemon presents its whole chain"
+            .into(),
+        BadEncoding => "certificate is malformed; check that --cert is in DER format".into(),
+        ref e => format!("certificate rejected: {}", e),
+    }
+}
+
+/// Name and version of the protocol identified by `alpn`
+fn describe_alpn(alpn: &[u8]) -> String {
+    for (role, protocols) in [
+        ("client", ms::client::PROTOCOLS),
+        ("game", ms::game::PROTOCOLS),
+    ] {
+        if let Some(i) = protocols.iter().position(|&x| x == alpn) {
+            return format!("{} protocol v{}", role, protocols.len() - i);
+        }
+    }
+    format!("unknown protocol {:?}", String::from_utf8_lossy(alpn))
+}
+
+/// Accepts any certificate, recording the chain and whether it should have been accepted
+///
+/// Letting the handshake complete regardless allows the rest of the checks to go ahead.
+#[derive(Debug)]
+struct Inspect {
+    inner: Arc<WebPkiServerVerifier>,
+    seen: Mutex<Option<Seen>>,
+}
+
+/// A presented certificate chain, leaf first, and whether it's trusted
+type Seen = (Vec<CertificateDer<'static>>, Result<(), rustls::Error>);
+
+impl ServerCertVerifier for Inspect {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verdict = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .map(|_| ());
+        let chain = std::iter::once(end_entity)
+            .chain(intermediates)
+            .map(|x| x.clone().into_owned())
+            .collect();
+        *self.seen.lock().unwrap_or_else(PoisonError::into_inner) = Some((chain, verdict));
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// The parts of an X.509 certificate worth reporting
+struct Certificate {
+    subject: String,
+    issuer: String,
+    not_before: Time,
+    not_after: Time,
+    /// Subject alternative names, e.g. "DNS:example.com" or "IP:192.0.2.1"
+    names: Vec<String>,
+}
+
+impl Certificate {
+    fn parse(der: &[u8]) -> Option<Self> {
+        let mut input = der;
+        let mut cert = expect(&mut input, SEQUENCE)?;
+        let mut tbs = expect(&mut cert, SEQUENCE)?;
+        if tbs.first() == Some(&0xa0) {
+            read(&mut tbs)?; // version
+        }
+        read(&mut tbs)?; // serial number
+        read(&mut tbs)?; // signature algorithm
+        let issuer = name(expect(&mut tbs, SEQUENCE)?)?;
+        let mut validity = expect(&mut tbs, SEQUENCE)?;
+        let not_before = Time::parse(read(&mut validity)?)?;
+        let not_after = Time::parse(read(&mut validity)?)?;
+        let subject = name(expect(&mut tbs, SEQUENCE)?)?;
+        read(&mut tbs)?; // public key
+        let mut names = Vec::new();
+        while let Some((tag, mut value)) = read(&mut tbs) {
+            if tag != 0xa3 {
+                continue;
+            }
+            let mut extensions = expect(&mut value, SEQUENCE)?;
+            while !extensions.is_empty() {
+                let mut extension = expect(&mut extensions, SEQUENCE)?;
+                if expect(&mut extension, OID)? != SUBJECT_ALT_NAME {
+                    continue;
+                }
+                if extension.first() == Some(&BOOLEAN) {
+                    read(&mut extension)?; // critical
+                }
+                let mut value = expect(&mut extension, OCTET_STRING)?;
+                let mut general_names = expect(&mut value, SEQUENCE)?;
+                while let Some((tag, value)) = read(&mut general_names) {
+                    names.push(match tag {
+                        0x82 => format!("DNS:{}", String::from_utf8_lossy(value)),
+                        0x87 => match value.len() {
+                            4 => format!("IP:{}", IpAddr::from(<[u8; 4]>::try_from(value).ok()?)),
+                            16 => {
+                                format!("IP:{}", IpAddr::from(<[u8; 16]>::try_from(value).ok()?))
+                            }
+                            _ => continue,
+                        },
+                        0x86 => format!("URI:{}", String::from_utf8_lossy(value)),
+                        _ => continue,
+                    });
+                }
+            }
+        }
+        Some(Self {
+            subject,
+            issuer,
+            not_before,
+            not_after,
+            names,
+        })
+    }
+}
+
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+
+/// 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Read a DER value from the start of `input`, returning its tag and contents
+fn read<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let (len, tail) = rest.split_at(n);
+        rest = tail;
+        len.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (value, rest) = rest.split_at(len);
+    *input = rest;
+    Some((tag, value))
+}
+
+/// Read a DER value with tag `tag` from the start of `input`, returning its contents
+fn expect<'a>(input: &mut &'a [u8], tag: u8) -> Option<&'a [u8]> {
+    read(input).filter(|&(x, _)| x == tag).map(|(_, x)| x)
+}
+
+/// Format an X.501 name, e.g. "CN=example.com, O=Example"
+fn name(mut input: &[u8]) -> Option<String> {
+    let mut parts = Vec::new();
+    while !input.is_empty() {
+        let mut set = expect(&mut input, SET)?;
+        while !set.is_empty() {
+            let mut attribute = expect(&mut set, SEQUENCE)?;
+            let oid = expect(&mut attribute, OID)?;
+            let (_, value) = read(&mut attribute)?;
+            let key = match oid {
+                [0x55, 0x04, 0x03] => "CN".into(),
+                [0x55, 0x04, 0x06] => "C".into(),
+                [0x55, 0x04, 0x07] => "L".into(),
+                [0x55, 0x04, 0x08] => "ST".into(),
+                [0x55, 0x04, 0x0a] => "O".into(),
+                [0x55, 0x04, 0x0b] => "OU".into(),
+                _ => format_oid(oid),
+            };
+            parts.push(format!("{}={}", key, String::from_utf8_lossy(value)));
+        }
+    }
+    if parts.is_empty() {
+        return Some("(empty)".into());
+    }
+    Some(parts.join(", "))
+}
+
+/// Dotted decimal form of a DER-encoded object identifier
+fn format_oid(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value = 0u64;
+    for &b in oid {
+        value = value << 7 | u64::from(b & 0x7f);
+        if b & 0x80 != 0 {
+            continue;
+        }
+        if arcs.is_empty() {
+            let first = (value / 40).min(2);
+            arcs.push(first);
+            arcs.push(value - first * 40);
+        } else {
+            arcs.push(value);
+        }
+        value = 0;
+    }
+    arcs.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// A moment in UTC, from a certificate
+struct Time {
+    /// Seconds since the Unix epoch
+    unix: i64,
+    fields: [u32; 6],
+}
+
+impl Time {
+    /// Parse an ASN.1 UTCTime or GeneralizedTime
+    fn parse((tag, value): (u8, &[u8])) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+        let (year, rest) = match tag {
+            0x17 => {
+                let year = value.get(..2)?.parse::<u32>().ok()?;
+                (if year < 50 { 2000 } else { 1900 } + year, &value[2..])
+            }
+            0x18 => (value.get(..4)?.parse().ok()?, &value[4..]),
+            _ => return None,
+        };
+        if rest.len() != 10 || !rest.bytes().all(|x| x.is_ascii_digit()) {
+            return None;
+        }
+        let field = |i: usize| rest[i..i + 2].parse::<u32>().unwrap();
+        let fields = [year, field(0), field(2), field(4), field(6), field(8)];
+        let [y, m, d, hour, minute, second] = fields.map(i64::from);
+        // Days from civil date, after Howard Hinnant
+        let y = if m <= 2 { y - 1 } else { y };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        Some(Self {
+            unix: days * 86400 + hour * 3600 + minute * 60 + second,
+            fields,
+        })
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [y, m, d, hour, minute, second] = self.fields;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            y, m, d, hour, minute, second
+        )
+    }
+}
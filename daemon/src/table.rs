@@ -0,0 +1,184 @@
+//! Slabs indexed by typed IDs, so that one kind of ID can't be used where another is expected
+
+use std::{
+    fmt, iter,
+    marker::PhantomData,
+    ops::{Index, IndexMut},
+};
+
+use metaserve_proto as ms;
+use slab::Slab;
+
+/// Identifies an entry in a [`Table`]
+pub trait Key: Copy {
+    fn from_index(index: usize) -> Self;
+    fn index(self) -> usize;
+}
+
+macro_rules! key {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(usize);
+
+        impl Key for $name {
+            fn from_index(index: usize) -> Self {
+                Self(index)
+            }
+
+            fn index(self) -> usize {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+key!(
+    /// A game server's entry in the server table, which clients know it by
+    ServerId
+);
+key!(
+    /// A client's entry in the client table
+    ClientId
+);
+
+impl ServerId {
+    /// The ID clients know this server by
+    pub fn wire(self) -> ms::client::ServerId {
+        ms::client::ServerId(self.0 as u64)
+    }
+
+    /// The server a client means by `id`, if it could exist
+    pub fn from_wire(id: ms::client::ServerId) -> Option<Self> {
+        usize::try_from(id.0).ok().map(Self)
+    }
+}
+
+/// A [`Slab`] whose entries are identified by `K`
+pub struct Table<K, V> {
+    slab: Slab<V>,
+    _key: PhantomData<fn(K) -> K>,
+}
+
+impl<K: Key, V> Table<K, V> {
+    pub fn new() -> Self {
+        Self {
+            slab: Slab::new(),
+            _key: PhantomData,
+        }
+    }
+
+    pub fn insert(&mut self, value: V) -> K {
+        K::from_index(self.slab.insert(value))
+    }
+
+    /// Panics if there's no entry for `key`
+    pub fn remove(&mut self, key: K) -> V {
+        self.slab.remove(key.index())
+    }
+
+    pub fn get(&self, key: K) -> Option<&V> {
+        self.slab.get(key.index())
+    }
+
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        self.slab.get_mut(key.index())
+    }
+
+    pub fn len(&self) -> usize {
+        self.slab.len()
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.slab.contains(key.index())
+    }
+
+    pub fn iter(&self) -> <&Self as IntoIterator>::IntoIter {
+        self.into_iter()
+    }
+
+    pub fn iter_mut(&mut self) -> <&mut Self as IntoIterator>::IntoIter {
+        self.into_iter()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.slab.shrink_to_fit();
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a Table<K, V> {
+    type Item = (K, &'a V);
+    type IntoIter = iter::Map<slab::Iter<'a, V>, fn((usize, &'a V)) -> (K, &'a V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slab.iter().map(|(i, x)| (K::from_index(i), x))
+    }
+}
+
+impl<'a, K: Key, V> IntoIterator for &'a mut Table<K, V> {
+    type Item = (K, &'a mut V);
+    type IntoIter = iter::Map<slab::IterMut<'a, V>, fn((usize, &'a mut V)) -> (K, &'a mut V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slab.iter_mut().map(|(i, x)| (K::from_index(i), x))
+    }
+}
+
+impl<K: Key, V> Index<K> for Table<K, V> {
+    type Output = V;
+
+    fn index(&self, key: K) -> &V {
+        &self.slab[key.index()]
+    }
+}
+
+impl<K: Key, V> IndexMut<K> for Table<K, V> {
+    fn index_mut(&mut self, key: K) -> &mut V {
+        &mut self.slab[key.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_typed_and_reused() {
+        let mut servers = Table::<ServerId, &str>::new();
+        let mut clients = Table::<ClientId, &str>::new();
+        let a = servers.insert("a");
+        let b = servers.insert("b");
+        let client = clients.insert("client");
+        assert_ne!(a, b);
+        assert_eq!(servers[b], "b");
+        assert_eq!(clients[client], "client");
+        assert_eq!(servers.len(), 2);
+
+        assert_eq!(servers.remove(a), "a");
+        assert!(!servers.contains(a));
+        assert!(servers.get(a).is_none());
+        // Freed entries are reused, so an ID is only meaningful while its entry exists
+        let c = servers.insert("c");
+        assert_eq!(c, a);
+        assert_eq!(
+            servers.iter().map(|(id, &x)| (id, x)).collect::<Vec<_>>(),
+            [(c, "c"), (b, "b")]
+        );
+    }
+
+    #[test]
+    fn server_ids_round_trip_through_wire() {
+        let mut servers = Table::<ServerId, ()>::new();
+        let ids = (0..3).map(|_| servers.insert(())).collect::<Vec<_>>();
+        for id in ids {
+            assert_eq!(ServerId::from_wire(id.wire()), Some(id));
+            assert_eq!(id.wire().0, id.index() as u64);
+        }
+    }
+}
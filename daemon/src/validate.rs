@@ -0,0 +1,39 @@
+/// A check that game server states must pass before they're relayed to clients
+pub trait StateValidator: Send + Sync {
+    /// Explain why `state` is unacceptable, if it is
+    fn validate(&self, state: &[u8]) -> Result<(), String>;
+}
+
+/// Requires states to be UTF-8 text
+pub struct Utf8;
+
+impl StateValidator for Utf8 {
+    fn validate(&self, state: &[u8]) -> Result<(), String> {
+        std::str::from_utf8(state)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Requires states to be a single JSON value
+pub struct Json;
+
+impl StateValidator for Json {
+    fn validate(&self, state: &[u8]) -> Result<(), String> {
+        serde_json::from_slice::<serde::de::IgnoredAny>(state)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The validators enabled by `config`
+pub fn from_config(config: &crate::config::Config) -> Vec<Box<dyn StateValidator>> {
+    let mut result = Vec::<Box<dyn StateValidator>>::new();
+    if config.require_utf8_state {
+        result.push(Box::new(Utf8));
+    }
+    if config.require_json_state {
+        result.push(Box::new(Json));
+    }
+    result
+}
@@ -1,14 +1,9 @@
-use std::{
-    fs,
-    io::{self, Write},
-    net::ToSocketAddrs,
-    path::PathBuf,
-    sync::Arc,
-};
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use clap::Parser;
-use metaserve_heartbeat::Heartbeat;
+use futures_util::StreamExt;
+use metaserve_heartbeat::{Error, Heartbeat, ServerEvent, Stats};
 
 #[derive(Parser, Debug)]
 #[clap(name = "print")]
@@ -19,6 +14,15 @@ struct Opt {
     /// Additional certificate authority to trust, in DER format
     #[clap(parse(from_os_str), long = "ca")]
     ca: Option<PathBuf>,
+    /// Print connection statistics after every heartbeat
+    #[clap(short = 'v', long = "verbose")]
+    verbose: bool,
+    /// Tag to advertise; may be repeated
+    #[clap(long = "tag")]
+    tags: Vec<String>,
+    /// Address to advertise to game clients on the same network; may be repeated
+    #[clap(long = "lan-address")]
+    lan_addresses: Vec<SocketAddr>,
 }
 
 fn main() {
@@ -36,49 +40,99 @@ fn main() {
 
 #[tokio::main(flavor = "current_thread")]
 async fn run(options: Opt) -> Result<()> {
-    let mut roots = rustls::RootCertStore::empty();
+    let mut builder = Heartbeat::builder()
+        .tags(options.tags)
+        .lan_addresses(options.lan_addresses);
     if let Some(ca_path) = options.ca {
-        roots.add(&rustls::Certificate(
-            fs::read(&ca_path).context("reading CA")?,
-        ))?;
+        builder = builder.ca(fs::read(&ca_path).context("reading CA")?);
     }
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
 
-    client_crypto.alpn_protocols = vec![metaserve_heartbeat::proto::PROTOCOL.into()];
-    let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
-    Arc::get_mut(&mut client_config.transport)
-        .unwrap()
-        .keep_alive_interval(Some(std::time::Duration::from_secs(5)))
-        .max_concurrent_bidi_streams(0u32.into())
-        .max_concurrent_uni_streams(1u32.into());
-
-    let endpoint = quinn::Endpoint::client("[::]:0".parse().unwrap())?;
-
-    let hostname = options.meta.split(':').next().unwrap();
-
-    let addr = options
-        .meta
-        .to_socket_addrs()
-        .map_err(|_| anyhow!("invalid meta server address -- did you forget a port number?"))?
-        .next()
-        .map_or_else(|| Err(anyhow!("no such hostname")), Ok)?;
-
-    print!("connecting to {}...", addr);
-    io::stdout().flush()?;
+    println!("connecting to {}...", options.meta);
+    let mut heartbeat = builder.connect(&options.meta, 1234).await?;
+    println!("connected to {}", heartbeat.remote_address());
+    if options.verbose {
+        println!("local address {:?}", heartbeat.local_ip());
+        println!(
+            "protocol v{} ({}), certificate {}",
+            heartbeat.protocol_version(),
+            hex(heartbeat.negotiated_alpn()),
+            heartbeat
+                .peer_certificate_fingerprint()
+                .map_or_else(|| "unknown".into(), |x| hex(&x))
+        );
+        println!("meta server version {:?}", heartbeat.peer_version());
+        println!("meta server limits {:?}", heartbeat.limits());
+    }
 
-    let conn = endpoint
-        .connect_with(client_config, addr, hostname)?
-        .await?;
-    println!(" connected");
+    let closed = heartbeat.closed();
+    tokio::select! {
+        result = beat(&mut heartbeat, options.verbose) => result?,
+        e = closed => return Err(e.into()),
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    heartbeat.close_with_reason("interrupted").await;
+    Ok(())
+}
 
-    let mut heartbeat = Heartbeat::new(conn, 1234).await?;
+async fn beat(heartbeat: &mut Heartbeat, verbose: bool) -> Result<()> {
     let mut i = 0;
     loop {
         let msg = format!("heartbeat #{}", i);
         i += 1;
-        heartbeat.send(msg.as_bytes()).await?;
+        let report = heartbeat.send(msg.as_bytes()).await?;
+        if verbose {
+            println!(
+                "sent {}B{}, rtt {:?}, {}",
+                report.bytes,
+                if report.throttled { " (throttled)" } else { "" },
+                report.rtt,
+                match heartbeat.last_ack() {
+                    Some(x) => format!("acked {:?} ago", x.elapsed()),
+                    None => "not acked yet".into(),
+                }
+            );
+        }
+        if i == 1 {
+            // Game clients can find us once the first heartbeat is stored
+            match heartbeat.advertised_within(Duration::from_secs(5)).await {
+                Ok(Some(x)) => println!("advertised as id {} at {}", x.id, x.address),
+                Ok(None) => println!("not advertised yet"),
+                Err(Error::Unsupported) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        // Send periodically, or sooner if asked
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while let Ok(event) = tokio::time::timeout_at(deadline, heartbeat.events().next()).await {
+            match event {
+                Some(ServerEvent::RefreshRequested) => {
+                    println!("refresh requested");
+                    break;
+                }
+                Some(ServerEvent::ObservedAddress(x)) => println!("visible at {}", x),
+                Some(x) => println!("{:?}", x),
+                // Reported by `Heartbeat::closed`
+                None => std::future::pending().await,
+            }
+        }
+        if verbose {
+            print_stats(&heartbeat.stats());
+        }
     }
 }
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn print_stats(stats: &Stats) {
+    println!(
+        "rtt {:?}, cwnd {}, congestion events {}, lost packets {}, sent {}B, received {}B",
+        stats.rtt,
+        stats.cwnd,
+        stats.congestion_events,
+        stats.lost_packets,
+        stats.bytes_sent,
+        stats.bytes_received
+    );
+}
@@ -0,0 +1,177 @@
+//! Assembling a game server's state from sections that change independently
+
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Poll, Waker},
+};
+
+use crate::{proto, Error, Heartbeat, SendReport};
+
+/// Assembles a game server's state from named sections that change independently, e.g. a player
+/// list, the current map, and performance metrics
+///
+/// The state is encoded as [`proto::Sections`], in the order sections were first set. Clones share
+/// the same sections, so each part of the game server can keep its own and update it at its own
+/// pace, while [`run`](Self::run) sends heartbeats as needed.
+#[derive(Clone, Default)]
+pub struct StateComposer(Arc<Mutex<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    sections: Vec<Section>,
+    /// `sections` as encoded
+    state: Vec<u8>,
+    /// Largest allowed `state`, from the meta server's limits
+    max_size: Option<usize>,
+    /// Set when the state changes, and cleared when it's sent
+    changed: bool,
+    /// Wakes [`StateComposer::run`] when the state changes
+    waker: Option<Waker>,
+}
+
+struct Section {
+    name: String,
+    data: Vec<u8>,
+    /// Whether `data` changed since the state was last sent
+    dirty: bool,
+}
+
+impl StateComposer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the contents of the section named `name`, adding it if it's new
+    ///
+    /// Fails with [`Error::StateTooLarge`], leaving the state as it was, if the state would then
+    /// exceed the meta server's [`max_state_size`](proto::Limits::max_state_size) as last seen by
+    /// [`run`](Self::run). Setting a section to its current contents changes nothing.
+    pub fn set_section(&self, name: &str, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        let data = data.into();
+        let mut inner = self.lock();
+        let index = inner.sections.iter().position(|x| x.name == name);
+        if index.is_some_and(|i| inner.sections[i].data == data) {
+            return Ok(());
+        }
+        let state = {
+            let mut sections = inner.view();
+            match index {
+                Some(i) => sections.0[i].1 = &data,
+                None => sections.0.push((name, &data)),
+            }
+            sections.encode()
+        };
+        if inner.max_size.is_some_and(|max| state.len() > max) {
+            return Err(Error::StateTooLarge);
+        }
+        match index {
+            Some(i) => {
+                let section = &mut inner.sections[i];
+                section.data = data;
+                section.dirty = true;
+            }
+            None => inner.sections.push(Section {
+                name: name.into(),
+                data,
+                dirty: true,
+            }),
+        }
+        inner.state = state;
+        inner.touch();
+        Ok(())
+    }
+
+    /// Remove the section named `name`, returning whether it existed
+    pub fn remove_section(&self, name: &str) -> bool {
+        let mut inner = self.lock();
+        let Some(index) = inner.sections.iter().position(|x| x.name == name) else {
+            return false;
+        };
+        inner.sections.remove(index);
+        inner.state = inner.view().encode();
+        inner.touch();
+        true
+    }
+
+    /// The current state, encoded as [`proto::Sections`]
+    pub fn state(&self) -> Vec<u8> {
+        self.lock().state.clone()
+    }
+
+    /// Names of the sections that changed since the state was last sent by [`run`](Self::run)
+    pub fn dirty_sections(&self) -> Vec<String> {
+        let inner = self.lock();
+        let dirty = inner.sections.iter().filter(|x| x.dirty);
+        dirty.map(|x| x.name.clone()).collect()
+    }
+
+    /// Send heartbeats carrying the state over `heartbeat` until it fails
+    ///
+    /// Sends the current state if any section has been set, and then again whenever a section
+    /// changes or the meta server [requests a refresh](Heartbeat::refresh_requested), waiting for
+    /// the meta server's minimum interval as [`Heartbeat::send`] does. Changes made meanwhile are
+    /// sent together. After reconnecting, call again with the new [`Heartbeat`].
+    pub async fn run(&self, heartbeat: &mut Heartbeat) -> Result<(), Error> {
+        if !self.lock().sections.is_empty() {
+            self.send(heartbeat).await?;
+        }
+        loop {
+            {
+                let mut refresh = pin!(heartbeat.refresh_requested());
+                poll_fn(|cx| {
+                    if let Poll::Ready(result) = refresh.as_mut().poll(cx) {
+                        return Poll::Ready(result);
+                    }
+                    let mut inner = self.lock();
+                    if inner.changed {
+                        return Poll::Ready(Ok(()));
+                    }
+                    inner.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                })
+                .await?;
+            }
+            self.send(heartbeat).await?;
+        }
+    }
+
+    /// Send the current state, noting the meta server's latest limits
+    async fn send(&self, heartbeat: &mut Heartbeat) -> Result<SendReport, Error> {
+        let state = {
+            let mut inner = self.lock();
+            inner.max_size = heartbeat.limits().map(|x| x.max_state_size as usize);
+            inner.changed = false;
+            for section in &mut inner.sections {
+                section.dirty = false;
+            }
+            inner.state.clone()
+        };
+        heartbeat.send(&state).await
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    /// The sections in their encodable form
+    fn view(&self) -> proto::Sections<'_> {
+        proto::Sections(
+            self.sections
+                .iter()
+                .map(|x| (&x.name[..], &x.data[..]))
+                .collect(),
+        )
+    }
+
+    /// Note that the state changed, waking [`StateComposer::run`]
+    fn touch(&mut self) {
+        self.changed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
@@ -1,32 +1,1425 @@
-use tokio::time::{Duration, Instant};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    net::{IpAddr, SocketAddr},
+    pin::{pin, Pin},
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "helpers")]
+use std::{collections::BTreeMap, io};
 
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream;
+#[cfg(feature = "helpers")]
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::pki_types::CertificateDer;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use thiserror::Error;
+
+pub use compose::StateComposer;
 pub use metaserve_proto::game as proto;
+pub use metaserve_proto::CloseCode;
+/// The TLS library used by [`Builder::tls_config`]
+#[cfg(feature = "helpers")]
+pub use quinn::rustls;
+
+mod compose;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("connection lost: {0}")]
+    Connection(#[source] BoxError),
+    #[error("failed to send to server: {0}")]
+    Write(#[source] BoxError),
+    #[error("game server port must not be 0")]
+    InvalidPort,
+    /// See [`proto::tags_valid`]
+    #[error("too many tags, or a tag is empty or too long")]
+    InvalidTags,
+    /// More than [`proto::MAX_LAN_ADDRESSES`] LAN addresses were given
+    #[error("too many LAN addresses")]
+    TooManyAddresses,
+    /// The state exceeds the meta server's
+    /// [`Limits::max_state_size`](proto::Limits::max_state_size)
+    #[error("state too large")]
+    StateTooLarge,
+    /// The meta server predates the feature, e.g. tags before [`proto::PROTOCOL_V2`]
+    #[error("meta server does not support this")]
+    Unsupported,
+    /// The meta server's [`proto::Challenge`] was harder than [`MAX_CHALLENGE_DIFFICULTY`], or
+    /// couldn't be solved before its timeout
+    #[error("failed to solve the meta server's challenge")]
+    Challenge,
+}
+
+impl Error {
+    fn connection(e: quinn::ConnectionError) -> Self {
+        Self::Connection(e.into())
+    }
+
+    fn write(e: quinn::WriteError) -> Self {
+        match e {
+            quinn::WriteError::ConnectionLost(e) => Self::connection(e),
+            e => Self::Write(e.into()),
+        }
+    }
+}
+
+/// Why a connection to a meta server ended, from [`Heartbeat::closed`]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Closed {
+    /// The meta server closed the connection, with a [`CloseCode`] if recognized
+    #[error("closed by meta server: {}", String::from_utf8_lossy(reason))]
+    ByMetaServer {
+        code: Option<CloseCode>,
+        reason: Vec<u8>,
+    },
+    /// We closed the connection, e.g. with [`Heartbeat::close`]
+    #[error("closed locally")]
+    Locally,
+    /// The meta server stopped responding
+    #[error("timed out")]
+    TimedOut,
+    #[error("connection lost: {0}")]
+    Lost(#[source] BoxError),
+}
+
+impl Closed {
+    fn new(e: quinn::ConnectionError) -> Self {
+        use quinn::ConnectionError::*;
+        match e {
+            ApplicationClosed(x) => Self::ByMetaServer {
+                code: CloseCode::from_code(x.error_code.into_inner()),
+                reason: x.reason.to_vec(),
+            },
+            LocallyClosed => Self::Locally,
+            TimedOut => Self::TimedOut,
+            e => Self::Lost(e.into()),
+        }
+    }
+}
+
+/// Failure to establish a connection to a meta server
+#[cfg(feature = "helpers")]
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("invalid meta server address {0:?}: expected host:port")]
+    InvalidAddress(String),
+    #[error("failed to resolve meta server address: {0}")]
+    Resolve(#[source] std::io::Error),
+    #[error("meta server hostname did not resolve to any addresses")]
+    NoAddresses,
+    #[error("invalid certificate authority: {0}")]
+    InvalidCa(#[source] BoxError),
+    #[error("unusable TLS configuration: {0}")]
+    Tls(#[source] BoxError),
+    #[error("failed to bind local socket: {0}")]
+    Bind(#[source] std::io::Error),
+    #[error("connection failed: {0}")]
+    Connection(#[source] BoxError),
+    /// The meta server's certificate isn't issued by a trusted certificate authority
+    #[error("meta server's certificate is not issued by a trusted authority")]
+    UntrustedRoot,
+    /// The meta server's certificate isn't valid for the host it was connected to by
+    ///
+    /// `presented` are the names it's valid for, if known.
+    #[error("{}", name_mismatch(presented, requested))]
+    NameMismatch {
+        presented: Vec<String>,
+        requested: String,
+    },
+    /// The meta server's certificate has expired, at `not_after` if known
+    #[error("meta server's certificate has expired")]
+    Expired {
+        not_after: Option<std::time::SystemTime>,
+    },
+    #[error(transparent)]
+    Hello(Error),
+}
+
+/// An established connection to a meta server
+///
+/// Only needed to use a connection established by external code. Most applications should use
+/// [`Heartbeat::builder`] instead.
+pub struct Connection(quinn::Connection);
+
+impl From<quinn::Connection> for Connection {
+    fn from(x: quinn::Connection) -> Self {
+        Self(x)
+    }
+}
 
 pub struct Heartbeat {
     connection: quinn::Connection,
-    prev_update: Instant,
+    /// Negotiated version of the game protocol
+    version: u32,
+    /// Negotiated ALPN ID
+    alpn: Vec<u8>,
+    /// See [`peer_certificate_fingerprint`](Self::peer_certificate_fingerprint)
+    fingerprint: Option<[u8; 32]>,
+    /// The meta server's [`proto::Welcome`], if it sent one
+    welcome: Option<proto::Welcome>,
+    runtime: Arc<dyn quinn::Runtime>,
+    /// When the previous heartbeat was sent, if any
+    prev_update: Option<Instant>,
+    /// Sequence number of the next heartbeat
+    seq: u64,
+    jitter: f64,
+    rng: StdRng,
+    /// Set if the connection was established by a [`Builder`] on an endpoint of its own
+    endpoint: Option<quinn::Endpoint>,
+    /// The meta server's latest limits, kept up to date by [`receive_events`]
+    limits: Arc<Mutex<Option<proto::Limits>>>,
+    /// See [`last_ack`](Self::last_ack), kept up to date by [`receive_events`]
+    acked: Arc<Mutex<Option<Instant>>>,
+    /// Events decoded by [`receive_events`]
+    events: mpsc::Receiver<ServerEvent>,
+    /// Events set aside while waiting for a particular kind, oldest first
+    stashed: VecDeque<ServerEvent>,
+    /// Resolved by [`receive_events`] once the meta server stores our first heartbeat
+    advertised: oneshot::Receiver<proto::Advertisement>,
+    /// What `advertised` resolved to, if it has
+    advertisement: Option<proto::Advertisement>,
+    /// Stops [`receive_events`] when dropped
+    _stop: oneshot::Sender<()>,
 }
 
 impl Heartbeat {
-    pub async fn new(
-        connection: quinn::NewConnection,
-        port: u16,
-    ) -> Result<Self, quinn::WriteError> {
-        let mut stream = connection.connection.open_uni().await?;
-        let msg = bincode::serialize(&proto::Hello { port }).unwrap();
-        stream.write_all(&msg).await?;
+    /// Register with the meta server over a connection that negotiated one of
+    /// [`proto::PROTOCOLS`]
+    ///
+    /// `port` is the port game clients should connect to. If the meta server sets a
+    /// [`proto::Challenge`], e.g. because it's under load, it's solved on a background thread.
+    pub async fn new(connection: Connection, port: u16) -> Result<Self, Error> {
+        let hello = proto::HelloV2 {
+            port,
+            tags: Vec::new(),
+            introductions: true,
+            lan_addresses: Vec::new(),
+        };
+        Self::register(connection.0, hello).await
+    }
+
+    async fn register(connection: quinn::Connection, hello: proto::HelloV2) -> Result<Self, Error> {
+        check_hello(&hello)?;
+        let alpn = alpn(&connection);
+        let version = version(&alpn);
+        let fingerprint = fingerprint(&connection);
+        let msg = match version {
+            1 if !hello.tags.is_empty() || !hello.lan_addresses.is_empty() => {
+                return Err(Error::Unsupported)
+            }
+            1 => bincode::serialize(&proto::Hello { port: hello.port }),
+            _ => bincode::serialize(&hello),
+        }
+        .unwrap();
+        let mut stream = connection.open_uni().await.map_err(Error::connection)?;
+        stream.write_all(&msg).await.map_err(Error::write)?;
+        drop(stream);
+        // Sent once the meta server accepts us, before any control messages
+        let welcome = match version {
+            1 => None,
+            2 => metaserve_proto::decode::<proto::Welcome>(&accept(&connection).await?).ok(),
+            _ => loop {
+                let msg = accept(&connection).await?;
+                match metaserve_proto::decode::<proto::Greeting>(&msg) {
+                    Ok(proto::Greeting::Welcome(x)) => break Some(x),
+                    Ok(proto::Greeting::Challenge(challenge)) => {
+                        let deadline =
+                            Instant::now() + challenge.timeout.saturating_sub(connection.rtt());
+                        let nonce = solve(challenge, deadline).await.ok_or(Error::Challenge)?;
+                        let msg = bincode::serialize(&proto::Solution { nonce }).unwrap();
+                        let mut stream = connection.open_uni().await.map_err(Error::connection)?;
+                        stream.write_all(&msg).await.map_err(Error::write)?;
+                    }
+                    Err(_) => break None,
+                }
+            },
+        };
 
+        let runtime = quinn::default_runtime()
+            .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+        let limits = Arc::new(Mutex::new(welcome.as_ref().map(|x| x.limits)));
+        let acked = Arc::new(Mutex::new(None));
+        // Holds one more than its buffer per sender
+        let (send, events) = mpsc::channel(MAX_PENDING_EVENTS - 1);
+        let (stop, stopped) = oneshot::channel();
+        let (advertise, advertised) = oneshot::channel();
+        runtime.spawn(Box::pin(receive_events(
+            connection.clone(),
+            send,
+            limits.clone(),
+            acked.clone(),
+            advertise,
+            stopped,
+        )));
         Ok(Self {
-            connection: connection.connection,
-            prev_update: Instant::now() - Duration::from_secs(1),
+            connection,
+            version,
+            alpn,
+            fingerprint,
+            welcome,
+            runtime,
+            prev_update: None,
+            seq: 0,
+            jitter: DEFAULT_JITTER,
+            rng: StdRng::from_entropy(),
+            endpoint: None,
+            limits,
+            acked,
+            events,
+            stashed: VecDeque::new(),
+            advertised,
+            advertisement: None,
+            _stop: stop,
+        })
+    }
+
+    /// Configure a new connection to a meta server
+    #[cfg(feature = "helpers")]
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Randomly lengthen the minimum time between heartbeats by up to `fraction` of it
+    ///
+    /// Keeps game servers that were started together from heartbeating in lockstep. Defaults to
+    /// 0.1.
+    pub fn set_jitter(&mut self, fraction: f64) {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "jitter must be between 0 and 1"
+        );
+        self.jitter = fraction;
+    }
+
+    /// Send a heartbeat carrying `state`
+    ///
+    /// Waits until the meta server's minimum interval, plus jitter, has passed since the previous
+    /// heartbeat. Fails with [`Error::StateTooLarge`] if the meta server would reject `state`.
+    pub async fn send(&mut self, state: &[u8]) -> Result<SendReport, Error> {
+        let limits = self.limits();
+        if limits.is_some_and(|x| state.len() > x.max_state_size as usize) {
+            return Err(Error::StateTooLarge);
+        }
+        // Send no more often than the meta server acts on, plus jitter
+        let interval = limits.map_or(DEFAULT_INTERVAL, |x| x.heartbeat_min_interval);
+        let interval = interval.mul_f64(1.0 + self.rng.gen_range(0.0..=self.jitter));
+        let mut throttled = false;
+        if let Some(prev) = self.prev_update {
+            let next = prev + interval;
+            throttled = next > Instant::now();
+            Sleep(self.runtime.new_timer(next)).await;
+        }
+        self.prev_update = Some(Instant::now());
+        let msg = match self.version {
+            1 => Cow::Borrowed(state),
+            2 => Cow::Owned(bincode::serialize(&proto::Update::State(state)).unwrap()),
+            _ => {
+                let seq = self.seq;
+                self.seq += 1;
+                let update = proto::Update::SequencedState { seq, state };
+                Cow::Owned(bincode::serialize(&update).unwrap())
+            }
+        };
+        self.write(&msg).await?;
+        Ok(SendReport {
+            bytes: msg.len(),
+            throttled,
+            rtt: self.connection.rtt(),
+        })
+    }
+
+    /// Replace the tags game clients can filter this game server by
+    ///
+    /// Takes effect for game clients without waiting for another heartbeat. Fails with
+    /// [`Error::Unsupported`] if the meta server predates tags.
+    pub async fn set_tags(&mut self, tags: Vec<String>) -> Result<(), Error> {
+        if !proto::tags_valid(&tags) {
+            return Err(Error::InvalidTags);
+        }
+        if self.version < 2 {
+            return Err(Error::Unsupported);
+        }
+        self.write(&bincode::serialize(&proto::Update::Tags(tags)).unwrap())
+            .await
+    }
+
+    /// Change the port game clients should connect to
+    ///
+    /// Takes effect for game clients without waiting for another heartbeat. Fails with
+    /// [`Error::Unsupported`] if the meta server predates port changes.
+    pub async fn set_port(&mut self, port: u16) -> Result<(), Error> {
+        if port == 0 {
+            return Err(Error::InvalidPort);
+        }
+        if self.version < 2 {
+            return Err(Error::Unsupported);
+        }
+        self.write(&bincode::serialize(&proto::Update::PortChange(port)).unwrap())
+            .await
+    }
+
+    /// Send `msg` on a new stream
+    async fn write(&self, msg: &[u8]) -> Result<(), Error> {
+        let mut stream = self
+            .connection
+            .open_uni()
+            .await
+            .map_err(Error::connection)?;
+        stream.write_all(msg).await.map_err(Error::write)?;
+        Ok(())
+    }
+
+    /// Events pushed by the meta server, such as refresh requests and introductions
+    ///
+    /// Received in the background, so none are missed while the game server is busy. At most 16
+    /// unread events are kept, discarding newer ones. Ends when the connection is lost.
+    pub fn events(&mut self) -> Events<'_> {
+        Events(self)
+    }
+
+    /// Wait until the meta server asks for a fresh heartbeat
+    ///
+    /// Meta servers request refreshes when game clients connect, so that the clients see current
+    /// state sooner. Respond by calling [`send`](Self::send) promptly. Other events received
+    /// meanwhile are kept for [`events`](Self::events).
+    pub async fn refresh_requested(&mut self) -> Result<(), Error> {
+        self.next_matching(|x| matches!(x, ServerEvent::RefreshRequested))
+            .await?;
+        Ok(())
+    }
+
+    /// Wait until a game client asks to be introduced, so that both sides can try to traverse NAT
+    ///
+    /// The game client is told this game server's address and the same token at the same time.
+    /// Punching through NAT is up to the game, e.g. by sending packets containing the token to
+    /// the game client's address. Opt out with [`Builder::introductions`]. Other events received
+    /// meanwhile are kept for [`events`](Self::events).
+    pub async fn next_introduction(&mut self) -> Result<proto::Introduction, Error> {
+        match self
+            .next_matching(|x| matches!(x, ServerEvent::Introduction(_)))
+            .await?
+        {
+            ServerEvent::Introduction(x) => Ok(x),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Wait until the meta server has stored the first heartbeat, making the game server visible
+    /// to game clients
+    ///
+    /// Useful for only accepting players once they can find the game server. Resolves immediately
+    /// if that already happened. Fails with [`Error::Unsupported`] if the meta server predates
+    /// [`proto::PROTOCOL_V2`].
+    pub async fn advertised(&mut self) -> Result<proto::Advertisement, Error> {
+        if let Some(x) = self.advertisement {
+            return Ok(x);
+        }
+        if self.version < 2 {
+            return Err(Error::Unsupported);
+        }
+        match (&mut self.advertised).await {
+            Ok(x) => {
+                self.advertisement = Some(x);
+                Ok(x)
+            }
+            Err(oneshot::Canceled) => Err(match self.connection.close_reason() {
+                Some(e) => Error::connection(e),
+                None => Error::Connection("event receiver stopped".into()),
+            }),
+        }
+    }
+
+    /// Like [`advertised`](Self::advertised), but give up after `timeout`, returning `None`
+    pub async fn advertised_within(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<proto::Advertisement>, Error> {
+        let mut sleep = Sleep(self.runtime.new_timer(Instant::now() + timeout));
+        let mut advertised = pin!(self.advertised());
+        poll_fn(|cx| {
+            if let Poll::Ready(x) = advertised.as_mut().poll(cx) {
+                return Poll::Ready(x.map(Some));
+            }
+            Pin::new(&mut sleep).poll(cx).map(|()| Ok(None))
         })
+        .await
+    }
+
+    /// Wait for an event for which `f` returns true, setting others aside
+    async fn next_matching(
+        &mut self,
+        f: impl Fn(&ServerEvent) -> bool,
+    ) -> Result<ServerEvent, Error> {
+        if let Some(i) = self.stashed.iter().position(&f) {
+            return Ok(self.stashed.remove(i).unwrap());
+        }
+        loop {
+            let Some(event) = poll_fn(|cx| Pin::new(&mut self.events).poll_next(cx)).await else {
+                return Err(match self.connection.close_reason() {
+                    Some(e) => Error::connection(e),
+                    None => Error::Connection("event receiver stopped".into()),
+                });
+            };
+            if f(&event) {
+                return Ok(event);
+            }
+            if self.stashed.len() == MAX_PENDING_EVENTS {
+                self.stashed.pop_front();
+            }
+            self.stashed.push_back(event);
+        }
+    }
+
+    /// Politely disconnect from the meta server, e.g. when the game server shuts down
+    ///
+    /// If the `Heartbeat` was established by a [`Builder`] on an endpoint of its own, waits briefly
+    /// for the meta server to be notified. Otherwise, call `quinn::Endpoint::wait_idle` before
+    /// exiting.
+    pub async fn close(self) {
+        self.connection
+            .close(CloseCode::Normal.code().into(), b"game server closed");
+        if let Some(endpoint) = self.endpoint {
+            endpoint.wait_idle().await;
+        }
+    }
+
+    /// Like [`close`](Self::close), but first tell the meta server why, for its logs
+    ///
+    /// Waits for the meta server to receive the reason, truncated to [`proto::MAX_REASON_LEN`].
+    /// Meta servers that predate [`proto::PROTOCOL_V2`] aren't told.
+    pub async fn close_with_reason(self, reason: &str) {
+        if self.version >= 2 {
+            let mut len = reason.len().min(proto::MAX_REASON_LEN);
+            while !reason.is_char_boundary(len) {
+                len -= 1;
+            }
+            let reason = &reason[..len];
+            let msg = proto::Update::Goodbye {
+                reason: Some(reason),
+            };
+            // Errors mean the connection is already gone, so there's nobody left to tell
+            let _ = self.goodbye(&bincode::serialize(&msg).unwrap()).await;
+        }
+        self.close().await;
     }
 
-    pub async fn send(&mut self, state: &[u8]) -> Result<(), quinn::WriteError> {
-        // Send at most once per second
-        tokio::time::sleep_until(self.prev_update + Duration::from_secs(1)).await;
+    /// Send `msg` on a new stream, waiting until the meta server has read it
+    async fn goodbye(&self, msg: &[u8]) -> Result<(), BoxError> {
         let mut stream = self.connection.open_uni().await?;
-        stream.write_all(state).await?;
+        stream.write_all(msg).await?;
+        stream.finish()?;
+        stream.stopped().await?;
         Ok(())
     }
+
+    /// Wait for the connection to the meta server to end, without sending or receiving anything
+    ///
+    /// Game servers that rarely send heartbeats can use this to reconnect promptly. The future
+    /// doesn't borrow the `Heartbeat`, so it can be awaited alongside other calls.
+    pub fn closed(&self) -> impl Future<Output = Closed> + Send + 'static {
+        let connection = self.connection.clone();
+        async move { Closed::new(connection.closed().await) }
+    }
+
+    /// Whether the connection to the meta server is still open
+    pub fn is_connected(&self) -> bool {
+        self.connection.close_reason().is_none()
+    }
+
+    /// Transport statistics for the connection to the meta server
+    pub fn stats(&self) -> Stats {
+        Stats::new(&self.connection)
+    }
+
+    /// Version of the game protocol negotiated with the meta server, e.g. 2 for
+    /// [`proto::PROTOCOL_V2`]
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// ALPN ID negotiated with the meta server, one of [`proto::PROTOCOLS`]
+    pub fn negotiated_alpn(&self) -> &[u8] {
+        &self.alpn
+    }
+
+    /// SHA-256 hash of the SubjectPublicKeyInfo of the certificate the meta server presented,
+    /// captured when connecting
+    ///
+    /// Depends only on the key, so it stays the same when a certificate is re-issued for the same
+    /// key, and matches `metaserve_client::spki_fingerprint`.
+    pub fn peer_certificate_fingerprint(&self) -> Option<[u8; 32]> {
+        self.fingerprint
+    }
+
+    /// The meta server's description of its version, for debugging
+    ///
+    /// `None` if the meta server predates [`proto::PROTOCOL_V2`].
+    pub fn peer_version(&self) -> Option<&str> {
+        self.welcome.as_ref().map(|x| &x.version[..])
+    }
+
+    /// Limits the meta server enforces, as of its latest [`ServerEvent::LimitsUpdated`]
+    ///
+    /// `None` if the meta server predates [`proto::PROTOCOL_V2`], in which case heartbeats are
+    /// sent at most once per second.
+    pub fn limits(&self) -> Option<proto::Limits> {
+        *self.limits.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// When the meta server last confirmed storing a heartbeat, if it has
+    ///
+    /// Confirmations arrive when the first heartbeat makes the game server visible, and whenever
+    /// the address game clients are told to connect to changes, not for every heartbeat. A
+    /// heartbeat the meta server rejects closes the connection instead, so a confirmed game
+    /// server that's still [connected](Self::is_connected) remains visible. Always `None` if the
+    /// meta server predates [`proto::PROTOCOL_V2`].
+    pub fn last_ack(&self) -> Option<Instant> {
+        *self.acked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Address of the meta server
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Local IP address the meta server was contacted from, if known
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.connection.local_ip()
+    }
+}
+
+/// What happened to a heartbeat, from [`Heartbeat::send`]
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct SendReport {
+    /// Size of the encoded heartbeat
+    pub bytes: usize,
+    /// Whether sending waited for the meta server's minimum interval between heartbeats
+    pub throttled: bool,
+    /// Current estimate of the round-trip time to the meta server
+    pub rtt: Duration,
+}
+
+/// Something the meta server told the game server, from [`Heartbeat::events`]
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub enum ServerEvent {
+    /// See [`Heartbeat::refresh_requested`]
+    RefreshRequested,
+    /// See [`Heartbeat::next_introduction`]
+    Introduction(proto::Introduction),
+    /// The meta server's limits changed, as now returned by [`Heartbeat::limits`]
+    LimitsUpdated(proto::Limits),
+    /// The address game clients are told to connect to, as observed by the meta server
+    ///
+    /// Useful for checking that game clients can reach the game server, e.g. from behind NAT.
+    ObservedAddress(SocketAddr),
+}
+
+/// Events pushed by the meta server as a [`Stream`], from [`Heartbeat::events`]
+pub struct Events<'a>(&'a mut Heartbeat);
+
+impl Stream for Events<'_> {
+    type Item = ServerEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerEvent>> {
+        let heartbeat = &mut *self.get_mut().0;
+        if let Some(x) = heartbeat.stashed.pop_front() {
+            return Poll::Ready(Some(x));
+        }
+        Pin::new(&mut heartbeat.events).poll_next(cx)
+    }
+}
+
+/// Decode control messages from the meta server into `events` until the connection is lost or
+/// `stop` is cancelled, keeping `limits` and `acked` up to date and resolving `advertised`
+async fn receive_events(
+    connection: quinn::Connection,
+    mut events: mpsc::Sender<ServerEvent>,
+    limits: Arc<Mutex<Option<proto::Limits>>>,
+    acked: Arc<Mutex<Option<Instant>>>,
+    advertised: oneshot::Sender<proto::Advertisement>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut advertised = Some(advertised);
+    let mut run = pin!(async move {
+        // Accepting promptly, even when events go unread, keeps the meta server from blocking
+        while let Ok(mut stream) = connection.accept_uni().await {
+            let msg = match stream.read_to_end(MAX_CONTROL_SIZE).await {
+                Ok(x) => x,
+                Err(quinn::ReadToEndError::Read(quinn::ReadError::ConnectionLost(_))) => return,
+                Err(_) => continue,
+            };
+            // Skip messages we don't understand, as they may have been added by a newer meta server
+            let msg = metaserve_proto::decode(&msg);
+            if let Ok(proto::Control::Advertised(_) | proto::Control::ObservedAddress(_)) = msg {
+                *acked.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+            }
+            let event = match msg {
+                Ok(proto::Control::RefreshRequest) => ServerEvent::RefreshRequested,
+                Ok(proto::Control::Introduce(x)) => ServerEvent::Introduction(x),
+                Ok(proto::Control::Limits(x)) => {
+                    *limits.lock().unwrap_or_else(PoisonError::into_inner) = Some(x);
+                    ServerEvent::LimitsUpdated(x)
+                }
+                Ok(proto::Control::ObservedAddress(x)) => ServerEvent::ObservedAddress(x),
+                Ok(proto::Control::Advertised(x)) => {
+                    if let Some(send) = advertised.take() {
+                        let _ = send.send(x);
+                    }
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            // Fails if the application has fallen behind, or dropped the `Heartbeat`
+            let _ = events.try_send(event);
+        }
+    });
+    poll_fn(|cx| {
+        if Pin::new(&mut stop).poll(cx).is_ready() {
+            return Poll::Ready(());
+        }
+        run.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Largest control message from the meta server that we'll read
+const MAX_CONTROL_SIZE: usize = 1024;
+/// Hardest [`proto::Challenge`] we attempt, taking around 16 million hashes
+pub const MAX_CHALLENGE_DIFFICULTY: u8 = 24;
+/// Most events kept for [`Heartbeat::events`]
+const MAX_PENDING_EVENTS: usize = 16;
+/// Least time between heartbeats if the meta server doesn't say
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default for [`Heartbeat::set_jitter`]
+const DEFAULT_JITTER: f64 = 0.1;
+
+/// Check the parts of `hello` that the meta server would reject
+fn check_hello(hello: &proto::HelloV2) -> Result<(), Error> {
+    if hello.port == 0 {
+        return Err(Error::InvalidPort);
+    }
+    if !proto::tags_valid(&hello.tags) {
+        return Err(Error::InvalidTags);
+    }
+    if hello.lan_addresses.len() > proto::MAX_LAN_ADDRESSES {
+        return Err(Error::TooManyAddresses);
+    }
+    Ok(())
+}
+
+/// Read the next stream the meta server opens, e.g. carrying a [`proto::Greeting`]
+async fn accept(connection: &quinn::Connection) -> Result<Vec<u8>, Error> {
+    let mut stream = connection.accept_uni().await.map_err(Error::connection)?;
+    stream
+        .read_to_end(MAX_CONTROL_SIZE)
+        .await
+        .map_err(|e| Error::Connection(e.into()))
+}
+
+/// Solve `challenge` on a background thread, so as not to block the async runtime
+///
+/// Gives up if the challenge is harder than [`MAX_CHALLENGE_DIFFICULTY`], or once `deadline`
+/// passes.
+async fn solve(challenge: proto::Challenge, deadline: Instant) -> Option<u64> {
+    if challenge.difficulty > MAX_CHALLENGE_DIFFICULTY {
+        return None;
+    }
+    let (send, recv) = oneshot::channel();
+    std::thread::spawn(move || {
+        let mut nonce = 0;
+        let solution = loop {
+            if nonce % 4096 == 0 && Instant::now() >= deadline {
+                break None;
+            }
+            if solves(&challenge, nonce) {
+                break Some(nonce);
+            }
+            nonce += 1;
+        };
+        let _ = send.send(solution);
+    });
+    recv.await.expect("solver thread panicked")
+}
+
+/// Whether `nonce` solves `challenge`, as described by [`proto::Challenge`]
+fn solves(challenge: &proto::Challenge, nonce: u64) -> bool {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(&challenge.prefix);
+    context.update(&nonce.to_le_bytes());
+    let mut zeros = 0;
+    for &byte in context.finish().as_ref() {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros >= u32::from(challenge.difficulty)
+}
+
+/// Version of the game protocol negotiated by `connection`
+fn version(alpn: &[u8]) -> u32 {
+    match alpn {
+        proto::PROTOCOL_V3 => 3,
+        proto::PROTOCOL_V2 => 2,
+        _ => 1,
+    }
+}
+
+/// ALPN ID negotiated by `connection`
+fn alpn(connection: &quinn::Connection) -> Vec<u8> {
+    connection
+        .handshake_data()
+        .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|x| x.protocol)
+        .unwrap_or_default()
+}
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of the certificate presented by `connection`'s peer,
+/// if any, as `metaserve_client::spki_fingerprint` computes it
+fn fingerprint(connection: &quinn::Connection) -> Option<[u8; 32]> {
+    let chain = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    let cert = webpki::EndEntityCert::try_from(chain.first()?).ok()?;
+    let digest = ring::digest::digest(&ring::digest::SHA256, &cert.subject_public_key_info());
+    digest.as_ref().try_into().ok()
+}
+
+/// Future that completes when a runtime-provided timer expires
+struct Sleep(Pin<Box<dyn quinn::AsyncTimer>>);
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Configuration for connecting a [`Heartbeat`] to a meta server
+#[cfg(feature = "helpers")]
+pub struct Builder {
+    roots: Vec<Vec<u8>>,
+    webpki_roots: bool,
+    jitter: f64,
+    tags: Vec<String>,
+    introductions: bool,
+    lan_addresses: Vec<SocketAddr>,
+    endpoint: Option<quinn::Endpoint>,
+    tls: Option<rustls::ClientConfig>,
+    address: Option<SocketAddr>,
+    resolver: Option<Arc<Resolver>>,
+    attempt_timeout: Duration,
+}
+
+#[cfg(feature = "helpers")]
+impl Builder {
+    fn new() -> Self {
+        Self {
+            tls: None,
+            address: None,
+            resolver: None,
+            attempt_timeout: DEFAULT_ATTEMPT_TIMEOUT,
+            roots: Vec::new(),
+            webpki_roots: true,
+            jitter: DEFAULT_JITTER,
+            tags: Vec::new(),
+            introductions: true,
+            lan_addresses: Vec::new(),
+            endpoint: None,
+        }
+    }
+
+    /// Trust an additional certificate authority, in DER format
+    pub fn ca(mut self, der: Vec<u8>) -> Self {
+        self.roots.push(der);
+        self
+    }
+
+    /// Connect from `endpoint` rather than a new one, e.g. to share the game's own UDP socket
+    ///
+    /// The meta server then sees the same address as game clients do. Only the connection to the
+    /// meta server uses this crate's TLS and transport configuration; `endpoint`'s default client
+    /// configuration is left alone. [`close`](Heartbeat::close) doesn't wait for a shared
+    /// endpoint to become idle.
+    pub fn endpoint(mut self, endpoint: quinn::Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Whether to trust the Mozilla root certificates, in addition to any added with
+    /// [`ca`](Self::ca)
+    ///
+    /// Enabled by default.
+    pub fn webpki_roots(mut self, enabled: bool) -> Self {
+        self.webpki_roots = enabled;
+        self
+    }
+
+    /// Secure the connection with `config`, e.g. to verify the meta server's certificate with a
+    /// custom [`ServerCertVerifier`](rustls::client::danger::ServerCertVerifier)
+    ///
+    /// Replaces the configuration built from [`ca`](Self::ca) and
+    /// [`webpki_roots`](Self::webpki_roots). Its ALPN protocols are replaced with
+    /// [`proto::PROTOCOLS`], and it must support TLS 1.3.
+    pub fn tls_config(mut self, config: rustls::ClientConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Connect to `address` rather than resolving the host passed to `connect`, which then only
+    /// names the meta server for certificate verification
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Resolve the `host:port` passed to `connect` with `resolver` rather than the system's, e.g.
+    /// to use a DNS client of the game's own
+    ///
+    /// Called on a background thread, so it may block.
+    pub fn resolver(
+        mut self,
+        resolver: impl Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static,
+    ) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Give up on an address that hasn't completed a handshake within `timeout`, and try the
+    /// next
+    ///
+    /// Defaults to 5 seconds.
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = timeout;
+        self
+    }
+
+    /// See [`Heartbeat::set_jitter`]
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "jitter must be between 0 and 1"
+        );
+        self.jitter = fraction;
+        self
+    }
+
+    /// Register with these tags, as if by [`Heartbeat::set_tags`]
+    ///
+    /// Connecting fails with [`Error::Unsupported`] if any are given and the meta server predates
+    /// tags.
+    pub fn tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Whether game clients may ask to be introduced, as returned by
+    /// [`Heartbeat::next_introduction`]
+    ///
+    /// Enabled by default.
+    pub fn introductions(mut self, enabled: bool) -> Self {
+        self.introductions = enabled;
+        self
+    }
+
+    /// Addresses game clients on the same local network can connect to, in case they can't reach
+    /// the public address, e.g. due to NAT without hairpinning
+    ///
+    /// At most [`proto::MAX_LAN_ADDRESSES`]. Connecting fails with [`Error::Unsupported`] if any
+    /// are given and the meta server predates them.
+    pub fn lan_addresses(mut self, addresses: Vec<SocketAddr>) -> Self {
+        self.lan_addresses = addresses;
+        self
+    }
+
+    /// Connect to the meta server at `server`, given as `host:port`, and register a game server
+    /// that game clients should connect to on `port`
+    ///
+    /// `server` is resolved afresh on each call, and each address it resolves to is tried in turn
+    /// until one completes a handshake, so reconnecting with a new builder follows changes to DNS,
+    /// e.g. after a failover. Addresses that failed in the last minute are tried last, and an
+    /// unreachable address delays the next by the [`attempt_timeout`](Self::attempt_timeout).
+    pub async fn connect(self, server: &str, port: u16) -> Result<Heartbeat, ConnectError> {
+        let hello = proto::HelloV2 {
+            port,
+            tags: self.tags,
+            introductions: self.introductions,
+            lan_addresses: self.lan_addresses,
+        };
+        check_hello(&hello).map_err(ConnectError::Hello)?;
+        let hostname = hostname(server)?;
+        let addrs = match self.address {
+            Some(x) => vec![x],
+            None => resolve(server, self.resolver).await?,
+        };
+
+        let mut rejections = None;
+        let mut client_crypto = match self.tls {
+            Some(x) => x,
+            None => {
+                let mut roots = rustls::RootCertStore::empty();
+                if self.webpki_roots {
+                    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                }
+                for der in self.roots {
+                    roots
+                        .add(CertificateDer::from(der))
+                        .map_err(|e| ConnectError::InvalidCa(e.into()))?;
+                }
+                let verifier = Arc::new(Verifier {
+                    inner: rustls::client::WebPkiServerVerifier::builder(Arc::new(roots))
+                        .build()
+                        .map_err(|e| ConnectError::Tls(e.into()))?,
+                    rejected: Mutex::new(None),
+                });
+                let config = rustls::ClientConfig::builder()
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier.clone())
+                    .with_no_client_auth();
+                rejections = Some(verifier);
+                config
+            }
+        };
+        client_crypto.alpn_protocols = proto::PROTOCOLS.iter().map(|&x| x.into()).collect();
+        let client_crypto =
+            QuicClientConfig::try_from(client_crypto).map_err(|e| ConnectError::Tls(e.into()))?;
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        let mut transport = quinn::TransportConfig::default();
+        transport
+            .keep_alive_interval(Some(Duration::from_secs(5)))
+            .max_concurrent_bidi_streams(0u32.into())
+            .max_concurrent_uni_streams(1u32.into());
+        client_config.transport_config(Arc::new(transport));
+
+        let shared = self.endpoint.is_some();
+        let (endpoint, conn) = connect_any(
+            self.endpoint,
+            &addrs,
+            client_config,
+            hostname,
+            rejections.as_deref(),
+            self.attempt_timeout,
+        )
+        .await?;
+        let mut heartbeat = Heartbeat::register(conn, hello)
+            .await
+            .map_err(ConnectError::Hello)?;
+        heartbeat.set_jitter(self.jitter);
+        heartbeat.endpoint = (!shared).then_some(endpoint);
+        Ok(heartbeat)
+    }
+}
+
+/// Verifies certificates as webpki does, remembering why the last one was rejected so that
+/// [`Builder::connect`] can say
+#[cfg(feature = "helpers")]
+#[derive(Debug)]
+struct Verifier {
+    inner: Arc<rustls::client::WebPkiServerVerifier>,
+    rejected: Mutex<Option<rustls::CertificateError>>,
+}
+
+#[cfg(feature = "helpers")]
+impl Verifier {
+    /// Describe the last rejection of a certificate for `requested`, if it's one of the common
+    /// mistakes given its own [`ConnectError`] variant
+    fn rejection(&self, requested: &str) -> Option<ConnectError> {
+        use rustls::CertificateError::*;
+        let error = self
+            .rejected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()?;
+        Some(match error {
+            UnknownIssuer => ConnectError::UntrustedRoot,
+            NotValidForName => ConnectError::NameMismatch {
+                presented: Vec::new(),
+                requested: requested.into(),
+            },
+            NotValidForNameContext { presented, .. } => ConnectError::NameMismatch {
+                presented: presented.into_iter().map(presented_name).collect(),
+                requested: requested.into(),
+            },
+            Expired => ConnectError::Expired { not_after: None },
+            ExpiredContext { not_after, .. } => ConnectError::Expired {
+                not_after: Some(std::time::UNIX_EPOCH + Duration::from_secs(not_after.as_secs())),
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(feature = "helpers")]
+impl rustls::client::danger::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let result = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        if let Err(rustls::Error::InvalidCertificate(ref e)) = result {
+            *self.rejected.lock().unwrap_or_else(PoisonError::into_inner) = Some(e.clone());
+        }
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Strip the type from a name as webpki reports it, e.g. `DnsName("example.com")`
+#[cfg(feature = "helpers")]
+fn presented_name(name: String) -> String {
+    let inner = name
+        .strip_prefix("DnsName(\"")
+        .and_then(|x| x.strip_suffix("\")"))
+        .or_else(|| {
+            name.strip_prefix("IpAddress(")
+                .and_then(|x| x.strip_suffix(')'))
+        });
+    match inner {
+        Some(x) => x.into(),
+        None => name,
+    }
+}
+
+/// Describe a certificate that isn't valid for the name it was requested by
+#[cfg(feature = "helpers")]
+fn name_mismatch(presented: &[String], requested: &str) -> String {
+    let mut message = if presented.is_empty() {
+        format!("meta server's certificate is not valid for {}", requested)
+    } else {
+        format!(
+            "meta server's certificate is valid for {}, not {}",
+            presented.join(", "),
+            requested
+        )
+    };
+    if requested.parse::<IpAddr>().is_ok() {
+        message.push_str("; certificates are usually issued for hostnames, so connect by one");
+    }
+    message
+}
+
+/// Connect to the first of `addrs` that completes a handshake within `timeout`, from `endpoint`
+/// if given, or else from a new one bound for each address family tried
+///
+/// Addresses that recently failed are tried last. Gives up early if `rejections` says the meta
+/// server's certificate was rejected, since every address for the same name should present the
+/// same one.
+#[cfg(feature = "helpers")]
+async fn connect_any(
+    mut endpoint: Option<quinn::Endpoint>,
+    addrs: &[SocketAddr],
+    config: quinn::ClientConfig,
+    hostname: &str,
+    rejections: Option<&Verifier>,
+    timeout: Duration,
+) -> Result<(quinn::Endpoint, quinn::Connection), ConnectError> {
+    let runtime = quinn::default_runtime()
+        .expect("no async runtime found; enable the tokio, smol, or async-std feature");
+    let mut addrs = addrs.to_vec();
+    prefer_working(&mut addrs, Instant::now());
+    let shared = endpoint.is_some();
+    let mut error = ConnectError::NoAddresses;
+    for addr in addrs {
+        let reusable = endpoint
+            .as_ref()
+            .is_some_and(|x| shared || x.local_addr().is_ok_and(|x| x.is_ipv4() == addr.is_ipv4()));
+        if !reusable {
+            let bind = match addr {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => "[::]:0",
+            };
+            endpoint =
+                Some(quinn::Endpoint::client(bind.parse().unwrap()).map_err(ConnectError::Bind)?);
+        }
+        let endpoint = endpoint.as_ref().unwrap();
+        let connecting = match endpoint.connect_with(config.clone(), addr, hostname) {
+            Ok(x) => x,
+            Err(e) => {
+                record_attempt(addr, false);
+                error = ConnectError::Connection(e.into());
+                continue;
+            }
+        };
+        let mut connecting = pin!(connecting);
+        let mut expired = Sleep(runtime.new_timer(Instant::now() + timeout));
+        let result = poll_fn(|cx| {
+            if let Poll::Ready(x) = connecting.as_mut().poll(cx) {
+                return Poll::Ready(Some(x));
+            }
+            Pin::new(&mut expired).poll(cx).map(|()| None)
+        })
+        .await;
+        record_attempt(addr, matches!(result, Some(Ok(_))));
+        match result {
+            Some(Ok(conn)) => return Ok((endpoint.clone(), conn)),
+            Some(Err(e)) => {
+                if let Some(rejection) = rejections.and_then(|x| x.rejection(hostname)) {
+                    return Err(rejection);
+                }
+                error = ConnectError::Connection(e.into());
+            }
+            None => {
+                error = ConnectError::Connection(format!("no response from {addr}").into());
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Addresses that recently failed to complete a handshake, and when, so that
+/// [`connect_any`] tries others first
+#[cfg(feature = "helpers")]
+static FAILED: Mutex<BTreeMap<SocketAddr, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Order `addrs` to try those that haven't failed in the last [`FAILURE_MEMORY`] first, in their
+/// original order, then those that failed longest ago
+#[cfg(feature = "helpers")]
+fn prefer_working(addrs: &mut [SocketAddr], now: Instant) {
+    let mut failed = FAILED.lock().unwrap_or_else(PoisonError::into_inner);
+    failed.retain(|_, &mut x| now.saturating_duration_since(x) < FAILURE_MEMORY);
+    addrs.sort_by_key(|x| failed.get(x).copied());
+}
+
+/// Remember whether a handshake with `addr` completed
+#[cfg(feature = "helpers")]
+fn record_attempt(addr: SocketAddr, ok: bool) {
+    let mut failed = FAILED.lock().unwrap_or_else(PoisonError::into_inner);
+    if ok {
+        failed.remove(&addr);
+    } else {
+        failed.insert(addr, Instant::now());
+    }
+}
+
+/// Addresses each meta server last resolved to, so that [`note_resolved`] can tell when they
+/// change
+#[cfg(feature = "helpers")]
+static RESOLVED: Mutex<BTreeMap<String, Vec<SocketAddr>>> = Mutex::new(BTreeMap::new());
+
+/// Log if `server` resolved to other addresses than it did last time, returning whether it did
+#[cfg(feature = "helpers")]
+fn note_resolved(server: &str, addrs: &[SocketAddr]) -> bool {
+    let mut addrs = addrs.to_vec();
+    addrs.sort_unstable();
+    addrs.dedup();
+    let mut resolved = RESOLVED.lock().unwrap_or_else(PoisonError::into_inner);
+    match resolved.insert(server.into(), addrs) {
+        Some(old) if old != resolved[server] => {
+            tracing::info!(server, ?old, new = ?resolved[server], "meta server addresses changed");
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Resolve `server` with `resolver`, or else the system's, on a background thread, so as not to
+/// block the async runtime
+#[cfg(feature = "helpers")]
+async fn resolve(
+    server: &str,
+    resolver: Option<Arc<Resolver>>,
+) -> Result<Vec<SocketAddr>, ConnectError> {
+    let (send, recv) = futures_channel::oneshot::channel();
+    use std::net::ToSocketAddrs;
+
+    let owned = server.to_owned();
+    std::thread::spawn(move || {
+        let _ = send.send(match resolver {
+            Some(resolver) => resolver(&owned),
+            None => owned.to_socket_addrs().map(|x| x.collect::<Vec<_>>()),
+        });
+    });
+    let addrs = recv
+        .await
+        .expect("resolver thread panicked")
+        .map_err(ConnectError::Resolve)?;
+    if addrs.is_empty() {
+        return Err(ConnectError::NoAddresses);
+    }
+    note_resolved(server, &addrs);
+    Ok(addrs)
+}
+
+/// See [`Builder::resolver`]
+#[cfg(feature = "helpers")]
+type Resolver = dyn Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync;
+
+/// How long an address that failed to complete a handshake is tried after others
+#[cfg(feature = "helpers")]
+const FAILURE_MEMORY: Duration = Duration::from_secs(60);
+/// Default for [`Builder::attempt_timeout`]
+#[cfg(feature = "helpers")]
+const DEFAULT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Extract the host from `host:port`, stripping brackets from IPv6 literals
+#[cfg(feature = "helpers")]
+fn hostname(server: &str) -> Result<&str, ConnectError> {
+    let (host, _) = server
+        .rsplit_once(':')
+        .ok_or_else(|| ConnectError::InvalidAddress(server.into()))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err(ConnectError::InvalidAddress(server.into()));
+    }
+    Ok(host)
+}
+
+/// Snapshot of a connection's transport statistics
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Current best estimate of the round-trip time
+    pub rtt: Duration,
+    /// Current congestion window, in bytes
+    pub cwnd: u64,
+    /// Number of congestion events, e.g. due to packet loss
+    pub congestion_events: u64,
+    /// Total UDP payload bytes sent
+    pub bytes_sent: u64,
+    /// Total UDP payload bytes received
+    pub bytes_received: u64,
+    /// Number of packets deemed lost
+    pub lost_packets: u64,
+}
+
+impl Stats {
+    fn new(connection: &quinn::Connection) -> Self {
+        let x = connection.stats();
+        Self {
+            rtt: x.path.rtt,
+            cwnd: x.path.cwnd,
+            congestion_events: x.path.congestion_events,
+            bytes_sent: x.udp_tx.bytes,
+            bytes_received: x.udp_rx.bytes,
+            lost_packets: x.path.lost_packets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(difficulty: u8) -> proto::Challenge {
+        proto::Challenge {
+            prefix: [7; 16],
+            difficulty,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn solve_challenges() {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let nonce = solve(challenge(12), deadline).await.unwrap();
+        assert!(solves(&challenge(12), nonce));
+        assert!((0..nonce).all(|x| !solves(&challenge(12), x)));
+        assert_eq!(solve(challenge(0), deadline).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn give_up_on_hard_or_late_challenges() {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let too_hard = challenge(MAX_CHALLENGE_DIFFICULTY + 1);
+        assert_eq!(solve(too_hard, deadline).await, None);
+        let expired = Instant::now();
+        let hard = challenge(MAX_CHALLENGE_DIFFICULTY);
+        assert_eq!(solve(hard, expired).await, None);
+    }
+
+    #[cfg(feature = "helpers")]
+    #[test]
+    fn failed_addresses_tried_last() {
+        let addrs = [1, 2, 3].map(|x| SocketAddr::from(([192, 0, 2, x], 4433)));
+        let order = |now| {
+            let mut x = addrs;
+            prefer_working(&mut x, now);
+            x
+        };
+        assert_eq!(order(Instant::now()), addrs);
+        record_attempt(addrs[1], false);
+        record_attempt(addrs[0], false);
+        assert_eq!(order(Instant::now()), [addrs[2], addrs[1], addrs[0]]);
+        record_attempt(addrs[1], true);
+        assert_eq!(order(Instant::now()), [addrs[1], addrs[2], addrs[0]]);
+        // Forgotten after a while
+        assert_eq!(order(Instant::now() + FAILURE_MEMORY), addrs);
+        assert_eq!(order(Instant::now()), addrs);
+    }
+
+    #[cfg(feature = "helpers")]
+    #[tokio::test]
+    async fn resolved_changes_noted() {
+        let a = SocketAddr::from(([192, 0, 2, 1], 4433));
+        let b = SocketAddr::from(([192, 0, 2, 2], 4433));
+        let answer = Arc::new(Mutex::new(vec![a, b]));
+        let stub = {
+            let answer = answer.clone();
+            Arc::new(move |_: &str| Ok(answer.lock().unwrap().clone())) as Arc<Resolver>
+        };
+        let server = "resolved-changes.invalid:4433";
+        let recorded = || RESOLVED.lock().unwrap().get(server).cloned();
+        assert_eq!(resolve(server, Some(stub.clone())).await.unwrap(), [a, b]);
+        assert_eq!(recorded(), Some(vec![a, b]));
+        // Reordering isn't a change
+        *answer.lock().unwrap() = vec![b, a];
+        resolve(server, Some(stub.clone())).await.unwrap();
+        assert!(!note_resolved(server, &[a, b]));
+        *answer.lock().unwrap() = vec![b];
+        assert_eq!(resolve(server, Some(stub.clone())).await.unwrap(), [b]);
+        assert_eq!(recorded(), Some(vec![b]));
+        assert!(note_resolved(server, &[a]));
+        *answer.lock().unwrap() = Vec::new();
+        assert!(matches!(
+            resolve(server, Some(stub)).await,
+            Err(ConnectError::NoAddresses)
+        ));
+    }
 }
@@ -1,32 +1,479 @@
 //! Protocol for communication between game clients and meta servers
+//!
+//! Each version of the protocol has its own ALPN ID, listed in [`PROTOCOLS`]. Later versions mostly
+//! add new [`Event`] variants, which meta servers send only to clients that negotiated a version
+//! that has them. Since [`PROTOCOL_V3`], clients send [`Request`]s rather than a [`Hello`], the
+//! meta server's first unidirectional stream carries a [`Welcome`] rather than a [`Message`], and
+//! [`Message`]s are encoded entry by entry, as described by [`Message::encode`].
 
-use std::net::SocketAddr;
+use std::{fmt, net::SocketAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+pub use crate::Limits;
+
+/// Changes to the set of game servers, sent by the meta server on a unidirectional stream
+///
+/// A message with no servers is a keep-alive, which meta servers may send periodically to show that
+/// the connection is still live.
+///
+/// Before [`PROTOCOL_V3`], encoded with `bincode` as is. See [`encode`](Self::encode).
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<'a> {
+    /// Changed servers, in ascending order of ID
+    ///
+    /// An ID may appear more than once if a server shut down and a new one was assigned the same
+    /// ID, in which case the shutdown comes first, or to carry events that supplement an
+    /// [`Event::Update`], which come immediately after it.
+    ///
+    /// An [`Event::Shutdown`] is never followed by an update describing the game server that shut
+    /// down, in the same message or later. An update after a shutdown for the same ID always
+    /// describes a game server that is visible as of that message, whether one newly assigned the
+    /// ID or one that matches the client's filter again.
     #[serde(borrow)]
     pub servers: Vec<Server<'a>>,
+    /// Entries that [`decode`](Self::decode) couldn't make sense of, in the order received
+    ///
+    /// Never encoded.
+    #[serde(skip)]
+    pub undecodable: Vec<Undecodable<'a>>,
+}
+
+#[cfg(feature = "bincode")]
+impl<'a> Message<'a> {
+    /// Encode for a client that negotiated `version` of the protocol
+    ///
+    /// Since [`PROTOCOL_V3`], each element of `servers` is encoded on its own, and the message is
+    /// the sequence of those encodings as `bincode` encodes a `Vec<&[u8]>`. Clients can then
+    /// decode the rest of a message despite an entry they can't, e.g. one carrying an [`Event`]
+    /// that a newer meta server added.
+    pub fn encode(&self, version: u32) -> Vec<u8> {
+        if version < 3 {
+            return bincode::serialize(self).unwrap();
+        }
+        let mut entries = Vec::new();
+        for server in &self.servers {
+            server.encode_entry(&mut entries);
+        }
+        Self::encode_entries(self.servers.len(), &entries)
+    }
+
+    /// Assemble a message as encoded since [`PROTOCOL_V3`] from `count` entries, each encoded by
+    /// [`Server::encode_entry`], concatenated in `entries`
+    pub fn encode_entries(count: usize, entries: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + entries.len());
+        data.extend_from_slice(&(count as u64).to_le_bytes());
+        data.extend_from_slice(entries);
+        data
+    }
+
+    /// Decode a message encoded by [`encode`](Self::encode) for `version`
+    ///
+    /// Since [`PROTOCOL_V3`], entries that fail to decode are set aside in
+    /// [`undecodable`](Self::undecodable) rather than failing the whole message. Allocates no
+    /// more than `data` can justify, as with [`decode`](crate::decode).
+    pub fn decode(data: &'a [u8], version: u32) -> bincode::Result<Self> {
+        if version < 3 {
+            // Each server's ID and event tag
+            crate::wire::check_count(data, 12)?;
+            return crate::decode(data);
+        }
+        // Each entry's length
+        crate::wire::check_count(data, 8)?;
+        let entries = crate::decode::<Vec<&'a [u8]>>(data)?;
+        let mut msg = Self {
+            servers: Vec::with_capacity(entries.len()),
+            undecodable: Vec::new(),
+        };
+        for bytes in entries {
+            match crate::decode(bytes) {
+                Ok(x) => msg.servers.push(x),
+                Err(_) => msg.undecodable.push(Undecodable {
+                    // Encoded first, so it may be intact regardless
+                    id: crate::decode(bytes).ok(),
+                    bytes,
+                }),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+/// An entry of a [`Message`] that couldn't be decoded, e.g. because it was corrupted or carries an
+/// [`Event`] this version doesn't know
+#[derive(Debug, Copy, Clone)]
+pub struct Undecodable<'a> {
+    /// The game server the entry was about, if that much could be decoded
+    pub id: Option<ServerId>,
+    /// The entry's encoding
+    pub bytes: &'a [u8],
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Server<'a> {
-    pub id: u64,
+    pub id: ServerId,
     /// Information about the game server's state
     #[serde(borrow)]
     pub event: Event<'a>,
 }
 
+#[cfg(feature = "bincode")]
+impl Server<'_> {
+    /// Append this entry to `out`, encoded as in a [`Message`] since [`PROTOCOL_V3`]
+    ///
+    /// Lets entries common to many messages be encoded once, for
+    /// [`Message::encode_entries`].
+    pub fn encode_entry(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; 8]);
+        bincode::serialize_into(&mut *out, self).unwrap();
+        let len = (out.len() - start - 8) as u64;
+        out[start..start + 8].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+/// Identifies a game server among those currently visible
+///
+/// IDs are assigned densely, so one may be reused for a different game server once the previous
+/// holder has been reported as shut down. Encoded as a plain `u64`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct ServerId(pub u64);
+
+impl ServerId {
+    /// Stands in for a game server in events that aren't about any, e.g. [`Event::Subscribed`]
+    pub const NONE: Self = Self(u64::MAX);
+}
+
+impl fmt::Display for ServerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// Change in a game server's state
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
 pub enum Event<'a> {
+    /// The game server is no longer visible, whether it disconnected or stopped matching the
+    /// client's filter
     Shutdown,
     /// The game server changed state
     Update(SocketAddr, &'a [u8]),
+    /// Where the game server is, if known, sent after every `Update` since [`PROTOCOL_V2`]
+    Region(Option<Region>),
+    /// The game server's tags, sent after every `Update` since [`PROTOCOL_V3`]
+    ///
+    /// See [`game::HelloV2::tags`](crate::game::HelloV2::tags).
+    Tags(#[serde(borrow, deserialize_with = "crate::wire::tags")] Vec<&'a str>),
+    /// Addresses on the client's local network that the game server may be reached at, sent
+    /// after `Update` since [`PROTOCOL_V3`] when the client seems to share the game server's
+    /// network
+    ///
+    /// Clients should try these alongside the address in `Update`. See
+    /// [`game::HelloV2::lan_addresses`](crate::game::HelloV2::lan_addresses).
+    LanAddresses(#[serde(deserialize_with = "crate::wire::lan_addresses")] Vec<SocketAddr>),
+    /// The [`Request::Subscribe`] with this generation has taken effect, so that this message and
+    /// later ones reflect its filter
+    ///
+    /// Not about any game server, so sent with the ID [`ServerId::NONE`], after all others.
+    Subscribed(u64),
+    /// This message completes the initial snapshot of the game servers visible to the client
+    ///
+    /// Sent with the ID [`ServerId::NONE`], after all others, in the first message, or the last of
+    /// a [paced](Pacing) snapshot.
+    Synchronized,
+    /// This many game servers of a [paced](Pacing) snapshot of `total` have been sent
+    ///
+    /// Sent with the ID [`ServerId::NONE`], after all others, in each message of the snapshot.
+    SnapshotProgress { delivered: u64, total: u64 },
+    /// The meta server's limits changed, superseding those in the [`Welcome`]
+    ///
+    /// Sent with the ID [`ServerId::NONE`], after all others.
+    Limits(Limits),
+    /// Forget every game server: this message begins a fresh snapshot, completed by
+    /// [`Event::Synchronized`]
+    ///
+    /// Sent with the ID [`ServerId::NONE`], after all others, so clients must look for it before
+    /// applying the rest of the message. Replaces shutdowns that have piled up faster than the
+    /// client could be sent them.
+    Reset,
+    /// The preceding `Update` carried only the start of the game server's state, which is this
+    /// many bytes long, at the client's [request](Request::TruncateStates)
+    Truncated(u32),
+}
+
+/// ISO 3166-1 alpha-2 code of the country a game server is in, e.g. `Region(*b"DE")`
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Region(pub [u8; 2]);
+
+impl Region {
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap_or("??")
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// First message from the meta server, on a unidirectional stream it opens as soon as the client
+/// connects, since [`PROTOCOL_V3`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    /// Free-form description of the meta server's version, for debugging
+    pub version: String,
+    pub limits: Limits,
+    /// Chosen at random when the meta server started
+    ///
+    /// [`ServerId`]s given by a meta server identify the same game servers across connections
+    /// for as long as it reports the same instance, but not after it restarts.
+    pub instance: u64,
+    /// Number of times a game server was added, changed, or removed since the meta server started,
+    /// as of the client connecting
+    ///
+    /// If this and `instance` are both unchanged since a previous connection, nothing the meta
+    /// server told the client over that connection has since gone out of date.
+    pub table_version: u64,
+}
+
+/// Optional message from a client, sent on a client-opened unidirectional stream before
+/// [`PROTOCOL_V3`]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Hello {
+    /// Minimum time the meta server should wait between updates
+    ///
+    /// Meta servers may wait longer than requested, may randomly vary the interval slightly, and
+    /// may send shutdown events sooner.
+    pub update_interval: Duration,
+}
+
+/// Message from a client, sent on a client-opened stream since [`PROTOCOL_V3`]
+///
+/// Clients may send any number of these, on separate streams. [`FindOne`](Self::FindOne) and
+/// [`Connect`](Self::Connect) are sent on bidirectional streams, on which the meta server replies
+/// with a [`Response`], and the others on unidirectional streams.
+///
+/// The meta server sends no [`Message`]s until it receives the first request on a unidirectional
+/// stream other than [`TruncateStates`](Self::TruncateStates), so that a filter applies from the
+/// initial snapshot onwards, and clients that only make queries don't receive them at all. Clients
+/// with nothing else to ask for may send `UpdateInterval(Duration::ZERO)`, which has no effect.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    /// See [`Hello::update_interval`]
+    UpdateInterval(Duration),
+    /// Only send information about game servers matching `filter`
+    ///
+    /// May be sent any number of times. Game servers that stop matching are reported as shut
+    /// down, and those that start matching as updated. The first message sent after the filter
+    /// takes effect carries [`Event::Subscribed`] with the same `generation`, which should increase
+    /// with each request. `pacing` only has an effect in a client's first request.
+    Subscribe {
+        filter: Filter,
+        generation: u64,
+        pacing: Option<Pacing>,
+    },
+    /// Pick a single game server matching `filter`, e.g. for a "quick play" button
+    FindOne { filter: Filter, strategy: Strategy },
+    /// Tell the game server with this ID that the client wants to connect, so both can try to
+    /// traverse NAT
+    ///
+    /// See [`game::Control::Introduce`](crate::game::Control::Introduce).
+    Connect { server_id: ServerId },
+    /// Send a fresh snapshot, starting with [`Event::Reset`], e.g. after the client lost track of
+    /// its state
+    ///
+    /// Rate-limited more strictly than other queries.
+    Resync,
+    /// Cut game server states longer than this many bytes short, or stop doing so if `None`,
+    /// e.g. to save bandwidth on a metered connection
+    ///
+    /// Applies to states sent after the request is received, and doesn't prompt the meta server
+    /// to start sending messages, so it may be sent first to apply to the initial snapshot. Each
+    /// truncated `Update` is followed by [`Event::Truncated`]. Answers to queries are never
+    /// truncated.
+    TruncateStates(Option<u32>),
+    /// Everything the meta server currently knows about the game server with this ID, e.g. to show
+    /// full details of a [truncated](Event::Truncated) state
+    ///
+    /// The meta server also asks the game server for a fresh heartbeat if its state is getting old.
+    GetServer { server_id: ServerId },
+}
+
+/// How to spread a client's initial snapshot over several messages, e.g. to leave bandwidth for
+/// other downloads
+///
+/// Shutdowns and changes to game servers already sent aren't delayed.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pacing {
+    /// Roughly how long to take
+    pub duration: Duration,
+    /// Which game servers to send first, with [`Strategy::Random`] meaning no particular order
+    pub order: Strategy,
 }
 
-/// ALPN ID for client connections
+/// How the meta server picks a game server for [`Request::FindOne`]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Any matching game server, at random
+    Random,
+    /// The matching game server with the smallest fraction of its player slots in use
+    ///
+    /// Only game servers whose state is a JSON object with numeric `players` and `max_players`
+    /// fields have a known load. Others are only picked if no matching game server has one.
+    LeastLoaded,
+}
+
+/// Reply to a [`Request`] made on a bidirectional stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub enum Response<'a> {
+    /// Answer to [`Request::FindOne`], or `None` if no game server matched
+    FindOne(#[serde(borrow)] Option<Found<'a>>),
+    /// The client made requests too often, and should try again later
+    RateLimited,
+    /// Answer to [`Request::Connect`], or `None` if the game server is unknown or doesn't accept
+    /// introductions
+    Introduced(Option<Introduction>),
+    /// Answer to [`Request::Resync`]
+    Resyncing,
+    /// Answer to [`Request::GetServer`], or `None` if the game server is unknown or not visible to
+    /// the client
+    Server(#[serde(borrow)] Option<Found<'a>>),
+}
+
+/// A game server that has been told a game client wants to connect
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Introduction {
+    /// The game server's address, as observed by the meta server
+    ///
+    /// Unlike the address in [`Event::Update`], the port is that of the game server's connection
+    /// to the meta server, so it may differ from the port it accepts game clients on.
+    pub address: SocketAddr,
+    /// Random value also given to the game server
+    pub token: u64,
+}
+
+/// A game server picked or looked up by the meta server, with everything a [`Message`] would say
+/// about it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Found<'a> {
+    pub id: ServerId,
+    pub address: SocketAddr,
+    pub state: &'a [u8],
+    pub region: Option<Region>,
+    #[serde(borrow, deserialize_with = "crate::wire::tags")]
+    pub tags: Vec<&'a str>,
+    /// See [`Event::LanAddresses`]
+    #[serde(deserialize_with = "crate::wire::lan_addresses")]
+    pub lan_addresses: Vec<SocketAddr>,
+}
+
+/// Prefix of [`Filter`] entries that match a game server's [`Region`] rather than its tags, e.g.
+/// `region:DE`
+pub const REGION_PREFIX: &str = "region:";
+
+/// Criteria for game servers' tags and regions
+///
+/// Entries starting with [`REGION_PREFIX`] are compared with the region the meta server looked up,
+/// ignoring ASCII case, so game servers can't claim a region by tagging themselves with it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Tags a game server must all have
+    #[serde(deserialize_with = "crate::wire::tags")]
+    pub required: Vec<String>,
+    /// Tags a game server must have none of
+    #[serde(deserialize_with = "crate::wire::tags")]
+    pub excluded: Vec<String>,
+}
+
+impl Filter {
+    /// Whether a game server with `tags` and no known region matches
+    pub fn matches<T: AsRef<str>>(&self, tags: &[T]) -> bool {
+        self.matches_in(tags, None)
+    }
+
+    /// Whether a game server with `tags` in `region` matches
+    pub fn matches_in<T: AsRef<str>>(&self, tags: &[T], region: Option<Region>) -> bool {
+        let has = |entry: &String| match entry.strip_prefix(REGION_PREFIX) {
+            Some(code) => region.is_some_and(|x| x.as_str().eq_ignore_ascii_case(code)),
+            None => tags.iter().any(|x| x.as_ref() == entry),
+        };
+        self.required.iter().all(has) && !self.excluded.iter().any(has)
+    }
+
+    /// Whether the filter is within the same limits as a game server's tags
+    pub fn is_valid(&self) -> bool {
+        crate::game::tags_valid(&self.required) && crate::game::tags_valid(&self.excluded)
+    }
+}
+
+/// ALPN ID for client connections using the original protocol
 pub const PROTOCOL: &[u8] = &[
     0xB6, 0x46, 0x55, 0x6E, 0x05, 0x65, 0xD0, 0x9C, 0xD2, 0xFA, 0xEE, 0x31, 0xFD, 0x8A, 0x0A, 0x95,
 ];
+
+/// ALPN ID for client connections that understand [`Event::Region`]
+pub const PROTOCOL_V2: &[u8] = &[
+    0x47, 0xC5, 0x97, 0x60, 0x1A, 0x2A, 0x12, 0xEB, 0xB0, 0xA1, 0x1F, 0x1B, 0xAC, 0x57, 0x7B, 0x36,
+];
+
+/// ALPN ID for client connections that understand [`Event::Tags`] and send [`Request`]s
+pub const PROTOCOL_V3: &[u8] = &[
+    0x08, 0xC2, 0xA6, 0x11, 0xC1, 0x64, 0xFF, 0x8B, 0xC3, 0x9B, 0x52, 0x02, 0xC4, 0xFF, 0x0B, 0x8B,
+];
+
+/// ALPN IDs for every version of the client protocol, newest first
+pub const PROTOCOLS: &[&[u8]] = &[PROTOCOL_V3, PROTOCOL_V2, PROTOCOL];
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_id_encodes_as_u64() {
+        let id = ServerId(0x0102_0304_0506_0708);
+        let encoded = bincode::serialize(&id).unwrap();
+        assert_eq!(encoded, bincode::serialize(&id.0).unwrap());
+        assert_eq!(bincode::deserialize::<ServerId>(&encoded).unwrap(), id);
+        assert_eq!(
+            bincode::serialize(&ServerId::NONE).unwrap(),
+            bincode::serialize(&u64::MAX).unwrap()
+        );
+    }
+
+    #[test]
+    fn server_entry_layout() {
+        let server = Server {
+            id: ServerId(7),
+            event: Event::Shutdown,
+        };
+        let encoded = bincode::serialize(&server).unwrap();
+        // The ID as a plain u64, followed by the event's variant index
+        assert_eq!(&encoded[..8], &7u64.to_le_bytes());
+        assert_eq!(&encoded[8..], &0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn region_filter() {
+        let filter = Filter {
+            required: vec!["region:de".into()],
+            excluded: vec![],
+        };
+        assert!(filter.matches_in(&["pvp"], Some(Region(*b"DE"))));
+        assert!(!filter.matches_in(&["pvp"], Some(Region(*b"FR"))));
+        assert!(!filter.matches_in(&["pvp"], None));
+        // Tags can't stand in for the looked-up region
+        assert!(!filter.matches_in(&["region:de"], None));
+
+        let filter = Filter {
+            required: vec![],
+            excluded: vec!["region:FR".into()],
+        };
+        assert!(filter.matches_in::<&str>(&[], Some(Region(*b"DE"))));
+        assert!(filter.matches_in::<&str>(&[], None));
+        assert!(!filter.matches_in::<&str>(&[], Some(Region(*b"FR"))));
+    }
+}
@@ -1,18 +1,304 @@
 //! Protocol for communication between game servers and meta servers
+//!
+//! Each version of the protocol has its own ALPN ID, listed in [`PROTOCOLS`]. In the original
+//! version, the game server sends a [`Hello`] followed by heartbeats, each a raw state on its own
+//! stream. Since [`PROTOCOL_V2`], it sends a [`HelloV2`] followed by [`Update`]s, and the meta
+//! server answers with a [`Welcome`] before any [`Control`] messages. Since [`PROTOCOL_V3`], the
+//! welcome is sent as a [`Greeting`], and may be preceded by a [`Challenge`], and states are sent
+//! as [`Update::SequencedState`].
+
+use std::{net::SocketAddr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+use crate::client::ServerId;
+pub use crate::Limits;
+
 /// Message sent by the game server on connect
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct Hello {
     /// The port game clients should connect to
+    ///
+    /// Must not be 0.
+    pub port: u16,
+}
+
+/// Message sent by the game server on connect, since [`PROTOCOL_V2`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloV2 {
+    /// The port game clients should connect to
+    ///
+    /// Must not be 0.
     pub port: u16,
+    /// Labels that game clients can filter game servers by, e.g. "eu" or "ranked"
+    ///
+    /// Must satisfy [`tags_valid`].
+    #[serde(deserialize_with = "crate::wire::tags")]
+    pub tags: Vec<String>,
+    /// Whether the meta server may send [`Control::Introduce`]
+    pub introductions: bool,
+    /// Addresses game clients on the same local network can connect to, e.g. when NAT doesn't
+    /// support connecting to the public address from behind it
+    ///
+    /// At most [`MAX_LAN_ADDRESSES`]. Only sent to game clients whose address, as observed by the
+    /// meta server, has the same IP as the game server's.
+    #[serde(deserialize_with = "crate::wire::lan_addresses")]
+    pub lan_addresses: Vec<SocketAddr>,
+}
+
+/// Message sent by the game server on a unidirectional stream it opens, since [`PROTOCOL_V2`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Update<'a> {
+    /// The game server's current state, as a heartbeat
+    State(&'a [u8]),
+    /// Replacement for the tags given in [`HelloV2`]
+    Tags(#[serde(deserialize_with = "crate::wire::tags")] Vec<String>),
+    /// Replacement for the port given in [`HelloV2`], which must not be 0
+    PortChange(u16),
+    /// The game server is shutting down, and will close the connection once this is received
+    Goodbye {
+        /// Explanation for the meta server's logs
+        reason: Option<&'a str>,
+    },
+    /// The game server's current state, as a heartbeat, since [`PROTOCOL_V3`]
+    ///
+    /// `seq` increases with each state sent over the connection. Meta servers ignore a state whose
+    /// `seq` isn't greater than that of every state received before it, so that a stream that's
+    /// delayed or processed out of order can't roll the state back.
+    SequencedState { seq: u64, state: &'a [u8] },
+}
+
+/// Most LAN addresses a game server may have
+pub const MAX_LAN_ADDRESSES: usize = 8;
+/// Most tags a game server may have
+pub const MAX_TAGS: usize = 16;
+/// Longest tag a game server may have, in bytes
+pub const MAX_TAG_LEN: usize = 32;
+/// Longest [`Update::Goodbye`] reason meta servers are guaranteed to accept, in bytes
+pub const MAX_REASON_LEN: usize = 256;
+
+/// Bytes that meta servers allow for encoding a message from a game server, beyond the state it
+/// may carry
+///
+/// Meta servers read each message up to their [`Limits::max_state_size`] plus this much, which is
+/// their [`Limits::max_message_size`]. Framing a state within the maximum as an [`Update`] never
+/// exceeds that, and neither does any other valid [`Update`] or [`HelloV2`], whatever the maximum
+/// state size.
+pub const MAX_UPDATE_OVERHEAD: usize = 1024;
+
+// Worst-case encodings, with 8 bytes per length prefix, 4 per enum tag, and 32 per address
+const _: () = assert!(4 + 8 + 8 <= MAX_UPDATE_OVERHEAD);
+const _: () = assert!(4 + 8 + MAX_TAGS * (8 + MAX_TAG_LEN) <= MAX_UPDATE_OVERHEAD);
+const _: () = assert!(4 + 1 + 8 + MAX_REASON_LEN <= MAX_UPDATE_OVERHEAD);
+const _: () = assert!(
+    2 + 8 + MAX_TAGS * (8 + MAX_TAG_LEN) + 1 + 8 + MAX_LAN_ADDRESSES * 32 <= MAX_UPDATE_OVERHEAD
+);
+
+/// Whether `tags` are within [`MAX_TAGS`] and each is nonempty and within [`MAX_TAG_LEN`]
+pub fn tags_valid<T: AsRef<str>>(tags: &[T]) -> bool {
+    tags.len() <= MAX_TAGS
+        && tags
+            .iter()
+            .all(|x| (1..=MAX_TAG_LEN).contains(&x.as_ref().len()))
+}
+
+/// First message sent by the meta server on a unidirectional stream it opens, once it accepts the
+/// game server's [`HelloV2`]
+///
+/// Since [`PROTOCOL_V3`], sent as [`Greeting::Welcome`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Welcome {
+    /// Free-form description of the meta server's version, for debugging
+    pub version: String,
+    pub limits: Limits,
+}
+
+/// Message sent by the meta server on a unidirectional stream it opens, in answer to the game
+/// server's [`HelloV2`], since [`PROTOCOL_V3`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Greeting {
+    /// The game server is registered
+    Welcome(Welcome),
+    /// The game server must prove work before it's registered, by sending a [`Solution`] on a
+    /// unidirectional stream it opens within [`Challenge::timeout`]
+    ///
+    /// The meta server then sends another `Greeting`, or closes the connection with
+    /// [`CloseCode::Rejected`](crate::CloseCode::Rejected) if the solution is wrong or late.
+    Challenge(Challenge),
+}
+
+/// Proof-of-work puzzle that meta servers may set game servers before registering them, e.g. to
+/// make registration floods expensive
+///
+/// Solved by a [`Solution::nonce`] such that the SHA-256 hash of `prefix` followed by the nonce,
+/// as a little-endian `u64`, begins with at least `difficulty` zero bits. Finding one takes
+/// `2^difficulty` attempts on average.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    /// Random value chosen for this challenge
+    pub prefix: [u8; 16],
+    /// At most [`MAX_DIFFICULTY`]
+    pub difficulty: u8,
+    /// How long the meta server waits for the solution
+    pub timeout: Duration,
+}
+
+/// Most leading zero bits a [`Challenge`] may require
+pub const MAX_DIFFICULTY: u8 = 32;
+
+/// Answer to a [`Challenge`], sent by the game server on a unidirectional stream it opens
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Solution {
+    pub nonce: u64,
+}
+
+/// Message sent by the meta server on a unidirectional stream it opens to a game server
+///
+/// Game servers should ignore streams that they can't decode, so that new messages can be added
+/// without breaking compatibility. Meta servers must tolerate game servers that never read these
+/// streams.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub enum Control {
+    /// The game server should send its current state promptly, e.g. because a new game client
+    /// wants to see it
+    RefreshRequest,
+    /// A game client wants to connect, and may need help traversing NAT
+    ///
+    /// Only sent since [`PROTOCOL_V2`], to game servers that accept introductions.
+    Introduce(Introduction),
+    /// Replacement for the limits given in the [`Welcome`], e.g. after the meta server's
+    /// configuration changed
+    ///
+    /// Only sent since [`PROTOCOL_V2`].
+    Limits(Limits),
+    /// The address game clients are told to connect to, as observed by the meta server
+    ///
+    /// Sent once the game server's first heartbeat makes it visible, and again whenever it changes,
+    /// e.g. due to NAT rebinding. Only sent since [`PROTOCOL_V2`].
+    ObservedAddress(SocketAddr),
+    /// The meta server stored the game server's first heartbeat, so game clients can now see it
+    ///
+    /// Sent once, before the first [`Control::ObservedAddress`]. Only sent since [`PROTOCOL_V2`].
+    Advertised(Advertisement),
+}
+
+/// How game clients see a game server, from [`Control::Advertised`]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Advertisement {
+    /// The ID game clients know the game server by
+    pub id: ServerId,
+    /// The address game clients are told to connect to, as observed by the meta server
+    pub address: SocketAddr,
 }
 
-pub struct Update {
+/// A game client that wants to connect to the game server
+///
+/// The game client is told the game server's address and the same token at the same time, so both
+/// sides may start sending packets to each other to open a path through NAT, e.g. including the
+/// token so the game server can recognize them.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Introduction {
+    /// The game client's address, as observed by the meta server
+    pub address: SocketAddr,
+    /// Random value also given to the game client
+    pub token: u64,
+}
+
+/// A game server state made of named sections, each from a different part of the game server,
+/// e.g. as assembled by `metaserve_heartbeat::StateComposer`
+///
+/// Meta servers treat states as opaque, so this is purely a convention between game servers and
+/// game clients. Encoded with `bincode`, as a sequence of name and contents pairs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sections<'a>(#[serde(borrow)] pub Vec<(&'a str, &'a [u8])>);
+
+impl<'a> Sections<'a> {
+    #[cfg(feature = "bincode")]
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decode a state encoded by [`encode`](Self::encode)
+    #[cfg(feature = "bincode")]
+    pub fn decode(state: &'a [u8]) -> bincode::Result<Self> {
+        crate::wire::check_count(state, 16)?;
+        crate::decode(state)
+    }
+
+    /// Contents of the first section named `name`, if any
+    pub fn get(&self, name: &str) -> Option<&'a [u8]> {
+        self.0.iter().find(|x| x.0 == name).map(|x| x.1)
+    }
 }
 
-/// ALPN ID for a game server's heartbeat connection
+/// ALPN ID for a game server's heartbeat connection using the original protocol
 pub const PROTOCOL: &[u8] = &[
     0x72, 0x7F, 0x4A, 0x53, 0x03, 0xDF, 0xDD, 0xB3, 0xAC, 0x79, 0x9E, 0x0F, 0x49, 0xB1, 0xE3, 0x60,
 ];
+
+/// ALPN ID for a game server's heartbeat connection with tags
+pub const PROTOCOL_V2: &[u8] = &[
+    0xFC, 0xDA, 0x22, 0x15, 0xDB, 0x44, 0xA7, 0x07, 0x76, 0x19, 0xBC, 0x0D, 0xF7, 0xB9, 0xFB, 0x54,
+];
+
+/// ALPN ID for a game server's heartbeat connection that can answer a [`Challenge`]
+pub const PROTOCOL_V3: &[u8] = &[
+    0x8D, 0x09, 0xB7, 0xFB, 0xFA, 0x62, 0x6D, 0x71, 0x9C, 0xC9, 0x70, 0xA3, 0x33, 0xEA, 0x44, 0x4F,
+];
+
+/// ALPN IDs for every version of the game server protocol, newest first
+pub const PROTOCOLS: &[&[u8]] = &[PROTOCOL_V3, PROTOCOL_V2, PROTOCOL];
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use super::*;
+
+    /// Every update, at the limits that apply to it
+    fn updates<'a>(state: &'a [u8], reason: &'a str) -> Vec<Update<'a>> {
+        let tag = "t".repeat(MAX_TAG_LEN);
+        vec![
+            Update::State(b""),
+            Update::State(state),
+            Update::SequencedState { seq: 0, state: b"" },
+            Update::SequencedState {
+                seq: u64::MAX,
+                state,
+            },
+            Update::Tags(Vec::new()),
+            Update::Tags(vec![tag; MAX_TAGS]),
+            Update::PortChange(1),
+            Update::PortChange(u16::MAX),
+            Update::Goodbye { reason: None },
+            Update::Goodbye {
+                reason: Some(reason),
+            },
+        ]
+    }
+
+    #[test]
+    fn update_round_trip() {
+        let state = vec![0xAB; 64 * 1024];
+        let reason = "r".repeat(MAX_REASON_LEN);
+        for update in updates(&state, &reason) {
+            let encoded = bincode::serialize(&update).unwrap();
+            assert_eq!(crate::decode::<Update<'_>>(&encoded).unwrap(), update);
+            let payload = match update {
+                Update::State(x) | Update::SequencedState { state: x, .. } => x.len(),
+                _ => 0,
+            };
+            assert!(encoded.len() - payload <= MAX_UPDATE_OVERHEAD, "{update:?}");
+            // Truncated anywhere, fails rather than decoding as something else
+            for len in 0..encoded.len().min(64) {
+                assert!(crate::decode::<Update<'_>>(&encoded[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn update_tags_bounded() {
+        let too_many = bincode::serialize(&Update::Tags(vec!["t".into(); MAX_TAGS + 1])).unwrap();
+        assert!(crate::decode::<Update<'_>>(&too_many).is_err());
+        let unknown = bincode::serialize(&(5u32, 0u64)).unwrap();
+        assert!(crate::decode::<Update<'_>>(&unknown).is_err());
+    }
+}
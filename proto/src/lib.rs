@@ -1,2 +1,64 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
 pub mod client;
 pub mod game;
+pub mod record;
+mod wire;
+
+#[cfg(feature = "bincode")]
+pub use wire::decode;
+
+/// Limits the meta server enforces, sent to peers in its welcome so they needn't discover them by
+/// exceeding them
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest game server state accepted, in bytes
+    ///
+    /// Excludes the encoding of the message carrying the state, which is allowed for separately by
+    /// [`game::MAX_UPDATE_OVERHEAD`].
+    pub max_state_size: u32,
+    /// Least time between heartbeats that the meta server acts on, with earlier ones held back
+    pub heartbeat_min_interval: Duration,
+    /// Least time between updates sent to game clients
+    pub client_update_interval: Duration,
+    /// Largest message the meta server reads from this peer, in bytes
+    pub max_message_size: u32,
+}
+
+/// Application error codes with which connections in either protocol may be closed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+#[non_exhaustive]
+pub enum CloseCode {
+    /// Nothing went wrong
+    Normal = 0,
+    /// The meta server won't serve this connection, e.g. because it's out of resources
+    Rejected = 1,
+    /// A new connection from the same game server replaced this one
+    Superseded = 2,
+    /// The peer sent a malformed or nonsensical message
+    ProtocolViolation = 3,
+    /// The meta server is about to shut down, so the peer should reconnect later or elsewhere
+    Draining = 4,
+}
+
+impl CloseCode {
+    /// The code as sent on the wire
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+
+    /// Interpret a code received from the peer, if known
+    pub fn from_code(code: u64) -> Option<Self> {
+        Some(match code {
+            0 => Self::Normal,
+            1 => Self::Rejected,
+            2 => Self::Superseded,
+            3 => Self::ProtocolViolation,
+            4 => Self::Draining,
+            _ => return None,
+        })
+    }
+}
@@ -0,0 +1,24 @@
+//! File format for recordings of the messages a game client receives from a meta server
+//!
+//! A recording begins with [`MAGIC`], followed by the format version as a little-endian `u32`,
+//! currently [`VERSION`], and the version of the client protocol the messages were received with,
+//! e.g. 3 for [`PROTOCOL_V3`](crate::client::PROTOCOL_V3), as a little-endian `u32`. The remainder
+//! of the file is a sequence of records, each consisting of:
+//!
+//! - the time at which the message was received, in microseconds since recording began, as a
+//!   little-endian `u64`
+//! - the length of the message, as a little-endian `u32`
+//! - the message itself, a [`client::Message`](crate::client::Message) encoded exactly as it was
+//!   received
+//!
+//! Version 1 recordings lack the client protocol version, and their messages are all encoded as
+//! before [`PROTOCOL_V3`](crate::client::PROTOCOL_V3).
+//!
+//! A recording that ends partway through a record was truncated, e.g. by a crash, and should be
+//! treated as ending after the last complete record.
+
+/// Identifies a file as a recording
+pub const MAGIC: [u8; 8] = *b"msrecord";
+
+/// Version of the recording format described by this module
+pub const VERSION: u32 = 2;
@@ -0,0 +1,140 @@
+//! Decoding that allocates no more than the input justifies, even if its length prefixes lie
+
+use std::{fmt, marker::PhantomData};
+
+#[cfg(feature = "bincode")]
+use bincode::Options;
+use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Visitor};
+
+use crate::game::{MAX_LAN_ADDRESSES, MAX_TAGS};
+
+/// Decode `data` as `bincode::deserialize` would, but fail on any length prefix that exceeds what
+/// remains of `data` before allocating for it
+#[cfg(feature = "bincode")]
+pub fn decode<'a, T: Deserialize<'a>>(data: &'a [u8]) -> bincode::Result<T> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(data.len() as u64)
+        .deserialize(data)
+}
+
+/// Fail unless `data` could hold as many elements as the sequence it begins with declares,
+/// assuming each takes at least `min_size` bytes
+#[cfg(feature = "bincode")]
+pub(crate) fn check_count(data: &[u8], min_size: usize) -> bincode::Result<()> {
+    let count = decode::<u64>(data)?;
+    if count > ((data.len() - 8) / min_size) as u64 {
+        return Err(bincode::ErrorKind::Custom(format!(
+            "{} elements declared in {} bytes",
+            count,
+            data.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Deserialize a sequence of at most [`MAX_TAGS`] elements
+pub(crate) fn tags<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    deserializer.deserialize_seq(Bounded(MAX_TAGS, PhantomData))
+}
+
+/// Deserialize a sequence of at most [`MAX_LAN_ADDRESSES`] elements
+pub(crate) fn lan_addresses<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    deserializer.deserialize_seq(Bounded(MAX_LAN_ADDRESSES, PhantomData))
+}
+
+/// Visits a sequence of at most `.0` elements, allocating for no more
+struct Bounded<T>(usize, PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for Bounded<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a sequence of at most {} elements", self.0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let len = seq.size_hint().unwrap_or(0);
+        if len > self.0 {
+            return Err(A::Error::invalid_length(len, &self));
+        }
+        let mut result = Vec::with_capacity(len);
+        while let Some(x) = seq.next_element()? {
+            if result.len() == self.0 {
+                return Err(A::Error::invalid_length(result.len() + 1, &self));
+            }
+            result.push(x);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Tagged {
+        #[serde(deserialize_with = "tags")]
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn decode_matches_bincode() {
+        let value = (7u32, vec![1u16, 2, 3], "text");
+        let data = bincode::serialize(&value).unwrap();
+        assert_eq!(decode::<(u32, Vec<u16>, &str)>(&data).unwrap(), value);
+        // Trailing bytes are ignored, as by `bincode::deserialize`
+        let mut padded = data.clone();
+        padded.push(0);
+        assert_eq!(decode::<(u32, Vec<u16>, &str)>(&padded).unwrap(), value);
+    }
+
+    #[test]
+    fn decode_rejects_lying_lengths() {
+        for len in [u64::MAX, 1 << 40, 9] {
+            let mut data = len.to_le_bytes().to_vec();
+            data.extend_from_slice(&[0; 8]);
+            assert!(decode::<Vec<u8>>(&data).is_err());
+            assert!(decode::<&[u8]>(&data).is_err());
+            assert!(decode::<String>(&data).is_err());
+        }
+        assert!(decode::<u64>(&[0; 7]).is_err());
+    }
+
+    #[test]
+    fn check_count_bounds() {
+        let data = |count: u64, len: usize| {
+            let mut data = count.to_le_bytes().to_vec();
+            data.resize(8 + len, 0);
+            data
+        };
+        // Exactly enough room for each element's minimum size
+        check_count(&data(4, 48), 12).unwrap();
+        check_count(&data(4, 50), 12).unwrap();
+        check_count(&data(0, 0), 12).unwrap();
+        assert!(check_count(&data(5, 48), 12).is_err());
+        assert!(check_count(&data(1, 11), 12).is_err());
+        assert!(check_count(&data(u64::MAX, 1024), 1).is_err());
+        assert!(check_count(&[0; 7], 1).is_err());
+    }
+
+    #[test]
+    fn bounded_sequences() {
+        let tags = |n| Tagged {
+            tags: (0..n).map(|x| x.to_string()).collect(),
+        };
+        let data = bincode::serialize(&tags(MAX_TAGS)).unwrap();
+        assert_eq!(decode::<Tagged>(&data).unwrap(), tags(MAX_TAGS));
+        let data = bincode::serialize(&tags(MAX_TAGS + 1)).unwrap();
+        assert!(decode::<Tagged>(&data).is_err());
+    }
+}
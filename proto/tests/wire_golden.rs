@@ -0,0 +1,566 @@
+//! Messages encode exactly as the fixtures in `proto/fixtures` say they did
+//!
+//! Each fixture is named for the latest version of the protocol it belongs to, as of when it was
+//! written, and frozen by its digest in `fixtures/frozen.txt`. An intentional change to the
+//! encoding must come with a new protocol version, after which running these tests with `BLESS=1`
+//! writes fresh fixtures for it alongside the old ones. Blessing never overwrites a fixture.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use metaserve_proto::{
+    client::{self, Event, ServerId},
+    game, Limits,
+};
+use serde::Serialize;
+
+#[test]
+fn encodings_match_fixtures() {
+    let _guard = lock();
+    let bless = env::var_os("BLESS").is_some();
+    let mut frozen = read_frozen();
+    let mut failures = String::new();
+    for (name, encoded) in samples() {
+        match fs::read(dir().join(&name)) {
+            Ok(expected) if expected == encoded => {}
+            Ok(expected) => {
+                writeln!(failures, "{}: encoding changed", name).unwrap();
+                failures.push_str(&diff(&expected, &encoded));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && bless => {
+                fs::write(dir().join(&name), &encoded).unwrap();
+            }
+            Err(e) => writeln!(failures, "{}: {}", name, e).unwrap(),
+        }
+    }
+    if bless {
+        // Freeze new fixtures, leaving the recorded digests of old ones be
+        for name in fixtures() {
+            let path = dir().join(&name);
+            frozen
+                .entry(name)
+                .or_insert_with(|| digest(&fs::read(path).unwrap()));
+        }
+        write_frozen(&frozen);
+    }
+    assert!(
+        failures.is_empty(),
+        "{}encodings may only change with a new protocol version, whose fixtures BLESS=1 writes",
+        failures
+    );
+}
+
+#[test]
+fn fixtures_are_frozen() {
+    let _guard = lock();
+    let mut frozen = read_frozen();
+    let mut failures = String::new();
+    for name in fixtures() {
+        if let Err(e) = check_version(&name) {
+            writeln!(failures, "{}: {}", name, e).unwrap();
+        }
+        let actual = digest(&fs::read(dir().join(&name)).unwrap());
+        match frozen.remove(&name) {
+            Some(x) if x == actual => {}
+            Some(_) => writeln!(
+                failures,
+                "{}: changed since it was written; record a new encoding under a new protocol \
+                 version instead",
+                name
+            )
+            .unwrap(),
+            None => writeln!(
+                failures,
+                "{}: not in frozen.txt; write it with BLESS=1",
+                name
+            )
+            .unwrap(),
+        }
+    }
+    for name in frozen.keys() {
+        writeln!(failures, "{}: missing", name).unwrap();
+    }
+    assert!(failures.is_empty(), "{}", failures);
+}
+
+/// Check that a fixture is named for a protocol and one of its versions
+fn check_version(name: &str) -> Result<(), String> {
+    let (protocol, rest) = name.split_once("-v").ok_or("no protocol version in name")?;
+    let latest = match protocol {
+        "client" => client::PROTOCOLS.len(),
+        "game" => game::PROTOCOLS.len(),
+        _ => return Err(format!("unknown protocol {:?}", protocol)),
+    };
+    let version = rest
+        .split_once('-')
+        .and_then(|(x, _)| x.parse::<usize>().ok())
+        .ok_or("no protocol version in name")?;
+    if !(1..=latest).contains(&version) {
+        return Err(format!(
+            "no version {} of the {} protocol",
+            version, protocol
+        ));
+    }
+    Ok(())
+}
+
+/// Names of the files in `dir` holding encoded messages
+fn fixtures() -> Vec<String> {
+    fs::read_dir(dir())
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .filter(|x| x.ends_with(".bin"))
+        .collect()
+}
+
+fn dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// Serialize access to the fixtures, which blessing modifies
+fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Digest of every fixture, by name
+fn read_frozen() -> BTreeMap<String, u64> {
+    let text = fs::read_to_string(dir().join("frozen.txt")).unwrap_or_default();
+    text.lines()
+        .filter(|x| !x.starts_with('#') && !x.is_empty())
+        .map(|line| {
+            let (digest, name) = line.split_once(' ').expect("malformed frozen.txt");
+            (name.into(), u64::from_str_radix(digest, 16).unwrap())
+        })
+        .collect()
+}
+
+fn write_frozen(frozen: &BTreeMap<String, u64>) {
+    let mut text = String::from(FROZEN_HEADER);
+    for (name, digest) in frozen {
+        writeln!(text, "{:016x} {}", digest, name).unwrap();
+    }
+    fs::write(dir().join("frozen.txt"), text).unwrap();
+}
+
+const FROZEN_HEADER: &str = "\
+# FNV-1a digests of the wire fixtures, which must never change. Written by running the wire_golden
+# tests with BLESS=1, which only adds fixtures.
+";
+
+/// 64-bit FNV-1a, which unlike `std`'s hashers is guaranteed not to change
+fn digest(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &x| {
+        (hash ^ u64::from(x)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// File name and encoding of each representative message
+fn samples() -> Vec<(String, Vec<u8>)> {
+    let client_version = client::PROTOCOLS.len() as u32;
+    let game_version = game::PROTOCOLS.len() as u32;
+    let mut out = Vec::new();
+    let mut client = |name: &str, encoded: Vec<u8>| {
+        out.push((format!("client-v{}-{}.bin", client_version, name), encoded))
+    };
+
+    let v4: SocketAddr = "192.0.2.1:27015".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:27015".parse().unwrap();
+    // Largest state the meta server accepts by default
+    let max_state = (0..8192).map(|x| x as u8).collect::<Vec<u8>>();
+    let max_tags = (0..game::MAX_TAGS)
+        .map(|i| format!("{:x<1$}", i, game::MAX_TAG_LEN))
+        .collect::<Vec<_>>();
+    let max_lan = (0..game::MAX_LAN_ADDRESSES as u16)
+        .map(|i| SocketAddr::new([10, 0, 0, i as u8 + 1].into(), 27015 + i))
+        .collect::<Vec<_>>();
+    let limits = Limits {
+        max_state_size: 8192,
+        heartbeat_min_interval: Duration::from_secs(1),
+        client_update_interval: Duration::from_millis(500),
+        max_message_size: 8192 + game::MAX_UPDATE_OVERHEAD as u32,
+    };
+    let filter = client::Filter {
+        required: vec!["eu".into()],
+        excluded: vec!["modded".into()],
+    };
+
+    let message = |servers: Vec<client::Server<'_>>| {
+        client::Message {
+            servers,
+            undecodable: Vec::new(),
+        }
+        .encode(client_version)
+    };
+    let server = |id: u64, event| client::Server {
+        id: ServerId(id),
+        event,
+    };
+    let none = ServerId::NONE.0;
+    client("message-empty", message(Vec::new()));
+    client(
+        "message-ipv4",
+        message(vec![
+            server(0, Event::Update(v4, b"state")),
+            server(0, Event::Region(Some(client::Region(*b"DE")))),
+            server(0, Event::Tags(vec!["eu", "ranked"])),
+            server(
+                0,
+                Event::LanAddresses(vec!["10.0.0.2:27015".parse().unwrap()]),
+            ),
+        ]),
+    );
+    client(
+        "message-ipv6",
+        message(vec![
+            server(1, Event::Update(v6, b"")),
+            server(1, Event::Region(None)),
+            server(1, Event::Tags(Vec::new())),
+        ]),
+    );
+    client(
+        "message-max",
+        message(vec![
+            server(2, Event::Update(v4, &max_state)),
+            server(2, Event::Tags(max_tags.iter().map(|x| &x[..]).collect())),
+            server(2, Event::LanAddresses(max_lan.clone())),
+        ]),
+    );
+    client(
+        "message-control",
+        message(vec![
+            server(3, Event::Shutdown),
+            server(none, Event::Reset),
+            server(none, Event::Limits(limits)),
+            server(none, Event::Subscribed(7)),
+            server(
+                none,
+                Event::SnapshotProgress {
+                    delivered: 1,
+                    total: 2,
+                },
+            ),
+            server(none, Event::Synchronized),
+        ]),
+    );
+    client(
+        "message-truncated",
+        message(vec![
+            server(4, Event::Update(v4, &max_state[..16])),
+            server(4, Event::Region(None)),
+            server(4, Event::Tags(Vec::new())),
+            server(4, Event::Truncated(max_state.len() as u32)),
+        ]),
+    );
+    client(
+        "message-pre-v3",
+        client::Message {
+            servers: vec![
+                server(0, Event::Update(v4, b"state")),
+                server(0, Event::Region(Some(client::Region(*b"DE")))),
+                server(1, Event::Update(v6, b"")),
+                server(3, Event::Shutdown),
+            ],
+            undecodable: Vec::new(),
+        }
+        .encode(2),
+    );
+    client(
+        "message-corrupt-entry",
+        client::Message::encode_entries(3, &corrupt_entries()),
+    );
+    client(
+        "hello",
+        encode(&client::Hello {
+            update_interval: Duration::from_millis(1500),
+        }),
+    );
+    client(
+        "welcome",
+        encode(&client::Welcome {
+            version: "metaserve 0.1.0".into(),
+            limits,
+            instance: 0x0123_4567_89ab_cdef,
+            table_version: 42,
+        }),
+    );
+    client(
+        "request-update-interval",
+        encode(&client::Request::UpdateInterval(Duration::ZERO)),
+    );
+    client(
+        "request-subscribe",
+        encode(&client::Request::Subscribe {
+            filter: filter.clone(),
+            generation: 1,
+            pacing: Some(client::Pacing {
+                duration: Duration::from_secs(2),
+                order: client::Strategy::LeastLoaded,
+            }),
+        }),
+    );
+    client(
+        "request-find-one",
+        encode(&client::Request::FindOne {
+            filter,
+            strategy: client::Strategy::Random,
+        }),
+    );
+    client(
+        "request-connect",
+        encode(&client::Request::Connect {
+            server_id: ServerId(2),
+        }),
+    );
+    client("request-resync", encode(&client::Request::Resync));
+    client(
+        "request-truncate-states",
+        encode(&client::Request::TruncateStates(Some(256))),
+    );
+    client(
+        "request-get-server",
+        encode(&client::Request::GetServer {
+            server_id: ServerId(2),
+        }),
+    );
+    client(
+        "response-find-one",
+        encode(&client::Response::FindOne(Some(client::Found {
+            id: ServerId(1),
+            address: v6,
+            state: b"state",
+            region: Some(client::Region(*b"US")),
+            tags: vec!["eu"],
+            lan_addresses: vec!["[fd00::2]:27015".parse().unwrap()],
+        }))),
+    );
+    client(
+        "response-find-none",
+        encode(&client::Response::FindOne(None)),
+    );
+    client(
+        "response-rate-limited",
+        encode(&client::Response::RateLimited),
+    );
+    client(
+        "response-introduced",
+        encode(&client::Response::Introduced(Some(client::Introduction {
+            address: v4,
+            token: 0xfedc_ba98_7654_3210,
+        }))),
+    );
+    client("response-resyncing", encode(&client::Response::Resyncing));
+    client(
+        "response-server",
+        encode(&client::Response::Server(Some(client::Found {
+            id: ServerId(4),
+            address: v4,
+            state: &max_state,
+            region: None,
+            tags: Vec::new(),
+            lan_addresses: Vec::new(),
+        }))),
+    );
+    client(
+        "response-server-not-found",
+        encode(&client::Response::Server(None)),
+    );
+
+    let mut game = |name: &str, encoded: Vec<u8>| {
+        out.push((format!("game-v{}-{}.bin", game_version, name), encoded))
+    };
+    game("hello", encode(&game::Hello { port: 27015 }));
+    game(
+        "hello-v2-ipv4",
+        encode(&game::HelloV2 {
+            port: 27015,
+            tags: vec!["eu".into(), "ranked".into()],
+            introductions: true,
+            lan_addresses: vec!["10.0.0.2:27015".parse().unwrap()],
+        }),
+    );
+    game(
+        "hello-v2-ipv6",
+        encode(&game::HelloV2 {
+            port: 27015,
+            tags: Vec::new(),
+            introductions: false,
+            lan_addresses: vec!["[fd00::2]:27015".parse().unwrap()],
+        }),
+    );
+    game(
+        "hello-v2-max",
+        encode(&game::HelloV2 {
+            port: u16::MAX,
+            tags: max_tags.clone(),
+            introductions: true,
+            lan_addresses: max_lan,
+        }),
+    );
+    game("update-state-empty", encode(&game::Update::State(b"")));
+    game("update-state-max", encode(&game::Update::State(&max_state)));
+    game(
+        "update-sequenced-state",
+        encode(&game::Update::SequencedState {
+            seq: 7,
+            state: b"state",
+        }),
+    );
+    game("update-tags", encode(&game::Update::Tags(max_tags)));
+    game(
+        "update-port-change",
+        encode(&game::Update::PortChange(27016)),
+    );
+    game(
+        "update-goodbye",
+        encode(&game::Update::Goodbye {
+            reason: Some("restarting"),
+        }),
+    );
+    game(
+        "update-goodbye-silent",
+        encode(&game::Update::Goodbye { reason: None }),
+    );
+    let welcome = game::Welcome {
+        version: "metaserve 0.1.0".into(),
+        limits,
+    };
+    // As sent before PROTOCOL_V3
+    game("welcome", encode(&welcome));
+    game(
+        "greeting-welcome",
+        encode(&game::Greeting::Welcome(welcome)),
+    );
+    game(
+        "greeting-challenge",
+        encode(&game::Greeting::Challenge(game::Challenge {
+            prefix: *b"0123456789abcdef",
+            difficulty: 16,
+            timeout: Duration::from_secs(10),
+        })),
+    );
+    game(
+        "solution",
+        encode(&game::Solution {
+            nonce: 0x0123_4567_89ab_cdef,
+        }),
+    );
+    game("control-refresh", encode(&game::Control::RefreshRequest));
+    game(
+        "control-introduce",
+        encode(&game::Control::Introduce(game::Introduction {
+            address: v6,
+            token: 0xfedc_ba98_7654_3210,
+        })),
+    );
+    game("control-limits", encode(&game::Control::Limits(limits)));
+    game(
+        "control-observed-address",
+        encode(&game::Control::ObservedAddress(v4)),
+    );
+    game(
+        "control-advertised",
+        encode(&game::Control::Advertised(game::Advertisement {
+            id: ServerId(2),
+            address: v6,
+        })),
+    );
+    game(
+        "sections",
+        game::Sections(vec![("players", b"\x03"), ("map", b"de_dust2")]).encode(),
+    );
+
+    out
+}
+
+/// Entries of a message in which the middle one, about server 6, is corrupt, between two intact
+/// updates of servers 5 and 7
+fn corrupt_entries() -> Vec<u8> {
+    let mut entries = Vec::new();
+    let update = |id, event| client::Server {
+        id: ServerId(id),
+        event,
+    };
+    let v4 = "192.0.2.1:27015".parse().unwrap();
+    update(5, Event::Update(v4, b"before")).encode_entry(&mut entries);
+    entries.extend_from_slice(&(CORRUPT_ENTRY.len() as u64).to_le_bytes());
+    entries.extend_from_slice(CORRUPT_ENTRY);
+    update(7, Event::Update(v4, b"after")).encode_entry(&mut entries);
+    entries
+}
+
+/// Server 6's ID, followed by an event number that no version defines
+const CORRUPT_ENTRY: &[u8] = b"\x06\0\0\0\0\0\0\0\xef\xbe\xad\xde";
+
+/// A corrupt entry is set aside, with its ID, and the entries either side of it still decode
+#[test]
+fn corrupt_entry_is_set_aside() {
+    let _guard = lock();
+    let data = fs::read(dir().join("client-v3-message-corrupt-entry.bin")).unwrap();
+    let msg = client::Message::decode(&data, 3).unwrap();
+    let decoded = msg
+        .servers
+        .iter()
+        .map(|x| match x.event {
+            Event::Update(_, state) => (x.id, state),
+            ref e => panic!("unexpected {:?}", e),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        decoded,
+        [(ServerId(5), &b"before"[..]), (ServerId(7), &b"after"[..])]
+    );
+    assert_eq!(msg.undecodable.len(), 1);
+    assert_eq!(msg.undecodable[0].id, Some(ServerId(6)));
+    assert_eq!(msg.undecodable[0].bytes, CORRUPT_ENTRY);
+}
+
+/// Encode `msg` as the meta server and the client libraries do
+fn encode<T: Serialize>(msg: &T) -> Vec<u8> {
+    bincode::serialize(msg).unwrap()
+}
+
+/// Hex dump of the 16-byte rows that differ between `expected` and `actual`
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    const MAX_ROWS: usize = 8;
+    let mut out = String::new();
+    if expected.len() != actual.len() {
+        writeln!(out, "  length {} -> {}", expected.len(), actual.len()).unwrap();
+    }
+    let rows = |x: &[u8]| x.chunks(16).map(<[u8]>::to_vec).collect::<Vec<_>>();
+    let (expected, actual) = (rows(expected), rows(actual));
+    let mut shown = 0;
+    for i in 0..expected.len().max(actual.len()) {
+        let (old, new) = (expected.get(i), actual.get(i));
+        if old == new {
+            continue;
+        }
+        if shown == MAX_ROWS {
+            writeln!(out, "  ...").unwrap();
+            break;
+        }
+        shown += 1;
+        for (sign, row) in [('-', old), ('+', new)] {
+            if let Some(row) = row {
+                let hex = row.iter().map(|x| format!("{:02x}", x));
+                writeln!(
+                    out,
+                    "  {} {:06x}: {}",
+                    sign,
+                    i * 16,
+                    hex.collect::<Vec<_>>().join(" ")
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,31 @@
+#!/bin/sh
+# Check that each documented combination of library features builds without warnings
+set -eu
+cd "$(dirname "$0")/.."
+
+check() {
+    echo "== $*"
+    cargo clippy --all-targets "$@" -- -D warnings
+}
+
+check -p metaserve-proto --no-default-features
+check -p metaserve-proto
+
+check -p metaserve-client --no-default-features
+check -p metaserve-client --no-default-features --features net
+for runtime in tokio smol async-std; do
+    check -p metaserve-client --no-default-features --features "$runtime"
+    check -p metaserve-client --no-default-features --features "$runtime,helpers"
+done
+check -p metaserve-client
+check -p metaserve-client --no-default-features --features web --target wasm32-unknown-unknown
+
+check -p metaserve-heartbeat --no-default-features
+check -p metaserve-heartbeat --no-default-features --features helpers
+for runtime in tokio smol async-std; do
+    check -p metaserve-heartbeat --no-default-features --features "$runtime"
+    check -p metaserve-heartbeat --no-default-features --features "$runtime,helpers"
+done
+check -p metaserve-heartbeat
+
+check -p metaserve-bevy